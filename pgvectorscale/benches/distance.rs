@@ -1,7 +1,7 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use vectorscale::access_method::distance::{
     distance_cosine, distance_l2, distance_l2_optimized_for_few_dimensions,
-    distance_l2_unoptimized, distance_xor_optimized,
+    distance_l2_unoptimized, distance_xor_optimized, distances_xor_optimized,
 };
 
 //copy and use qdrants simd code, purely for benchmarking purposes
@@ -331,6 +331,37 @@ fn benchmark_distance_xor(c: &mut Criterion) {
     });
 }
 
+fn benchmark_distance_xor_batch(c: &mut Criterion) {
+    /* the neighbor vectors of a high-degree node: 100 neighbors of 1536 dimensions */
+    let query = pack_bools_to_u64((0..1536).map(|v| v % 2 == 0).collect());
+    let neighbors: Vec<Vec<u64>> = (0..100)
+        .map(|n| pack_bools_to_u64((0..1536).map(|v| (v + n) % 3 == 0).collect()))
+        .collect();
+    let neighbor_slices: Vec<&[u64]> = neighbors.iter().map(|n| n.as_slice()).collect();
+
+    let mut group = c.benchmark_group("Distance xor neighbor list");
+    group.bench_function("one at a time", |b| {
+        b.iter(|| {
+            let distances: Vec<usize> = black_box(&neighbor_slices)
+                .iter()
+                .map(|n| distance_xor_optimized(black_box(&query), n))
+                .collect();
+            distances
+        })
+    });
+    group.bench_function("batched", |b| {
+        b.iter(|| {
+            let mut distances = Vec::with_capacity(neighbor_slices.len());
+            distances_xor_optimized(
+                black_box(&query),
+                black_box(&neighbor_slices),
+                &mut distances,
+            );
+            distances
+        })
+    });
+}
+
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 criterion_group!(
     benches,
@@ -339,6 +370,7 @@ criterion_group!(
     benchmark_distance_x86_unaligned_vectors,
     benchmark_distance_x86_aligned_vectors,
     benchmark_distance_xor,
+    benchmark_distance_xor_batch,
 );
 #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
 criterion_group!(
@@ -346,6 +378,7 @@ criterion_group!(
     benchmark_distance,
     benchmark_distance_few_dimensions,
     benchmark_distance_xor,
+    benchmark_distance_xor_batch,
 );
 
 criterion_main!(benches);
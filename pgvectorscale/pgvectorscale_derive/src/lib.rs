@@ -1,7 +1,9 @@
 use proc_macro::TokenStream;
 use quote::{format_ident, quote};
 
-#[proc_macro_derive(Readable)]
+/// An optional `#[page_type(...)]` attribute names the PageType of the pages the struct is
+/// stored on. `read` then errors out on items of other pages, e.g. from a corrupted pointer.
+#[proc_macro_derive(Readable, attributes(page_type))]
 pub fn readable_macro_derive(input: TokenStream) -> TokenStream {
     // Construct a representation of Rust code as a syntax tree
     // that we can manipulate
@@ -21,6 +23,13 @@ fn impl_readable_macro(ast: &syn::DeriveInput) -> TokenStream {
     let name = &ast.ident;
     let readable_name = format_ident!("Readable{}", name);
     let archived_name = format_ident!("Archived{}", name);
+    let read_bytes = match ast.attrs.iter().find(|a| a.path.is_ident("page_type")) {
+        Some(attr) => {
+            let page_type: syn::Ident = attr.parse_args().unwrap();
+            quote! { index_pointer.read_bytes_of_type(index, crate::util::page::PageType::#page_type) }
+        }
+        None => quote! { index_pointer.read_bytes(index) },
+    };
     let gen = quote! {
         pub struct #readable_name<'a> {
             _rb: ReadableBuffer<'a>,
@@ -45,7 +54,7 @@ fn impl_readable_macro(ast: &syn::DeriveInput) -> TokenStream {
 
         impl #name {
             pub unsafe fn read<'a, 'b, S: crate::access_method::stats::StatsNodeRead>(index: &'a PgRelation, index_pointer: ItemPointer, stats: &'b mut S) -> #readable_name<'a> {
-                let rb = index_pointer.read_bytes(index);
+                let rb = #read_bytes;
                 stats.record_read();
                 #readable_name::with_readable_buffer(rb)
             }
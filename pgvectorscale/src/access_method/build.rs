@@ -1,27 +1,42 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
 use std::time::Instant;
 
 use pgrx::pg_sys::{pgstat_progress_update_param, AsPgCStr};
 use pgrx::*;
+use rand::Rng;
 
-use crate::access_method::graph::Graph;
+use crate::access_method::graph::{Graph, IndexType};
 use crate::access_method::graph_neighbor_store::GraphNeighborStore;
 use crate::access_method::options::TSVIndexOptions;
 use crate::access_method::pg_vector::PgVector;
-use crate::access_method::stats::{InsertStats, WriteStats};
+use crate::access_method::stats::{GreedySearchStats, InsertStats, PruneNeighborStats, WriteStats};
 
 use crate::util::page::PageType;
+use crate::util::table_slot::TableSlot;
 use crate::util::tape::Tape;
 use crate::util::*;
 
 use self::ports::PROGRESS_CREATE_IDX_SUBPHASE;
 
+use super::drift;
 use super::graph_neighbor_store::BuilderNeighborCache;
 use super::sbq::SbqSpeedupStorage;
 
 use super::meta_page::MetaPage;
+use super::neighbor_with_distance::NeighborWithDistance;
 
 use super::plain_storage::PlainStorage;
-use super::storage::{Storage, StorageType};
+use super::storage::{ArchivedData, NodeDistanceMeasure, Storage, StorageType};
+use super::storage_common::{
+    check_index_owner, check_is_diskann_index, for_each_node, get_attribute_number_from_index,
+    with_node,
+};
+
+/// The most dimensions a diskann index can be built on: the nodes of the plain layout, which
+/// hold the whole vector, must fit on a page.
+pub const MAX_DIMENSIONS: i32 = 1999;
 
 enum StorageBuildState<'a, 'b, 'c, 'd, 'e> {
     SbqSpeedup(&'a mut SbqSpeedupStorage<'b>, &'c mut BuildState<'d, 'e>),
@@ -36,16 +51,89 @@ struct BuildState<'a, 'b> {
     graph: Graph<'b>,
     started: Instant,
     stats: InsertStats,
+    /// The rows of a locality_layout build whose nodes are not created yet, see
+    /// write_locality_buffer().
+    locality_buffer: Option<LocalityBuffer>,
+    /// The distinct vectors of a collapse_duplicate_vectors build, see insert_duplicate().
+    duplicates: Option<DuplicateVectors<'a>>,
+}
+
+/// The distinct vectors of a collapse_duplicate_vectors build.
+struct DuplicateVectors<'a> {
+    /// The chains of the distinct vectors by hash.
+    chains: HashMap<u64, Vec<DuplicateChain>>,
+    /// The hash of the vectors for `chains`.
+    hash: fn(&[f32]) -> u64,
+    /// The table to read the vectors of the representatives back from, opened once per build.
+    heap: &'a PgRelation,
+    heap_attr: pg_sys::AttrNumber,
+}
+
+/// The nodes of the rows of a collapse_duplicate_vectors build with the same vector.
+struct DuplicateChain {
+    /// The node in the graph, the first one created for the vector.
+    representative: IndexPointer,
+    /// The heap row of the representative, to compare vectors with the same hash to.
+    representative_heap_pointer: HeapPointer,
+    /// The newest duplicate, which the next one links to.
+    head: Option<IndexPointer>,
+}
+
+/// The hash collapse_duplicate_vectors groups vectors by. Vectors that compare equal hash
+/// alike: -0 hashes as 0.
+fn duplicate_hash(vector: &[f32]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for v in vector {
+        let v = if *v == 0.0 { 0.0_f32 } else { *v };
+        v.to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+thread_local! {
+    /// Replaces duplicate_hash() in the builds of this backend, to force collisions in tests.
+    static DUPLICATE_HASH_OVERRIDE: std::cell::Cell<Option<fn(&[f32]) -> u64>> =
+        std::cell::Cell::new(None);
+}
+
+/// The hash of the vectors of a collapse_duplicate_vectors build.
+#[cfg(not(any(test, feature = "pg_test")))]
+fn build_duplicate_hash() -> fn(&[f32]) -> u64 {
+    duplicate_hash
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+fn build_duplicate_hash() -> fn(&[f32]) -> u64 {
+    DUPLICATE_HASH_OVERRIDE
+        .with(|h| h.get())
+        .unwrap_or(duplicate_hash)
 }
 
 impl<'a, 'b> BuildState<'a, 'b> {
     fn new(
         index_relation: &'a PgRelation,
+        heap_relation: &'a PgRelation,
         meta_page: MetaPage,
         graph: Graph<'b>,
         page_type: PageType,
     ) -> Self {
         let tape = unsafe { Tape::new(index_relation, page_type) };
+        let locality_buffer = if meta_page.get_locality_layout() {
+            Some(LocalityBuffer::new())
+        } else {
+            None
+        };
+        let duplicates = if meta_page.get_collapse_duplicate_vectors() {
+            Some(DuplicateVectors {
+                chains: HashMap::new(),
+                hash: build_duplicate_hash(),
+                heap: heap_relation,
+                heap_attr: get_attribute_number_from_index(index_relation),
+            })
+        } else {
+            None
+        };
 
         BuildState {
             memcxt: PgMemoryContexts::new("diskann build context"),
@@ -55,6 +143,8 @@ impl<'a, 'b> BuildState<'a, 'b> {
             graph: graph,
             started: Instant::now(),
             stats: InsertStats::new(),
+            locality_buffer,
+            duplicates,
         }
     }
 }
@@ -70,19 +160,57 @@ pub extern "C" fn ambuild(
     let opt = TSVIndexOptions::from_relation(&index_relation);
 
     notice!(
-        "Starting index build. num_neighbors={} search_list_size={}, max_alpha={}, storage_layout={:?}",
+        "Starting index build. num_neighbors={} search_list_size={}, max_alpha={}, storage_layout={}",
         opt.get_num_neighbors(),
         opt.search_list_size,
         opt.max_alpha,
-        opt.get_storage_type(),
+        opt.get_storage_layout(),
     );
 
-    let dimensions = index_relation.tuple_desc().get(0).unwrap().atttypmod;
-    assert!(dimensions > 0 && dimensions < 2000);
+    let attribute = index_relation.tuple_desc().get(0).unwrap();
+    let dimensions = attribute.atttypmod;
+    if dimensions <= 0 {
+        error!(
+            "column \"{}\" has no dimensions, diskann indexes need a column declared like vector(3)",
+            attribute.name()
+        );
+    }
+    let max_dimensions = super::guc::TSV_MAX_DIMENSIONS.get();
+    if dimensions > max_dimensions {
+        error!(
+            "column \"{}\" has {} dimensions, more than diskann.max_dimensions ({})",
+            attribute.name(),
+            dimensions,
+            max_dimensions
+        );
+    }
+    let estimate_intrinsic_dimensionality = opt.estimate_intrinsic_dimensionality;
     let meta_page = unsafe { MetaPage::create(&index_relation, dimensions as _, opt) };
 
     let ntuples = do_heap_scan(index_info, &heap_relation, &index_relation, meta_page);
 
+    if estimate_intrinsic_dimensionality {
+        let meta_page = MetaPage::fetch(&index_relation);
+        let estimate = match meta_page.get_storage_type() {
+            StorageType::Plain => estimate_intrinsic_dimensionality::<PlainStorage>(
+                &index_relation,
+                &heap_relation,
+                &meta_page,
+            ),
+            StorageType::SbqSpeedup | StorageType::SbqCompression => {
+                estimate_intrinsic_dimensionality::<SbqSpeedupStorage>(
+                    &index_relation,
+                    &heap_relation,
+                    &meta_page,
+                )
+            }
+        };
+        if let Some(estimate) = estimate {
+            let mut stats = WriteStats::new();
+            MetaPage::update_intrinsic_dimensionality(&index_relation, estimate, &mut stats);
+        }
+    }
+
     let mut result = unsafe { PgBox::<pg_sys::IndexBuildResult>::alloc0() };
     result.heap_tuples = ntuples as f64;
     result.index_tuples = ntuples as f64;
@@ -90,6 +218,90 @@ pub extern "C" fn ambuild(
     result.into_pg()
 }
 
+/// The number of nodes whose neighbors the intrinsic dimensionality is estimated from.
+const INTRINSIC_DIMENSIONALITY_SAMPLE_SIZE: usize = 1000;
+
+/// Estimates the intrinsic dimensionality of the vectors with the TwoNN estimator: for points
+/// spread over a d-dimensional manifold, the ratio of the distances from a point to its second
+/// and first nearest neighbors follows a Pareto distribution with parameter d, whose maximum
+/// likelihood estimate over n points is n / sum(ln(r2 / r1)). The two nearest neighbors of a
+/// node are taken from its neighbor list, with distances between the vectors of the rows, over
+/// a uniform sample of the nodes, drawn by reservoir sampling in a single pass so that memory
+/// stays within the sample. None if the graph has too few edges.
+fn estimate_intrinsic_dimensionality<S: Storage>(
+    index: &PgRelation,
+    heap: &PgRelation,
+    meta_page: &MetaPage,
+) -> Option<f64> {
+    let mut sample: Vec<(HeapPointer, Vec<IndexPointer>)> =
+        Vec::with_capacity(INTRINSIC_DIMENSIONALITY_SAMPLE_SIZE);
+    let mut num_nodes = 0;
+    let mut rng = rand::thread_rng();
+    for_each_node::<S, _>(index, |_, node| {
+        if node.is_deleted() {
+            return;
+        }
+        num_nodes += 1;
+        let entry = (
+            node.get_heap_item_pointer(),
+            node.get_index_pointer_to_neighbors(),
+        );
+        if sample.len() < INTRINSIC_DIMENSIONALITY_SAMPLE_SIZE {
+            sample.push(entry);
+        } else {
+            /* the node replaces a sampled one with probability sample size / nodes so far */
+            let replaced = rng.gen_range(0..num_nodes);
+            if replaced < INTRINSIC_DIMENSIONALITY_SAMPLE_SIZE {
+                sample[replaced] = entry;
+            }
+        }
+    });
+
+    let heap_attr = get_attribute_number_from_index(index);
+    let mut stats = GreedySearchStats::new();
+    let mut vector_of = |heap_pointer: HeapPointer| unsafe {
+        let slot = TableSlot::new_visible(
+            heap,
+            heap_pointer,
+            std::ptr::addr_of_mut!(pg_sys::SnapshotAnyData),
+            &mut stats,
+        )?;
+        let datum = slot.get_attribute(heap_attr)?;
+        Some(PgVector::from_heap_datum(datum, meta_page, true, false))
+    };
+
+    let mut sum_log_ratios = 0.0;
+    let mut num_points = 0;
+    for (heap_pointer, neighbors) in sample.iter() {
+        check_for_interrupts!();
+        let vector = match vector_of(*heap_pointer) {
+            Some(vector) => vector,
+            None => continue,
+        };
+        /* squared euclidean distances, whose ratio is the square of the ratio of the distances */
+        let mut distances: Vec<f32> = neighbors
+            .iter()
+            .filter_map(|&neighbor| {
+                with_node::<S, _, _>(index, neighbor, |node| {
+                    (!node.is_deleted()).then(|| node.get_heap_item_pointer())
+                })
+            })
+            .filter_map(&mut vector_of)
+            .map(|neighbor| {
+                super::distance::distance_l2(vector.to_index_slice(), neighbor.to_index_slice())
+            })
+            .collect();
+        distances.sort_by(|a, b| a.total_cmp(b));
+        if distances.len() < 2 || distances[0] <= 0.0 {
+            continue;
+        }
+        sum_log_ratios += 0.5 * (distances[1] as f64 / distances[0] as f64).ln();
+        num_points += 1;
+    }
+
+    (num_points > 0 && sum_log_ratios > 0.0).then(|| num_points as f64 / sum_log_ratios)
+}
+
 #[cfg(any(feature = "pg14", feature = "pg15", feature = "pg16"))]
 #[pg_guard]
 pub unsafe extern "C" fn aminsert(
@@ -105,6 +317,7 @@ pub unsafe extern "C" fn aminsert(
     let index_relation = unsafe { PgRelation::from_pg(indexrel) };
     let heap_relation = unsafe { PgRelation::from_pg(heaprel) };
     let mut meta_page = MetaPage::fetch(&index_relation);
+    meta_page.check_column_dimensions(&index_relation);
     let vec = PgVector::from_pg_parts(values, isnull, 0, &meta_page, true, false);
     if let None = vec {
         //todo handle NULLs?
@@ -138,6 +351,12 @@ pub unsafe extern "C" fn aminsert(
                 &meta_page,
                 &mut stats.quantizer_stats,
             );
+            drift::record_insert(
+                &index_relation,
+                &meta_page,
+                bq.get_quantizer(),
+                vec.to_index_slice(),
+            );
             insert_storage(
                 &bq,
                 &index_relation,
@@ -165,6 +384,7 @@ pub unsafe extern "C" fn aminsert(
     let index_relation = unsafe { PgRelation::from_pg(indexrel) };
     let heap_relation = unsafe { PgRelation::from_pg(heaprel) };
     let mut meta_page = MetaPage::fetch(&index_relation);
+    meta_page.check_column_dimensions(&index_relation);
     let vec = PgVector::from_pg_parts(values, isnull, 0, &meta_page, true, false);
     if let None = vec {
         //todo handle NULLs?
@@ -198,6 +418,12 @@ pub unsafe extern "C" fn aminsert(
                 &meta_page,
                 &mut stats.quantizer_stats,
             );
+            drift::record_insert(
+                &index_relation,
+                &meta_page,
+                bq.get_quantizer(),
+                vec.to_index_slice(),
+            );
             insert_storage(
                 &bq,
                 &index_relation,
@@ -228,15 +454,263 @@ unsafe fn insert_storage<S: Storage>(
         stats,
     );
 
+    if !meta_page.get_index_type().has_graph() {
+        return;
+    }
+
     let mut graph = Graph::new(GraphNeighborStore::Disk, meta_page);
     graph.insert(&index_relation, index_pointer, vector, storage, stats)
 }
 
+/// Links all the nodes of a deferred index into the graph, in physical order.
+/// Returns the number of nodes linked.
+fn finalize_deferred_storage<S: Storage>(
+    storage: &S,
+    index: &PgRelation,
+    heap: &PgRelation,
+    meta_page: &mut MetaPage,
+    stats: &mut InsertStats,
+) -> i64 {
+    let mut nodes = vec![];
+    for_each_node::<S, _>(index, |index_pointer, node| {
+        if !node.is_deleted() {
+            nodes.push((index_pointer, node.get_heap_item_pointer()));
+        }
+    });
+
+    let heap_attr = get_attribute_number_from_index(index);
+    let mut graph = Graph::new(GraphNeighborStore::Disk, meta_page);
+    for &(index_pointer, heap_pointer) in nodes.iter() {
+        check_for_interrupts!();
+        /* dead rows stay in the graph until vacuum like any other, unless they were pruned already */
+        let slot = unsafe { TableSlot::new(heap, heap_pointer, &mut stats.greedy_search_stats) };
+        let datum = match unsafe { slot.get_attribute(heap_attr) } {
+            Some(datum) => datum,
+            None => continue,
+        };
+        let vec = unsafe { PgVector::from_heap_datum(datum, graph.get_meta_page(), true, false) };
+        graph.insert(index, index_pointer, vec, storage, stats);
+    }
+    nodes.len() as i64
+}
+
+/// Builds the graph of an index created with `index_type = deferred` and turns it into a
+/// regular graph index. Until then, the index is searched like a flat one. Inserts are
+/// blocked while the graph is being built. Returns the number of nodes linked.
+#[pg_extern]
+pub fn tsv_finalize(index: PgRelation) -> i64 {
+    check_is_diskann_index(&index);
+    check_index_owner(&index);
+    unsafe { pg_sys::PreventCommandDuringRecovery("tsv_finalize()".as_pg_cstr()) };
+
+    /* block concurrent inserts, which would add nodes that don't get linked, and self-conflicting
+     * so that two calls don't link the same nodes */
+    unsafe { pg_sys::LockRelationOid(index.oid(), pg_sys::ShareRowExclusiveLock as _) };
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let mut meta_page = MetaPage::fetch(&index);
+    match meta_page.get_index_type() {
+        IndexType::Graph => return 0,
+        IndexType::Flat => error!("\"{}\" is a flat index, it has no graph", index.name()),
+        IndexType::Deferred => {}
+    }
+
+    let mut stats = InsertStats::new();
+    let linked = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let plain =
+                PlainStorage::load_for_insert(&index, &heap, meta_page.get_distance_function());
+            finalize_deferred_storage(&plain, &index, &heap, &mut meta_page, &mut stats)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let bq = SbqSpeedupStorage::load_for_insert(
+                &heap,
+                &index,
+                &meta_page,
+                &mut stats.quantizer_stats,
+            );
+            finalize_deferred_storage(&bq, &index, &heap, &mut meta_page, &mut stats)
+        }
+    };
+
+    MetaPage::update_index_type(&index, IndexType::Graph, &mut stats);
+    linked
+}
+
+/// Writes the neighbor lists of a deferred index from the given edges, each sorted by
+/// distance. Errors out if an edge doesn't join two distinct live nodes or a node gets more
+/// than num_neighbors neighbors. Returns the number of edges written.
+fn load_edges_storage<S: Storage>(
+    storage: &S,
+    index: &PgRelation,
+    meta_page: &MetaPage,
+    edges: &[(HeapPointer, HeapPointer)],
+    stats: &mut PruneNeighborStats,
+) -> i64 {
+    let mut nodes: HashMap<HeapPointer, IndexPointer> = HashMap::new();
+    let mut first_node = None;
+    for_each_node::<S, _>(index, |index_pointer, node| {
+        if !node.is_deleted() {
+            nodes.insert(node.get_heap_item_pointer(), index_pointer);
+            first_node.get_or_insert(index_pointer);
+        }
+    });
+    let node_of = |heap_pointer: &HeapPointer| match nodes.get(heap_pointer) {
+        Some(&index_pointer) => index_pointer,
+        None => error!("no index node found for ctid {:?}", heap_pointer),
+    };
+
+    let mut neighbors: BTreeMap<IndexPointer, Vec<IndexPointer>> = BTreeMap::new();
+    for (from, to) in edges {
+        if from == to {
+            error!("the edge from ctid {:?} leads to itself", from);
+        }
+        let list = neighbors.entry(node_of(from)).or_default();
+        let to = node_of(to);
+        if !list.contains(&to) {
+            list.push(to);
+        }
+        if list.len() > meta_page.get_num_neighbors() as usize {
+            error!(
+                "ctid {:?} has more than num_neighbors ({}) neighbors",
+                from,
+                meta_page.get_num_neighbors()
+            );
+        }
+    }
+
+    let mut written = 0;
+    for (index_pointer, list) in neighbors {
+        check_for_interrupts!();
+        let dm = unsafe { storage.get_node_distance_measure(index_pointer, stats) };
+        let mut list: Vec<NeighborWithDistance> = list
+            .into_iter()
+            .map(|n| unsafe { NeighborWithDistance::new(n, dm.get_distance(n, stats)) })
+            .collect();
+        drop(dm);
+        list.sort();
+        storage.set_neighbors_on_disk(meta_page, index_pointer, &list, stats);
+        written += list.len() as i64;
+    }
+
+    if let Some(first_node) = first_node {
+        if meta_page.get_init_ids().is_none() {
+            MetaPage::update_init_ids(index, vec![first_node], stats);
+        }
+    }
+    written
+}
+
+/// Turns an index created with `index_type = deferred` into a graph index whose graph is
+/// given instead of built: the row `from_ctids[i]` of the table gets the row `to_ctids[i]` as
+/// a neighbor. The vectors were already quantized when the rows were indexed. Meant for
+/// comparing graphs built elsewhere against the built-in construction on the same storage,
+/// e.g. with `SELECT tsv_finalize_with_edges('<index>'::regclass, array_agg(from_ctid),
+/// array_agg(to_ctid)) FROM <edges table>`. Searches start from the first node of the index.
+/// Inserts are blocked while the edges are written, and link new rows like in any graph index
+/// afterwards. Returns the number of edges written.
+#[pg_extern]
+pub fn tsv_finalize_with_edges(
+    index: PgRelation,
+    from_ctids: Vec<pg_sys::ItemPointerData>,
+    to_ctids: Vec<pg_sys::ItemPointerData>,
+) -> i64 {
+    check_is_diskann_index(&index);
+    check_index_owner(&index);
+    unsafe { pg_sys::PreventCommandDuringRecovery("tsv_finalize_with_edges()".as_pg_cstr()) };
+    if from_ctids.len() != to_ctids.len() {
+        error!("from_ctids and to_ctids must have the same length");
+    }
+
+    /* block concurrent inserts, which would add nodes and edges of their own, and self-conflicting
+     * so that two calls don't both build the graph */
+    unsafe { pg_sys::LockRelationOid(index.oid(), pg_sys::ShareRowExclusiveLock as _) };
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let meta_page = MetaPage::fetch(&index);
+    match meta_page.get_index_type() {
+        IndexType::Graph => error!("\"{}\" already has a graph", index.name()),
+        IndexType::Flat => error!("\"{}\" is a flat index, it has no graph", index.name()),
+        IndexType::Deferred => {}
+    }
+
+    let edges: Vec<(HeapPointer, HeapPointer)> = from_ctids
+        .into_iter()
+        .zip(to_ctids)
+        .map(|(from, to)| unsafe {
+            (
+                HeapPointer::with_item_pointer_data(from),
+                HeapPointer::with_item_pointer_data(to),
+            )
+        })
+        .collect();
+
+    let mut stats = PruneNeighborStats::new();
+    let written = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let plain =
+                PlainStorage::load_for_insert(&index, &heap, meta_page.get_distance_function());
+            load_edges_storage(&plain, &index, &meta_page, &edges, &mut stats)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let bq = SbqSpeedupStorage::load_for_insert(&heap, &index, &meta_page, &mut stats);
+            load_edges_storage(&bq, &index, &meta_page, &edges, &mut stats)
+        }
+    };
+
+    MetaPage::update_index_type(&index, IndexType::Graph, &mut stats);
+    written
+}
+
 #[pg_guard]
 pub extern "C" fn ambuildempty(_index_relation: pg_sys::Relation) {
     panic!("ambuildempty: not yet implemented")
 }
 
+/// Reports the wall-clock time of each phase of a build as a NOTICE when
+/// diskann.build_verbose is on.
+struct BuildPhaseTimer {
+    verbose: bool,
+    build_started: Instant,
+    phase_started: Instant,
+}
+
+impl BuildPhaseTimer {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            verbose: super::guc::TSV_BUILD_VERBOSE.get(),
+            build_started: now,
+            phase_started: now,
+        }
+    }
+
+    fn end_phase(&mut self, phase: &str) {
+        let now = Instant::now();
+        if self.verbose {
+            notice!(
+                "diskann build: {} took {:.3}s",
+                phase,
+                now.duration_since(self.phase_started).as_secs_f64()
+            );
+        }
+        self.phase_started = now;
+    }
+
+    fn end_build(&self) {
+        if self.verbose {
+            notice!(
+                "diskann build: total {:.3}s",
+                self.build_started.elapsed().as_secs_f64()
+            );
+        }
+    }
+}
+
 fn do_heap_scan<'a>(
     index_info: *mut pg_sys::IndexInfo,
     heap_relation: &'a PgRelation,
@@ -251,7 +725,8 @@ fn do_heap_scan<'a>(
         &mut mp2,
     );
     let mut write_stats = WriteStats::new();
-    match storage {
+    let mut timer = BuildPhaseTimer::new();
+    let ntuples = match storage {
         StorageType::Plain => {
             let mut plain = PlainStorage::new_for_build(
                 index_relation,
@@ -260,7 +735,8 @@ fn do_heap_scan<'a>(
             );
             plain.start_training(&meta_page);
             let page_type = PlainStorage::page_type();
-            let mut bs = BuildState::new(index_relation, meta_page, graph, page_type);
+            let mut bs =
+                BuildState::new(index_relation, heap_relation, meta_page, graph, page_type);
             let mut state = StorageBuildState::Plain(&mut plain, &mut bs);
 
             unsafe {
@@ -273,6 +749,8 @@ fn do_heap_scan<'a>(
                 );
             }
 
+            write_locality_buffer(index_relation, &mut bs, &mut plain);
+            timer.end_phase("node creation");
             finalize_index_build(&mut plain, &mut bs, write_stats)
         }
         StorageType::SbqSpeedup | StorageType::SbqCompression => {
@@ -287,7 +765,8 @@ fn do_heap_scan<'a>(
 
             bq.start_training(&meta_page);
 
-            let mut bs = BuildState::new(index_relation, meta_page, graph, page_type);
+            let mut bs =
+                BuildState::new(index_relation, heap_relation, meta_page, graph, page_type);
             let mut state = StorageBuildState::SbqSpeedup(&mut bq, &mut bs);
 
             unsafe {
@@ -300,6 +779,7 @@ fn do_heap_scan<'a>(
                 );
             }
             bq.finish_training(&mut write_stats);
+            timer.end_phase("quantizer training");
 
             unsafe {
                 pgstat_progress_update_param(
@@ -320,6 +800,9 @@ fn do_heap_scan<'a>(
                 );
             }
 
+            write_locality_buffer(index_relation, &mut bs, &mut bq);
+            timer.end_phase("node creation");
+
             unsafe {
                 pgstat_progress_update_param(
                     PROGRESS_CREATE_IDX_SUBPHASE,
@@ -328,7 +811,60 @@ fn do_heap_scan<'a>(
             }
             finalize_index_build(&mut bq, &mut bs, write_stats)
         }
+    };
+    timer.end_phase("graph finalization");
+    timer.end_build();
+    ntuples
+}
+
+/// Returns the final neighbor lists of the build, where the reverse of every edge A -> B
+/// has been added to the list of B. Lists that go over capacity are pruned again, so a
+/// reverse edge can still lose to closer neighbors.
+fn bidirectional_neighbor_lists<S: Storage>(
+    builder: &BuilderNeighborCache,
+    graph: &Graph,
+    storage: &S,
+    stats: &mut PruneNeighborStats,
+) -> BTreeMap<IndexPointer, Vec<NeighborWithDistance>> {
+    let max_neighbors = graph.get_meta_page().get_num_neighbors_for_build() as usize;
+    let mut lists: BTreeMap<IndexPointer, Vec<NeighborWithDistance>> = builder
+        .iter()
+        .map(|(&index_pointer, neighbors)| {
+            let neighbors = if neighbors.len() > max_neighbors {
+                graph.prune_neighbors(neighbors.clone(), storage, stats)
+            } else {
+                neighbors.clone()
+            };
+            (index_pointer, neighbors)
+        })
+        .collect();
+
+    let edges: Vec<(IndexPointer, IndexPointer, f32)> = lists
+        .iter()
+        .flat_map(|(&from, neighbors)| {
+            neighbors
+                .iter()
+                .map(move |n| (from, n.get_index_pointer_to_neighbor(), n.get_distance()))
+        })
+        .collect();
+    for (from, to, distance) in edges {
+        let reverse = match lists.get_mut(&to) {
+            Some(reverse) => reverse,
+            None => continue,
+        };
+        if reverse
+            .iter()
+            .any(|n| n.get_index_pointer_to_neighbor() == from)
+        {
+            continue;
+        }
+        reverse.push(NeighborWithDistance::new(from, distance));
+        if reverse.len() > max_neighbors {
+            let candidates = std::mem::take(reverse);
+            *reverse = graph.prune_neighbors(candidates, storage, stats);
+        }
     }
+    lists
 }
 
 fn finalize_index_build<S: Storage>(
@@ -337,22 +873,42 @@ fn finalize_index_build<S: Storage>(
     mut write_stats: WriteStats,
 ) -> usize {
     match state.graph.get_neighbor_store() {
+        GraphNeighborStore::Builder(builder) if state.meta_page.get_enforce_bidirectional() => {
+            let lists = bidirectional_neighbor_lists(
+                builder,
+                &state.graph,
+                storage,
+                &mut write_stats.prune_stats,
+            );
+            for (&index_pointer, neighbors) in lists.iter() {
+                write_stats.num_nodes += 1;
+                write_stats.num_neighbors += neighbors.len();
+
+                storage.finalize_node_at_end_of_build(
+                    &state.meta_page,
+                    index_pointer,
+                    neighbors,
+                    &mut write_stats,
+                );
+            }
+        }
         GraphNeighborStore::Builder(builder) => {
             for (&index_pointer, neighbors) in builder.iter() {
                 write_stats.num_nodes += 1;
                 let prune_neighbors;
-                let neighbors =
-                    if neighbors.len() > state.graph.get_meta_page().get_num_neighbors() as _ {
-                        //OPT: get rid of this clone
-                        prune_neighbors = state.graph.prune_neighbors(
-                            neighbors.clone(),
-                            storage,
-                            &mut write_stats.prune_stats,
-                        );
-                        &prune_neighbors
-                    } else {
-                        neighbors
-                    };
+                let neighbors = if neighbors.len()
+                    > state.graph.get_meta_page().get_num_neighbors_for_build() as _
+                {
+                    //OPT: get rid of this clone
+                    prune_neighbors = state.graph.prune_neighbors(
+                        neighbors.clone(),
+                        storage,
+                        &mut write_stats.prune_stats,
+                    );
+                    &prune_neighbors
+                } else {
+                    neighbors
+                };
                 write_stats.num_neighbors += neighbors.len();
 
                 storage.finalize_node_at_end_of_build(
@@ -368,6 +924,11 @@ fn finalize_index_build<S: Storage>(
         }
     }
 
+    if !state.meta_page.get_index_type().has_graph() {
+        /* no neighbor lists to write */
+        write_stats.num_nodes = state.ntuples;
+    }
+
     debug1!("write done");
     assert_eq!(write_stats.num_nodes, state.ntuples);
 
@@ -532,6 +1093,14 @@ fn build_callback_internal<S: Storage>(
 ) {
     check_for_interrupts!();
 
+    if let Some(buffer) = state.locality_buffer.as_mut() {
+        buffer.push(heap_pointer, vector.to_index_slice());
+        if buffer.is_full() {
+            write_locality_buffer(&index, state, storage);
+        }
+        return;
+    }
+
     state.ntuples = state.ntuples + 1;
 
     if state.ntuples % 1000 == 0 {
@@ -554,11 +1123,167 @@ fn build_callback_internal<S: Storage>(
         &mut state.stats,
     );
 
+    if !state.meta_page.get_index_type().has_graph() {
+        /* flat and deferred indexes have no graph, the node is complete as created */
+        return;
+    }
+
+    if let Some(duplicates) = state.duplicates.as_mut() {
+        /* hashes only narrow down the candidates: a vector joins a chain only if it equals
+         * the vector of the representative, read back from the table */
+        let key = (duplicates.hash)(vector.to_index_slice());
+        let (heap, heap_attr) = (duplicates.heap, duplicates.heap_attr);
+        let chains = duplicates.chains.entry(key).or_default();
+        let chain = chains.iter_mut().find(|chain| unsafe {
+            let slot = TableSlot::new(
+                heap,
+                chain.representative_heap_pointer,
+                &mut state.stats.greedy_search_stats,
+            );
+            slot.get_attribute(heap_attr).map_or(false, |datum| {
+                let representative =
+                    PgVector::from_heap_datum(datum, &state.meta_page, true, false);
+                representative.to_index_slice() == vector.to_index_slice()
+            })
+        });
+        match chain {
+            Some(chain) => {
+                state.graph.insert_duplicate(
+                    chain.representative,
+                    chain.head,
+                    index_pointer,
+                    storage,
+                    &mut state.stats,
+                );
+                chain.head = Some(index_pointer);
+                return;
+            }
+            None => chains.push(DuplicateChain {
+                representative: index_pointer,
+                representative_heap_pointer: heap_pointer,
+                head: None,
+            }),
+        }
+    }
+
     state
         .graph
         .insert(&index, index_pointer, vector, storage, &mut state.stats);
 }
 
+/// The rows of a locality_layout build whose nodes are not created yet. The nodes are
+/// created in locality order once the vectors fill maintenance_work_mem, and after the last
+/// row, so each run of rows that fits in memory is sorted on its own.
+struct LocalityBuffer {
+    rows: Vec<(HeapPointer, Vec<f32>)>,
+    /// The memory held by `rows`.
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl LocalityBuffer {
+    fn new() -> Self {
+        Self {
+            rows: vec![],
+            bytes: 0,
+            max_bytes: unsafe { pg_sys::maintenance_work_mem } as usize * 1024,
+        }
+    }
+
+    fn push(&mut self, heap_pointer: HeapPointer, vector: &[f32]) {
+        self.bytes +=
+            std::mem::size_of::<(HeapPointer, Vec<f32>)>() + std::mem::size_of_val(vector);
+        self.rows.push((heap_pointer, vector.to_vec()));
+    }
+
+    fn is_full(&self) -> bool {
+        self.bytes >= self.max_bytes
+    }
+}
+
+/// The number of leading dimensions that make up the locality key of a vector, and the
+/// number of bits of each.
+const LOCALITY_KEY_DIMENSIONS: usize = 16;
+const LOCALITY_KEY_BITS: usize = 4;
+
+/// Sorts vectors so that similar ones end up next to each other, along a Z-order curve over
+/// their leading dimensions: each dimension is cut into 16 intervals a quarter of its
+/// standard deviation wide, centered on its mean, and the key interleaves the bits of the
+/// interval numbers of all the dimensions, most significant first. Vectors with close keys
+/// lie in the same region of space.
+fn locality_order(vectors: &mut [(HeapPointer, Vec<f32>)]) {
+    if vectors.is_empty() {
+        return;
+    }
+
+    let key_dimensions = std::cmp::min(LOCALITY_KEY_DIMENSIONS, vectors[0].1.len());
+    let mut means = vec![0.0_f64; key_dimensions];
+    let mut squares = vec![0.0_f64; key_dimensions];
+    for (_, vector) in vectors.iter() {
+        for ((mean, square), value) in means.iter_mut().zip(squares.iter_mut()).zip(vector) {
+            *mean += *value as f64;
+            *square += *value as f64 * *value as f64;
+        }
+    }
+    let n = vectors.len() as f64;
+    let scales: Vec<f64> = means
+        .iter_mut()
+        .zip(squares.iter())
+        .map(|(mean, square)| {
+            *mean /= n;
+            let std_dev = (square / n - *mean * *mean).max(0.0).sqrt();
+            /* four intervals per standard deviation */
+            if std_dev > 0.0 {
+                4.0 / std_dev
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    let max_interval = (1_u64 << LOCALITY_KEY_BITS) - 1;
+    vectors.sort_by_cached_key(|(_, vector)| {
+        let intervals: Vec<u64> = means
+            .iter()
+            .zip(scales.iter())
+            .zip(vector.iter())
+            .map(|((mean, scale), value)| {
+                let interval = (*value as f64 - mean) * scale + (max_interval + 1) as f64 / 2.0;
+                interval.floor().clamp(0.0, max_interval as f64) as u64
+            })
+            .collect();
+        let mut key = 0_u64;
+        for bit in (0..LOCALITY_KEY_BITS).rev() {
+            for interval in intervals.iter() {
+                key = (key << 1) | ((interval >> bit) & 1);
+            }
+        }
+        key
+    });
+}
+
+/// Creates the nodes of the rows buffered by a locality_layout build, in locality order, so
+/// that nodes that are likely to be neighbors in the graph share pages.
+fn write_locality_buffer<S: Storage>(index: &PgRelation, state: &mut BuildState, storage: &mut S) {
+    let mut buffer = match state.locality_buffer.take() {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    locality_order(&mut buffer.rows);
+
+    for (heap_pointer, vector) in buffer.rows.drain(..) {
+        unsafe {
+            let vector = PgVector::from_index_slice(&vector);
+            let index_relation = PgRelation::from_pg(index.as_ptr());
+            build_callback_memory_wrapper(index_relation, heap_pointer, vector, state, storage);
+        }
+    }
+
+    /* the next rows go to a new run */
+    buffer.bytes = 0;
+    state.locality_buffer = Some(buffer);
+}
+
 const BUILD_PHASE_TRAINING: i64 = 0;
 const BUILD_PHASE_BUILDING_GRAPH: i64 = 1;
 const BUILD_PHASE_FINALIZING_GRAPH: i64 = 2;
@@ -584,6 +1309,31 @@ pub mod tests {
 
     //TODO: add test where inserting and querying with vectors that are all the same.
 
+    /// Creates `test_data` with an `embedding` column of `num_dimensions` dimensions and fills
+    /// it with `num_rows` random vectors, the same ones on every run. Replaces the table if it
+    /// exists, for tests that create it more than once.
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn create_test_data(num_dimensions: usize, num_rows: usize) -> spi::Result<()> {
+        Spi::run(&format!(
+            "DROP TABLE IF EXISTS test_data;
+            CREATE TABLE test_data (
+                embedding vector ({num_dimensions})
+            );
+
+            select setseed(0.5);
+            INSERT INTO test_data (embedding)
+            SELECT
+                *
+            FROM (
+                SELECT
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, {num_dimensions} * {num_rows}) i
+                GROUP BY
+                    i % {num_rows}) g;"
+        ))
+    }
+
     #[cfg(any(test, feature = "pg_test"))]
     pub unsafe fn test_index_creation_and_accuracy_scaffold(
         index_options: &str,
@@ -953,4 +1703,730 @@ pub mod tests {
 
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_deferred_index_finalize() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (
+                embedding vector (64)
+            );
+
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding) WITH (index_type = deferred);
+
+            select setseed(0.5);
+            INSERT INTO test_data (embedding)
+            SELECT
+                *
+            FROM (
+                SELECT
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 64 * 300) i
+                GROUP BY
+                    i % 300) g;",
+        )?;
+
+        let query = "SET enable_seqscan = 0;
+            SET diskann.query_search_list_size = 2;
+            WITH cte as (select * from test_data order by embedding <=> (SELECT embedding FROM test_data LIMIT 1)) SELECT count(*) from cte;";
+
+        /* before finalizing, searches compare the query to every node */
+        let cnt: Option<i64> = Spi::get_one(query)?;
+        assert_eq!(cnt.unwrap(), 300);
+
+        let linked: Option<i64> = Spi::get_one("SELECT tsv_finalize('idx_diskann'::regclass)")?;
+        assert_eq!(linked.unwrap(), 300);
+
+        /* the graph reaches every node */
+        let cnt: Option<i64> = Spi::get_one(query)?;
+        assert_eq!(cnt.unwrap(), 300);
+
+        /* inserts after finalizing are linked into the graph right away */
+        Spi::run(
+            "INSERT INTO test_data (embedding)
+            SELECT ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector
+            FROM generate_series(1, 64);",
+        )?;
+        let cnt: Option<i64> = Spi::get_one(query)?;
+        assert_eq!(cnt.unwrap(), 301);
+
+        /* finalizing again is a no-op */
+        let linked: Option<i64> = Spi::get_one("SELECT tsv_finalize('idx_diskann'::regclass)")?;
+        assert_eq!(linked.unwrap(), 0);
+
+        Ok(())
+    }
+
+    #[pg_test(error = "must be owner of index idx_diskann")]
+    unsafe fn test_tsv_finalize_not_owner() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);
+            CREATE ROLE test_not_owner;
+            SET ROLE test_not_owner;
+            SELECT tsv_finalize('idx_diskann'::regclass);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_finalize_with_edges() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized"] {
+            Spi::run(&format!(
+                "DROP TABLE IF EXISTS test_data, test_edges;
+                CREATE TABLE test_data (
+                    id int,
+                    embedding vector (32)
+                );
+
+                select setseed(0.5);
+                INSERT INTO test_data (id, embedding)
+                SELECT
+                    *
+                FROM (
+                    SELECT
+                        i % 300,
+                        ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                    FROM
+                        generate_series(1, 32 * 300) i
+                    GROUP BY
+                        i % 300) g;
+
+                CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout}, index_type = deferred, num_neighbors = 10);
+
+                /* a ring where every row also links to the rows 1, 2, 4, ... 128 further */
+                CREATE TABLE test_edges AS
+                    SELECT a.ctid AS from_ctid, b.ctid AS to_ctid
+                    FROM test_data a, generate_series(0, 7) s, test_data b
+                    WHERE b.id = (a.id + (1 << s)) % 300;"
+            ))?;
+
+            let written = Spi::get_one::<i64>(
+                "SELECT tsv_finalize_with_edges('idx_diskann'::regclass, array_agg(from_ctid), array_agg(to_ctid)) FROM test_edges",
+            )?;
+            assert_eq!(written.unwrap(), 300 * 8);
+
+            /* the index is a graph index with exactly the given edges */
+            let (ddl, max_degree) = Spi::get_two::<String, i32>(
+                "SELECT tsv_index_ddl('idx_diskann'::regclass), (SELECT max(degree) FROM tsv_degree_histogram('idx_diskann'::regclass))",
+            )?;
+            assert!(ddl.unwrap().contains("index_type = 'graph'"));
+            assert_eq!(max_degree.unwrap(), 8);
+            let missing = Spi::get_one::<i64>(
+                "SELECT count(*) FROM (
+                    SELECT from_ctid, to_ctid FROM test_edges
+                    EXCEPT
+                    SELECT from_ctid, to_ctid FROM test_data, tsv_sample_neighbors('idx_diskann'::regclass, ctid, 1)
+                ) e",
+            )?;
+            assert_eq!(missing.unwrap(), 0);
+
+            /* the graph reaches every node */
+            let cnt: Option<i64> = Spi::get_one(
+                "SET enable_seqscan = 0;
+                SET diskann.query_search_list_size = 2;
+                WITH cte as (select * from test_data order by embedding <=> (SELECT embedding FROM test_data LIMIT 1)) SELECT count(*) from cte;",
+            )?;
+            assert_eq!(cnt.unwrap(), 300);
+            Spi::run("RESET enable_seqscan; RESET diskann.query_search_list_size;")?;
+        }
+
+        Ok(())
+    }
+
+    #[pg_test(error = "no index node found for ctid ItemPointer { block_number: 4000, offset: 1 }")]
+    unsafe fn test_tsv_finalize_with_edges_invalid_ctid() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            INSERT INTO test_data VALUES ('[1,2,3]'), ('[4,5,6]');
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding) WITH (index_type = deferred);
+            SELECT tsv_finalize_with_edges('idx_diskann'::regclass, ARRAY['(0,1)'::tid], ARRAY['(4000,1)'::tid]);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_estimate_intrinsic_dimensionality() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_plane (embedding vector (8));
+            CREATE TABLE test_random (embedding vector (32));
+
+            select setseed(0.5);
+            /* a 2-dimensional plane embedded in 8 dimensions */
+            INSERT INTO test_plane (embedding)
+            SELECT ARRAY[a, b, a + b, a - b, 2 * a, 2 * b, a + 2 * b, 1]::vector
+            FROM (SELECT random() AS a, random() AS b FROM generate_series(1, 1000)) g;
+
+            INSERT INTO test_random (embedding)
+            SELECT
+                *
+            FROM (
+                SELECT
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 32 * 1000) i
+                GROUP BY
+                    i % 1000) g;
+
+            CREATE INDEX idx_plane ON test_plane USING diskann (embedding)
+                WITH (storage_layout = plain, estimate_intrinsic_dimensionality = true);
+            CREATE INDEX idx_random ON test_random USING diskann (embedding)
+                WITH (storage_layout = memory_optimized, estimate_intrinsic_dimensionality = true);
+            CREATE INDEX idx_random_off ON test_random USING diskann (embedding);",
+        )?;
+
+        let plane =
+            Spi::get_one::<f64>("SELECT tsv_intrinsic_dimensionality('idx_plane'::regclass)")?
+                .unwrap();
+        assert!(plane > 1.0 && plane < 4.0, "{}", plane);
+        let random =
+            Spi::get_one::<f64>("SELECT tsv_intrinsic_dimensionality('idx_random'::regclass)")?
+                .unwrap();
+        assert!(random > 8.0, "{}", random);
+        let off =
+            Spi::get_one::<f64>("SELECT tsv_intrinsic_dimensionality('idx_random_off'::regclass)")?;
+        assert_eq!(off, None);
+        assert!(
+            Spi::get_one::<String>("SELECT tsv_index_ddl('idx_random'::regclass)")?
+                .unwrap()
+                .contains("estimate_intrinsic_dimensionality = true")
+        );
+
+        Ok(())
+    }
+
+    /// Converts a vector with `value` as its second component the way inserts and builds do,
+    /// for an index created with `index_options`, and returns whether it would be indexed.
+    /// pgvector refuses NaN and infinite values in its input functions, so the datum is
+    /// built by hand.
+    #[cfg(any(test, feature = "pg_test"))]
+    unsafe fn non_finite_vector_is_indexed(index_options: &str, value: f32) -> spi::Result<bool> {
+        use crate::access_method::meta_page::MetaPage;
+        use crate::access_method::pg_vector::{PgVector, PgVectorInternal};
+
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]');
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH ({index_options});"
+        ))?;
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let meta_page = MetaPage::fetch(&indexrel);
+
+        let size = std::mem::size_of::<PgVectorInternal>() + 3 * std::mem::size_of::<f32>();
+        let raw = pg_sys::palloc0(size).cast::<PgVectorInternal>();
+        set_varsize(raw.cast(), size as i32);
+        (*raw).dim = 3;
+        (*raw).x.as_mut_slice(3).copy_from_slice(&[1.0, value, 3.0]);
+
+        let mut datum = pg_sys::Datum::from(raw);
+        let mut isnull = false;
+        let vector = PgVector::from_pg_parts(&mut datum, &mut isnull, 0, &meta_page, true, false);
+        Ok(vector.is_some())
+    }
+
+    #[pg_test]
+    unsafe fn test_build_verbose() -> spi::Result<()> {
+        /* the phase timings are reported as notices, the build itself is unchanged */
+        for storage_layout in ["plain", "memory_optimized"] {
+            Spi::run(&format!(
+                "SET diskann.build_verbose = on;
+                DROP TABLE IF EXISTS test;
+                CREATE TABLE test(embedding vector(3));
+                INSERT INTO test(embedding) SELECT ARRAY[i, i + 1, i * 2]::vector FROM generate_series(1, 100) i;
+                CREATE INDEX idxtest ON test USING diskann(embedding) WITH (storage_layout = {storage_layout});
+                SET enable_seqscan = 0;"
+            ))?;
+            let cnt: Option<i64> = Spi::get_one(
+                "SELECT count(*) FROM (SELECT * FROM test ORDER BY embedding <=> '[1,2,3]' LIMIT 200) t",
+            )?;
+            assert_eq!(cnt.unwrap(), 100);
+        }
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_on_invalid_skip() -> spi::Result<()> {
+        assert!(!non_finite_vector_is_indexed(
+            "on_invalid = skip",
+            f32::NAN
+        )?);
+        Spi::run("DROP TABLE test;")?;
+        assert!(!non_finite_vector_is_indexed(
+            "on_invalid = skip",
+            f32::INFINITY
+        )?);
+        Spi::run("DROP TABLE test;")?;
+        assert!(non_finite_vector_is_indexed("on_invalid = skip", 2.0)?);
+        Ok(())
+    }
+
+    #[pg_test(error = "vector has a non-finite value (NaN) at position 2, it can't be indexed")]
+    unsafe fn test_on_invalid_error_nan() -> spi::Result<()> {
+        non_finite_vector_is_indexed("storage_layout = plain", f32::NAN)?;
+        Ok(())
+    }
+
+    #[pg_test(error = "vector has a non-finite value (-inf) at position 2, it can't be indexed")]
+    unsafe fn test_on_invalid_error_inf() -> spi::Result<()> {
+        non_finite_vector_is_indexed("on_invalid = error", f32::NEG_INFINITY)?;
+        Ok(())
+    }
+
+    /// Builds an index over vectors stored negated, with a decrypt_function that negates them back,
+    /// and returns the id of the row nearest to the plaintext of row 42.
+    #[cfg(any(test, feature = "pg_test"))]
+    unsafe fn nearest_with_decrypt_function(index_options: &str) -> spi::Result<Option<i32>> {
+        Spi::run(&format!(
+            "CREATE FUNCTION test_decrypt(v vector) RETURNS vector
+                AS $$ SELECT array(SELECT -x FROM unnest(v::real[]) x)::vector $$
+                LANGUAGE sql IMMUTABLE STRICT;
+
+            CREATE TABLE test_enc (id int, embedding vector(3));
+            INSERT INTO test_enc (id, embedding)
+                SELECT i, test_decrypt(format('[%s,%s,%s]', i, 300 - i, i % 7 + 1)::vector)
+                FROM generate_series(1, 200) i;
+
+            CREATE INDEX idx_enc ON test_enc USING diskann (embedding)
+                WITH (decrypt_function = 'test_decrypt', {index_options});
+
+            INSERT INTO test_enc (id, embedding)
+                SELECT i, test_decrypt(format('[%s,%s,%s]', i, 300 - i, i % 7 + 1)::vector)
+                FROM generate_series(201, 250) i;
+
+            SET enable_seqscan = 0;"
+        ))?;
+
+        Spi::get_one("SELECT id FROM test_enc ORDER BY embedding <=> '[42,258,1]' LIMIT 1")
+    }
+
+    #[pg_test]
+    unsafe fn test_decrypt_function_sbq() -> spi::Result<()> {
+        assert_eq!(
+            nearest_with_decrypt_function("storage_layout = memory_optimized")?,
+            Some(42)
+        );
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_decrypt_function_plain() -> spi::Result<()> {
+        assert_eq!(
+            nearest_with_decrypt_function("storage_layout = plain, num_dimensions = 2")?,
+            Some(42)
+        );
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "decrypt_function abs(integer) must take a single argument and return the type of the indexed column"
+    )]
+    unsafe fn test_decrypt_function_wrong_type() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_enc (embedding vector(3));
+            CREATE INDEX idx_enc ON test_enc USING diskann (embedding)
+                WITH (decrypt_function = 'abs(integer)');",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(error = "decrypt_function test_decrypt must be IMMUTABLE")]
+    unsafe fn test_decrypt_function_volatile() -> spi::Result<()> {
+        Spi::run(
+            "CREATE FUNCTION test_decrypt(v vector) RETURNS vector
+                AS $$ SELECT v $$
+                LANGUAGE sql VOLATILE STRICT;
+
+            CREATE TABLE test_enc (embedding vector(3));
+            CREATE INDEX idx_enc ON test_enc USING diskann (embedding)
+                WITH (decrypt_function = 'test_decrypt');",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(error = "function \"test_missing\" does not exist")]
+    unsafe fn test_decrypt_function_validated_on_alter() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_enc (embedding vector(3));
+            CREATE INDEX idx_enc ON test_enc USING diskann (embedding);
+            ALTER INDEX idx_enc SET (decrypt_function = 'test_missing');",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "cannot drop function test_decrypt(vector) because other objects depend on it"
+    )]
+    unsafe fn test_decrypt_function_dependency() -> spi::Result<()> {
+        nearest_with_decrypt_function("storage_layout = memory_optimized")?;
+
+        /* rebuilding doesn't record the dependency twice */
+        Spi::run("REINDEX INDEX idx_enc;")?;
+        let dependencies: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM pg_depend
+            WHERE objid = 'idx_enc'::regclass AND refobjid = 'test_decrypt'::regproc",
+        )?;
+        assert_eq!(dependencies, Some(1));
+
+        Spi::run("DROP FUNCTION test_decrypt;")?;
+        Ok(())
+    }
+
+    /// Returns the fraction of the edges of the graph of `index_name` that point to a node on
+    /// the same page, which a traversal can follow without reading another page.
+    #[cfg(any(test, feature = "pg_test"))]
+    unsafe fn same_page_edge_fraction(index_name: &str) -> spi::Result<f64> {
+        use crate::access_method::plain_storage::PlainStorage;
+        use crate::access_method::storage::ArchivedData;
+        use crate::access_method::storage_common::for_each_node;
+
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>(&format!("SELECT '{index_name}'::regclass::oid"))?
+                .expect("oid was null");
+        let index = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+
+        let mut same_page = 0;
+        let mut total = 0;
+        for_each_node::<PlainStorage, _>(&index, |index_pointer, node| {
+            for neighbor in node.get_index_pointer_to_neighbors() {
+                total += 1;
+                if neighbor.block_number == index_pointer.block_number {
+                    same_page += 1;
+                }
+            }
+        });
+        Ok(same_page as f64 / total as f64)
+    }
+
+    #[pg_test]
+    unsafe fn test_locality_layout() -> spi::Result<()> {
+        /* 20 clusters of vectors, interleaved in heap order */
+        Spi::run(
+            "CREATE TABLE test_data (
+                embedding vector (32)
+            );
+
+            select setseed(0.5);
+            CREATE TABLE centers AS
+            SELECT c, array_agg(random() * 10) AS center
+            FROM generate_series(0, 19) c, generate_series(1, 32) d
+            GROUP BY c;
+
+            INSERT INTO test_data (embedding)
+            SELECT (SELECT array_agg(x + random() * 0.1) FROM unnest(center) x)::vector
+            FROM generate_series(1, 2000) i
+            JOIN centers ON c = i % 20;",
+        )?;
+
+        Spi::run(
+            "CREATE INDEX idx_heap_order ON test_data USING diskann (embedding)
+                WITH (storage_layout = plain, num_neighbors = 10);
+            CREATE INDEX idx_locality ON test_data USING diskann (embedding)
+                WITH (storage_layout = plain, num_neighbors = 10, locality_layout = true);",
+        )?;
+
+        let heap_order = same_page_edge_fraction("idx_heap_order")?;
+        let locality = same_page_edge_fraction("idx_locality")?;
+        info!(
+            "edges to the same page: heap order {:.3}, locality layout {:.3}",
+            heap_order, locality
+        );
+        assert!(
+            locality > heap_order * 2.0,
+            "{} vs {}",
+            locality,
+            heap_order
+        );
+
+        /* the index still finds each row as its own nearest neighbor */
+        Spi::run("DROP INDEX idx_heap_order; SET enable_seqscan = 0;")?;
+        let nearest: Option<pg_sys::ItemPointerData> = Spi::get_one(
+            "SELECT ctid FROM test_data
+            ORDER BY embedding <=> (SELECT embedding FROM test_data WHERE ctid = '(3,1)')
+            LIMIT 1",
+        )?;
+        assert_eq!(
+            ItemPointer::with_item_pointer_data(nearest.unwrap()),
+            ItemPointer::new(3, 1)
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_locality_layout_runs() -> spi::Result<()> {
+        /* 3000 vectors of 1kB don't fit in 1MB, so the build sorts them in runs */
+        Spi::run(
+            "CREATE TABLE test_data (
+                embedding vector (256)
+            );
+
+            select setseed(0.5);
+            INSERT INTO test_data (embedding)
+            SELECT array_agg(random())::vector
+            FROM generate_series(1, 256 * 3000) i
+            GROUP BY i % 3000;
+
+            SET maintenance_work_mem = '1MB';
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (locality_layout = true);
+            RESET maintenance_work_mem;
+            SET enable_seqscan = 0;",
+        )?;
+
+        let count: Option<i64> = Spi::get_one(
+            "WITH cte AS (
+                SELECT * FROM test_data
+                ORDER BY embedding <=> (SELECT embedding FROM test_data LIMIT 1)
+            )
+            SELECT count(*) FROM cte;",
+        )?;
+        assert_eq!(count, Some(3000));
+
+        let nearest: Option<pg_sys::ItemPointerData> = Spi::get_one(
+            "SELECT ctid FROM test_data
+            ORDER BY embedding <=> (SELECT embedding FROM test_data WHERE ctid = '(100,1)')
+            LIMIT 1",
+        )?;
+        assert_eq!(
+            ItemPointer::with_item_pointer_data(nearest.unwrap()),
+            ItemPointer::new(100, 1)
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_locality_layout_sbq_creation() -> spi::Result<()> {
+        test_index_creation_and_accuracy_scaffold("locality_layout = true")?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_compensated_sum_index_creation() -> spi::Result<()> {
+        test_index_creation_and_accuracy_scaffold("compensated_sum = true")?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_compensated_sum_plain_index_creation() -> spi::Result<()> {
+        test_index_creation_and_accuracy_scaffold(
+            "storage_layout = plain, compensated_sum = true",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_exact_traversal_index_creation() -> spi::Result<()> {
+        test_index_creation_and_accuracy_scaffold("exact_traversal = true")?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_exact_traversal_io_optimized_index_creation() -> spi::Result<()> {
+        test_index_creation_and_accuracy_scaffold(
+            "storage_layout = io_optimized, exact_traversal = true",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "exact_traversal is only supported with the memory_optimized and io_optimized storage layouts, plain searches already use full vectors"
+    )]
+    unsafe fn test_exact_traversal_plain() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH (storage_layout = plain, exact_traversal = true);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "column \"embedding\" has 32 dimensions, more than diskann.max_dimensions (16)"
+    )]
+    unsafe fn test_max_dimensions() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (32));
+            SET diskann.max_dimensions = 16;
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "column \"embedding\" has 5000 dimensions, more than diskann.max_dimensions (1999)"
+    )]
+    unsafe fn test_max_dimensions_default() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (5000));
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_collapse_duplicate_vectors() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized", "io_optimized"] {
+            /* 50 distinct vectors, 20 rows each */
+            Spi::run(&format!(
+                "DROP TABLE IF EXISTS test_data;
+                CREATE TABLE test_data (id serial, embedding vector (32));
+
+                select setseed(0.5);
+                INSERT INTO test_data (embedding)
+                SELECT g.embedding
+                FROM (
+                    SELECT
+                        ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                    FROM
+                        generate_series(1, 32 * 50) i
+                    GROUP BY
+                        i % 50) g, generate_series(1, 20) copy;
+
+                CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout}, num_neighbors = 20, collapse_duplicate_vectors = true);
+
+                SET enable_seqscan = 0;
+                SET diskann.query_search_list_size = 100;"
+            ))?;
+
+            /* every row is still found, and all the copies of the closest vector come first */
+            Spi::run(
+                "DROP TABLE IF EXISTS target;
+                CREATE TEMP TABLE target AS SELECT embedding FROM test_data WHERE id = 1;",
+            )?;
+            let count = Spi::get_one::<i64>(
+                "SELECT count(*) FROM (SELECT * FROM test_data ORDER BY embedding <=> (SELECT embedding FROM target) LIMIT 10000) t",
+            )?;
+            assert_eq!(count.unwrap(), 1000, "{}", storage_layout);
+            let top_copies = "SELECT count(*) FROM (SELECT embedding FROM test_data ORDER BY embedding <=> (SELECT embedding FROM target) LIMIT 20) t
+                WHERE embedding = (SELECT embedding FROM target)";
+            assert_eq!(
+                Spi::get_one::<i64>(top_copies)?.unwrap(),
+                20,
+                "{}",
+                storage_layout
+            );
+
+            /* the chain survives deleting the representative, the first row of the vector, and
+             * some of the copies */
+            Spi::run(
+                "DELETE FROM test_data WHERE embedding = (SELECT embedding FROM target)
+                    AND (id % 3 = 1 OR id = (SELECT min(id) FROM test_data WHERE embedding = (SELECT embedding FROM target)));",
+            )?;
+            let remaining = Spi::get_one::<i64>(
+                "SELECT count(*) FROM test_data WHERE embedding = (SELECT embedding FROM target)",
+            )?
+            .unwrap();
+            assert!(remaining > 0 && remaining < 20);
+            assert_eq!(
+                Spi::get_one::<i64>(top_copies)?.unwrap(),
+                remaining,
+                "{}",
+                storage_layout
+            );
+        }
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_collapse_duplicate_vectors_hash_collisions() -> spi::Result<()> {
+        /* every vector hashes alike, so only the comparison of the vectors tells them apart */
+        fn colliding_hash(_: &[f32]) -> u64 {
+            0
+        }
+        let hash: fn(&[f32]) -> u64 = colliding_hash;
+        super::DUPLICATE_HASH_OVERRIDE.with(|h| h.set(Some(hash)));
+
+        /* 50 distinct vectors, 20 rows each */
+        let result = Spi::run(
+            "CREATE TABLE test_data (id serial, embedding vector (32));
+
+            select setseed(0.5);
+            INSERT INTO test_data (embedding)
+            SELECT g.embedding
+            FROM (
+                SELECT
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 32 * 50) i
+                GROUP BY
+                    i % 50) g, generate_series(1, 20) copy;
+
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = plain, num_neighbors = 20, collapse_duplicate_vectors = true);",
+        );
+        super::DUPLICATE_HASH_OVERRIDE.with(|h| h.set(None));
+        result?;
+
+        /* each distinct vector has its own node in the graph, the copies only link to the
+         * next one in their chain */
+        let linked = Spi::get_one::<i64>(
+            "SELECT sum(num_nodes)::int8 FROM tsv_degree_histogram('idx_diskann'::regclass) WHERE degree > 1",
+        )?;
+        assert_eq!(linked.unwrap(), 50);
+
+        Spi::run("SET enable_seqscan = 0; SET diskann.query_search_list_size = 100;")?;
+        let top_copies = Spi::get_one::<i64>(
+            "SELECT count(*) FROM (SELECT embedding FROM test_data ORDER BY embedding <=> (SELECT embedding FROM test_data WHERE id = 1) LIMIT 20) t
+                WHERE embedding = (SELECT embedding FROM test_data WHERE id = 1)",
+        )?;
+        assert_eq!(top_copies.unwrap(), 20);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_collapse_duplicate_vectors_negative_zero() -> spi::Result<()> {
+        /* -0 and 0 are the same value: the two rows share a node */
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            INSERT INTO test_data (embedding) VALUES ('[0,1,1]'), ('[-0,1,1]'), ('[1,0,1]'), ('[1,1,0]');
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = plain, collapse_duplicate_vectors = true);",
+        )?;
+        let linked = Spi::get_one::<i64>(
+            "SELECT sum(num_nodes)::int8 FROM tsv_degree_histogram('idx_diskann'::regclass) WHERE degree > 1",
+        )?;
+        assert_eq!(linked.unwrap(), 3);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_build_candidate_sample_rate() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 2000)?;
+
+        let mut recalls = vec![];
+        for sample_rate in [1.0, 0.5, 0.2] {
+            let start = std::time::Instant::now();
+            Spi::run(&format!(
+                "DROP INDEX IF EXISTS idx_diskann;
+                CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (num_neighbors = 20, build_candidate_sample_rate = {sample_rate});"
+            ))?;
+            let build_time = start.elapsed();
+            let recall = Spi::get_one::<f64>(
+                "SELECT recall FROM tsv_selftest('idx_diskann'::regclass, 50, 10)",
+            )?
+            .unwrap();
+            info!(
+                "build_candidate_sample_rate {}: build {:?}, recall@10 {:.3}",
+                sample_rate, build_time, recall
+            );
+            recalls.push(recall);
+        }
+
+        assert!(recalls[0] > 0.9, "{:?}", recalls);
+        /* sampling costs some recall but still yields a usable graph */
+        assert!(recalls.iter().all(|&r| r > 0.5), "{:?}", recalls);
+
+        Ok(())
+    }
 }
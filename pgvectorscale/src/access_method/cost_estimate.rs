@@ -1,5 +1,25 @@
+use std::collections::HashSet;
+
+use pgrx::pg_sys::AsPgCStr;
 use pgrx::*;
 
+use super::distance::{distance_cosine, distance_xor_optimized, preprocess_cosine};
+use super::meta_page::MetaPage;
+use super::plain_node::Node;
+use super::plain_storage::PlainStorage;
+use super::sbq::{SbqNode, SbqQuantizer, SbqSpeedupStorage};
+use super::storage::StorageType;
+use super::storage_common::{check_is_diskann_index, live_nodes};
+
+/// The cost of reading a node from a page in shared buffers, in microseconds.
+const NODE_READ_US: f64 = 0.5;
+/// The cost of fetching a row from the table to rescore it, in microseconds.
+const HEAP_READ_US: f64 = 2.0;
+/// The cost of a full distance comparison per dimension, in microseconds.
+const FULL_DISTANCE_US_PER_DIMENSION: f64 = 0.0003;
+/// The cost of an SBQ distance comparison per 64 bits of code, in microseconds.
+const SBQ_DISTANCE_US_PER_WORD: f64 = 0.001;
+
 /// cost estimate function loosely based on how ivfflat does things
 #[pg_guard(immutable, parallel_safe)]
 pub unsafe extern "C" fn amcostestimate(
@@ -54,3 +74,526 @@ pub unsafe extern "C" fn amcostestimate(
     *index_pages = generic_costs.numIndexPages;
     //pg_sys::cpu_index_tuple_cost;
 }
+
+/// The number of nodes a greedy search visits and the number of distance comparisons it makes
+/// in a graph of `num_nodes` nodes with `degree` neighbors each: about `search_list_size` nodes
+/// plus the hops it takes to get close to the query, and every neighbor of those, at most the
+/// whole graph.
+fn search_work(num_nodes: f64, degree: f64, search_list_size: f64) -> (f64, f64) {
+    let hops_to_query = if num_nodes > 1.0 && degree > 1.0 {
+        num_nodes.ln() / degree.ln()
+    } else {
+        0.0
+    };
+    let visited = num_nodes.min(search_list_size + hops_to_query);
+    let comparisons = num_nodes.min(visited * degree);
+    (visited, comparisons)
+}
+
+/// The node reads of a search that visits `visited` nodes and makes `comparisons` distance
+/// comparisons, and the cost of one comparison in microseconds.
+fn search_reads_and_distance_cost(
+    storage_type: StorageType,
+    dimensions: f64,
+    num_bits_per_dimension: u8,
+    visited: f64,
+    comparisons: f64,
+) -> (f64, f64) {
+    match storage_type {
+        /* every candidate is read for its vector, which also holds its neighbors */
+        StorageType::Plain => (comparisons, dimensions * FULL_DISTANCE_US_PER_DIMENSION),
+        /* the codes of the neighbors are in the node with io_optimized, memory_optimized reads
+         * the node of every candidate for its code */
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let words = (dimensions * num_bits_per_dimension as f64 / 64.0).ceil();
+            let node_reads = if storage_type == StorageType::SbqSpeedup {
+                visited
+            } else {
+                visited + comparisons
+            };
+            (node_reads, words * SBQ_DISTANCE_US_PER_WORD)
+        }
+    }
+}
+
+/// Predicts the work of an index search with the given search list size, from the number of
+/// nodes (the row estimate of the index, or a count of its nodes if it was never analyzed) and
+/// num_neighbors, without running one. A greedy search visits about `search_list_size` nodes
+/// plus the hops it takes to get close to the query, and compares the query to every neighbor
+/// of the visited nodes it hasn't seen yet, at most num_neighbors each. The latency assumes
+/// the index is in shared buffers and costs each node read, row fetch for rescoring and
+/// distance comparison (full or SBQ, depending on the storage layout) at a fixed rate: it is
+/// meant for comparing search list sizes, tsv_search_hops() and EXPLAIN ANALYZE measure the
+/// real thing.
+#[pg_extern]
+pub fn tsv_estimate_latency(
+    index: PgRelation,
+    search_list_size: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(visited_nodes, i64),
+        name!(distance_comparisons, i64),
+        name!(node_reads, i64),
+        name!(heap_reads, i64),
+        name!(estimated_ms, f64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    if search_list_size < 1 {
+        error!("search_list_size must be at least 1");
+    }
+    let meta_page = MetaPage::fetch(&index);
+    let storage_type = meta_page.get_storage_type();
+
+    let num_nodes = match index.reltuples() {
+        Some(reltuples) if reltuples > 0.0 => reltuples as f64,
+        _ => match storage_type {
+            StorageType::Plain => live_nodes::<PlainStorage>(&index).len() as f64,
+            StorageType::SbqSpeedup | StorageType::SbqCompression => {
+                live_nodes::<SbqSpeedupStorage>(&index).len() as f64
+            }
+        },
+    };
+    let degree = meta_page.get_num_neighbors() as f64;
+
+    let (visited, comparisons) = search_work(num_nodes, degree, search_list_size as f64);
+    let dimensions = meta_page.get_num_dimensions_to_index() as f64;
+    let (node_reads, distance_us) = search_reads_and_distance_cost(
+        storage_type,
+        dimensions,
+        meta_page.get_bq_num_bits_per_dimension(),
+        visited,
+        comparisons,
+    );
+    /* SBQ results get rescored with the vectors from the table */
+    let heap_reads = match storage_type {
+        StorageType::Plain => 0.0,
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            num_nodes.min(super::guc::TSV_RESORT_SIZE.get() as f64)
+        }
+    };
+
+    let estimated_us = node_reads * NODE_READ_US
+        + heap_reads * (HEAP_READ_US + dimensions * FULL_DISTANCE_US_PER_DIMENSION)
+        + comparisons * distance_us;
+    TableIterator::once((
+        visited.round() as i64,
+        comparisons.round() as i64,
+        node_reads.round() as i64,
+        heap_reads.round() as i64,
+        estimated_us / 1000.0,
+    ))
+}
+
+/// The number of nearest neighbors whose recall tsv_build_dryrun() estimates.
+const DRYRUN_RECALL_K: usize = 10;
+/// The number of sampled rows tsv_build_dryrun() uses as queries for its recall estimate.
+const DRYRUN_RECALL_QUERIES: usize = 100;
+/// The most rows tsv_build_dryrun() samples. Every query of the recall estimate is compared to
+/// the whole sample, which is also held in memory.
+const DRYRUN_MAX_SAMPLE_SIZE: i32 = 10000;
+
+/// Errors out unless `column` of `table` is a vector column declared with its number of
+/// dimensions, which it returns.
+fn vector_column_dimensions(table: &PgRelation, column: &str) -> u32 {
+    let tuple_desc = table.tuple_desc();
+    let attr = match tuple_desc
+        .iter()
+        .find(|attr| !attr.is_dropped() && attr.name() == column)
+    {
+        Some(attr) => attr,
+        None => error!(
+            "column \"{}\" does not exist in \"{}\"",
+            column,
+            table.name()
+        ),
+    };
+
+    let type_name = unsafe {
+        std::ffi::CStr::from_ptr(pg_sys::format_type_be(attr.type_oid().value()))
+            .to_string_lossy()
+            .into_owned()
+    };
+    if type_name != "vector" && !type_name.ends_with(".vector") {
+        error!(
+            "column \"{}\" has type {}, diskann indexes are on vector columns",
+            column, type_name
+        );
+    }
+    if attr.atttypmod <= 0 {
+        error!(
+            "column \"{}\" has no dimensions, diskann indexes need a column declared like vector(3)",
+            column
+        );
+    }
+    attr.atttypmod as u32
+}
+
+/// Reads the vectors of about `sample_size` rows of `column` of `table`, which has about
+/// `rows` rows, from random pages, normalized like an index normalizes them.
+fn sample_vectors(table: &PgRelation, column: &str, rows: f64, sample_size: i32) -> Vec<Vec<f32>> {
+    let (table_name, column_name) = unsafe {
+        (
+            std::ffi::CStr::from_ptr(pg_sys::quote_qualified_identifier(
+                table.namespace().as_pg_cstr(),
+                table.name().as_pg_cstr(),
+            ))
+            .to_string_lossy()
+            .into_owned(),
+            std::ffi::CStr::from_ptr(pg_sys::quote_identifier(column.as_pg_cstr()))
+                .to_string_lossy()
+                .into_owned(),
+        )
+    };
+    /* twice the share of the pages the sample needs, as some rows are NULL or the sample
+     * comes up short */
+    let percent = if rows > 0.0 {
+        (200.0 * sample_size as f64 / rows).min(100.0)
+    } else {
+        100.0
+    };
+    let query = format!(
+        "SELECT {column_name}::real[] FROM {table_name} TABLESAMPLE SYSTEM ({percent}) REPEATABLE (0)
+        WHERE {column_name} IS NOT NULL LIMIT {sample_size}"
+    );
+
+    Spi::connect(|client| {
+        let mut vectors = vec![];
+        for row in client.select(&query, None, None)? {
+            if let Some(mut vector) = row.get::<Vec<f32>>(1)? {
+                preprocess_cosine(&mut vector);
+                vectors.push(vector);
+            }
+        }
+        Ok::<_, spi::Error>(vectors)
+    })
+    .unwrap_or_else(|e| error!("cannot sample \"{}\": {}", table.name(), e))
+}
+
+/// Estimates the recall that SBQ with `num_bits_per_dimension` allows on `samples`: the share
+/// of the DRYRUN_RECALL_K nearest neighbors of sampled rows among the samples that are among
+/// the `rescore` nearest by SBQ distance, which a search rescoring that many candidates finds.
+fn sbq_recall_estimate(samples: &[Vec<f32>], num_bits_per_dimension: u8, rescore: usize) -> f64 {
+    let quantizer = SbqQuantizer::train(samples, num_bits_per_dimension);
+    let codes: Vec<_> = samples
+        .iter()
+        .map(|sample| quantizer.quantize(sample))
+        .collect();
+
+    let num_queries = std::cmp::min(DRYRUN_RECALL_QUERIES, samples.len());
+    let mut found = 0;
+    let mut total = 0;
+    for query in 0..num_queries {
+        check_for_interrupts!();
+        let mut exact: Vec<(f32, usize)> = (0..samples.len())
+            .filter(|&i| i != query)
+            .map(|i| (distance_cosine(&samples[query], &samples[i]), i))
+            .collect();
+        let mut approximate: Vec<(usize, usize)> = (0..samples.len())
+            .filter(|&i| i != query)
+            .map(|i| (distance_xor_optimized(&codes[query], &codes[i]), i))
+            .collect();
+        exact.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        approximate.sort();
+
+        let candidates: HashSet<usize> = approximate.iter().take(rescore).map(|c| c.1).collect();
+        for (_, i) in exact.iter().take(DRYRUN_RECALL_K) {
+            total += 1;
+            if candidates.contains(i) {
+                found += 1;
+            }
+        }
+    }
+    if total == 0 {
+        1.0
+    } else {
+        found as f64 / total as f64
+    }
+}
+
+/// Estimates the size, build time and recall of a diskann index on `column` of `table` with
+/// the given options, without building it: the row count is the planner's estimate for the
+/// table, the node size that of a node of the storage layout serialized with the dimensions
+/// of the column, and the build time that of inserting every row with a greedy search of
+/// `search_list_size` costed like tsv_estimate_latency() does, plus pruning the visited nodes
+/// down to num_neighbors. Options left NULL get the defaults CREATE INDEX would use.
+///
+/// The recall is that of the quantization: an SBQ quantizer is trained on a sample of
+/// `sample_size` rows, at most 10000, and for up to 100 of them, the share of their 10
+/// nearest neighbors among the sample, by exact distance, that are among the
+/// diskann.query_rescore nearest by SBQ distance, the candidates a search rescores. Searches
+/// can miss neighbors the graph doesn't lead to on top of that. Plain indexes don't quantize
+/// and get 1. Nothing is written.
+#[pg_extern]
+pub fn tsv_build_dryrun(
+    table: PgRelation,
+    column: &str,
+    storage_layout: default!(&str, "'memory_optimized'"),
+    num_neighbors: default!(Option<i32>, "NULL"),
+    num_bits_per_dimension: default!(Option<i32>, "NULL"),
+    search_list_size: default!(i32, 100),
+    sample_size: default!(i32, 1000),
+) -> TableIterator<
+    'static,
+    (
+        name!(storage_layout, String),
+        name!(num_neighbors, i32),
+        name!(num_bits_per_dimension, i32),
+        name!(estimated_rows, i64),
+        name!(node_bytes, i32),
+        name!(estimated_size_bytes, i64),
+        name!(estimated_build_seconds, f64),
+        name!(sampled_rows, i64),
+        name!(estimated_recall, f64),
+    ),
+> {
+    let dimensions = vector_column_dimensions(&table, column);
+    if search_list_size < 1 {
+        error!("search_list_size must be at least 1");
+    }
+    if !(1..=DRYRUN_MAX_SAMPLE_SIZE).contains(&sample_size) {
+        error!(
+            "sample_size must be between 1 and {}",
+            DRYRUN_MAX_SAMPLE_SIZE
+        );
+    }
+
+    let storage_type =
+        unsafe { MetaPage::resolve_storage_type(&table, dimensions, storage_layout) };
+    let num_bits_per_dimension = match num_bits_per_dimension {
+        Some(bits) if !(1..=32).contains(&bits) => {
+            error!("num_bits_per_dimension must be between 1 and 32")
+        }
+        Some(bits) => bits as u8,
+        None => MetaPage::default_bq_num_bits_per_dimension(dimensions, storage_type),
+    };
+    let num_neighbors = match num_neighbors {
+        Some(n) if n <= 10 => error!("num_neighbors must be greater than 10"),
+        Some(n) => n as u32,
+        None => MetaPage::default_num_neighbors(dimensions, num_bits_per_dimension, storage_type),
+    };
+
+    let node_bytes = match storage_type {
+        StorageType::Plain => Node::test_size(num_neighbors as usize, dimensions as usize),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let dimensions_for_neighbors = if storage_type == StorageType::SbqSpeedup {
+                dimensions as usize
+            } else {
+                0
+            };
+            SbqNode::test_size(
+                num_neighbors as usize,
+                dimensions as usize,
+                dimensions_for_neighbors,
+                num_bits_per_dimension,
+            )
+        }
+    };
+    /* a page has a header and a special area, and every node a line pointer */
+    let page_space = pg_sys::BLCKSZ as usize - pg_sys::SizeOfPageHeaderData - pg_sys::MAXALIGN(4);
+    let stored_bytes = pg_sys::MAXALIGN(node_bytes) + std::mem::size_of::<pg_sys::ItemIdData>();
+    if stored_bytes > page_space {
+        error!(
+            "a node of {} bytes does not fit on a page, use fewer num_neighbors or dimensions",
+            node_bytes
+        );
+    }
+    let nodes_per_page = page_space / stored_bytes;
+
+    let mut pages: pg_sys::BlockNumber = 0;
+    let mut rows: f64 = 0.0;
+    let mut allvisfrac: f64 = 0.0;
+    unsafe {
+        pg_sys::estimate_rel_size(
+            table.as_ptr(),
+            std::ptr::null_mut(),
+            &mut pages,
+            &mut rows,
+            &mut allvisfrac,
+        )
+    };
+    let rows = rows.max(0.0);
+
+    /* the meta page, and the page of the SBQ means */
+    let extra_pages = match storage_type {
+        StorageType::Plain => 1.0,
+        StorageType::SbqSpeedup | StorageType::SbqCompression => 2.0,
+    };
+    let index_pages = (rows / nodes_per_page as f64).ceil() + extra_pages;
+
+    /* every insert searches the graph built so far, on average half the rows, and prunes the
+     * nodes it visited */
+    let degree = num_neighbors as f64;
+    let (visited, comparisons) = search_work(rows / 2.0, degree, search_list_size as f64);
+    let (node_reads, distance_us) = search_reads_and_distance_cost(
+        storage_type,
+        dimensions as f64,
+        num_bits_per_dimension,
+        visited,
+        comparisons,
+    );
+    let prune_comparisons = visited * degree;
+    let insert_us =
+        node_reads * NODE_READ_US + (comparisons + prune_comparisons) * distance_us + HEAP_READ_US;
+
+    let samples = sample_vectors(&table, column, rows, sample_size);
+    let estimated_recall = match storage_type {
+        StorageType::Plain => 1.0,
+        StorageType::SbqSpeedup | StorageType::SbqCompression => sbq_recall_estimate(
+            &samples,
+            num_bits_per_dimension,
+            super::guc::TSV_RESORT_SIZE.get() as usize,
+        ),
+    };
+
+    TableIterator::once((
+        storage_type.as_str().to_string(),
+        num_neighbors as i32,
+        num_bits_per_dimension as i32,
+        rows.round() as i64,
+        node_bytes as i32,
+        (index_pages * pg_sys::BLCKSZ as f64) as i64,
+        rows * insert_us / 1_000_000.0,
+        samples.len() as i64,
+        estimated_recall,
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_tsv_estimate_latency() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized", "io_optimized"] {
+            crate::access_method::build::tests::create_test_data(32, 300)?;
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout}, num_neighbors = 20);"
+            ))?;
+
+            let estimate = |search_list_size: i32| {
+                Spi::get_three::<i64, i64, f64>(&format!(
+                    "SELECT visited_nodes, distance_comparisons, estimated_ms FROM tsv_estimate_latency('idx_diskann'::regclass, {search_list_size})"
+                ))
+            };
+            let (visited, comparisons, ms) = estimate(10)?;
+            let (visited, comparisons, ms) = (visited.unwrap(), comparisons.unwrap(), ms.unwrap());
+            assert!(visited >= 10 && visited < 20, "{}", visited);
+            assert!(
+                comparisons > visited && comparisons <= 300,
+                "{}",
+                comparisons
+            );
+            assert!(ms > 0.0);
+
+            /* larger lists cost more, and never more than the whole index */
+            let (visited_more, comparisons_more, ms_more) = estimate(100)?;
+            assert!(visited_more.unwrap() > visited);
+            assert_eq!(comparisons_more.unwrap(), 300);
+            assert!(ms_more.unwrap() > ms);
+            assert_eq!(estimate(1000)?.0.unwrap(), 300);
+        }
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_build_dryrun() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 300)?;
+        Spi::run("ANALYZE test_data;")?;
+
+        for storage_layout in ["plain", "memory_optimized", "io_optimized"] {
+            let (rows, size) = Spi::get_two::<i64, i64>(&format!(
+                "SELECT estimated_rows, estimated_size_bytes FROM tsv_build_dryrun('test_data'::regclass, 'embedding', '{storage_layout}', num_neighbors => 20)"
+            ))?;
+            assert_eq!(rows.unwrap(), 300);
+            let estimated_size = size.unwrap();
+
+            /* the estimate is within a few pages of the real index */
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout}, num_neighbors = 20);"
+            ))?;
+            let size =
+                Spi::get_one::<i64>("SELECT pg_relation_size('idx_diskann'::regclass)")?.unwrap();
+            assert!(
+                (estimated_size - size).abs() <= 4 * 8192,
+                "{}: estimated {} bytes, built {}",
+                storage_layout,
+                estimated_size,
+                size
+            );
+            Spi::run("DROP INDEX idx_diskann;")?;
+        }
+
+        /* the defaults are those of CREATE INDEX */
+        let (num_neighbors, bits) = Spi::get_two::<i32, i32>(
+            "SELECT num_neighbors, num_bits_per_dimension FROM tsv_build_dryrun('test_data'::regclass, 'embedding')",
+        )?;
+        assert_eq!(num_neighbors.unwrap(), 50);
+        assert_eq!(bits.unwrap(), 2);
+
+        /* the recall is estimated on a sample of every row here, and rescoring all of them
+         * finds every neighbor */
+        let (sampled, recall) = Spi::get_two::<i64, f64>(
+            "SELECT sampled_rows, estimated_recall FROM tsv_build_dryrun('test_data'::regclass, 'embedding')",
+        )?;
+        assert_eq!(sampled.unwrap(), 300);
+        let recall = recall.unwrap();
+        assert!(recall > 0.5 && recall <= 1.0, "{}", recall);
+        Spi::run("SET diskann.query_rescore = 300;")?;
+        let recall = Spi::get_one::<f64>(
+            "SELECT estimated_recall FROM tsv_build_dryrun('test_data'::regclass, 'embedding');",
+        )?;
+        assert_eq!(recall.unwrap(), 1.0);
+        Spi::run("RESET diskann.query_rescore;")?;
+        let recall = Spi::get_one::<f64>(
+            "SELECT estimated_recall FROM tsv_build_dryrun('test_data'::regclass, 'embedding', 'plain')",
+        )?;
+        assert_eq!(recall.unwrap(), 1.0);
+
+        /* auto picks a layout like CREATE INDEX does */
+        let storage_layout = Spi::get_one::<String>(
+            "SELECT storage_layout FROM tsv_build_dryrun('test_data'::regclass, 'embedding', 'auto')",
+        )?;
+        assert_eq!(storage_layout.unwrap(), "plain");
+
+        /* nothing was created */
+        let indexes = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pg_index WHERE indrelid = 'test_data'::regclass",
+        )?;
+        assert_eq!(indexes.unwrap(), 0);
+
+        Ok(())
+    }
+
+    #[pg_test(error = "column \"id\" has type integer, diskann indexes are on vector columns")]
+    unsafe fn test_tsv_build_dryrun_not_a_vector() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (id int, embedding vector (3));
+            SELECT * FROM tsv_build_dryrun('test_data'::regclass, 'id');",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(error = "column \"missing\" does not exist in \"test_data\"")]
+    unsafe fn test_tsv_build_dryrun_missing_column() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            SELECT * FROM tsv_build_dryrun('test_data'::regclass, 'missing');",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(error = "sample_size must be between 1 and 10000")]
+    unsafe fn test_tsv_build_dryrun_sample_size_too_large() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            SELECT * FROM tsv_build_dryrun('test_data'::regclass, 'embedding', sample_size => 100000);",
+        )?;
+        Ok(())
+    }
+}
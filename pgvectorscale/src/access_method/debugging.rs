@@ -1,11 +1,22 @@
 //! Debugging methods
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
-use pgrx::PgRelation;
+use pgrx::*;
 
-use crate::util::ItemPointer;
+use crate::util::{ports, table_slot::TableSlot, HeapPointer, IndexPointer, ItemPointer};
 
+use super::meta_page::MetaPage;
+use super::neighbor_with_distance::NeighborWithDistance;
+use super::pg_vector::{heap_datum_to_vec, PgVector};
+use super::plain_storage::PlainStorage;
+use super::sbq::{SbqMeans, SbqSpeedupStorage};
+use super::search::find_node_by_heap_pointer;
+use super::storage::{ArchivedData, Storage, StorageType};
+use super::storage_common::{
+    check_heap_select_privilege, check_is_diskann_index, for_each_node,
+    get_attribute_number_from_index, live_heap_pointers, with_node,
+};
 use super::{plain_node::Node, stats::GreedySearchStats};
 
 #[allow(dead_code)]
@@ -28,8 +39,7 @@ unsafe fn print_graph_from_disk_visitor(
     let mut stats = GreedySearchStats::new();
     let data_node = Node::read(&index, index_pointer, &mut stats);
     let node = data_node.get_archived_node();
-    let v = node.vector.as_slice();
-    let copy: Vec<f32> = v.iter().map(|f| *f).collect();
+    let copy: Vec<f32> = node.vector().into_owned();
     let name = format!("node {:?}", &copy);
 
     map.insert(index_pointer, copy);
@@ -49,3 +59,649 @@ unsafe fn print_graph_from_disk_visitor(
     }
     sb.push_str("\n")
 }
+
+/// Walks the graph breadth-first from `start` and returns the edges found within `depth` hops,
+/// as (hop, from, to) heap pointers, stopping after `max_edges` edges. Deleted nodes are skipped.
+fn sample_neighbors<S: Storage>(
+    index: &PgRelation,
+    start: IndexPointer,
+    depth: i32,
+    max_edges: usize,
+) -> Vec<(i32, HeapPointer, HeapPointer)> {
+    let mut heap_pointers: HashMap<IndexPointer, Option<HeapPointer>> = HashMap::new();
+    let mut heap_pointer_of = |index_pointer: IndexPointer| {
+        *heap_pointers.entry(index_pointer).or_insert_with(|| {
+            with_node::<S, _, _>(index, index_pointer, |node| {
+                if node.is_deleted() {
+                    None
+                } else {
+                    Some(node.get_heap_item_pointer())
+                }
+            })
+        })
+    };
+
+    let mut edges = vec![];
+    let mut visited: HashSet<IndexPointer> = HashSet::from([start]);
+    let mut queue: VecDeque<(IndexPointer, i32)> = VecDeque::from([(start, 1)]);
+    while let Some((from, hop)) = queue.pop_front() {
+        let from_heap_pointer = match heap_pointer_of(from) {
+            Some(heap_pointer) => heap_pointer,
+            None => continue,
+        };
+        let neighbors =
+            with_node::<S, _, _>(index, from, |node| node.get_index_pointer_to_neighbors());
+        for to in neighbors {
+            let to_heap_pointer = match heap_pointer_of(to) {
+                Some(heap_pointer) => heap_pointer,
+                None => continue,
+            };
+            edges.push((hop, from_heap_pointer, to_heap_pointer));
+            if edges.len() >= max_edges {
+                return edges;
+            }
+            if hop < depth && visited.insert(to) {
+                queue.push_back((to, hop + 1));
+            }
+        }
+    }
+    edges
+}
+
+/// Returns the edges of the graph reachable within `depth` hops of the node of the heap row
+/// `start_ctid`, for visualizing the local structure of the graph. `hop` is the distance of
+/// the edge from the start node. At most `max_edges` edges are returned.
+#[pg_extern]
+pub fn tsv_sample_neighbors(
+    index: PgRelation,
+    start_ctid: pg_sys::ItemPointerData,
+    depth: i32,
+    max_edges: default!(i32, 10000),
+) -> TableIterator<
+    'static,
+    (
+        name!(hop, i32),
+        name!(from_ctid, pg_sys::ItemPointerData),
+        name!(to_ctid, pg_sys::ItemPointerData),
+    ),
+> {
+    check_is_diskann_index(&index);
+    if depth < 1 {
+        error!("depth must be at least 1");
+    }
+    if max_edges < 1 {
+        error!("max_edges must be at least 1");
+    }
+
+    let start_heap_pointer = unsafe { HeapPointer::with_item_pointer_data(start_ctid) };
+    let start = match find_node_by_heap_pointer(&index, start_heap_pointer) {
+        Some(start) => start,
+        None => error!("no index node found for ctid {:?}", start_heap_pointer),
+    };
+
+    let edges = match MetaPage::fetch(&index).get_storage_type() {
+        StorageType::Plain => {
+            sample_neighbors::<PlainStorage>(&index, start, depth, max_edges as usize)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            sample_neighbors::<SbqSpeedupStorage>(&index, start, depth, max_edges as usize)
+        }
+    };
+
+    let rows = edges
+        .into_iter()
+        .map(|(hop, from, to)| {
+            let mut from_ctid = pg_sys::ItemPointerData::default();
+            from.to_item_pointer_data(&mut from_ctid);
+            let mut to_ctid = pg_sys::ItemPointerData::default();
+            to.to_item_pointer_data(&mut to_ctid);
+            (hop, from_ctid, to_ctid)
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// Counts the non-deleted nodes by number of outgoing edges.
+fn degree_histogram<S: Storage>(index: &PgRelation) -> BTreeMap<usize, i64> {
+    let mut histogram = BTreeMap::new();
+    for_each_node::<S, _>(index, |_, node| {
+        if !node.is_deleted() {
+            let degree = node.get_index_pointer_to_neighbors().len();
+            *histogram.entry(degree).or_insert(0) += 1;
+        }
+    });
+    histogram
+}
+
+/// Returns the number of non-deleted nodes for each out-degree present in the graph, in
+/// increasing order of degree. Many low-degree nodes point to a poorly connected graph
+/// (and poor recall), high-degree hubs to costly traversals.
+#[pg_extern]
+pub fn tsv_degree_histogram(
+    index: PgRelation,
+) -> TableIterator<'static, (name!(degree, i32), name!(num_nodes, i64))> {
+    check_is_diskann_index(&index);
+
+    let histogram = match MetaPage::fetch(&index).get_storage_type() {
+        StorageType::Plain => degree_histogram::<PlainStorage>(&index),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            degree_histogram::<SbqSpeedupStorage>(&index)
+        }
+    };
+
+    let rows = histogram
+        .into_iter()
+        .map(|(degree, num_nodes)| (degree as i32, num_nodes))
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// Counts the edges pointing to each non-deleted node, from the neighbor lists of all the
+/// nodes, and returns the `n` nodes with the most, most first, with their rows.
+fn top_hubs<S: Storage>(index: &PgRelation, n: usize) -> Vec<(HeapPointer, i64)> {
+    let mut rows: HashMap<IndexPointer, HeapPointer> = HashMap::new();
+    let mut in_degrees: HashMap<IndexPointer, i64> = HashMap::new();
+    for_each_node::<S, _>(index, |index_pointer, node| {
+        if !node.is_deleted() {
+            rows.insert(index_pointer, node.get_heap_item_pointer());
+            in_degrees.entry(index_pointer).or_insert(0);
+        }
+        for neighbor in node.get_index_pointer_to_neighbors() {
+            *in_degrees.entry(neighbor).or_insert(0) += 1;
+        }
+    });
+
+    let mut hubs: Vec<(IndexPointer, i64)> = in_degrees
+        .into_iter()
+        .filter(|(index_pointer, _)| rows.contains_key(index_pointer))
+        .collect();
+    hubs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    hubs.truncate(n);
+    hubs.into_iter()
+        .map(|(index_pointer, in_degree)| (rows[&index_pointer], in_degree))
+        .collect()
+}
+
+/// Returns the `n` non-deleted nodes with the most incoming edges, most first, with their
+/// rows. Searches go through these hubs on most queries, so they dominate traversal cost and
+/// are the nodes worth prewarming; a few hubs with far more in-edges than the rest call for
+/// tsv_rebalance(). Reads every node of the index.
+#[pg_extern]
+pub fn tsv_top_hubs(
+    index: PgRelation,
+    n: i32,
+) -> TableIterator<'static, (name!(ctid, pg_sys::ItemPointerData), name!(in_degree, i64))> {
+    check_is_diskann_index(&index);
+    if n < 1 {
+        error!("n must be at least 1");
+    }
+
+    let hubs = match MetaPage::fetch(&index).get_storage_type() {
+        StorageType::Plain => top_hubs::<PlainStorage>(&index, n as usize),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            top_hubs::<SbqSpeedupStorage>(&index, n as usize)
+        }
+    };
+
+    let rows = hubs
+        .into_iter()
+        .map(|(heap_pointer, in_degree)| {
+            let mut ctid = pg_sys::ItemPointerData::default();
+            heap_pointer.to_item_pointer_data(&mut ctid);
+            (ctid, in_degree)
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// Counts the non-deleted nodes that have a non-deleted neighbor within `epsilon` of them.
+fn count_near_duplicates<S: Storage>(storage: &S, index: &PgRelation, epsilon: f32) -> i64 {
+    let mut live = HashSet::new();
+    for_each_node::<S, _>(index, |index_pointer, node| {
+        if !node.is_deleted() {
+            live.insert(index_pointer);
+        }
+    });
+
+    let mut stats = GreedySearchStats::new();
+    let mut neighbors: Vec<NeighborWithDistance> = vec![];
+    let mut count = 0;
+    for &index_pointer in live.iter() {
+        check_for_interrupts!();
+        neighbors.clear();
+        storage.get_neighbors_with_distances_from_disk(index_pointer, &mut neighbors, &mut stats);
+        if neighbors.iter().any(|neighbor| {
+            neighbor.get_distance() <= epsilon
+                && live.contains(&neighbor.get_index_pointer_to_neighbor())
+        }) {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Estimates the number of rows that have a near-duplicate, another row within `epsilon` of
+/// them, by comparing every node to its neighbors in the graph only. The neighbors of a node
+/// are the closest rows the build found, so most near-duplicates are counted, but the count
+/// is approximate: a duplicate pruned from the neighbor list is missed.
+///
+/// The distances are those the index keeps: between the stored vectors for the plain
+/// layout, and between the SBQ codes, in differing bits, for the memory_optimized and
+/// io_optimized layouts. There, an `epsilon` of 0 counts the rows whose vectors quantize
+/// to the same code.
+#[pg_extern]
+pub fn tsv_count_near_duplicates(index: PgRelation, epsilon: f64) -> i64 {
+    check_is_diskann_index(&index);
+    if epsilon < 0.0 {
+        error!("epsilon must not be negative");
+    }
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let meta_page = MetaPage::fetch(&index);
+    if !meta_page.get_index_type().has_graph() {
+        error!(
+            "diskann index \"{}\" has no graph to find near-duplicates with, its index_type is {}",
+            index.name(),
+            meta_page.get_index_type().as_str()
+        );
+    }
+
+    match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::load_for_insert(&index, &heap, meta_page.get_distance_function());
+            count_near_duplicates(&storage, &index, epsilon as f32)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let mut stats = GreedySearchStats::new();
+            let storage = SbqSpeedupStorage::load_for_insert(&heap, &index, &meta_page, &mut stats);
+            count_near_duplicates(&storage, &index, epsilon as f32)
+        }
+    }
+}
+
+/// Returns the ctid and the vector of every row in the index, for exporting the indexed
+/// vectors without scanning the rest of the table. The vectors are read from the table
+/// (through the decrypt_function of the index, if any): the nodes only hold the vectors as
+/// compared by the index, normalized, truncated to num_dimensions or quantized. Rows that
+/// aren't visible to the current snapshot are left out. The rows are streamed in index order.
+#[pg_extern]
+pub fn tsv_export_vectors(
+    index: PgRelation,
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(vector, Vec<f32>),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let meta_page = MetaPage::fetch(&index);
+    let heap_pointers = match meta_page.get_storage_type() {
+        StorageType::Plain => live_heap_pointers::<PlainStorage>(&index),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            live_heap_pointers::<SbqSpeedupStorage>(&index)
+        }
+    };
+    let heap_attr = get_attribute_number_from_index(&index);
+    let snapshot = unsafe { pg_sys::GetActiveSnapshot() };
+    let mut stats = GreedySearchStats::new();
+
+    TableIterator::new(heap_pointers.into_iter().filter_map(move |heap_pointer| {
+        check_for_interrupts!();
+        let slot = unsafe { TableSlot::new_visible(&heap, heap_pointer, snapshot, &mut stats) }?;
+        let datum = unsafe { slot.get_attribute(heap_attr) }?;
+        let vector = unsafe { heap_datum_to_vec(datum, &meta_page) };
+
+        let mut ctid = pg_sys::ItemPointerData::default();
+        heap_pointer.to_item_pointer_data(&mut ctid);
+        Some((ctid, vector))
+    }))
+}
+
+/// Returns the rows whose SBQ code stored in the index disagrees with the code their vector
+/// in the table quantizes to now, with the number of bits that differ. Codes are computed once,
+/// when the row is indexed, so a row listed here was indexed from a different vector than the
+/// one the table returns today: a decrypt_function whose result changed, or a corrupted node.
+/// Searches compare against the stored codes, so these rows are found where their old vector
+/// was, until a REINDEX. Rows that aren't visible to the current snapshot are skipped.
+#[pg_extern]
+pub fn tsv_verify_sbq_codes(
+    index: PgRelation,
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(differing_bits, i32),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    let meta_page = MetaPage::fetch(&index);
+    if meta_page.get_storage_type() == StorageType::Plain {
+        error!(
+            "diskann index \"{}\" has no SBQ codes, its storage_layout is plain",
+            index.name()
+        );
+    }
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let heap_attr = get_attribute_number_from_index(&index);
+    let snapshot = unsafe { pg_sys::GetActiveSnapshot() };
+    let mut stats = GreedySearchStats::new();
+    let quantizer = unsafe { SbqMeans::load(&index, &meta_page, &mut stats) };
+
+    let mut codes: Vec<(HeapPointer, Vec<u64>)> = vec![];
+    for_each_node::<SbqSpeedupStorage, _>(&index, |_, node| {
+        if !node.is_deleted() {
+            codes.push((
+                node.get_heap_item_pointer(),
+                node.bq_vector.as_slice().to_vec(),
+            ));
+        }
+    });
+
+    let mut rows = vec![];
+    for (heap_pointer, stored) in codes {
+        check_for_interrupts!();
+        let slot =
+            match unsafe { TableSlot::new_visible(&heap, heap_pointer, snapshot, &mut stats) } {
+                Some(slot) => slot,
+                None => continue,
+            };
+        let datum = match unsafe { slot.get_attribute(heap_attr) } {
+            Some(datum) => datum,
+            None => continue,
+        };
+        let vector = unsafe { PgVector::from_heap_datum(datum, &meta_page, true, false) };
+        let differing_bits: u32 = quantizer
+            .quantize(vector.to_index_slice())
+            .iter()
+            .zip(stored.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        if differing_bits > 0 {
+            let mut ctid = pg_sys::ItemPointerData::default();
+            heap_pointer.to_item_pointer_data(&mut ctid);
+            rows.push((ctid, differing_bits as i32));
+        }
+    }
+    TableIterator::new(rows)
+}
+
+/// Drops the pages of a diskann index from shared buffers, so that the next searches read it
+/// from disk (or the OS cache), for measuring cold-cache performance without a restart.
+/// Dirty pages are written out first. Takes an AccessExclusiveLock on the index, held until
+/// the end of the transaction. Only superusers may call it.
+#[pg_extern]
+pub fn tsv_evict(index: PgRelation) {
+    check_is_diskann_index(&index);
+    if unsafe { !pg_sys::superuser() } {
+        error!("must be superuser to evict an index from shared buffers");
+    }
+
+    unsafe {
+        /* no one else may read the pages between the flush and the drop */
+        pg_sys::LockRelationOid(index.oid(), pg_sys::AccessExclusiveLock as _);
+        pg_sys::FlushRelationBuffers(index.as_ptr());
+        ports::DropRelationMainForkBuffers(index.as_ptr());
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_tsv_sample_neighbors() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 300)?;
+        Spi::run(
+            "CREATE INDEX idx_diskann ON test_data USING diskann (embedding) WITH (num_neighbors = 10);",
+        )?;
+
+        /* one hop: the out-edges of the start node */
+        let (cnt, from_start) = Spi::get_two::<i64, bool>(
+            "SELECT count(*), bool_and(from_ctid = '(0,1)') FROM tsv_sample_neighbors('idx_diskann'::regclass, '(0,1)', 1)",
+        )?;
+        let cnt = cnt.unwrap();
+        assert!(cnt >= 1 && cnt <= 10, "unexpected number of edges {}", cnt);
+        assert!(from_start.unwrap());
+
+        /* two hops reach further, and every edge starts at a node reached by the previous hop */
+        let (cnt2, max_hop) = Spi::get_two::<i64, i32>(
+            "SELECT count(*), max(hop) FROM tsv_sample_neighbors('idx_diskann'::regclass, '(0,1)', 2)",
+        )?;
+        assert!(cnt2.unwrap() > cnt);
+        assert_eq!(max_hop.unwrap(), 2);
+        let orphans: Option<i64> = Spi::get_one(
+            "WITH e AS (SELECT * FROM tsv_sample_neighbors('idx_diskann'::regclass, '(0,1)', 2))
+            SELECT count(*) FROM e WHERE hop = 2 AND from_ctid NOT IN (SELECT to_ctid FROM e WHERE hop = 1)",
+        )?;
+        assert_eq!(orphans.unwrap(), 0);
+
+        /* the number of edges is bounded */
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM tsv_sample_neighbors('idx_diskann'::regclass, '(0,1)', 5, 7)",
+        )?;
+        assert_eq!(cnt.unwrap(), 7);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_degree_histogram() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 300)?;
+        Spi::run(
+            "CREATE INDEX idx_diskann ON test_data USING diskann (embedding) WITH (num_neighbors = 10);",
+        )?;
+
+        /* every node is counted once, and no node has more than num_neighbors edges */
+        let (num_nodes, max_degree) = Spi::get_two::<i64, i32>(
+            "SELECT sum(num_nodes)::int8, max(degree) FROM tsv_degree_histogram('idx_diskann'::regclass)",
+        )?;
+        assert_eq!(num_nodes.unwrap(), 300);
+        let max_degree = max_degree.unwrap();
+        assert!(max_degree > 0 && max_degree <= 10, "{}", max_degree);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_top_hubs() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized"] {
+            crate::access_method::build::tests::create_test_data(32, 300)?;
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout}, num_neighbors = 10);"
+            ))?;
+
+            /* the hubs come most connected first, and have more in-edges than the average node */
+            let (cnt, top, out_of_order) = Spi::get_three::<i64, i64, i64>(
+                "SELECT count(*), max(in_degree), count(*) FILTER (WHERE in_degree > prev)
+                FROM (SELECT in_degree, lag(in_degree) OVER (ORDER BY ord) AS prev
+                      FROM tsv_top_hubs('idx_diskann'::regclass, 5) WITH ORDINALITY h(ctid, in_degree, ord)) h",
+            )?;
+            assert_eq!(cnt, Some(5), "{}", storage_layout);
+            assert_eq!(out_of_order, Some(0), "{}", storage_layout);
+            let (num_nodes, edges) = Spi::get_two::<i64, i64>(
+                "SELECT sum(num_nodes)::int8, sum(degree * num_nodes)::int8 FROM tsv_degree_histogram('idx_diskann'::regclass)",
+            )?;
+            let average = edges.unwrap() as f64 / num_nodes.unwrap() as f64;
+            assert!(top.unwrap() as f64 > average, "{}", storage_layout);
+
+            /* the in-degrees of all the nodes add up to the number of edges */
+            let total = Spi::get_one::<i64>(
+                "SELECT sum(in_degree)::int8 FROM tsv_top_hubs('idx_diskann'::regclass, 1000)",
+            )?;
+            assert_eq!(total, edges, "{}", storage_layout);
+
+            /* the hubs are rows of the table */
+            let joined = Spi::get_one::<i64>(
+                "SELECT count(*) FROM tsv_top_hubs('idx_diskann'::regclass, 5) h JOIN test_data t ON t.ctid = h.ctid",
+            )?;
+            assert_eq!(joined, Some(5), "{}", storage_layout);
+        }
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_count_near_duplicates() -> spi::Result<()> {
+        for (storage_layout, epsilon) in [("plain", 1e-6), ("memory_optimized", 0.0)] {
+            crate::access_method::build::tests::create_test_data(32, 300)?;
+            Spi::run(&format!(
+                "/* 30 exact copies */
+                INSERT INTO test_data (embedding) SELECT embedding FROM test_data LIMIT 30;
+
+                CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout});"
+            ))?;
+
+            /* each copy finds its original as a neighbor, and often the other way around */
+            let cnt = Spi::get_one::<i64>(&format!(
+                "SELECT tsv_count_near_duplicates('idx_diskann'::regclass, {epsilon})"
+            ))?
+            .unwrap();
+            assert!(cnt >= 30 && cnt <= 60, "{} {}", storage_layout, cnt);
+        }
+
+        Ok(())
+    }
+
+    #[pg_test(error = "epsilon must not be negative")]
+    unsafe fn test_tsv_count_near_duplicates_negative_epsilon() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);
+            SELECT tsv_count_near_duplicates('idx_diskann'::regclass, -1);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_evict() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            INSERT INTO test_data (embedding) VALUES ('[1,2,3]'), ('[4,5,6]');
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);
+            INSERT INTO test_data (embedding) VALUES ('[7,8,10]');",
+        )?;
+
+        /* the dirty pages written by the insert are flushed, not lost */
+        Spi::run("SELECT tsv_evict('idx_diskann'::regclass); SET enable_seqscan = 0;")?;
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM (SELECT * FROM test_data ORDER BY embedding <=> '[7,8,10]' LIMIT 3) t",
+        )?;
+        assert_eq!(cnt.unwrap(), 3);
+        let nearest: Option<String> = Spi::get_one(
+            "SELECT embedding::text FROM test_data ORDER BY embedding <=> '[7,8,10]' LIMIT 1",
+        )?;
+        assert_eq!(nearest.unwrap(), "[7,8,10]");
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_export_vectors() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized"] {
+            Spi::run(&format!(
+                "DROP TABLE IF EXISTS test_data;
+                CREATE TABLE test_data (embedding vector (3));
+                INSERT INTO test_data (embedding)
+                    SELECT ARRAY[i, i + 1, i * 2]::vector FROM generate_series(1, 100) i;
+                CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout}, num_dimensions = 2);
+                INSERT INTO test_data (embedding) VALUES ('[-1,-2,-3]');
+                DELETE FROM test_data WHERE embedding = '[1,2,2]';"
+            ))?;
+
+            /* the full, unnormalized vectors of the visible rows */
+            let (exported, matching) = Spi::get_two::<i64, i64>(
+                "SELECT count(*), count(t.ctid)
+                   FROM tsv_export_vectors('idx_diskann'::regclass) e
+                   LEFT JOIN test_data t ON t.ctid = e.ctid AND t.embedding = e.vector::vector",
+            )?;
+            assert_eq!(exported.unwrap(), 100);
+            assert_eq!(matching.unwrap(), 100);
+        }
+
+        Ok(())
+    }
+
+    #[pg_test(error = "permission denied for table test_data")]
+    unsafe fn test_tsv_export_vectors_without_select() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);
+            CREATE ROLE test_no_select;
+            SET ROLE test_no_select;
+            SELECT count(*) FROM tsv_export_vectors('idx_diskann'::regclass);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_verify_sbq_codes() -> spi::Result<()> {
+        Spi::run(
+            "CREATE FUNCTION test_decrypt(v vector) RETURNS vector
+                AS $$ SELECT array(SELECT -x FROM unnest(v::real[]) x)::vector $$
+                LANGUAGE sql IMMUTABLE STRICT;
+
+            CREATE TABLE test_data (embedding vector (32));
+
+            select setseed(0.5);
+            INSERT INTO test_data (embedding)
+            SELECT
+                *
+            FROM (
+                SELECT
+                    ('[' || array_to_string(array_agg(random() - 0.5), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 32 * 300) i
+                GROUP BY
+                    i % 300) g;
+
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = memory_optimized, decrypt_function = 'test_decrypt');
+            DELETE FROM test_data WHERE ctid IN (SELECT ctid FROM test_data LIMIT 10);",
+        )?;
+
+        let mismatches =
+            "SELECT count(*), min(differing_bits) FROM tsv_verify_sbq_codes('idx_diskann'::regclass)";
+        let (count, _) = Spi::get_two::<i64, i32>(mismatches)?;
+        assert_eq!(count.unwrap(), 0);
+
+        /* a decrypt function that now returns other vectors invalidates the codes */
+        Spi::run(
+            "CREATE OR REPLACE FUNCTION test_decrypt(v vector) RETURNS vector
+                AS $$ SELECT v $$
+                LANGUAGE sql IMMUTABLE STRICT;",
+        )?;
+        let (count, min_bits) = Spi::get_two::<i64, i32>(mismatches)?;
+        assert_eq!(count.unwrap(), 290);
+        assert!(min_bits.unwrap() > 0);
+
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "diskann index \"idx_diskann\" has no SBQ codes, its storage_layout is plain"
+    )]
+    unsafe fn test_tsv_verify_sbq_codes_plain() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding) WITH (storage_layout = plain);
+            SELECT tsv_verify_sbq_codes('idx_diskann'::regclass);",
+        )?;
+        Ok(())
+    }
+}
@@ -109,6 +109,38 @@ pub fn distance_cosine_unoptimized(a: &[f32], b: &[f32]) -> f32 {
     (1.0 - res).max(0.0)
 }
 
+/// Adds up the values with Kahan (compensated) summation. The rounding error doesn't grow
+/// with the number of values, unlike with a plain f32 sum, at the cost of a few more
+/// operations per value.
+#[inline]
+fn compensated_sum<I: Iterator<Item = f32>>(values: I) -> f32 {
+    let mut sum = 0.0_f32;
+    let mut compensation = 0.0_f32;
+    for value in values {
+        let y = value - compensation;
+        let t = sum + y;
+        compensation = (t - sum) - y;
+        sum = t;
+    }
+    sum
+}
+
+/// distance_cosine with compensated summation, for vectors with so many dimensions that the
+/// rounding errors of the plain sum change the order of the results.
+pub fn distance_cosine_compensated(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    let res = compensated_sum(a.iter().zip(b).map(|(a, b)| *a * *b));
+    (1.0 - res).max(0.0)
+}
+
+/// distance_l2 with compensated summation, see distance_cosine_compensated.
+pub fn distance_l2_compensated(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    let norm = compensated_sum(a.iter().zip(b).map(|(a, b)| (*a - *b) * (*a - *b)));
+    assert!(norm >= 0.);
+    norm
+}
+
 pub fn preprocess_cosine_get_norm(a: &[f32]) -> Option<f32> {
     let norm = a.iter().map(|v| v * v).sum::<f32>();
     //adjust the epsilon to the length of the vector
@@ -208,3 +240,145 @@ pub fn distance_xor_optimized(a: &[u64], b: &[u64]) -> usize {
             .sum(),
     }
 }
+
+macro_rules! xor_batch_arm {
+    ($a: expr, $bs: expr, $distances: expr, $sz: expr) => {
+        for b in $bs.iter() {
+            $distances.push(xor_arm!($a, b, $sz));
+        }
+    };
+}
+
+/// Computes distance_xor_optimized between `a` and each of `bs`, appending the results to
+/// `distances`. The length is dispatched on once for the whole batch instead of once per
+/// vector, so comparing a node's neighbor list runs as a single tight loop.
+pub fn distances_xor_optimized(a: &[u64], bs: &[&[u64]], distances: &mut Vec<usize>) {
+    distances.reserve(bs.len());
+    match a.len() {
+        1 => xor_batch_arm!(a, bs, distances, 1),
+        2 => xor_batch_arm!(a, bs, distances, 2),
+        3 => xor_batch_arm!(a, bs, distances, 3),
+        4 => xor_batch_arm!(a, bs, distances, 4),
+        5 => xor_batch_arm!(a, bs, distances, 5),
+        6 => xor_batch_arm!(a, bs, distances, 6),
+        7 => xor_batch_arm!(a, bs, distances, 7),
+        8 => xor_batch_arm!(a, bs, distances, 8),
+        9 => xor_batch_arm!(a, bs, distances, 9),
+        10 => xor_batch_arm!(a, bs, distances, 10),
+        11 => xor_batch_arm!(a, bs, distances, 11),
+        12 => xor_batch_arm!(a, bs, distances, 12),
+        13 => xor_batch_arm!(a, bs, distances, 13),
+        14 => xor_batch_arm!(a, bs, distances, 14),
+        15 => xor_batch_arm!(a, bs, distances, 15),
+        16 => xor_batch_arm!(a, bs, distances, 16),
+        17 => xor_batch_arm!(a, bs, distances, 17),
+        18 => xor_batch_arm!(a, bs, distances, 18),
+        19 => xor_batch_arm!(a, bs, distances, 19),
+        20 => xor_batch_arm!(a, bs, distances, 20),
+        21 => xor_batch_arm!(a, bs, distances, 21),
+        22 => xor_batch_arm!(a, bs, distances, 22),
+        23 => xor_batch_arm!(a, bs, distances, 23),
+        24 => xor_batch_arm!(a, bs, distances, 24),
+        25 => xor_batch_arm!(a, bs, distances, 25),
+        26 => xor_batch_arm!(a, bs, distances, 26),
+        27 => xor_batch_arm!(a, bs, distances, 27),
+        28 => xor_batch_arm!(a, bs, distances, 28),
+        29 => xor_batch_arm!(a, bs, distances, 29),
+        30 => xor_batch_arm!(a, bs, distances, 30),
+        31 => xor_batch_arm!(a, bs, distances, 31),
+        32 => xor_batch_arm!(a, bs, distances, 32),
+        33 => xor_batch_arm!(a, bs, distances, 33),
+        34 => xor_batch_arm!(a, bs, distances, 34),
+        35 => xor_batch_arm!(a, bs, distances, 35),
+        36 => xor_batch_arm!(a, bs, distances, 36),
+        37 => xor_batch_arm!(a, bs, distances, 37),
+        38 => xor_batch_arm!(a, bs, distances, 38),
+        39 => xor_batch_arm!(a, bs, distances, 39),
+        40 => xor_batch_arm!(a, bs, distances, 40),
+        41 => xor_batch_arm!(a, bs, distances, 41),
+        42 => xor_batch_arm!(a, bs, distances, 42),
+        43 => xor_batch_arm!(a, bs, distances, 43),
+        44 => xor_batch_arm!(a, bs, distances, 44),
+        45 => xor_batch_arm!(a, bs, distances, 45),
+        46 => xor_batch_arm!(a, bs, distances, 46),
+        47 => xor_batch_arm!(a, bs, distances, 47),
+        48 => xor_batch_arm!(a, bs, distances, 48),
+        49 => xor_batch_arm!(a, bs, distances, 49),
+        _ => {
+            for b in bs.iter() {
+                distances.push(
+                    a.iter()
+                        .zip(b.iter())
+                        .map(|(&l, &r)| (l ^ r).count_ones() as usize)
+                        .sum(),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distances_xor_optimized_matches_single() {
+        for len in [1, 7, 24, 49, 50, 64] {
+            let a: Vec<u64> = (0..len as u64)
+                .map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+                .collect();
+            let bs: Vec<Vec<u64>> = (0..10u64)
+                .map(|j| {
+                    (0..len as u64)
+                        .map(|i| (i + j).wrapping_mul(0xC2B2_AE3D_27D4_EB4F))
+                        .collect()
+                })
+                .collect();
+            let b_slices: Vec<&[u64]> = bs.iter().map(|b| b.as_slice()).collect();
+
+            let mut distances = vec![];
+            distances_xor_optimized(&a, &b_slices, &mut distances);
+            let expected: Vec<usize> = bs.iter().map(|b| distance_xor_optimized(&a, b)).collect();
+            assert_eq!(distances, expected);
+        }
+    }
+
+    #[test]
+    fn test_compensated_sum_ranks_high_dimensional_vectors() {
+        /* a unit query with most of its weight on the first dimension and a little on 20000
+        others. Each of the small products is below half an ulp of the running sum, so the
+        plain sum drops all of them. */
+        let num_dimensions = 20001;
+        let mut query = vec![1e-4_f32; num_dimensions];
+        query[0] = (1.0_f64 - (num_dimensions - 1) as f64 * 1e-8).sqrt() as f32;
+        let mut first_axis = vec![0.0_f32; num_dimensions];
+        first_axis[0] = 1.0;
+
+        /* the query itself is the nearest, ahead of the first axis */
+        let naive_self = distance_cosine_unoptimized(&query, &query);
+        let naive_axis = distance_cosine_unoptimized(&query, &first_axis);
+        assert!(naive_self > naive_axis, "{} {}", naive_self, naive_axis);
+
+        let compensated_self = distance_cosine_compensated(&query, &query);
+        let compensated_axis = distance_cosine_compensated(&query, &first_axis);
+        assert!(
+            compensated_self < compensated_axis,
+            "{} {}",
+            compensated_self,
+            compensated_axis
+        );
+        assert!(compensated_self < 1e-6, "{}", compensated_self);
+
+        /* the same sums as the plain kernels when there is nothing to compensate */
+        let a = [0.5_f32, 0.5, 0.5, 0.5];
+        let b = [1.0_f32, 0.0, 0.0, 0.0];
+        assert_eq!(
+            distance_cosine_compensated(&a, &b),
+            distance_cosine_unoptimized(&a, &b)
+        );
+        assert_eq!(
+            distance_l2_compensated(&a, &b),
+            distance_l2_unoptimized(&a, &b)
+        );
+    }
+}
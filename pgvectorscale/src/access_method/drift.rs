@@ -0,0 +1,218 @@
+//! Detecting when the SBQ quantizer of an index has gone stale.
+//!
+//! SBQ encodes every dimension relative to the mean the build measured over the table. When
+//! the rows inserted later are distributed differently, the codes lose their balance and the
+//! quantized distances their precision, until a REINDEX trains the quantizer again. The drift
+//! of a set of vectors is how far their mean has moved from the trained one: the distance
+//! between the two means in each dimension, in standard deviations of the training data,
+//! averaged over the dimensions. The mean of a random sample of n training vectors drifts by
+//! about 0.8 / sqrt(n) already, 0.025 for a thousand vectors.
+//!
+//! Inserts into indexes built with quantizer_drift_threshold add their vector to a window of
+//! the backend, and raise a NOTICE when the drift of a full window of DRIFT_WINDOW inserts is
+//! above the threshold. tsv_quantizer_drift() measures the drift of the most recently inserted
+//! rows of any SBQ index on demand.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use pgrx::*;
+
+use crate::util::table_slot::TableSlot;
+
+use super::meta_page::MetaPage;
+use super::pg_vector::PgVector;
+use super::sbq::{SbqMeans, SbqQuantizer, SbqSpeedupStorage};
+use super::stats::GreedySearchStats;
+use super::storage::StorageType;
+use super::storage_common::{
+    check_heap_select_privilege, check_is_diskann_index, get_attribute_number_from_index,
+    live_heap_pointers,
+};
+
+/// The number of inserts whose drift is checked at once.
+const DRIFT_WINDOW: u64 = 1000;
+
+/// The vectors inserted into an index by this backend since its last check.
+struct Window {
+    count: u64,
+    sum: Vec<f64>,
+}
+
+thread_local! {
+    static WINDOWS: RefCell<HashMap<pg_sys::Oid, Window>> = RefCell::new(HashMap::new());
+}
+
+/// The drift of vectors whose mean is `mean` from the means the quantizer was trained with.
+fn drift(quantizer: &SbqQuantizer, mean: &[f64]) -> f64 {
+    let mut total = 0.0;
+    let mut dimensions = 0;
+    for (d, &mean) in mean.iter().enumerate() {
+        let variance = quantizer.m2[d] as f64 / quantizer.count as f64;
+        if variance > 0.0 {
+            total += (mean - quantizer.mean[d] as f64).abs() / variance.sqrt();
+            dimensions += 1;
+        }
+    }
+    if dimensions == 0 {
+        0.0
+    } else {
+        total / dimensions as f64
+    }
+}
+
+/// Adds `vector`, inserted into `index`, to the window of the index, and raises a notice if
+/// the window is full and drifted beyond the quantizer_drift_threshold of the index.
+pub fn record_insert(
+    index: &PgRelation,
+    meta_page: &MetaPage,
+    quantizer: &SbqQuantizer,
+    vector: &[f32],
+) {
+    let threshold = meta_page.get_quantizer_drift_threshold();
+    if threshold <= 0.0 || quantizer.count == 0 {
+        return;
+    }
+
+    let relfilenode = unsafe { (*index.rd_rel).relfilenode };
+    let full_window = WINDOWS.with(|windows| {
+        let mut windows = windows.borrow_mut();
+        let window = windows.entry(relfilenode).or_insert_with(|| Window {
+            count: 0,
+            sum: vec![0.0; vector.len()],
+        });
+        for (sum, &v) in window.sum.iter_mut().zip(vector.iter()) {
+            *sum += v as f64;
+        }
+        window.count += 1;
+        if window.count < DRIFT_WINDOW {
+            return None;
+        }
+        windows.remove(&relfilenode)
+    });
+
+    if let Some(window) = full_window {
+        let mean: Vec<f64> = window
+            .sum
+            .iter()
+            .map(|sum| sum / window.count as f64)
+            .collect();
+        let drift = drift(quantizer, &mean);
+        if drift > threshold {
+            notice!(
+                "the last {} vectors inserted into diskann index \"{}\" drifted {:.3} standard deviations from its quantizer, above quantizer_drift_threshold ({}), REINDEX to retrain it",
+                window.count,
+                index.name(),
+                drift,
+                threshold
+            );
+        }
+    }
+}
+
+/// Returns the drift from the SBQ quantizer of `index` of the vectors of the `sample_size`
+/// rows last in index order, which are the most recently inserted ones unless vacuum freed
+/// space for inserts earlier in the index: how far their mean has moved from the mean the
+/// quantizer was trained with, per dimension in standard deviations of the training data,
+/// averaged over the dimensions. Values well above 0.8 / sqrt(sample_size) mean that the
+/// inserted data is distributed differently and that a REINDEX would retrain the quantizer
+/// on it. NULL if the index has no visible rows.
+#[pg_extern]
+pub fn tsv_quantizer_drift(index: PgRelation, sample_size: default!(i32, 1000)) -> Option<f64> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if sample_size < 1 {
+        error!("sample_size must be at least 1");
+    }
+    let meta_page = MetaPage::fetch(&index);
+    if meta_page.get_storage_type() == StorageType::Plain {
+        error!(
+            "diskann index \"{}\" has no quantizer, its storage_layout is plain",
+            index.name()
+        );
+    }
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let heap_attr = get_attribute_number_from_index(&index);
+    let snapshot = unsafe { pg_sys::GetActiveSnapshot() };
+    let mut stats = GreedySearchStats::new();
+    let quantizer = unsafe { SbqMeans::load(&index, &meta_page, &mut stats) };
+
+    let heap_pointers = live_heap_pointers::<SbqSpeedupStorage>(&index);
+    let mut sum = vec![0.0_f64; meta_page.get_num_dimensions_to_index() as usize];
+    let mut count = 0;
+    for &heap_pointer in heap_pointers.iter().rev() {
+        check_for_interrupts!();
+        let slot =
+            match unsafe { TableSlot::new_visible(&heap, heap_pointer, snapshot, &mut stats) } {
+                Some(slot) => slot,
+                None => continue,
+            };
+        let datum = match unsafe { slot.get_attribute(heap_attr) } {
+            Some(datum) => datum,
+            None => continue,
+        };
+        let vector = unsafe { PgVector::from_heap_datum(datum, &meta_page, true, false) };
+        for (sum, &v) in sum.iter_mut().zip(vector.to_index_slice().iter()) {
+            *sum += v as f64;
+        }
+        count += 1;
+        if count == sample_size {
+            break;
+        }
+    }
+    if count == 0 {
+        return None;
+    }
+
+    let mean: Vec<f64> = sum.iter().map(|sum| sum / count as f64).collect();
+    Some(drift(&quantizer, &mean))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_quantizer_drift() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 1000)?;
+        Spi::run(
+            "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = memory_optimized, quantizer_drift_threshold = 0.5);",
+        )?;
+
+        /* the rows the quantizer was trained on hardly drift */
+        let drift = Spi::get_one::<f64>("SELECT tsv_quantizer_drift('idx_diskann'::regclass)")?;
+        assert!(drift.unwrap() < 0.1, "{:?}", drift);
+
+        /* rows from another distribution do */
+        Spi::run(
+            "INSERT INTO test_data (embedding)
+            SELECT
+                ('[' || array_to_string(array_agg(CASE WHEN d <= 16 THEN random() * 10 ELSE random() END ORDER BY d), ',') || ']')::vector
+            FROM
+                generate_series(1, 1000) g, generate_series(1, 32) d
+            GROUP BY
+                g;",
+        )?;
+        let drift = Spi::get_one::<f64>("SELECT tsv_quantizer_drift('idx_diskann'::regclass)")?;
+        assert!(drift.unwrap() > 0.5, "{:?}", drift);
+
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "quantizer_drift_threshold is only supported with the memory_optimized and io_optimized storage layouts, plain indexes have no quantizer"
+    )]
+    unsafe fn test_quantizer_drift_threshold_plain() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = plain, quantizer_drift_threshold = 0.5);",
+        )?;
+        Ok(())
+    }
+}
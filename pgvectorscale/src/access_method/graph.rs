@@ -3,6 +3,7 @@ use std::collections::BinaryHeap;
 use std::{cmp::Ordering, collections::HashSet};
 
 use pgrx::PgRelation;
+use rand::seq::SliceRandom;
 
 use crate::access_method::storage::NodeDistanceMeasure;
 
@@ -13,17 +14,110 @@ use super::graph_neighbor_store::GraphNeighborStore;
 use super::pg_vector::PgVector;
 use super::stats::{GreedySearchStats, InsertStats, PruneNeighborStats, StatsNodeVisit};
 use super::storage::Storage;
+use super::trace;
 use super::{meta_page::MetaPage, neighbor_with_distance::NeighborWithDistance};
 
+/// How the neighbors of a node are chosen out of the build-time candidates.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NeighborSelection {
+    /// Relative-neighborhood-graph pruning with alpha (the DiskANN heuristic)
+    Rng = 0,
+    /// Simply keep the closest `num_neighbors` candidates. Faster to build, lower recall.
+    TopK = 1,
+}
+
+pub const DEFAULT_NEIGHBOR_SELECTION_STR: &str = "rng";
+
+impl NeighborSelection {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => NeighborSelection::Rng,
+            1 => NeighborSelection::TopK,
+            _ => panic!("Invalid neighbor selection"),
+        }
+    }
+
+    pub fn from_str(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "rng" => NeighborSelection::Rng,
+            "topk" => NeighborSelection::TopK,
+            _ => panic!("Invalid neighbor selection. Must be one of 'rng', 'topk'"),
+        }
+    }
+
+    /// The neighbor_selection value of this selection.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NeighborSelection::Rng => "rng",
+            NeighborSelection::TopK => "topk",
+        }
+    }
+}
+
+/// How an index is searched.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IndexType {
+    /// Greedy search over the DiskANN graph
+    Graph = 0,
+    /// No graph is built, searches compare the query to every node. Exact with respect to
+    /// the quantized distances, but linear in the size of the index.
+    Flat = 1,
+    /// Like Flat until tsv_finalize() builds the graph, after which the index is a Graph one.
+    /// Makes bulk loads fast by building the graph once, at the end.
+    Deferred = 2,
+}
+
+pub const DEFAULT_INDEX_TYPE_STR: &str = "graph";
+
+impl IndexType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => IndexType::Graph,
+            1 => IndexType::Flat,
+            2 => IndexType::Deferred,
+            _ => panic!("Invalid index type"),
+        }
+    }
+
+    pub fn from_str(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "graph" => IndexType::Graph,
+            "flat" => IndexType::Flat,
+            "deferred" => IndexType::Deferred,
+            _ => panic!("Invalid index type. Must be one of 'graph', 'flat', 'deferred'"),
+        }
+    }
+
+    /// The index_type value of this index type.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexType::Graph => "graph",
+            IndexType::Flat => "flat",
+            IndexType::Deferred => "deferred",
+        }
+    }
+
+    /// Whether the nodes are linked into a graph, as opposed to being searched linearly.
+    pub fn has_graph(&self) -> bool {
+        *self == IndexType::Graph
+    }
+}
+
 pub struct ListSearchNeighbor<PD> {
     pub index_pointer: IndexPointer,
     distance: f32,
     private_data: PD,
+    /// The number of edges between the entry point the search found the node from and the
+    /// node, along the path the search took when it first found it.
+    discovery_hops: u32,
+    /// The number of deleted nodes in a row that the search went through, along that path,
+    /// right before finding the node.
+    deleted_depth: u32,
 }
 
 impl<PD> PartialOrd for ListSearchNeighbor<PD> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.distance.partial_cmp(&other.distance)
+        Some(self.cmp(other))
     }
 }
 
@@ -35,9 +129,15 @@ impl<PD> PartialEq for ListSearchNeighbor<PD> {
 
 impl<PD> Eq for ListSearchNeighbor<PD> {}
 
+/// Orders by distance, then by index pointer: nodes with the same SBQ code are at the same
+/// distance from every query, and breaking their ties by position keeps the order in which
+/// searches visit and return them the same from one run to the next.
 impl<PD> Ord for ListSearchNeighbor<PD> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.distance.partial_cmp(&other.distance).unwrap()
+        self.distance
+            .partial_cmp(&other.distance)
+            .unwrap()
+            .then_with(|| self.index_pointer.cmp(&other.index_pointer))
     }
 }
 
@@ -49,6 +149,8 @@ impl<PD> ListSearchNeighbor<PD> {
             index_pointer,
             private_data,
             distance,
+            discovery_hops: 0,
+            deleted_depth: 0,
         }
     }
 
@@ -57,12 +159,31 @@ impl<PD> ListSearchNeighbor<PD> {
     }
 }
 
+/// Relative distance past the end of the search list within which candidates count as
+/// possibly missed neighbors, see ListSearchResult::estimated_recall().
+const RECALL_ESTIMATE_MARGIN: f32 = 0.1;
+
 pub struct ListSearchResult<QDM, PD> {
     candidates: BinaryHeap<Reverse<ListSearchNeighbor<PD>>>,
     visited: Vec<ListSearchNeighbor<PD>>,
     inserted: HashSet<ItemPointer>,
     pub sdm: Option<QDM>,
     pub stats: GreedySearchStats,
+    /// The memory the search may use, in bytes, see check_memory_limit().
+    max_memory: Option<usize>,
+    /// The number of nodes the search may expand, see greedy_search_iterate().
+    max_hops: Option<usize>,
+    hops: usize,
+    /// The discovery_hops of the neighbors inserted now: one more than those of the node being
+    /// visited, 0 for the entry points.
+    discovery_hops: u32,
+    /// The id of the search in the candidate trace of the session, see trace.rs.
+    trace: Option<u64>,
+    /// The number of deleted nodes in a row the search may go through, see
+    /// may_visit_neighbors().
+    max_deleted_bridge_depth: Option<u32>,
+    /// The deleted_depth of the neighbors inserted now.
+    deleted_depth: u32,
 }
 
 impl<QDM, PD> ListSearchResult<QDM, PD> {
@@ -73,6 +194,13 @@ impl<QDM, PD> ListSearchResult<QDM, PD> {
             inserted: HashSet::new(),
             sdm: None,
             stats: GreedySearchStats::new(),
+            max_memory: None,
+            max_hops: None,
+            hops: 0,
+            discovery_hops: 0,
+            trace: None,
+            max_deleted_bridge_depth: None,
+            deleted_depth: 0,
         }
     }
 
@@ -93,6 +221,13 @@ impl<QDM, PD> ListSearchResult<QDM, PD> {
             inserted: HashSet::with_capacity(search_list_size * neigbors),
             stats: GreedySearchStats::new(),
             sdm: Some(sdm),
+            max_memory: None,
+            max_hops: None,
+            hops: 0,
+            discovery_hops: 0,
+            trace: None,
+            max_deleted_bridge_depth: None,
+            deleted_depth: 0,
         };
         res.stats.record_call();
         for index_pointer in init_ids {
@@ -107,11 +242,134 @@ impl<QDM, PD> ListSearchResult<QDM, PD> {
     }
 
     /// Internal function
-    pub fn insert_neighbor(&mut self, n: ListSearchNeighbor<PD>) {
+    pub fn insert_neighbor(&mut self, mut n: ListSearchNeighbor<PD>) {
+        if let Some(search_id) = self.trace {
+            trace::record(search_id, n.index_pointer, n.distance);
+        }
+        n.discovery_hops = self.discovery_hops;
+        n.deleted_depth = self.deleted_depth;
         self.stats.record_candidate();
         self.candidates.push(Reverse(n));
     }
 
+    /// Removes the candidates that haven't been visited and returns them with the distance the
+    /// search computed to them, closest first.
+    pub fn drain_candidates(&mut self) -> Vec<(IndexPointer, f32)> {
+        let mut candidates: Vec<(IndexPointer, f32)> = self
+            .candidates
+            .drain()
+            .map(|Reverse(n)| (n.index_pointer, n.distance))
+            .collect();
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        candidates
+    }
+
+    /// Records the candidates of the search in the trace of the session if
+    /// diskann.trace_candidates is on, starting with the entry points.
+    pub fn start_trace(&mut self, index: &PgRelation, query: &[f32]) {
+        if !trace::is_enabled() {
+            return;
+        }
+        let search_id = trace::start_search(index, query);
+        for Reverse(n) in self.candidates.iter() {
+            trace::record(search_id, n.index_pointer, n.distance);
+        }
+        self.trace = Some(search_id);
+    }
+
+    /// Limits the memory of the search to `max_memory_kb` kilobytes, or not at all if negative.
+    pub fn set_max_memory_kb(&mut self, max_memory_kb: i32) {
+        self.max_memory = if max_memory_kb < 0 {
+            None
+        } else {
+            Some(max_memory_kb as usize * 1024)
+        };
+    }
+
+    /// Limits the number of nodes the search expands to `max_hops`, or not at all if negative.
+    pub fn set_max_hops(&mut self, max_hops: i32) {
+        self.max_hops = if max_hops < 0 {
+            None
+        } else {
+            Some(max_hops as usize)
+        };
+    }
+
+    /// Limits the number of deleted nodes in a row the search goes through to `max_depth`, or
+    /// not at all if negative.
+    pub fn set_max_deleted_bridge_depth(&mut self, max_depth: i32) {
+        self.max_deleted_bridge_depth = if max_depth < 0 {
+            None
+        } else {
+            Some(max_depth as u32)
+        };
+    }
+
+    /// Called by the storages when they visit a node, before they look at its neighbors.
+    /// Deleted nodes stay in the graph until a rebuild and bridge the live nodes around them,
+    /// so the search goes through them too, but not through more than
+    /// diskann.deleted_bridge_depth of them in a row: returns false if the neighbors of this
+    /// deleted node are too deep to look at.
+    pub fn may_visit_neighbors(&mut self, is_deleted: bool) -> bool {
+        if !is_deleted {
+            self.deleted_depth = 0;
+            return true;
+        }
+        self.deleted_depth += 1;
+        self.max_deleted_bridge_depth
+            .map_or(true, |max_depth| self.deleted_depth <= max_depth)
+    }
+
+    fn hop_limit_reached(&self) -> bool {
+        self.max_hops
+            .map_or(false, |max_hops| self.hops >= max_hops)
+    }
+
+    /// An estimate of the memory held by the candidate lists, from the capacity of the
+    /// collections.
+    fn memory_used(&self) -> usize {
+        self.candidates.capacity() * std::mem::size_of::<Reverse<ListSearchNeighbor<PD>>>()
+            + self.visited.capacity() * std::mem::size_of::<ListSearchNeighbor<PD>>()
+            /* one control byte per bucket */
+            + self.inserted.capacity() * (std::mem::size_of::<ItemPointer>() + 1)
+    }
+
+    /// Errors out when the search uses more memory than allowed.
+    fn check_memory_limit(&self) {
+        if let Some(max_memory) = self.max_memory {
+            if self.memory_used() > max_memory {
+                pgrx::error!(
+                    "diskann index search exceeded diskann.search_work_mem ({}kB), increase it or lower the number of rows requested",
+                    max_memory / 1024
+                );
+            }
+        }
+    }
+
+    /// Estimates the fraction of the true `list_size` nearest nodes that are in the visited list.
+    ///
+    /// Nodes are missed when their approximate distance puts them just outside the list, so
+    /// the estimate compares the nodes in the list to the candidates that were found but not
+    /// visited and whose distance is within RECALL_ESTIMATE_MARGIN of the last one in the list.
+    pub fn estimated_recall(&self, list_size: usize) -> f64 {
+        let in_list = std::cmp::min(list_size, self.visited.len());
+        if in_list == 0 {
+            return 1.0;
+        }
+
+        let boundary = self.visited[in_list - 1].distance * (1.0 + RECALL_ESTIMATE_MARGIN);
+        let near_boundary = self.visited[in_list..]
+            .iter()
+            .filter(|n| n.distance <= boundary)
+            .count()
+            + self
+                .candidates
+                .iter()
+                .filter(|n| n.0.distance <= boundary)
+                .count();
+        in_list as f64 / (in_list + near_boundary) as f64
+    }
+
     pub fn get_lsn_by_idx(&self, idx: usize) -> &ListSearchNeighbor<PD> {
         &self.visited[idx]
     }
@@ -124,15 +382,13 @@ impl<QDM, PD> ListSearchResult<QDM, PD> {
         if self.visited.len() > pos_limit {
             let node_at_pos = &self.visited[pos_limit - 1];
             let head = self.candidates.peek().unwrap();
-            if head.0.distance >= node_at_pos.distance {
+            if head.0 >= *node_at_pos {
                 return None;
             }
         }
 
         let head = self.candidates.pop().unwrap();
-        let idx = self
-            .visited
-            .partition_point(|x| x.distance < head.0.distance);
+        let idx = self.visited.partition_point(|x| *x < head.0);
         self.visited.insert(idx, head.0);
         Some(idx)
     }
@@ -143,12 +399,24 @@ impl<QDM, PD> ListSearchResult<QDM, PD> {
         &mut self,
         storage: &S,
     ) -> Option<(HeapPointer, IndexPointer)> {
+        self.consume_with_discovery_hops(storage)
+            .map(|(heap_pointer, index_pointer, _)| (heap_pointer, index_pointer))
+    }
+
+    /// Like consume, also returning the number of hops from an entry point after which the
+    /// search found the node.
+    pub fn consume_with_discovery_hops<
+        S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>,
+    >(
+        &mut self,
+        storage: &S,
+    ) -> Option<(HeapPointer, IndexPointer, u32)> {
         if self.visited.len() == 0 {
             return None;
         }
         let lsn = self.visited.remove(0);
         let heap_pointer = storage.return_lsn(&lsn, &mut self.stats);
-        return Some((heap_pointer, lsn.index_pointer));
+        return Some((heap_pointer, lsn.index_pointer, lsn.discovery_hops));
     }
 }
 
@@ -288,19 +556,38 @@ impl<'a> Graph<'a> {
             //no nodes in the graph
             return ListSearchResult::empty();
         }
+        self.greedy_search_streaming_init_from(init_ids.unwrap(), query, search_list_size, storage)
+    }
+
+    /// Like greedy_search_streaming_init, but starts the search from the given nodes
+    /// instead of the init ids of the index.
+    pub fn greedy_search_streaming_init_from<S: Storage>(
+        &self,
+        init_ids: Vec<ItemPointer>,
+        query: PgVector,
+        search_list_size: usize,
+        storage: &S,
+    ) -> ListSearchResult<S::QueryDistanceMeasure, S::LSNPrivateData> {
         let dm = storage.get_query_distance_measure(query);
 
-        ListSearchResult::new(
-            init_ids.unwrap(),
+        let mut lsr = ListSearchResult::new(
+            init_ids,
             dm,
             search_list_size,
             &self.meta_page,
             self.get_neighbor_store(),
             storage,
-        )
+        );
+        lsr.set_max_memory_kb(super::guc::TSV_SEARCH_WORK_MEM.get());
+        lsr.set_max_hops(super::guc::TSV_MAX_SEARCH_HOPS.get());
+        lsr.set_max_deleted_bridge_depth(super::guc::TSV_DELETED_BRIDGE_DEPTH.get());
+        lsr
     }
 
     /// Advance the state of the lsr until the closest `visit_n_closest` elements have been visited.
+    ///
+    /// Once the lsr has expanded its maximum number of hops, the candidates found so far are still
+    /// moved to the visited list, in distance order, but their neighbors aren't looked at anymore.
     pub fn greedy_search_iterate<S: Storage>(
         &self,
         lsr: &mut ListSearchResult<S::QueryDistanceMeasure, S::LSNPrivateData>,
@@ -309,6 +596,7 @@ impl<'a> Graph<'a> {
         storage: &S,
     ) {
         while let Some(list_search_entry_idx) = lsr.visit_closest(visit_n_closest) {
+            lsr.check_memory_limit();
             match visited_nodes {
                 None => {}
                 Some(ref mut visited_nodes) => {
@@ -319,11 +607,26 @@ impl<'a> Graph<'a> {
                     ));
                 }
             }
+            if lsr.hop_limit_reached() {
+                continue;
+            }
+            lsr.hops += 1;
             lsr.stats.record_visit();
+            lsr.discovery_hops = lsr.visited[list_search_entry_idx].discovery_hops + 1;
+            lsr.deleted_depth = lsr.visited[list_search_entry_idx].deleted_depth;
             storage.visit_lsn(lsr, list_search_entry_idx, &self.neighbor_store);
         }
     }
 
+    /// The number of neighbors prune_neighbors() keeps. A build keeps fewer than num_neighbors
+    /// when build_neighbor_slack is set, inserts into the built graph fill all the slots.
+    fn num_neighbors_after_prune(&self) -> usize {
+        match self.neighbor_store {
+            GraphNeighborStore::Builder(_) => self.meta_page.get_num_neighbors_for_build() as _,
+            GraphNeighborStore::Disk => self.meta_page.get_num_neighbors() as _,
+        }
+    }
+
     /// Prune neigbors by prefering neighbors closer to the point in question
     /// than to other neighbors of the point.
     ///
@@ -338,6 +641,7 @@ impl<'a> Graph<'a> {
         stats.calls += 1;
         //TODO make configurable?
         let max_alpha = self.get_meta_page().get_max_alpha();
+        let num_neighbors = self.num_neighbors_after_prune();
 
         stats.num_neighbors_before_prune += candidates.len();
         //TODO remove deleted nodes
@@ -346,9 +650,14 @@ impl<'a> Graph<'a> {
 
         //sort by distance
         candidates.sort();
-        let mut results = Vec::<NeighborWithDistance>::with_capacity(
-            self.get_meta_page().get_num_neighbors() as _,
-        );
+
+        if self.get_meta_page().get_neighbor_selection() == NeighborSelection::TopK {
+            candidates.truncate(num_neighbors);
+            stats.num_neighbors_after_prune += candidates.len();
+            return candidates;
+        }
+
+        let mut results = Vec::<NeighborWithDistance>::with_capacity(num_neighbors);
 
         let mut max_factors: Vec<f64> = vec![0.0; candidates.len()];
 
@@ -356,9 +665,9 @@ impl<'a> Graph<'a> {
         let dimension_epsilon = self.get_meta_page().get_num_dimensions() as f32 * f32::EPSILON;
         //first we add nodes that "pass" a small alpha. Then, if there
         //is still room we loop again with a larger alpha.
-        while alpha <= max_alpha && results.len() < self.get_meta_page().get_num_neighbors() as _ {
+        while alpha <= max_alpha && results.len() < num_neighbors {
             for (i, neighbor) in candidates.iter().enumerate() {
-                if results.len() >= self.get_meta_page().get_num_neighbors() as _ {
+                if results.len() >= num_neighbors {
                     return results;
                 }
                 if max_factors[i] > alpha {
@@ -469,10 +778,21 @@ impl<'a> Graph<'a> {
         let v =
             self.greedy_search_for_build(vec, meta_page, storage, &mut stats.greedy_search_stats);
 
+        let mut candidates: Vec<NeighborWithDistance> = v.into_iter().collect();
+        let sample_rate = meta_page.get_build_candidate_sample_rate();
+        if sample_rate < 1.0 && matches!(self.neighbor_store, GraphNeighborStore::Builder(_)) {
+            /* neighbor selection only evaluates a random sample of the candidates during a build */
+            let sample_size = ((candidates.len() as f64 * sample_rate).ceil() as usize)
+                .max(self.neighbor_store.max_neighbors(meta_page))
+                .min(candidates.len());
+            candidates.shuffle(&mut rand::thread_rng());
+            candidates.truncate(sample_size);
+        }
+
         let (_, neighbor_list) = self.add_neighbors(
             storage,
             index_pointer,
-            v.into_iter().collect(),
+            candidates,
             &mut stats.prune_neighbor_stats,
         );
 
@@ -492,6 +812,55 @@ impl<'a> Graph<'a> {
         }
     }
 
+    /// Links the node at `index_pointer`, whose vector is identical to the vector of the node at
+    /// `representative`, into the chain of duplicates of the representative instead of
+    /// inserting it into the graph: the representative links to the newest duplicate, which
+    /// links to `head`, the duplicate before it, if any. The duplicates of a vector take a
+    /// single neighbor slot of the representative and none of the other nodes.
+    pub fn insert_duplicate<S: Storage>(
+        &mut self,
+        representative: IndexPointer,
+        head: Option<IndexPointer>,
+        index_pointer: IndexPointer,
+        storage: &S,
+        stats: &mut InsertStats,
+    ) {
+        let mut neighbors = vec![];
+        self.neighbor_store
+            .get_neighbors_with_full_vector_distances(
+                representative,
+                storage,
+                &mut neighbors,
+                &mut stats.prune_neighbor_stats,
+            );
+        neighbors.retain(|n| Some(n.get_index_pointer_to_neighbor()) != head);
+        if neighbors.len() >= self.neighbor_store.max_neighbors(self.get_meta_page()) {
+            /* make room by dropping the farthest neighbor */
+            let farthest = (0..neighbors.len())
+                .max_by(|&a, &b| {
+                    neighbors[a]
+                        .get_distance()
+                        .total_cmp(&neighbors[b].get_distance())
+                })
+                .unwrap();
+            neighbors.remove(farthest);
+        }
+        neighbors.insert(0, NeighborWithDistance::new(index_pointer, 0.0));
+        self.neighbor_store.set_neighbors(
+            storage,
+            self.meta_page,
+            representative,
+            neighbors,
+            stats,
+        );
+
+        let chain = head
+            .map(|head| vec![NeighborWithDistance::new(head, 0.0)])
+            .unwrap_or_default();
+        self.neighbor_store
+            .set_neighbors(storage, self.meta_page, index_pointer, chain, stats);
+    }
+
     fn update_back_pointer<S: Storage>(
         &mut self,
         from: IndexPointer,
@@ -500,8 +869,343 @@ impl<'a> Graph<'a> {
         storage: &S,
         prune_stats: &mut PruneNeighborStats,
     ) -> bool {
+        /* most back pointers fit in the list as is: add them in place, so that concurrent
+        inserts linking to the same node don't lose each other's edges */
+        if let GraphNeighborStore::Disk = self.neighbor_store {
+            if storage.append_neighbor_on_disk(self.meta_page, from, to, prune_stats) {
+                return false;
+            }
+        }
+
         let new = vec![NeighborWithDistance::new(to, distance)];
         let (pruned, _) = self.add_neighbors(storage, from, new, prune_stats);
         pruned
     }
 }
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    use pgrx::*;
+
+    use crate::util::ItemPointer;
+
+    /// Builds an index with the given neighbor selection and returns the build time
+    /// and the recall@10 for a fixed query vector.
+    unsafe fn build_and_measure_recall(
+        options: &str,
+        test_vec: &Option<Vec<f32>>,
+    ) -> spi::Result<(Duration, usize)> {
+        let start = Instant::now();
+        Spi::run(&format!(
+            "CREATE INDEX idx_diskann_selection ON test_data USING diskann (embedding)
+                WITH ({options});"
+        ))?;
+        let build_time = start.elapsed();
+
+        let query = "WITH cte AS (
+                SELECT ctid FROM test_data ORDER BY embedding <=> $1::vector LIMIT 10
+            )
+            SELECT array_agg(ctid) from cte;";
+
+        let with_index: Option<Vec<pg_sys::ItemPointerData>> = Spi::get_one_with_args(
+            &format!(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                SET diskann.query_search_list_size = 25;
+                {query}"
+            ),
+            vec![(
+                PgOid::Custom(pg_sys::FLOAT4ARRAYOID),
+                test_vec.clone().into_datum(),
+            )],
+        )?;
+
+        let without_index: Option<Vec<pg_sys::ItemPointerData>> = Spi::get_one_with_args(
+            &format!(
+                "SET enable_seqscan = 1;
+                SET enable_indexscan = 0;
+                {query}"
+            ),
+            vec![(
+                PgOid::Custom(pg_sys::FLOAT4ARRAYOID),
+                test_vec.clone().into_datum(),
+            )],
+        )?;
+
+        let set: HashSet<_> = without_index
+            .unwrap()
+            .iter()
+            .map(|&ctid| ItemPointer::with_item_pointer_data(ctid))
+            .collect();
+        let matches = with_index
+            .unwrap()
+            .iter()
+            .filter(|&&ctid| set.contains(&ItemPointer::with_item_pointer_data(ctid)))
+            .count();
+
+        Spi::run("DROP INDEX idx_diskann_selection;")?;
+        Ok((build_time, matches))
+    }
+
+    #[pg_test]
+    unsafe fn test_neighbor_selection_build_time_and_recall() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(128, 1000)?;
+
+        let test_vec: Option<Vec<f32>> = Spi::get_one(
+            "SELECT('{' || array_to_string(array_agg(random()), ',', '0') || '}')::real[] AS embedding
+            FROM generate_series(1, 128)",
+        )?;
+
+        let (rng_time, rng_matches) =
+            build_and_measure_recall("neighbor_selection = rng, num_neighbors = 20", &test_vec)?;
+        let (topk_time, topk_matches) =
+            build_and_measure_recall("neighbor_selection = topk, num_neighbors = 20", &test_vec)?;
+
+        info!(
+            "neighbor_selection=rng: build {:?}, recall@10 {}/10; neighbor_selection=topk: build {:?}, recall@10 {}/10",
+            rng_time, rng_matches, topk_time, topk_matches
+        );
+
+        assert!(
+            rng_matches >= 8,
+            "Low number of rng matches: {}",
+            rng_matches
+        );
+        assert!(
+            topk_matches >= 5,
+            "Low number of topk matches: {}",
+            topk_matches
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_search_list_size_build_time_and_recall() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(128, 1000)?;
+
+        let test_vec: Option<Vec<f32>> = Spi::get_one(
+            "SELECT('{' || array_to_string(array_agg(random()), ',', '0') || '}')::real[] AS embedding
+            FROM generate_series(1, 128)",
+        )?;
+
+        /* the search list of the build is independent of diskann.query_search_list_size, which
+        build_and_measure_recall sets for the queries */
+        let mut results = vec![];
+        for search_list_size in [10, 50, 200] {
+            let (time, matches) = build_and_measure_recall(
+                &format!("search_list_size = {search_list_size}, num_neighbors = 20"),
+                &test_vec,
+            )?;
+            info!(
+                "search_list_size={}: build {:?}, recall@10 {}/10",
+                search_list_size, time, matches
+            );
+            results.push(matches);
+        }
+
+        assert!(
+            results[2] >= results[0],
+            "search_list_size=200 recalls less than search_list_size=10: {:?}",
+            results
+        );
+        assert!(results[2] >= 8, "Low number of matches: {:?}", results);
+
+        Ok(())
+    }
+
+    /// Builds an index with the given options and returns the number of edges whose reverse
+    /// edge is missing, and the number of nodes reachable from the first row.
+    unsafe fn build_and_check_connectivity(index_options: &str) -> spi::Result<(i64, i64)> {
+        Spi::run(&format!(
+            "CREATE INDEX idx_diskann_connectivity ON test_data USING diskann (embedding)
+                WITH ({index_options});"
+        ))?;
+
+        let one_directional: Option<i64> = Spi::get_one(
+            "WITH e AS (
+                SELECT s.from_ctid, s.to_ctid
+                FROM test_data t, tsv_sample_neighbors('idx_diskann_connectivity'::regclass, t.ctid, 1) s)
+            SELECT count(*) FROM e
+            WHERE NOT EXISTS (SELECT 1 FROM e r WHERE r.from_ctid = e.to_ctid AND r.to_ctid = e.from_ctid)",
+        )?;
+        let reachable: Option<i64> = Spi::get_one(
+            "SELECT count(DISTINCT to_ctid::text)
+            FROM tsv_sample_neighbors('idx_diskann_connectivity'::regclass, '(0,1)', 1000, 1000000)",
+        )?;
+
+        Spi::run("DROP INDEX idx_diskann_connectivity;")?;
+        Ok((one_directional.unwrap(), reachable.unwrap()))
+    }
+
+    #[pg_test]
+    unsafe fn test_insert_appends_back_pointers() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized", "io_optimized"] {
+            /* with lists that never fill up, every insert links to all the existing nodes and
+            appends itself to their lists: the graph ends up complete */
+            Spi::run(&format!(
+                "DROP TABLE IF EXISTS test_data;
+                CREATE TABLE test_data (embedding vector (3));
+                CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout}, num_neighbors = 10);
+                INSERT INTO test_data (embedding) VALUES ('[1,2,3]');
+                INSERT INTO test_data (embedding) VALUES ('[4,5,6]');
+                INSERT INTO test_data (embedding) VALUES ('[7,8,10]');
+                INSERT INTO test_data (embedding) VALUES ('[-1,2,-3]');
+                INSERT INTO test_data (embedding) VALUES ('[3,-2,1]');"
+            ))?;
+
+            let (degree, num_nodes) = Spi::get_two::<i32, i64>(
+                "SELECT degree, num_nodes FROM tsv_degree_histogram('idx_diskann'::regclass)",
+            )?;
+            assert_eq!(
+                (degree.unwrap(), num_nodes.unwrap()),
+                (4, 5),
+                "{}",
+                storage_layout
+            );
+
+            /* the vectors copied into the lists of io_optimized nodes are the right ones */
+            Spi::run("SET enable_seqscan = 0;")?;
+            let nearest: Option<String> = Spi::get_one(
+                "SELECT embedding::text FROM test_data ORDER BY embedding <=> '[3,-2,1]' LIMIT 1",
+            )?;
+            assert_eq!(nearest.unwrap(), "[3,-2,1]");
+            Spi::run("RESET enable_seqscan;")?;
+        }
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_enforce_bidirectional() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 300)?;
+
+        let (one_directional_before, reachable_before) =
+            build_and_check_connectivity("num_neighbors = 10")?;
+        let (one_directional_after, reachable_after) =
+            build_and_check_connectivity("num_neighbors = 10, enforce_bidirectional = true")?;
+
+        info!(
+            "one-directional edges: {} -> {}, reachable nodes: {} -> {}",
+            one_directional_before, one_directional_after, reachable_before, reachable_after
+        );
+        assert!(
+            one_directional_after < one_directional_before,
+            "{} >= {}",
+            one_directional_after,
+            one_directional_before
+        );
+        assert!(reachable_after >= reachable_before);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_build_neighbor_slack() -> spi::Result<()> {
+        let insert = |from: i32, to: i32| {
+            format!(
+                "INSERT INTO test_data (embedding)
+                SELECT
+                    *
+                FROM (
+                    SELECT
+                        ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                    FROM
+                        generate_series({from} * 32, {to} * 32 - 1) i
+                    GROUP BY
+                        i / 32) g;"
+            )
+        };
+        Spi::run(&format!(
+            "CREATE TABLE test_data (
+                embedding vector (32)
+            );
+
+            select setseed(0.5);
+            {}
+
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = plain, num_neighbors = 20, build_neighbor_slack = 8);",
+            insert(0, 300)
+        ))?;
+
+        let max_degree = "SELECT max(degree) FROM tsv_degree_histogram('idx_diskann'::regclass)";
+
+        /* the build leaves 8 of the 20 slots free */
+        let degree = Spi::get_one::<i32>(max_degree)?.unwrap();
+        assert!(degree <= 12, "{}", degree);
+
+        /* which inserts then use */
+        Spi::run(&insert(300, 500))?;
+        let degree = Spi::get_one::<i32>(max_degree)?.unwrap();
+        assert!(degree > 12 && degree <= 20, "{}", degree);
+
+        Ok(())
+    }
+
+    #[pg_test(error = "build_neighbor_slack (50) must be less than num_neighbors (50)")]
+    unsafe fn test_build_neighbor_slack_too_large() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = plain, build_neighbor_slack = 50);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    fn test_estimated_recall() {
+        use super::{ListSearchNeighbor, ListSearchResult};
+
+        let mut lsr = ListSearchResult::<(), ()>::empty();
+        assert_eq!(lsr.estimated_recall(4), 1.0);
+
+        for (offset, distance) in [(1, 0.1), (2, 0.2), (3, 0.3), (4, 0.32)] {
+            lsr.visited.push(ListSearchNeighbor::new(
+                ItemPointer::new(1, offset),
+                distance,
+                (),
+            ));
+        }
+        /* nothing close to the end of the list */
+        lsr.insert_neighbor(ListSearchNeighbor::new(ItemPointer::new(2, 1), 1.0, ()));
+        assert_eq!(lsr.estimated_recall(4), 1.0);
+
+        /* a candidate just past the last node of the list could be one of the nearest */
+        lsr.insert_neighbor(ListSearchNeighbor::new(ItemPointer::new(2, 2), 0.34, ()));
+        assert_eq!(lsr.estimated_recall(4), 0.8);
+
+        /* visited nodes past a shorter list count as well */
+        assert_eq!(lsr.estimated_recall(3), 0.75);
+    }
+
+    #[pg_test]
+    fn test_distance_ties_ordered_by_index_pointer() {
+        use super::{ListSearchNeighbor, ListSearchResult};
+
+        let mut lsr = ListSearchResult::<(), ()>::empty();
+        for (block, offset, distance) in [(2, 5, 0.5), (1, 7, 0.5), (3, 1, 0.2), (2, 1, 0.5)] {
+            lsr.insert_neighbor(ListSearchNeighbor::new(
+                ItemPointer::new(block, offset),
+                distance,
+                (),
+            ));
+        }
+        while lsr.visit_closest(10).is_some() {}
+
+        let order: Vec<ItemPointer> = lsr.visited.iter().map(|n| n.index_pointer).collect();
+        assert_eq!(
+            order,
+            vec![
+                ItemPointer::new(3, 1),
+                ItemPointer::new(1, 7),
+                ItemPointer::new(2, 1),
+                ItemPointer::new(2, 5),
+            ]
+        );
+    }
+}
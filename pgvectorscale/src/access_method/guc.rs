@@ -1,7 +1,39 @@
+use pgrx::pg_sys::AsPgCStr;
 use pgrx::*;
 
+use super::build::MAX_DIMENSIONS;
+use super::pg_vector::DimensionMode;
+use super::sbq::OnCorruptCode;
+use super::scan::OnEmptyResult;
+use super::storage::DefaultStorageLayout;
+
 pub static TSV_QUERY_SEARCH_LIST_SIZE: GucSetting<i32> = GucSetting::<i32>::new(100);
 pub static TSV_RESORT_SIZE: GucSetting<i32> = GucSetting::<i32>::new(50);
+pub static TSV_LOG_MIN_DURATION_SEARCH: GucSetting<i32> = GucSetting::<i32>::new(-1);
+pub static TSV_SEARCH_WORK_MEM: GucSetting<i32> = GucSetting::<i32>::new(-1);
+pub static TSV_MAX_SEARCH_HOPS: GucSetting<i32> = GucSetting::<i32>::new(-1);
+pub static TSV_TARGET_RECALL: GucSetting<f64> = GucSetting::<f64>::new(0.0);
+pub static TSV_RESCORE_WITH_PGVECTOR: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static TSV_EXACT_TRAVERSAL: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static TSV_BUILD_VERBOSE: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static TSV_NODE_CACHE_SIZE: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub static TSV_AUTO_STORAGE_MIN_ROWS: GucSetting<i32> = GucSetting::<i32>::new(100000);
+pub static TSV_AUTO_STORAGE_MIN_DIMENSIONS: GucSetting<i32> = GucSetting::<i32>::new(1024);
+pub static TSV_MAX_DIMENSIONS: GucSetting<i32> = GucSetting::<i32>::new(MAX_DIMENSIONS);
+pub static TSV_DELETED_BRIDGE_DEPTH: GucSetting<i32> = GucSetting::<i32>::new(-1);
+pub static TSV_TRACE_CANDIDATES: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static TSV_ON_EMPTY_RESULT: GucSetting<OnEmptyResult> =
+    GucSetting::<OnEmptyResult>::new(OnEmptyResult::empty);
+pub static TSV_DIMENSION_MODE: GucSetting<DimensionMode> =
+    GucSetting::<DimensionMode>::new(DimensionMode::strict);
+pub static TSV_ON_CORRUPT_CODE: GucSetting<OnCorruptCode> =
+    GucSetting::<OnCorruptCode>::new(OnCorruptCode::error);
+pub static TSV_DEFAULT_STORAGE_LAYOUT: GucSetting<DefaultStorageLayout> =
+    GucSetting::<DefaultStorageLayout>::new(DefaultStorageLayout::memory_optimized);
+/// The value of diskann.default_num_neighbors, see default_num_neighbors(). Not a GucSetting,
+/// which can't have the check hook that keeps it at -1 or at least 10.
+static mut TSV_DEFAULT_NUM_NEIGHBORS: std::os::raw::c_int = -1;
+pub static TSV_DEFAULT_NUM_BITS_PER_DIMENSION: GucSetting<i32> = GucSetting::<i32>::new(0);
 
 pub fn init() {
     GucRegistry::define_int_guc(
@@ -25,4 +57,225 @@ pub fn init() {
         GucContext::Userset,
         GucFlags::default(),
     );
+
+    GucRegistry::define_int_guc(
+        "diskann.log_min_duration_search",
+        "Log the stats of index searches that take at least this long",
+        "Logs the search stats (node reads, heap reads, distance comparisons, visited nodes, duration) of every diskann index scan whose duration is at least this many milliseconds. -1 disables logging; 0 logs all scans.",
+        &TSV_LOG_MIN_DURATION_SEARCH,
+        -1,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::UNIT_MS,
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.search_work_mem",
+        "The maximum memory used by the candidate lists of an index search (-1 for no limit)",
+        "Searches that return many rows, like an ORDER BY with a very large LIMIT, keep track of every node they have seen. A search that goes over this amount of memory fails with an ERROR instead of exhausting the memory of the backend: there is no spill to disk. Off by default.",
+        &TSV_SEARCH_WORK_MEM,
+        -1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_KB,
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.max_search_hops",
+        "The maximum number of nodes whose neighbors an index search looks at (-1 for no limit)",
+        "Each hop reads a node and computes the distances to its neighbors. Once a search reaches this many hops it returns the best nodes it has found so far, which bounds its latency at the cost of recall.",
+        &TSV_MAX_SEARCH_HOPS,
+        -1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_float_guc(
+        "diskann.target_recall",
+        "The recall index scans aim for by growing their search list (0 to disable)",
+        "Before returning its first row, a scan estimates its recall from the distances of the nodes just outside its search list and doubles diskann.query_search_list_size until the estimate reaches this value.",
+        &TSV_TARGET_RECALL,
+        0.0,
+        1.0,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        "diskann.rescore_with_pgvector",
+        "Compute the exact distances used for rescoring with the distance operator of pgvector",
+        "The exact distances then match what the <=> operator returns, bit for bit, which matters when rows are at nearly the same distance. It costs a function call per rescored row, little next to reading the row from the table.",
+        &TSV_RESCORE_WITH_PGVECTOR,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        "diskann.build_verbose",
+        "Report the time taken by each phase of an index build",
+        "Index builds then emit a NOTICE with the wall-clock time of each phase: quantizer training (memory_optimized and io_optimized layouts only), node creation and graph finalization.",
+        &TSV_BUILD_VERBOSE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.node_cache_size",
+        "The number of quantized node vectors each session keeps in memory across searches (0 to disable)",
+        "Searches of memory_optimized indexes read every neighbor they compare the query to. With a cache, the queries of a session that visit the same nodes read them once; the least recently used vectors are evicted first. tsv_node_cache_stats() reports the hit rate.",
+        &TSV_NODE_CACHE_SIZE,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_enum_guc(
+        "diskann.dimension_mode",
+        "What to do with query vectors that don't have the number of dimensions of the index",
+        "strict fails the query. truncate drops the extra dimensions of longer vectors and pad_zero appends zeros to shorter ones, for clients that send vectors padded or cut to a fixed size. Vectors that differ the other way still fail.",
+        &TSV_DIMENSION_MODE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.auto_storage_min_rows",
+        "The number of rows from which storage_layout = auto picks memory_optimized",
+        "Indexes built with storage_layout = auto on tables with fewer rows (as estimated by the planner) and vectors with fewer than diskann.auto_storage_min_dimensions dimensions use the plain layout.",
+        &TSV_AUTO_STORAGE_MIN_ROWS,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.auto_storage_min_dimensions",
+        "The number of dimensions from which storage_layout = auto picks memory_optimized",
+        "Indexes built with storage_layout = auto on vectors with fewer dimensions and tables with fewer than diskann.auto_storage_min_rows rows use the plain layout.",
+        &TSV_AUTO_STORAGE_MIN_DIMENSIONS,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_enum_guc(
+        "diskann.default_storage_layout",
+        "The storage_layout of indexes created without the option",
+        "Read when an index is built, the layout is then stored in the index. An explicit storage_layout in CREATE INDEX takes precedence.",
+        &TSV_DEFAULT_STORAGE_LAYOUT,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    unsafe {
+        pg_sys::DefineCustomIntVariable(
+            PgMemoryContexts::TopMemoryContext.pstrdup("diskann.default_num_neighbors"),
+            PgMemoryContexts::TopMemoryContext.pstrdup("The num_neighbors of indexes created without the option (-1 for the default of the storage layout)"),
+            PgMemoryContexts::TopMemoryContext.pstrdup("Read when an index is built, the value is then stored in the index. An explicit num_neighbors in CREATE INDEX takes precedence. Either -1 or at least 10, like num_neighbors."),
+            std::ptr::addr_of_mut!(TSV_DEFAULT_NUM_NEIGHBORS),
+            -1,
+            -1,
+            1000,
+            pg_sys::GucContext_PGC_USERSET,
+            0,
+            Some(check_default_num_neighbors),
+            None,
+            None,
+        );
+    }
+
+    GucRegistry::define_int_guc(
+        "diskann.default_num_bits_per_dimension",
+        "The num_bits_per_dimension of indexes created without the option (0 for the default of the storage layout)",
+        "Read when an index is built, the value is then stored in the index. An explicit num_bits_per_dimension in CREATE INDEX takes precedence.",
+        &TSV_DEFAULT_NUM_BITS_PER_DIMENSION,
+        0,
+        32,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.max_dimensions",
+        "The maximum number of dimensions of the vector columns that diskann indexes may be built on",
+        "Index builds on columns with more dimensions fail right away, which catches typos like vector(100000) before they cost a long build. Defaults to the most dimensions the nodes of an index can hold.",
+        &TSV_MAX_DIMENSIONS,
+        1,
+        MAX_DIMENSIONS,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        "diskann.trace_candidates",
+        "Record every candidate evaluated by index searches",
+        "Searches record their query and each node they compute a distance to, with that distance, in the memory of the session. tsv_traced_candidates() returns the trace with the exact distances and tsv_trace_reset() empties it. Meant for collecting data to tune the index, it slows searches down and the trace grows until it is reset: each search adds 4 bytes per dimension for its query and 12 bytes per candidate, a few kilobytes with the default diskann.query_search_list_size.",
+        &TSV_TRACE_CANDIDATES,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.deleted_bridge_depth",
+        "The maximum number of deleted nodes in a row an index search goes through (-1 for no limit)",
+        "Deleted nodes stay in the graph and keep the live nodes around them reachable, so searches go through them. When most of the graph is deleted, chains of deleted nodes can make a search read many nodes that it can't return. A limit skips the neighbors of deleted nodes deeper than it, at the cost of the live nodes only reachable through them until the index is rebuilt.",
+        &TSV_DELETED_BRIDGE_DEPTH,
+        -1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_enum_guc(
+        "diskann.on_empty_result",
+        "What to do when an index scan finds no rows",
+        "empty returns no rows, like any scan. error fails the query instead, for applications that treat a similarity search without results as a failure. Only the rows the index scan itself returns count: a scan that returns rows that a WHERE clause then filters out doesn't fail.",
+        &TSV_ON_EMPTY_RESULT,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_enum_guc(
+        "diskann.on_corrupt_code",
+        "What to do when the SBQ code of a node doesn't match the quantizer of the index",
+        "error fails the search. max_distance treats the node as infinitely far from the query, so that a search can still return the other rows of a corrupted index until it is rebuilt.",
+        &TSV_ON_CORRUPT_CODE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        "diskann.exact_traversal",
+        "Whether index searches compute exact distances like indexes built with exact_traversal",
+        "Searches of memory_optimized and io_optimized indexes then compute the exact distance to every node they visit from the vector in the table, as if the index had been built with exact_traversal = true. Meant for the few queries that need exact traversal, with SET LOCAL around them.",
+        &TSV_EXACT_TRAVERSAL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+}
+
+/// The diskann.default_num_neighbors GUC.
+pub fn default_num_neighbors() -> i32 {
+    unsafe { TSV_DEFAULT_NUM_NEIGHBORS }
+}
+
+#[pg_guard]
+extern "C" fn check_default_num_neighbors(
+    newval: *mut std::os::raw::c_int,
+    _extra: *mut *mut std::os::raw::c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    let value = unsafe { *newval };
+    if value == -1 || value >= 10 {
+        return true;
+    }
+    unsafe {
+        pg_sys::GUC_check_errdetail_string =
+            "diskann.default_num_neighbors must be -1 or at least 10.".as_pg_cstr();
+    }
+    false
 }
@@ -0,0 +1,215 @@
+//! Late-interaction (ColBERT-style) search over multi-vector documents.
+//!
+//! Each document is stored as several rows, one per sub-vector, with a column
+//! referencing the document. The score of a document is the sum, over the query
+//! vectors, of the best similarity of that query vector to any of the document's
+//! vectors. Candidates are generated with one index search per query vector and
+//! then aggregated by document.
+
+use std::collections::HashMap;
+
+use pgrx::*;
+
+use super::meta_page::MetaPage;
+use super::pg_vector::VectorArg;
+use super::search::search_index;
+use super::storage_common::{check_heap_select_privilege, check_is_diskann_index};
+
+/// The best similarities found for one query vector, by document.
+struct QueryCandidates {
+    best: HashMap<i64, f64>,
+    /// The lowest similarity among the candidates. Documents that weren't found are
+    /// assumed to be at most this similar to the query vector.
+    cutoff: f64,
+}
+
+/// Finds the attribute number of the integer document id column of the heap.
+fn document_attribute_number(
+    heap: &PgRelation,
+    document_column: &str,
+) -> (pg_sys::AttrNumber, pg_sys::Oid) {
+    let tuple_desc = heap.tuple_desc();
+    let attr = tuple_desc
+        .iter()
+        .find(|attr| !attr.is_dropped() && attr.name() == document_column);
+    let attr = match attr {
+        Some(attr) => attr,
+        None => error!(
+            "column \"{}\" does not exist in \"{}\"",
+            document_column,
+            heap.name()
+        ),
+    };
+
+    let type_oid = attr.type_oid().value();
+    if type_oid != pg_sys::INT2OID && type_oid != pg_sys::INT4OID && type_oid != pg_sys::INT8OID {
+        error!(
+            "document column \"{}\" must be of an integer type",
+            document_column
+        );
+    }
+    (attr.num(), type_oid)
+}
+
+unsafe fn document_id(datum: pg_sys::Datum, type_oid: pg_sys::Oid) -> i64 {
+    match type_oid {
+        pg_sys::INT2OID => i16::from_datum(datum, false).unwrap() as i64,
+        pg_sys::INT4OID => i32::from_datum(datum, false).unwrap() as i64,
+        _ => i64::from_datum(datum, false).unwrap(),
+    }
+}
+
+/// Runs one search per query vector and keeps, for each of them, the best similarity
+/// of the documents among its first `num_candidates` results.
+fn maxsim_candidates(
+    index: &PgRelation,
+    queries: &[VectorArg],
+    document_column: &str,
+    num_candidates: usize,
+) -> Vec<QueryCandidates> {
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let (attribute_number, type_oid) = document_attribute_number(&heap, document_column);
+    let search_list_size = super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize;
+
+    queries
+        .iter()
+        .map(|query| {
+            let mut candidates = QueryCandidates {
+                best: HashMap::new(),
+                cutoff: f64::MAX,
+            };
+            let mut seen = 0;
+            search_index(index, query, search_list_size, |result| {
+                seen += 1;
+                /* rows without a document are still counted as candidates */
+                if let Some(datum) = unsafe { result.slot.get_attribute(attribute_number) } {
+                    let document = unsafe { document_id(datum, type_oid) };
+                    let similarity = 1.0 - result.distance as f64;
+                    let best = candidates.best.entry(document).or_insert(similarity);
+                    *best = best.max(similarity);
+                    candidates.cutoff = candidates.cutoff.min(similarity);
+                }
+                seen < num_candidates
+            });
+            candidates
+        })
+        .collect()
+}
+
+/// Scores multi-vector documents against the multi-vector `queries` and returns the `k`
+/// best. `document_column` is the integer column of the indexed table that references the
+/// document each vector belongs to. The score of a document is the sum over the query
+/// vectors of the maximum similarity (1 - distance) to the document's vectors. The
+/// similarity is only that of cosine distance, so indexes measuring another distance
+/// are rejected.
+///
+/// Only the documents among the nearest neighbors of at least one query vector are scored,
+/// and a query vector that didn't find a document contributes the lowest similarity seen
+/// for it. Raising `diskann.query_search_list_size` considers more candidates.
+#[pg_extern]
+pub fn tsv_maxsim(
+    index: PgRelation,
+    queries: Vec<VectorArg>,
+    k: i32,
+    document_column: &str,
+) -> TableIterator<'static, (name!(document_id, i64), name!(score, f64))> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if !MetaPage::fetch(&index).is_cosine_distance() {
+        error!("tsv_maxsim requires an index using cosine distance");
+    }
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+
+    let num_candidates = std::cmp::max(
+        k as usize,
+        super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize,
+    );
+    let candidates = maxsim_candidates(&index, &queries, document_column, num_candidates);
+
+    let mut scores: HashMap<i64, f64> = HashMap::new();
+    for c in candidates.iter() {
+        for &document in c.best.keys() {
+            scores.entry(document).or_insert(0.0);
+        }
+    }
+    for (document, score) in scores.iter_mut() {
+        *score = candidates
+            .iter()
+            .map(|c| c.best.get(document).copied().unwrap_or(c.cutoff))
+            .sum();
+    }
+
+    let mut rows: Vec<(i64, f64)> = scores.into_iter().collect();
+    rows.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    rows.truncate(k as usize);
+    TableIterator::new(rows)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_tsv_maxsim() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (
+                document_id int,
+                embedding vector (16)
+            );
+
+            select setseed(0.5);
+            INSERT INTO test_data (document_id, embedding)
+            SELECT
+                g.i / 4, g.embedding
+            FROM (
+                SELECT
+                    i % 400 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 16 * 400) i
+                GROUP BY
+                    i % 400) g;
+
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);",
+        )?;
+
+        /* querying with the vectors of a document finds that document first, with a perfect score */
+        let (document, score) = Spi::get_two::<i64, f64>(
+            "SELECT document_id, score FROM tsv_maxsim(
+                'idx_diskann'::regclass,
+                (SELECT array_agg(embedding) FROM test_data WHERE document_id = 7),
+                5,
+                'document_id')
+            LIMIT 1",
+        )?;
+        assert_eq!(document.unwrap(), 7);
+        assert!((score.unwrap() - 4.0).abs() < 0.0001, "{:?}", score);
+
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM tsv_maxsim(
+                'idx_diskann'::regclass,
+                (SELECT array_agg(embedding) FROM test_data WHERE document_id = 7),
+                5,
+                'document_id')",
+        )?;
+        assert_eq!(cnt.unwrap(), 5);
+
+        Ok(())
+    }
+
+    #[pg_test(error = "document column \"embedding\" must be of an integer type")]
+    unsafe fn test_tsv_maxsim_bad_document_column() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            INSERT INTO test_data (embedding) VALUES ('[1,2,3]'), ('[4,5,6]');
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);
+            SELECT * FROM tsv_maxsim('idx_diskann'::regclass, ARRAY['[1,2,3]'::vector], 1, 'embedding');",
+        )?;
+        Ok(())
+    }
+}
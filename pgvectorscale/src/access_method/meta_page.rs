@@ -1,4 +1,4 @@
-use pgrx::pg_sys::{BufferGetBlockNumber, InvalidBlockNumber, InvalidOffsetNumber};
+use pgrx::pg_sys::{AsPgCStr, BufferGetBlockNumber, InvalidBlockNumber, InvalidOffsetNumber};
 use pgrx::*;
 use pgvectorscale_derive::{Readable, Writeable};
 use rkyv::{Archive, Deserialize, Serialize};
@@ -9,16 +9,21 @@ use crate::util::page;
 use crate::util::*;
 
 use super::distance;
+use super::graph::{IndexType, NeighborSelection};
+use super::guc::{TSV_AUTO_STORAGE_MIN_DIMENSIONS, TSV_AUTO_STORAGE_MIN_ROWS};
 use super::options::{
-    NUM_DIMENSIONS_DEFAULT_SENTINEL, NUM_NEIGHBORS_DEFAULT_SENTINEL,
+    lookup_decrypt_function, NUM_DIMENSIONS_DEFAULT_SENTINEL, NUM_NEIGHBORS_DEFAULT_SENTINEL,
     SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL,
 };
+use super::pg_vector::OnInvalid;
+use super::plain_node::StoredVectorType;
 use super::sbq::SbqNode;
 use super::stats::StatsNodeModify;
 use super::storage::StorageType;
+use super::storage_common::{check_is_diskann_index, get_attribute_number_from_index};
 
 const TSV_MAGIC_NUMBER: u32 = 768756476; //Magic number, random
-const TSV_VERSION: u32 = 2;
+const TSV_VERSION: u32 = 3;
 const GRAPH_SLACK_FACTOR: f64 = 1.3_f64;
 
 const META_BLOCK_NUMBER: pg_sys::BlockNumber = 0;
@@ -76,6 +81,74 @@ impl MetaPageV1 {
             max_alpha: self.max_alpha,
             init_ids: ItemPointer::new(self.init_ids_block_number, self.init_ids_offset),
             quantizer_metadata: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            neighbor_selection: NeighborSelection::Rng as u8,
+            index_type: IndexType::Graph as u8,
+            stored_vector_type: StoredVectorType::F32 as u8,
+            on_invalid: OnInvalid::Error as u8,
+            enforce_bidirectional: false,
+            locality_layout: false,
+            decrypt_function: pg_sys::InvalidOid.as_u32(),
+            build_neighbor_slack: 0,
+            compensated_sum: false,
+            exact_traversal: false,
+            quantizer_drift_threshold: 0.0,
+            intrinsic_dimensionality: 0.0,
+            collapse_duplicate_vectors: false,
+            build_candidate_sample_rate: 1.0,
+        }
+    }
+}
+
+/// This is old metadata version for extension versions <=0.2.0.
+/// Stored as the first page (offset 2) in the index relation, after a MetaPageHeader with version 2.
+#[derive(Clone, PartialEq, Archive, Deserialize, Serialize, Readable)]
+#[archive(check_bytes)]
+pub struct MetaPageV2 {
+    magic_number: u32,
+    version: u32,
+    extension_version_when_built: String,
+    distance_type: u16,
+    num_dimensions: u32,
+    num_dimensions_to_index: u32,
+    bq_num_bits_per_dimension: u8,
+    storage_type: u8,
+    num_neighbors: u32,
+    search_list_size: u32,
+    max_alpha: f64,
+    init_ids: ItemPointer,
+    quantizer_metadata: ItemPointer,
+}
+
+impl MetaPageV2 {
+    pub fn get_new_meta(&self) -> MetaPage {
+        MetaPage {
+            magic_number: TSV_MAGIC_NUMBER,
+            version: TSV_VERSION,
+            extension_version_when_built: self.extension_version_when_built.clone(),
+            distance_type: self.distance_type,
+            num_dimensions: self.num_dimensions,
+            num_dimensions_to_index: self.num_dimensions_to_index,
+            bq_num_bits_per_dimension: self.bq_num_bits_per_dimension,
+            storage_type: self.storage_type,
+            num_neighbors: self.num_neighbors,
+            search_list_size: self.search_list_size,
+            max_alpha: self.max_alpha,
+            init_ids: self.init_ids,
+            quantizer_metadata: self.quantizer_metadata,
+            neighbor_selection: NeighborSelection::Rng as u8,
+            index_type: IndexType::Graph as u8,
+            stored_vector_type: StoredVectorType::F32 as u8,
+            on_invalid: OnInvalid::Error as u8,
+            enforce_bidirectional: false,
+            locality_layout: false,
+            decrypt_function: pg_sys::InvalidOid.as_u32(),
+            build_neighbor_slack: 0,
+            compensated_sum: false,
+            exact_traversal: false,
+            quantizer_drift_threshold: 0.0,
+            intrinsic_dimensionality: 0.0,
+            collapse_duplicate_vectors: false,
+            build_candidate_sample_rate: 1.0,
         }
     }
 }
@@ -132,6 +205,34 @@ pub struct MetaPage {
     max_alpha: f64,
     init_ids: ItemPointer,
     quantizer_metadata: ItemPointer,
+    /// the value of the NeighborSelection enum
+    neighbor_selection: u8,
+    /// the value of the IndexType enum
+    index_type: u8,
+    /// the value of the StoredVectorType enum
+    stored_vector_type: u8,
+    /// the value of the OnInvalid enum
+    on_invalid: u8,
+    /// whether the build adds the reverse of every edge, space permitting
+    enforce_bidirectional: bool,
+    /// whether the build writes the nodes of similar vectors next to each other
+    locality_layout: bool,
+    /// the oid of the function applied to the heap vectors, InvalidOid if none
+    decrypt_function: u32,
+    /// the number of neighbor slots the build leaves free for later inserts
+    build_neighbor_slack: u32,
+    /// whether the distance functions use compensated summation
+    compensated_sum: bool,
+    /// whether searches compute exact distances from the heap while traversing the graph
+    exact_traversal: bool,
+    /// the drift of inserted vectors from the SBQ means above which inserts raise a notice, 0 if off
+    quantizer_drift_threshold: f64,
+    /// the intrinsic dimensionality of the vectors estimated by the build, 0 if not estimated
+    intrinsic_dimensionality: f64,
+    /// whether the build chains the rows with identical vectors behind a single graph node
+    collapse_duplicate_vectors: bool,
+    /// the fraction of the build search candidates that neighbor selection evaluates
+    build_candidate_sample_rate: f64,
 }
 
 impl MetaPage {
@@ -145,6 +246,47 @@ impl MetaPage {
         self.num_dimensions_to_index
     }
 
+    /// Errors out if the indexed column no longer has the number of dimensions the index was
+    /// built for, which happens when its type is changed without the index being rebuilt.
+    pub fn check_column_dimensions(&self, index: &PgRelation) {
+        let mut type_oid = pg_sys::InvalidOid;
+        let mut typmod = -1;
+        let mut collation = pg_sys::InvalidOid;
+        unsafe {
+            pg_sys::get_atttypetypmodcoll(
+                (*index.rd_index).indrelid,
+                get_attribute_number_from_index(index),
+                &mut type_oid,
+                &mut typmod,
+                &mut collation,
+            )
+        };
+        if typmod != self.num_dimensions as i32 {
+            let column_dimensions = if typmod < 0 {
+                "no fixed number of".to_string()
+            } else {
+                typmod.to_string()
+            };
+            error!(
+                "diskann index \"{}\" was built for vectors of {} dimensions but the column now has {} dimensions, REINDEX the index",
+                index.name(),
+                self.num_dimensions,
+                column_dimensions
+            );
+        }
+    }
+
+    /// Errors out unless `dimensions`, those of a query vector, match the index.
+    pub fn check_query_dimensions(&self, dimensions: usize) {
+        if dimensions != self.num_dimensions as usize {
+            /* the message of pgvector's operators */
+            error!(
+                "different vector dimensions {} and {}",
+                self.num_dimensions, dimensions
+            );
+        }
+    }
+
     pub fn get_bq_num_bits_per_dimension(&self) -> u8 {
         self.bq_num_bits_per_dimension
     }
@@ -165,6 +307,12 @@ impl MetaPage {
         self.num_neighbors
     }
 
+    /// The number of neighbors the build gives each node. The remaining slots, up to
+    /// num_neighbors, are left free so that later inserts can add edges without pruning.
+    pub fn get_num_neighbors_for_build(&self) -> u32 {
+        self.num_neighbors - self.build_neighbor_slack
+    }
+
     pub fn get_search_list_size_for_build(&self) -> u32 {
         self.search_list_size
     }
@@ -173,10 +321,78 @@ impl MetaPage {
         self.max_alpha
     }
 
+    pub fn get_neighbor_selection(&self) -> NeighborSelection {
+        NeighborSelection::from_u8(self.neighbor_selection)
+    }
+
+    pub fn get_index_type(&self) -> IndexType {
+        IndexType::from_u8(self.index_type)
+    }
+
+    pub fn get_stored_vector_type(&self) -> StoredVectorType {
+        StoredVectorType::from_u8(self.stored_vector_type)
+    }
+
+    pub fn get_on_invalid(&self) -> OnInvalid {
+        OnInvalid::from_u8(self.on_invalid)
+    }
+
+    pub fn get_enforce_bidirectional(&self) -> bool {
+        self.enforce_bidirectional
+    }
+
+    pub fn get_locality_layout(&self) -> bool {
+        self.locality_layout
+    }
+
+    pub fn get_collapse_duplicate_vectors(&self) -> bool {
+        self.collapse_duplicate_vectors
+    }
+
+    pub fn get_build_candidate_sample_rate(&self) -> f64 {
+        self.build_candidate_sample_rate
+    }
+
+    pub fn get_decrypt_function(&self) -> Option<pg_sys::Oid> {
+        let oid = pg_sys::Oid::from(self.decrypt_function);
+        if oid == pg_sys::InvalidOid {
+            None
+        } else {
+            Some(oid)
+        }
+    }
+
+    pub fn get_exact_traversal(&self) -> bool {
+        self.exact_traversal
+    }
+
+    pub fn get_quantizer_drift_threshold(&self) -> f64 {
+        self.quantizer_drift_threshold
+    }
+
+    /// The intrinsic dimensionality of the vectors, if the build estimated it.
+    pub fn get_intrinsic_dimensionality(&self) -> Option<f64> {
+        (self.intrinsic_dimensionality > 0.0).then_some(self.intrinsic_dimensionality)
+    }
+
+    /// Whether the index measures cosine distance. Indexes upgraded from version 1 meta
+    /// pages measure L2 distance.
+    pub fn is_cosine_distance(&self) -> bool {
+        matches!(
+            DistanceType::from_u16(self.distance_type),
+            DistanceType::Cosine
+        )
+    }
+
     pub fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
-        match DistanceType::from_u16(self.distance_type) {
-            DistanceType::Cosine => distance::distance_cosine,
-            DistanceType::L2 => distance::distance_l2,
+        match (
+            DistanceType::from_u16(self.distance_type),
+            self.compensated_sum,
+        ) {
+            (DistanceType::Cosine, false) => distance::distance_cosine,
+            (DistanceType::Cosine, true) => distance::distance_cosine_compensated,
+            (DistanceType::L2, false) => distance::distance_l2,
+            (DistanceType::L2, true) => distance::distance_l2_compensated,
         }
     }
 
@@ -185,7 +401,7 @@ impl MetaPage {
     }
 
     pub fn get_max_neighbors_during_build(&self) -> usize {
-        return ((self.get_num_neighbors() as f64) * GRAPH_SLACK_FACTOR).ceil() as usize;
+        return ((self.get_num_neighbors_for_build() as f64) * GRAPH_SLACK_FACTOR).ceil() as usize;
     }
 
     pub fn get_init_ids(&self) -> Option<Vec<IndexPointer>> {
@@ -210,24 +426,137 @@ impl MetaPage {
     fn calculate_num_neighbors(
         num_dimensions: u32,
         num_bits_per_dimension: u8,
+        storage_type: StorageType,
         opt: &PgBox<TSVIndexOptions>,
     ) -> u32 {
         let num_neighbors = (*opt).get_num_neighbors();
         if num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
-            match (*opt).get_storage_type() {
-                StorageType::Plain => 50,
-                StorageType::SbqSpeedup => SbqNode::get_default_num_neighbors(
-                    num_dimensions as usize,
-                    num_dimensions as usize,
-                    num_bits_per_dimension,
-                ) as u32,
-                StorageType::SbqCompression => 50,
-            }
+            Self::default_num_neighbors(num_dimensions, num_bits_per_dimension, storage_type)
         } else {
             num_neighbors as u32
         }
     }
 
+    /// The num_neighbors of indexes built without the option.
+    pub fn default_num_neighbors(
+        num_dimensions: u32,
+        num_bits_per_dimension: u8,
+        storage_type: StorageType,
+    ) -> u32 {
+        match storage_type {
+            StorageType::Plain => 50,
+            StorageType::SbqSpeedup => SbqNode::get_default_num_neighbors(
+                num_dimensions as usize,
+                num_dimensions as usize,
+                num_bits_per_dimension,
+            ) as u32,
+            StorageType::SbqCompression => 50,
+        }
+    }
+
+    /// The num_bits_per_dimension of indexes built without the option.
+    pub fn default_bq_num_bits_per_dimension(
+        num_dimensions_to_index: u32,
+        storage_type: StorageType,
+    ) -> u8 {
+        if storage_type == StorageType::SbqCompression && num_dimensions_to_index < 900 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Returns the storage type of the storage_layout option `storage_layout` for an index on
+    /// `heap`. For `auto`, picks memory_optimized if the table has at least
+    /// diskann.auto_storage_min_rows rows (as estimated by the planner) or the vectors have at
+    /// least diskann.auto_storage_min_dimensions dimensions, and plain otherwise.
+    pub unsafe fn resolve_storage_type(
+        heap: &PgRelation,
+        num_dimensions: u32,
+        storage_layout: &str,
+    ) -> StorageType {
+        if !storage_layout.eq_ignore_ascii_case(super::storage::AUTO_STORAGE_TYPE_STR) {
+            return StorageType::from_str(storage_layout);
+        }
+
+        let mut pages: pg_sys::BlockNumber = 0;
+        let mut tuples: f64 = 0.0;
+        let mut allvisfrac: f64 = 0.0;
+        pg_sys::estimate_rel_size(
+            heap.as_ptr(),
+            std::ptr::null_mut(),
+            &mut pages,
+            &mut tuples,
+            &mut allvisfrac,
+        );
+
+        let storage_type = if tuples >= TSV_AUTO_STORAGE_MIN_ROWS.get() as f64
+            || num_dimensions >= TSV_AUTO_STORAGE_MIN_DIMENSIONS.get() as u32
+        {
+            StorageType::SbqCompression
+        } else {
+            StorageType::Plain
+        };
+        notice!(
+            "storage_layout = auto chose {} for about {} rows of {} dimensions",
+            storage_type.as_str(),
+            tuples.max(0.0) as i64,
+            num_dimensions
+        );
+        storage_type
+    }
+
+    /// Resolves the decrypt_function option, which also has to return the type of the indexed
+    /// column, and records that the index depends on the function so that it can't be dropped
+    /// from under the index. A REINDEX writes the meta page again, so the dependency is only
+    /// recorded if the index doesn't have it yet.
+    unsafe fn resolve_decrypt_function(index: &PgRelation, name: &str) -> pg_sys::Oid {
+        let oid = lookup_decrypt_function(name);
+        let column_type = index
+            .tuple_desc()
+            .get(0)
+            .expect("index has no attributes")
+            .atttypid;
+        if pg_sys::get_func_rettype(oid) != column_type {
+            pgrx::error!(
+                "decrypt_function {} must take a single argument and return the type of the indexed column",
+                name
+            );
+        }
+
+        let recorded: Option<bool> = Spi::get_one_with_args(
+            "SELECT EXISTS (SELECT 1 FROM pg_depend
+                WHERE classid = 'pg_class'::regclass AND objid = $1
+                    AND refclassid = 'pg_proc'::regclass AND refobjid = $2 AND deptype = 'n')",
+            vec![
+                (
+                    PgOid::BuiltIn(PgBuiltInOids::OIDOID),
+                    index.oid().into_datum(),
+                ),
+                (PgOid::BuiltIn(PgBuiltInOids::OIDOID), oid.into_datum()),
+            ],
+        )
+        .expect("failed to look up the dependencies of the index");
+        if recorded != Some(true) {
+            let depender = pg_sys::ObjectAddress {
+                classId: pg_sys::RelationRelationId,
+                objectId: index.oid(),
+                objectSubId: 0,
+            };
+            let referenced = pg_sys::ObjectAddress {
+                classId: pg_sys::ProcedureRelationId,
+                objectId: oid,
+                objectSubId: 0,
+            };
+            pg_sys::recordDependencyOn(
+                &depender,
+                &referenced,
+                pg_sys::DependencyType_DEPENDENCY_NORMAL,
+            );
+        }
+        oid
+    }
+
     /// Write out a new meta page.
     /// Has to be done as the first write to a new relation.
     pub unsafe fn create(
@@ -242,31 +571,62 @@ impl MetaPage {
         } else {
             (*opt).num_dimensions
         };
+        let heap = index
+            .heap_relation()
+            .expect("diskann index has no heap relation");
+        let storage_type =
+            Self::resolve_storage_type(&heap, num_dimensions, &(*opt).get_storage_layout());
 
-        let bq_num_bits_per_dimension =
-            if (*opt).bq_num_bits_per_dimension == SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL {
-                if (*opt).get_storage_type() == StorageType::SbqCompression
-                    && num_dimensions_to_index < 900
-                {
-                    2
-                } else {
-                    1
-                }
-            } else {
-                (*opt).bq_num_bits_per_dimension as u8
-            };
+        let bq_num_bits_per_dimension = if (*opt).get_bq_num_bits_per_dimension()
+            == SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL
+        {
+            Self::default_bq_num_bits_per_dimension(num_dimensions_to_index, storage_type)
+        } else {
+            (*opt).get_bq_num_bits_per_dimension() as u8
+        };
 
         if bq_num_bits_per_dimension > 1 && num_dimensions_to_index > 930 {
             //limited by SbqMeans fitting on a page
             pgrx::error!("SBQ with more than 1 bit per dimension is not supported for more than 900 dimensions");
         }
-        if bq_num_bits_per_dimension > 1 && (*opt).get_storage_type() != StorageType::SbqCompression
-        {
+        if bq_num_bits_per_dimension > 1 && storage_type != StorageType::SbqCompression {
             pgrx::error!(
                 "SBQ with more than 1 bit per dimension is only supported with the memory_optimized storage layout"
             );
         }
 
+        if (*opt).get_stored_vector_type() != StoredVectorType::F32
+            && storage_type != StorageType::Plain
+        {
+            pgrx::error!(
+                "stored_vector_type is only supported with the plain storage layout, the other layouts don't store full vectors"
+            );
+        }
+        if (*opt).exact_traversal && storage_type == StorageType::Plain {
+            pgrx::error!(
+                "exact_traversal is only supported with the memory_optimized and io_optimized storage layouts, plain searches already use full vectors"
+            );
+        }
+        if (*opt).quantizer_drift_threshold > 0.0 && storage_type == StorageType::Plain {
+            pgrx::error!(
+                "quantizer_drift_threshold is only supported with the memory_optimized and io_optimized storage layouts, plain indexes have no quantizer"
+            );
+        }
+
+        let num_neighbors = Self::calculate_num_neighbors(
+            num_dimensions,
+            bq_num_bits_per_dimension,
+            storage_type,
+            &opt,
+        );
+        if (*opt).build_neighbor_slack >= num_neighbors {
+            pgrx::error!(
+                "build_neighbor_slack ({}) must be less than num_neighbors ({})",
+                (*opt).build_neighbor_slack,
+                num_neighbors
+            );
+        }
+
         let meta = MetaPage {
             magic_number: TSV_MAGIC_NUMBER,
             version: TSV_VERSION,
@@ -274,17 +634,30 @@ impl MetaPage {
             distance_type: DistanceType::Cosine as u16,
             num_dimensions,
             num_dimensions_to_index,
-            storage_type: (*opt).get_storage_type() as u8,
-            num_neighbors: Self::calculate_num_neighbors(
-                num_dimensions,
-                bq_num_bits_per_dimension,
-                &opt,
-            ),
+            storage_type: storage_type as u8,
+            num_neighbors,
             bq_num_bits_per_dimension,
             search_list_size: (*opt).search_list_size,
             max_alpha: (*opt).max_alpha,
             init_ids: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
             quantizer_metadata: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            neighbor_selection: (*opt).get_neighbor_selection() as u8,
+            index_type: (*opt).get_index_type() as u8,
+            stored_vector_type: (*opt).get_stored_vector_type() as u8,
+            on_invalid: (*opt).get_on_invalid() as u8,
+            enforce_bidirectional: (*opt).enforce_bidirectional,
+            locality_layout: (*opt).locality_layout,
+            decrypt_function: match (*opt).get_decrypt_function() {
+                Some(name) => Self::resolve_decrypt_function(index, &name).as_u32(),
+                None => pg_sys::InvalidOid.as_u32(),
+            },
+            build_neighbor_slack: (*opt).build_neighbor_slack,
+            compensated_sum: (*opt).compensated_sum,
+            exact_traversal: (*opt).exact_traversal,
+            quantizer_drift_threshold: (*opt).quantizer_drift_threshold,
+            intrinsic_dimensionality: 0.0,
+            collapse_duplicate_vectors: (*opt).collapse_duplicate_vectors,
+            build_candidate_sample_rate: (*opt).build_candidate_sample_rate,
         };
         let page = page::WritablePage::new(index, crate::util::page::PageType::Meta);
         meta.write_to_page(page);
@@ -344,25 +717,65 @@ impl MetaPage {
                 //release the page
                 std::mem::drop(page);
 
-                Self::overwrite(index, &new_meta);
+                /* the upgrade can't be written on a hot standby, where searches keep
+                converting the old meta page in memory until the primary writes it */
+                if !pg_sys::RecoveryInProgress() {
+                    Self::overwrite(index, &new_meta);
+                }
                 return new_meta;
             }
             Self::get_meta_from_page(page)
         }
     }
 
+    /// Read the on-disk format version of an index without upgrading it
+    pub fn fetch_format_version(index: &PgRelation) -> u32 {
+        unsafe {
+            let page = page::ReadablePage::read(index, META_BLOCK_NUMBER);
+            if page.get_type() == crate::util::page::PageType::MetaV1 {
+                return 1;
+            }
+            let rb = page.get_item_unchecked(META_HEADER_OFFSET);
+            let header = ReadableMetaPageHeader::with_readable_buffer(rb);
+            let archived = header.get_archived_node();
+            assert!(archived.magic_number == TSV_MAGIC_NUMBER);
+            archived.version
+        }
+    }
+
+    /// Read the LSN of the last write of the meta page. Every build writes a new meta page, so
+    /// it tells the builds of an index apart, except for unlogged indexes, whose LSN stays 0.
+    pub fn fetch_lsn(index: &PgRelation) -> pg_sys::XLogRecPtr {
+        unsafe {
+            let page = page::ReadablePage::read(index, META_BLOCK_NUMBER);
+            let lsn = (*(*page as *const pg_sys::PageHeaderData)).pd_lsn;
+            ((lsn.xlogid as u64) << 32) | lsn.xrecoff as u64
+        }
+    }
+
     unsafe fn get_meta_from_page(page: page::ReadablePage) -> MetaPage {
-        //check the header. In the future, we can use this to check the version
+        //check the header to find out which version of the meta page follows it
         let rb = page.get_item_unchecked(META_HEADER_OFFSET);
         let meta = ReadableMetaPageHeader::with_readable_buffer(rb);
         let archived = meta.get_archived_node();
         assert!(archived.magic_number == TSV_MAGIC_NUMBER);
-        assert!(archived.version == TSV_VERSION);
+        let version = archived.version;
 
         let page = meta.get_owned_page();
 
         //retrieve the MetaPage itself and deserialize it
         let rb = page.get_item_unchecked(META_OFFSET);
+        if version == 2 {
+            //old meta pages are upgraded in memory, the new format is written on the next meta page update
+            let meta = ReadableMetaPageV2::with_readable_buffer(rb);
+            let archived = meta.get_archived_node();
+            assert!(archived.magic_number == TSV_MAGIC_NUMBER);
+            assert!(archived.version == 2);
+            let old_meta: MetaPageV2 = archived.deserialize(&mut rkyv::Infallible).unwrap();
+            return old_meta.get_new_meta();
+        }
+        assert!(version == TSV_VERSION);
+
         let meta = ReadableMetaPage::with_readable_buffer(rb);
         let archived = meta.get_archived_node();
         assert!(archived.magic_number == TSV_MAGIC_NUMBER);
@@ -389,6 +802,51 @@ impl MetaPage {
         };
     }
 
+    /// Change the index type, when the graph of a deferred index gets built.
+    pub fn update_index_type<S: StatsNodeModify>(
+        index: &PgRelation,
+        index_type: IndexType,
+        stats: &mut S,
+    ) {
+        let mut meta = Self::fetch(index);
+        meta.index_type = index_type as u8;
+
+        unsafe {
+            Self::overwrite(index, &meta);
+            stats.record_modify();
+        };
+    }
+
+    /// Change num_neighbors, when the neighbor lists get pruned to fewer neighbors.
+    pub fn update_num_neighbors<S: StatsNodeModify>(
+        index: &PgRelation,
+        num_neighbors: u32,
+        stats: &mut S,
+    ) {
+        let mut meta = Self::fetch(index);
+        meta.num_neighbors = num_neighbors;
+
+        unsafe {
+            Self::overwrite(index, &meta);
+            stats.record_modify();
+        };
+    }
+
+    /// Record the intrinsic dimensionality estimated at the end of a build.
+    pub fn update_intrinsic_dimensionality<S: StatsNodeModify>(
+        index: &PgRelation,
+        intrinsic_dimensionality: f64,
+        stats: &mut S,
+    ) {
+        let mut meta = Self::fetch(index);
+        meta.intrinsic_dimensionality = intrinsic_dimensionality;
+
+        unsafe {
+            Self::overwrite(index, &meta);
+            stats.record_modify();
+        };
+    }
+
     pub fn update_quantizer_metadata_pointer<S: StatsNodeModify>(
         index: &PgRelation,
         quantizer_pointer: IndexPointer,
@@ -403,3 +861,387 @@ impl MetaPage {
         };
     }
 }
+
+/// Returns the version of the extension library currently loaded.
+#[pg_extern]
+pub fn tsv_version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Returns the on-disk format version of a diskann index.
+#[pg_extern]
+pub fn tsv_index_format_version(index: PgRelation) -> i32 {
+    check_is_diskann_index(&index);
+    MetaPage::fetch_format_version(&index) as i32
+}
+
+/// Returns the intrinsic dimensionality of the vectors of `index`, as estimated by the build
+/// of an index created with estimate_intrinsic_dimensionality = true: the number of dimensions
+/// the data actually varies in, as opposed to the number of dimensions of the column. Data
+/// with a high intrinsic dimensionality is hard to search approximately and needs larger
+/// search lists for the same recall. NULL if the build didn't estimate it.
+#[pg_extern]
+pub fn tsv_intrinsic_dimensionality(index: PgRelation) -> Option<f64> {
+    check_is_diskann_index(&index);
+    MetaPage::fetch(&index).get_intrinsic_dimensionality()
+}
+
+/// Returns the name and attribute number of the table column that `index` covers.
+#[pg_extern]
+pub fn tsv_index_column(
+    index: PgRelation,
+) -> TableIterator<'static, (name!(column_name, String), name!(attnum, i16))> {
+    check_is_diskann_index(&index);
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let attnum = get_attribute_number_from_index(&index);
+    if attnum == 0 {
+        error!(
+            "diskann index \"{}\" covers an expression, not a column",
+            index.name()
+        );
+    }
+    let column_name = unsafe { cstr_to_string(pg_sys::get_attname(heap.oid(), attnum, false)) };
+    TableIterator::once((column_name, attnum))
+}
+
+unsafe fn cstr_to_string(s: *const std::os::raw::c_char) -> String {
+    std::ffi::CStr::from_ptr(s).to_string_lossy().into_owned()
+}
+
+/// Returns the `CREATE INDEX` statement that builds an index like `index`, with every option
+/// that the index was built with as persisted in its meta page, options that were left to
+/// their defaults included. Defaults resolved at build time (storage_layout = auto,
+/// num_neighbors, num_bits_per_dimension) appear as the values they resolved to, so that
+/// the statement builds the same kind of index on another table or server.
+#[pg_extern]
+pub fn tsv_index_ddl(index: PgRelation) -> String {
+    check_is_diskann_index(&index);
+    let meta_page = MetaPage::fetch(&index);
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let storage_type = meta_page.get_storage_type();
+
+    let mut options = vec![
+        format!("storage_layout = '{}'", storage_type.as_str()),
+        format!("num_neighbors = {}", meta_page.num_neighbors),
+        format!("search_list_size = {}", meta_page.search_list_size),
+        format!("max_alpha = {}", meta_page.max_alpha),
+    ];
+    if meta_page.num_dimensions_to_index != meta_page.num_dimensions {
+        options.push(format!(
+            "num_dimensions = {}",
+            meta_page.num_dimensions_to_index
+        ));
+    }
+    if storage_type != StorageType::Plain {
+        options.push(format!(
+            "num_bits_per_dimension = {}",
+            meta_page.bq_num_bits_per_dimension
+        ));
+    }
+    options.push(format!(
+        "neighbor_selection = '{}'",
+        meta_page.get_neighbor_selection().as_str()
+    ));
+    options.push(format!(
+        "index_type = '{}'",
+        meta_page.get_index_type().as_str()
+    ));
+    if storage_type == StorageType::Plain {
+        options.push(format!(
+            "stored_vector_type = '{}'",
+            meta_page.get_stored_vector_type().as_str()
+        ));
+    }
+    options.push(format!(
+        "on_invalid = '{}'",
+        meta_page.get_on_invalid().as_str()
+    ));
+    options.push(format!(
+        "enforce_bidirectional = {}",
+        meta_page.enforce_bidirectional
+    ));
+    options.push(format!("locality_layout = {}", meta_page.locality_layout));
+    if let Some(decrypt_function) = meta_page.get_decrypt_function() {
+        options.push(format!("decrypt_function = {}", unsafe {
+            cstr_to_string(pg_sys::quote_literal_cstr(
+                pg_sys::format_procedure_qualified(decrypt_function),
+            ))
+        }));
+    }
+    options.push(format!(
+        "build_neighbor_slack = {}",
+        meta_page.build_neighbor_slack
+    ));
+    options.push(format!("compensated_sum = {}", meta_page.compensated_sum));
+    options.push(format!("exact_traversal = {}", meta_page.exact_traversal));
+    options.push(format!(
+        "collapse_duplicate_vectors = {}",
+        meta_page.collapse_duplicate_vectors
+    ));
+    options.push(format!(
+        "build_candidate_sample_rate = {}",
+        meta_page.build_candidate_sample_rate
+    ));
+    if storage_type != StorageType::Plain {
+        options.push(format!(
+            "quantizer_drift_threshold = {}",
+            meta_page.quantizer_drift_threshold
+        ));
+    }
+    if meta_page.get_intrinsic_dimensionality().is_some() {
+        options.push("estimate_intrinsic_dimensionality = true".to_string());
+    }
+
+    unsafe {
+        let index_name = cstr_to_string(pg_sys::quote_identifier(index.name().as_pg_cstr()));
+        let table_name = cstr_to_string(pg_sys::quote_qualified_identifier(
+            heap.namespace().as_pg_cstr(),
+            heap.name().as_pg_cstr(),
+        ));
+        let column = cstr_to_string(pg_sys::pg_get_indexdef_columns(index.oid(), false));
+
+        let mut ddl = format!(
+            "CREATE INDEX {} ON {} USING diskann ({}) WITH ({})",
+            index_name,
+            table_name,
+            column,
+            options.join(", ")
+        );
+
+        let predicate = pg_sys::RelationGetIndexPredicate(index.as_ptr());
+        if !predicate.is_null() {
+            let context = pg_sys::deparse_context_for(heap.name().as_pg_cstr(), heap.oid());
+            let predicate = pg_sys::deparse_expression(
+                pg_sys::make_ands_explicit(predicate) as *mut pg_sys::Node,
+                context,
+                false,
+                false,
+            );
+            ddl.push_str(&format!(" WHERE {}", cstr_to_string(predicate)));
+        }
+        ddl
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use crate::access_method::storage::StorageType;
+
+    #[pg_test]
+    unsafe fn test_tsv_version_functions() -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+            ",
+        ))?;
+
+        let version: Option<String> = Spi::get_one("SELECT tsv_version()")?;
+        assert_eq!(version.unwrap(), env!("CARGO_PKG_VERSION"));
+
+        let format_version: Option<i32> =
+            Spi::get_one("SELECT tsv_index_format_version('idxtest'::regclass)")?;
+        assert_eq!(format_version.unwrap(), super::TSV_VERSION as i32);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_index_column() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, title vector(3), body vector(3));
+            ALTER TABLE test DROP COLUMN id;
+            CREATE INDEX idx_title ON test USING diskann(title);
+            CREATE INDEX idx_body ON test USING diskann(body);",
+        )?;
+
+        let (name, attnum) = Spi::get_two::<String, i16>(
+            "SELECT column_name, attnum FROM tsv_index_column('idx_title'::regclass)",
+        )?;
+        assert_eq!((name.unwrap().as_str(), attnum.unwrap()), ("title", 2));
+
+        let (name, attnum) = Spi::get_two::<String, i16>(
+            "SELECT column_name, attnum FROM tsv_index_column('idx_body'::regclass)",
+        )?;
+        assert_eq!((name.unwrap().as_str(), attnum.unwrap()), ("body", 3));
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_index_ddl() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+
+            INSERT INTO test(id, embedding) VALUES (1, '[1,2,3]'), (2, '[4,5,6]'), (3, '[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH (storage_layout = plain, num_neighbors = 20, max_alpha = 1.4,
+                      stored_vector_type = bf16, locality_layout = true)
+               WHERE id > 1;
+            ",
+        )?;
+
+        let ddl = Spi::get_one::<String>("SELECT tsv_index_ddl('idxtest'::regclass)")?.unwrap();
+        assert_eq!(
+            ddl,
+            "CREATE INDEX idxtest ON public.test USING diskann (embedding) WITH (\
+            storage_layout = 'plain', num_neighbors = 20, search_list_size = 100, max_alpha = 1.4, \
+            neighbor_selection = 'rng', index_type = 'graph', stored_vector_type = 'bf16', \
+            on_invalid = 'error', enforce_bidirectional = false, locality_layout = true, \
+            build_neighbor_slack = 0, compensated_sum = false, exact_traversal = false, \
+            collapse_duplicate_vectors = false, build_candidate_sample_rate = 1) \
+            WHERE (id > 1)"
+        );
+
+        /* the statement rebuilds the same index */
+        Spi::run(&format!("DROP INDEX idxtest; {};", ddl))?;
+        assert_eq!(
+            Spi::get_one::<String>("SELECT tsv_index_ddl('idxtest'::regclass)")?.unwrap(),
+            ddl
+        );
+
+        /* defaults resolved at build time are spelled out */
+        Spi::run(
+            "DROP INDEX idxtest;
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH (storage_layout = memory_optimized);",
+        )?;
+        let ddl = Spi::get_one::<String>("SELECT tsv_index_ddl('idxtest'::regclass)")?.unwrap();
+        assert!(
+            ddl.contains("storage_layout = 'memory_optimized', num_neighbors = 50")
+                && ddl.contains("num_bits_per_dimension = 2")
+                && !ddl.contains("stored_vector_type")
+                && !ddl.contains("WHERE"),
+            "{}",
+            ddl
+        );
+
+        Ok(())
+    }
+
+    #[pg_test(error = "\"test\" is not a diskann index")]
+    unsafe fn test_tsv_index_format_version_not_diskann() -> spi::Result<()> {
+        Spi::run("CREATE TABLE test(embedding vector(3));")?;
+        Spi::run("SELECT tsv_index_format_version('test'::regclass)")?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "SBQ means have 3 dimensions but the meta page has 2 dimensions to index, the index is corrupted and must be rebuilt"
+    )]
+    unsafe fn test_quantizer_dimension_mismatch() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);",
+        )?;
+
+        /* corrupt the number of dimensions stored in the meta page */
+        let index = PgRelation::open_with_name_and_share_lock("idxtest").unwrap();
+        let mut meta = super::MetaPage::fetch(&index);
+        meta.num_dimensions_to_index = 2;
+        super::MetaPage::overwrite(&index, &meta);
+        drop(index);
+
+        Spi::run(
+            "SET enable_seqscan = 0;
+            SELECT * FROM test ORDER BY embedding <=> '[1,1,1]' LIMIT 1;",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "the SBQ code of a node has 1 words but the quantizer expects 2, the index is corrupted and must be rebuilt"
+    )]
+    unsafe fn test_corrupt_sbq_code() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(32));
+
+            INSERT INTO test(embedding) SELECT array_fill(i, ARRAY[32])::vector FROM generate_series(1, 10) i;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH (storage_layout = memory_optimized, num_bits_per_dimension = 1);",
+        )?;
+
+        /* queries are quantized to 4 bits per dimension, the nodes hold 1 bit codes */
+        let index = PgRelation::open_with_name_and_share_lock("idxtest").unwrap();
+        let mut meta = super::MetaPage::fetch(&index);
+        meta.bq_num_bits_per_dimension = 4;
+        super::MetaPage::overwrite(&index, &meta);
+        drop(index);
+
+        /* leniently, the nodes are infinitely far but the rows are still returned */
+        Spi::run(
+            "SET enable_seqscan = 0;
+            SET diskann.on_corrupt_code = max_distance;",
+        )?;
+        let count = Spi::get_one::<i64>(
+            "SELECT count(*) FROM (SELECT * FROM test ORDER BY embedding <=> array_fill(1, ARRAY[32])::vector LIMIT 5) t;",
+        )?;
+        assert_eq!(count, Some(5));
+
+        Spi::run(
+            "RESET diskann.on_corrupt_code;
+            SELECT * FROM test ORDER BY embedding <=> array_fill(1, ARRAY[32])::vector LIMIT 1;",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_auto_storage_layout() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) SELECT ARRAY[i, i + 1, i + 2]::vector FROM generate_series(1, 300) i;
+            ANALYZE test;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH (storage_layout = auto);",
+        )?;
+        let storage_type = || {
+            let index = PgRelation::open_with_name_and_share_lock("idxtest").unwrap();
+            super::MetaPage::fetch(&index).get_storage_type()
+        };
+        assert_eq!(storage_type(), StorageType::Plain);
+
+        Spi::run(
+            "SET diskann.auto_storage_min_rows = 100;
+            REINDEX INDEX idxtest;",
+        )?;
+        assert_eq!(storage_type(), StorageType::SbqCompression);
+
+        Spi::run(
+            "RESET diskann.auto_storage_min_rows;
+            SET diskann.auto_storage_min_dimensions = 3;
+            REINDEX INDEX idxtest;",
+        )?;
+        assert_eq!(storage_type(), StorageType::SbqCompression);
+
+        Spi::run(
+            "SET enable_seqscan = 0;
+            SELECT * FROM test ORDER BY embedding <=> '[1,1,1]' LIMIT 1;",
+        )?;
+        Ok(())
+    }
+}
@@ -2,19 +2,25 @@ use pgrx::*;
 mod build;
 mod cost_estimate;
 mod debugging;
+mod drift;
 mod graph;
 mod graph_neighbor_store;
 pub mod guc;
+mod maxsim;
 mod meta_page;
 mod neighbor_with_distance;
+mod node_cache;
 pub mod options;
 pub mod pg_vector;
 mod plain_node;
 mod plain_storage;
+mod rebalance;
 mod scan;
+mod search;
 pub mod stats;
 mod storage;
 mod storage_common;
+mod trace;
 mod upgrade_test;
 mod vacuum;
 
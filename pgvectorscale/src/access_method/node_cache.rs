@@ -0,0 +1,221 @@
+//! A per-backend cache of the quantized vectors of nodes, shared by the searches of a session.
+//!
+//! The memory_optimized layout doesn't keep the vectors of the neighbors in the node, so a
+//! search reads every neighbor it compares the query to. When the queries of a session land
+//! in the same region of the graph, they read the same nodes over and over. The cache keeps
+//! the most recently used quantized vectors in backend memory instead, up to
+//! `diskann.node_cache_size` of them.
+//!
+//! The quantized vector of a node never changes once written, but the relfilenode of an
+//! index can be reused by another index after a DROP. Entries are keyed by the relfilenode
+//! and by the LSN of the meta page, which every build and every update of the meta page
+//! writes anew, so the entries of a dropped, rebuilt or modified index are never found again
+//! and age out of the cache. Unlogged indexes have no LSN to tell their builds apart and
+//! aren't cached. Neither are indexes whose build wasn't WAL-logged under wal_level =
+//! minimal, until a logged write of their meta page gives it an LSN.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use pgrx::*;
+
+use crate::util::IndexPointer;
+
+type CacheKey = (pg_sys::Oid, pg_sys::XLogRecPtr, IndexPointer);
+
+struct NodeCache {
+    /// The vector of each cached node and the tick of its last use.
+    entries: HashMap<CacheKey, (Vec<u64>, u64)>,
+    /// The cached nodes by tick of last use, least recently used first.
+    by_last_use: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl NodeCache {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_last_use: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, key: CacheKey) -> Option<Vec<u64>> {
+        self.tick += 1;
+        match self.entries.get_mut(&key) {
+            Some((vector, last_use)) => {
+                self.by_last_use.remove(last_use);
+                *last_use = self.tick;
+                self.by_last_use.insert(self.tick, key);
+                self.hits += 1;
+                Some(vector.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: CacheKey, vector: Vec<u64>, capacity: usize) {
+        self.tick += 1;
+        if let Some((_, last_use)) = self.entries.insert(key, (vector, self.tick)) {
+            self.by_last_use.remove(&last_use);
+        }
+        self.by_last_use.insert(self.tick, key);
+
+        while self.entries.len() > capacity {
+            let (_, evicted) = self.by_last_use.pop_first().unwrap();
+            self.entries.remove(&evicted);
+        }
+    }
+}
+
+thread_local! {
+    static NODE_CACHE: RefCell<NodeCache> = RefCell::new(NodeCache::new());
+}
+
+fn capacity() -> usize {
+    super::guc::TSV_NODE_CACHE_SIZE.get() as usize
+}
+
+/// Whether diskann.node_cache_size enables the cache.
+pub fn is_enabled() -> bool {
+    capacity() > 0
+}
+
+/// The LSN of the meta page of `index` to key its entries with, None if the cache is off or
+/// the meta page has no LSN: the index is unlogged, or was built without WAL under
+/// wal_level = minimal. The cache is then off for the index.
+pub fn meta_lsn(index: &PgRelation) -> Option<pg_sys::XLogRecPtr> {
+    if !is_enabled() {
+        return None;
+    }
+    match super::meta_page::MetaPage::fetch_lsn(index) {
+        0 => None,
+        lsn => Some(lsn),
+    }
+}
+
+/// Returns the quantized vector of the node at `index_pointer` from the cache, or calls `read`
+/// to get it from the index and caches it. `meta_lsn` is the LSN of the meta page of the
+/// index, see MetaPage::fetch_lsn().
+pub fn get_or_read<F: FnOnce() -> Vec<u64>>(
+    index: &PgRelation,
+    meta_lsn: pg_sys::XLogRecPtr,
+    index_pointer: IndexPointer,
+    read: F,
+) -> Vec<u64> {
+    let key = (
+        unsafe { (*index.rd_rel).relfilenode },
+        meta_lsn,
+        index_pointer,
+    );
+    if let Some(vector) = NODE_CACHE.with(|cache| cache.borrow_mut().get(key)) {
+        return vector;
+    }
+
+    let vector = read();
+    NODE_CACHE.with(|cache| cache.borrow_mut().insert(key, vector.clone(), capacity()));
+    vector
+}
+
+/// Returns the number of nodes in the node cache of this session, and the number of lookups
+/// that found their node in it (hits) or had to read it from the index (misses) since the
+/// session started or the last tsv_node_cache_reset().
+#[pg_extern]
+pub fn tsv_node_cache_stats(
+) -> TableIterator<'static, (name!(entries, i64), name!(hits, i64), name!(misses, i64))> {
+    let row = NODE_CACHE.with(|cache| {
+        let cache = cache.borrow();
+        (
+            cache.entries.len() as i64,
+            cache.hits as i64,
+            cache.misses as i64,
+        )
+    });
+    TableIterator::once(row)
+}
+
+/// Empties the node cache of this session and zeroes its counters.
+#[pg_extern]
+pub fn tsv_node_cache_reset() {
+    NODE_CACHE.with(|cache| *cache.borrow_mut() = NodeCache::new());
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_node_cache() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 300)?;
+        Spi::run(
+            "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = memory_optimized, num_neighbors = 50);
+
+            SET enable_seqscan = 0;",
+        )?;
+
+        let query = "SELECT array_agg(ctid::text) FROM (SELECT ctid FROM test_data ORDER BY embedding <=> (SELECT embedding FROM test_data WHERE ctid = '(0,1)') LIMIT 10) t";
+        let uncached = Spi::get_one::<Vec<String>>(query)?;
+        let (entries, hits, misses) = Spi::get_three::<i64, i64, i64>(
+            "SELECT entries, hits, misses FROM tsv_node_cache_stats()",
+        )?;
+        assert_eq!((entries, hits, misses), (Some(0), Some(0), Some(0)));
+
+        /* the same query twice: the second one finds all its nodes in the cache */
+        Spi::run("SET diskann.node_cache_size = 100000;")?;
+        assert_eq!(Spi::get_one::<Vec<String>>(query)?, uncached);
+        let (entries, misses) =
+            Spi::get_two::<i64, i64>("SELECT entries, misses FROM tsv_node_cache_stats()")?;
+        let (entries, misses) = (entries.unwrap(), misses.unwrap());
+        assert!(entries > 0 && entries == misses, "{} {}", entries, misses);
+
+        assert_eq!(Spi::get_one::<Vec<String>>(query)?, uncached);
+        let (hits, misses_after) =
+            Spi::get_two::<i64, i64>("SELECT hits, misses FROM tsv_node_cache_stats()")?;
+        assert_eq!(misses_after.unwrap(), misses);
+        assert!(hits.unwrap() > 0);
+
+        /* the cache stays within its size */
+        Spi::run("SELECT tsv_node_cache_reset(); SET diskann.node_cache_size = 5;")?;
+        assert_eq!(Spi::get_one::<Vec<String>>(query)?, uncached);
+        let entries = Spi::get_one::<i64>("SELECT entries FROM tsv_node_cache_stats()")?;
+        assert_eq!(entries.unwrap(), 5);
+
+        /* an index modified in place, in the same relfilenode, doesn't see the entries from
+         * before the modification */
+        Spi::run(
+            "SELECT tsv_node_cache_reset();
+            SET diskann.node_cache_size = 100000;",
+        )?;
+        let filenode = "SELECT pg_relation_filenode('idx_diskann')::bigint";
+        let filenode_before = Spi::get_one::<i64>(filenode)?;
+        Spi::get_one::<Vec<String>>(query)?;
+        Spi::run("SELECT tsv_reduce_num_neighbors('idx_diskann'::regclass, 40);")?;
+        assert_eq!(Spi::get_one::<i64>(filenode)?, filenode_before);
+        Spi::get_one::<Vec<String>>(query)?;
+        let (hits, misses) =
+            Spi::get_two::<i64, i64>("SELECT hits, misses FROM tsv_node_cache_stats()")?;
+        assert_eq!(hits.unwrap(), 0);
+        assert!(misses.unwrap() > 0);
+
+        /* unlogged indexes aren't cached */
+        Spi::run(
+            "SELECT tsv_node_cache_reset();
+            ALTER TABLE test_data SET UNLOGGED;
+            REINDEX INDEX idx_diskann;",
+        )?;
+        assert_eq!(Spi::get_one::<Vec<String>>(query)?, uncached);
+        let entries = Spi::get_one::<i64>("SELECT entries FROM tsv_node_cache_stats()")?;
+        assert_eq!(entries.unwrap(), 0);
+
+        Ok(())
+    }
+}
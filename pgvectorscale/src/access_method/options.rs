@@ -1,8 +1,11 @@
 use crate::access_method::void_mut_ptr;
 use memoffset::*;
-use pgrx::{pg_sys::AsPgCStr, prelude::*, set_varsize, void_ptr, PgRelation};
+use pgrx::{direct_function_call, pg_sys::AsPgCStr, prelude::*, set_varsize, void_ptr, PgRelation};
 use std::{ffi::CStr, fmt::Debug};
 
+use super::graph::{IndexType, NeighborSelection};
+use super::pg_vector::OnInvalid;
+use super::plain_node::StoredVectorType;
 use super::storage::StorageType;
 
 //DO NOT derive Clone for this struct. The storage layout string comes at the end and wouldn't be copied properly.
@@ -19,12 +22,27 @@ pub struct TSVIndexOptions {
     pub num_dimensions: u32,
     pub max_alpha: f64,
     pub bq_num_bits_per_dimension: u32,
+    pub neighbor_selection_offset: i32,
+    pub index_type_offset: i32,
+    pub stored_vector_type_offset: i32,
+    pub on_invalid_offset: i32,
+    pub decrypt_function_offset: i32,
+    pub build_neighbor_slack: u32,
+    pub enforce_bidirectional: bool,
+    pub locality_layout: bool,
+    pub compensated_sum: bool,
+    pub exact_traversal: bool,
+    pub quantizer_drift_threshold: f64,
+    pub estimate_intrinsic_dimensionality: bool,
+    pub collapse_duplicate_vectors: bool,
+    pub build_candidate_sample_rate: f64,
 }
 
 pub const NUM_NEIGHBORS_DEFAULT_SENTINEL: i32 = -1;
 pub const NUM_DIMENSIONS_DEFAULT_SENTINEL: u32 = 0;
 pub const SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL: u32 = 0;
 const DEFAULT_MAX_ALPHA: f64 = 1.2;
+const DEFAULT_BUILD_CANDIDATE_SAMPLE_RATE: f64 = 1.0;
 
 impl TSVIndexOptions {
     //note: this should only be used when building a new index. The options aren't really versioned.
@@ -41,6 +59,20 @@ impl TSVIndexOptions {
             ops.max_alpha = DEFAULT_MAX_ALPHA;
             ops.num_dimensions = NUM_DIMENSIONS_DEFAULT_SENTINEL;
             ops.bq_num_bits_per_dimension = SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL;
+            ops.neighbor_selection_offset = 0;
+            ops.index_type_offset = 0;
+            ops.stored_vector_type_offset = 0;
+            ops.on_invalid_offset = 0;
+            ops.decrypt_function_offset = 0;
+            ops.build_neighbor_slack = 0;
+            ops.enforce_bidirectional = false;
+            ops.locality_layout = false;
+            ops.compensated_sum = false;
+            ops.exact_traversal = false;
+            ops.quantizer_drift_threshold = 0.0;
+            ops.estimate_intrinsic_dimensionality = false;
+            ops.collapse_duplicate_vectors = false;
+            ops.build_candidate_sample_rate = DEFAULT_BUILD_CANDIDATE_SAMPLE_RATE;
             unsafe {
                 set_varsize(
                     ops.as_ptr().cast(),
@@ -53,25 +85,90 @@ impl TSVIndexOptions {
         }
     }
 
+    /// The num_neighbors option, or the diskann.default_num_neighbors GUC if it isn't given.
     pub fn get_num_neighbors(&self) -> i32 {
-        if self.num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
+        let num_neighbors = if self.num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
+            super::guc::default_num_neighbors()
+        } else {
+            self.num_neighbors
+        };
+        if num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
             //specify to use the default value here
             //we can't derive the default at this point in the code because the default is based on the number of dimensions in the vector in the io_optimized case.
             NUM_NEIGHBORS_DEFAULT_SENTINEL
         } else {
-            if self.num_neighbors < 10 {
+            if num_neighbors < 10 {
                 panic!("num_neighbors must be greater than 10, or -1 for default")
             }
-            self.num_neighbors
+            num_neighbors
+        }
+    }
+
+    /// The num_bits_per_dimension option, or the diskann.default_num_bits_per_dimension GUC if
+    /// it isn't given.
+    pub fn get_bq_num_bits_per_dimension(&self) -> u32 {
+        if self.bq_num_bits_per_dimension == SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL {
+            super::guc::TSV_DEFAULT_NUM_BITS_PER_DIMENSION.get() as u32
+        } else {
+            self.bq_num_bits_per_dimension
         }
     }
 
+    /// The storage_layout option as given, which can be `auto`, or the
+    /// diskann.default_storage_layout GUC if it isn't given.
+    pub fn get_storage_layout(&self) -> String {
+        self.get_str(self.storage_layout_offset, || {
+            super::guc::TSV_DEFAULT_STORAGE_LAYOUT
+                .get()
+                .as_str()
+                .to_owned()
+        })
+    }
+
     pub fn get_storage_type(&self) -> StorageType {
-        let s = self.get_str(self.storage_layout_offset, || {
-            super::storage::DEFAULT_STORAGE_TYPE_STR.to_owned()
+        StorageType::from_str(self.get_storage_layout().as_str())
+    }
+
+    pub fn get_neighbor_selection(&self) -> NeighborSelection {
+        let s = self.get_str(self.neighbor_selection_offset, || {
+            super::graph::DEFAULT_NEIGHBOR_SELECTION_STR.to_owned()
+        });
+
+        NeighborSelection::from_str(s.as_str())
+    }
+
+    pub fn get_index_type(&self) -> IndexType {
+        let s = self.get_str(self.index_type_offset, || {
+            super::graph::DEFAULT_INDEX_TYPE_STR.to_owned()
+        });
+
+        IndexType::from_str(s.as_str())
+    }
+
+    pub fn get_stored_vector_type(&self) -> StoredVectorType {
+        let s = self.get_str(self.stored_vector_type_offset, || {
+            super::plain_node::DEFAULT_STORED_VECTOR_TYPE_STR.to_owned()
         });
 
-        StorageType::from_str(s.as_str())
+        StoredVectorType::from_str(s.as_str())
+    }
+
+    pub fn get_on_invalid(&self) -> OnInvalid {
+        let s = self.get_str(self.on_invalid_offset, || {
+            super::pg_vector::DEFAULT_ON_INVALID_STR.to_owned()
+        });
+
+        OnInvalid::from_str(s.as_str())
+    }
+
+    /// The function the heap vectors go through before being indexed, if any.
+    pub fn get_decrypt_function(&self) -> Option<String> {
+        let s = self.get_str(self.decrypt_function_offset, String::new);
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
     }
 
     fn get_str<F: FnOnce() -> String>(&self, offset: i32, default: F) -> String {
@@ -87,7 +184,7 @@ impl TSVIndexOptions {
     }
 }
 
-const NUM_REL_OPTS: usize = 6;
+const NUM_REL_OPTS: usize = 20;
 static mut RELOPT_KIND_TSV: pg_sys::relopt_kind = 0;
 
 // amoptions is a function that gets a datum of text[] data from pg_class.reloptions (which contains text in the format "key=value") and returns a bytea for the struct for the parsed options.
@@ -137,6 +234,76 @@ pub unsafe extern "C" fn amoptions(
             opttype: pg_sys::relopt_type_RELOPT_TYPE_REAL,
             offset: offset_of!(TSVIndexOptions, max_alpha) as i32,
         },
+        pg_sys::relopt_parse_elt {
+            optname: "neighbor_selection".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(TSVIndexOptions, neighbor_selection_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "index_type".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(TSVIndexOptions, index_type_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "stored_vector_type".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(TSVIndexOptions, stored_vector_type_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "on_invalid".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(TSVIndexOptions, on_invalid_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "enforce_bidirectional".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, enforce_bidirectional) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "locality_layout".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, locality_layout) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "decrypt_function".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(TSVIndexOptions, decrypt_function_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "build_neighbor_slack".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, build_neighbor_slack) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "compensated_sum".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, compensated_sum) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "exact_traversal".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, exact_traversal) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "quantizer_drift_threshold".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_REAL,
+            offset: offset_of!(TSVIndexOptions, quantizer_drift_threshold) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "estimate_intrinsic_dimensionality".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, estimate_intrinsic_dimensionality) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "collapse_duplicate_vectors".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, collapse_duplicate_vectors) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "build_candidate_sample_rate".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_REAL,
+            offset: offset_of!(TSVIndexOptions, build_candidate_sample_rate) as i32,
+        },
     ];
 
     build_relopts(reloptions, validate, tab)
@@ -194,6 +361,44 @@ unsafe fn build_relopts(
     rdopts as *mut pg_sys::bytea
 }
 
+/// Resolves the decrypt_function option, either a function name or a signature such as
+/// `decrypt(vector)`. The function has to take a single argument and be IMMUTABLE, since the
+/// index keeps what it computed from the results.
+pub unsafe fn lookup_decrypt_function(name: &str) -> pg_sys::Oid {
+    let input = if name.contains('(') {
+        pg_sys::regprocedurein
+    } else {
+        pg_sys::regprocin
+    };
+    let oid =
+        direct_function_call::<pg_sys::Oid>(input, &[Some(pg_sys::Datum::from(name.as_pg_cstr()))])
+            .expect("decrypt_function lookup returned null");
+
+    if pg_sys::get_func_nargs(oid) != 1 {
+        error!(
+            "decrypt_function {} must take a single argument and return the type of the indexed column",
+            name
+        );
+    }
+    if pg_sys::func_volatile(oid) as u8 != pg_sys::PROVOLATILE_IMMUTABLE {
+        error!("decrypt_function {} must be IMMUTABLE", name);
+    }
+    oid
+}
+
+#[pg_guard]
+extern "C" fn validate_decrypt_function(value: *const std::os::raw::c_char) {
+    if value.is_null() {
+        // use a default value
+        return;
+    }
+
+    let value = unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .expect("failed to parse decrypt_function value");
+    unsafe { lookup_decrypt_function(value) };
+}
+
 #[pg_guard]
 extern "C" fn validate_storage_layout(value: *const std::os::raw::c_char) {
     if value.is_null() {
@@ -204,7 +409,61 @@ extern "C" fn validate_storage_layout(value: *const std::os::raw::c_char) {
     let value = unsafe { CStr::from_ptr(value) }
         .to_str()
         .expect("failed to parse storage_layout value");
-    _ = StorageType::from_str(value);
+    if !value.eq_ignore_ascii_case(super::storage::AUTO_STORAGE_TYPE_STR) {
+        _ = StorageType::from_str(value);
+    }
+}
+
+#[pg_guard]
+extern "C" fn validate_neighbor_selection(value: *const std::os::raw::c_char) {
+    if value.is_null() {
+        // use a default value
+        return;
+    }
+
+    let value = unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .expect("failed to parse neighbor_selection value");
+    _ = NeighborSelection::from_str(value);
+}
+
+#[pg_guard]
+extern "C" fn validate_index_type(value: *const std::os::raw::c_char) {
+    if value.is_null() {
+        // use a default value
+        return;
+    }
+
+    let value = unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .expect("failed to parse index_type value");
+    _ = IndexType::from_str(value);
+}
+
+#[pg_guard]
+extern "C" fn validate_stored_vector_type(value: *const std::os::raw::c_char) {
+    if value.is_null() {
+        // use a default value
+        return;
+    }
+
+    let value = unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .expect("failed to parse stored_vector_type value");
+    _ = StoredVectorType::from_str(value);
+}
+
+#[pg_guard]
+extern "C" fn validate_on_invalid(value: *const std::os::raw::c_char) {
+    if value.is_null() {
+        // use a default value
+        return;
+    }
+
+    let value = unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .expect("failed to parse on_invalid value");
+    _ = OnInvalid::from_str(value);
 }
 
 pub unsafe fn init() {
@@ -214,7 +473,8 @@ pub unsafe fn init() {
         RELOPT_KIND_TSV,
         "storage_layout".as_pg_cstr(),
         "Storage layout: either memory_optimized, io_optimized, or plain".as_pg_cstr(),
-        super::storage::DEFAULT_STORAGE_TYPE_STR.as_pg_cstr(),
+        /* no default, so that an index without the option gets diskann.default_storage_layout */
+        std::ptr::null(),
         Some(validate_storage_layout),
         #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
         {
@@ -286,16 +546,196 @@ pub unsafe fn init() {
             pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
         },
     );
+
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_TSV,
+        "neighbor_selection".as_pg_cstr(),
+        "Neighbor selection heuristic used during build: either rng or topk".as_pg_cstr(),
+        super::graph::DEFAULT_NEIGHBOR_SELECTION_STR.as_pg_cstr(),
+        Some(validate_neighbor_selection),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_TSV,
+        "index_type".as_pg_cstr(),
+        "How the index is searched: graph, flat (no graph, compares the query to every vector) or deferred (flat until tsv_finalize() builds the graph)"
+            .as_pg_cstr(),
+        super::graph::DEFAULT_INDEX_TYPE_STR.as_pg_cstr(),
+        Some(validate_index_type),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_TSV,
+        "stored_vector_type".as_pg_cstr(),
+        "Element type of the vectors stored by the plain storage layout: either f32 or bf16"
+            .as_pg_cstr(),
+        super::plain_node::DEFAULT_STORED_VECTOR_TYPE_STR.as_pg_cstr(),
+        Some(validate_stored_vector_type),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_TSV,
+        "on_invalid".as_pg_cstr(),
+        "What to do with vectors that contain NaN or infinite values: either error or skip"
+            .as_pg_cstr(),
+        super::pg_vector::DEFAULT_ON_INVALID_STR.as_pg_cstr(),
+        Some(validate_on_invalid),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "enforce_bidirectional".as_pg_cstr(),
+        "Whether the build adds the reverse of every edge of the graph, space permitting"
+            .as_pg_cstr(),
+        false,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "locality_layout".as_pg_cstr(),
+        "Whether the build writes the nodes of similar vectors next to each other".as_pg_cstr(),
+        false,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_TSV,
+        "decrypt_function".as_pg_cstr(),
+        "Function applied to the vectors read from the table before they are indexed or compared, e.g. to decrypt them"
+            .as_pg_cstr(),
+        std::ptr::null(),
+        Some(validate_decrypt_function),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "build_neighbor_slack".as_pg_cstr(),
+        "The number of neighbor slots per node the build leaves free for the edges of later inserts"
+            .as_pg_cstr(),
+        0,
+        0,
+        1000,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "compensated_sum".as_pg_cstr(),
+        "Whether distances are summed with Kahan summation, for accuracy with many dimensions"
+            .as_pg_cstr(),
+        false,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "exact_traversal".as_pg_cstr(),
+        "Whether searches compute the exact distance to every node they visit from the table instead of using the quantized vectors"
+            .as_pg_cstr(),
+        false,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_real_reloption(
+        RELOPT_KIND_TSV,
+        "quantizer_drift_threshold".as_pg_cstr(),
+        "The drift of inserted vectors from the quantizer, in standard deviations, above which inserts suggest a REINDEX (0 to disable)"
+            .as_pg_cstr(),
+        0.0,
+        0.0,
+        1000.0,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "estimate_intrinsic_dimensionality".as_pg_cstr(),
+        "Whether the build estimates the intrinsic dimensionality of the vectors from the graph"
+            .as_pg_cstr(),
+        false,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "collapse_duplicate_vectors".as_pg_cstr(),
+        "Whether the build chains the rows with identical vectors behind a single node of the graph"
+            .as_pg_cstr(),
+        false,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+
+    pg_sys::add_real_reloption(
+        RELOPT_KIND_TSV,
+        "build_candidate_sample_rate".as_pg_cstr(),
+        "The fraction of the candidates found by the build search that neighbor selection evaluates"
+            .as_pg_cstr(),
+        DEFAULT_BUILD_CANDIDATE_SAMPLE_RATE,
+        0.01,
+        1.0,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
 }
 
 #[cfg(any(test, feature = "pg_test"))]
 #[pgrx::pg_schema]
 mod tests {
     use crate::access_method::{
+        graph::{IndexType, NeighborSelection},
+        meta_page::MetaPage,
         options::{
             TSVIndexOptions, DEFAULT_MAX_ALPHA, NUM_DIMENSIONS_DEFAULT_SENTINEL,
             NUM_NEIGHBORS_DEFAULT_SENTINEL, SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL,
         },
+        plain_node::StoredVectorType,
         storage::StorageType,
     };
     use pgrx::*;
@@ -345,6 +785,10 @@ mod tests {
             options.bq_num_bits_per_dimension,
             SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL,
         );
+        assert_eq!(options.get_neighbor_selection(), NeighborSelection::Rng);
+        assert_eq!(options.get_index_type(), IndexType::Graph);
+        assert_eq!(options.get_decrypt_function(), None);
+        assert_eq!(options.build_neighbor_slack, 0);
         Ok(())
     }
 
@@ -439,4 +883,125 @@ mod tests {
         assert_eq!(options.bq_num_bits_per_dimension, 5);
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_index_options_neighbor_selection() -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(encoding vector(3));
+        CREATE INDEX idxtest
+                  ON test
+               USING diskann(encoding)
+               WITH (neighbor_selection = topk);",
+        ))?;
+
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let options = TSVIndexOptions::from_relation(&indexrel);
+        assert_eq!(options.get_neighbor_selection(), NeighborSelection::TopK);
+        assert_eq!(options.get_storage_type(), StorageType::SbqCompression);
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_index_options_index_type() -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(encoding vector(3));
+        CREATE INDEX idxtest
+                  ON test
+               USING diskann(encoding)
+               WITH (index_type = flat);",
+        ))?;
+
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let options = TSVIndexOptions::from_relation(&indexrel);
+        assert_eq!(options.get_index_type(), IndexType::Flat);
+        assert_eq!(options.get_neighbor_selection(), NeighborSelection::Rng);
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_index_options_stored_vector_type() -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(encoding vector(3));
+        CREATE INDEX idxtest
+                  ON test
+               USING diskann(encoding)
+               WITH (storage_layout = plain, stored_vector_type = bf16);",
+        ))?;
+
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let options = TSVIndexOptions::from_relation(&indexrel);
+        assert_eq!(options.get_stored_vector_type(), StoredVectorType::Bf16);
+        assert_eq!(options.get_index_type(), IndexType::Graph);
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "stored_vector_type is only supported with the plain storage layout, the other layouts don't store full vectors"
+    )]
+    unsafe fn test_index_options_stored_vector_type_sbq() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(encoding vector(3));
+        CREATE INDEX idxtest
+                  ON test
+               USING diskann(encoding)
+               WITH (stored_vector_type = bf16);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(error = "invalid value for parameter \"diskann.default_num_neighbors\": 5")]
+    unsafe fn test_default_num_neighbors_guc_below_minimum() -> spi::Result<()> {
+        Spi::run("SET diskann.default_num_neighbors = 5;")?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_index_options_default_gucs() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(encoding vector(3));
+            SET diskann.default_storage_layout = plain;
+            SET diskann.default_num_neighbors = 30;
+            CREATE INDEX idxdefaults ON test USING diskann(encoding);
+            CREATE INDEX idxexplicit ON test USING diskann(encoding)
+                WITH (storage_layout = memory_optimized, num_neighbors = 20, num_bits_per_dimension = 2);
+            RESET diskann.default_storage_layout;
+            RESET diskann.default_num_neighbors;",
+        )?;
+
+        /* the GUCs are resolved by the build and stored in the index */
+        let index_oid = Spi::get_one::<pg_sys::Oid>("SELECT 'idxdefaults'::regclass::oid")?
+            .expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let meta = MetaPage::fetch(&indexrel);
+        assert_eq!(meta.get_storage_type(), StorageType::Plain);
+        assert_eq!(meta.get_num_neighbors(), 30);
+
+        /* explicit options win */
+        let index_oid = Spi::get_one::<pg_sys::Oid>("SELECT 'idxexplicit'::regclass::oid")?
+            .expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let meta = MetaPage::fetch(&indexrel);
+        assert_eq!(meta.get_storage_type(), StorageType::SbqCompression);
+        assert_eq!(meta.get_num_neighbors(), 20);
+        assert_eq!(meta.get_bq_num_bits_per_dimension(), 2);
+
+        Spi::run(
+            "SET diskann.default_num_bits_per_dimension = 2;
+            CREATE INDEX idxbits ON test USING diskann(encoding);
+            RESET diskann.default_num_bits_per_dimension;",
+        )?;
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxbits'::regclass::oid")?.expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let meta = MetaPage::fetch(&indexrel);
+        assert_eq!(meta.get_storage_type(), StorageType::SbqCompression);
+        assert_eq!(meta.get_bq_num_bits_per_dimension(), 2);
+        Ok(())
+    }
 }
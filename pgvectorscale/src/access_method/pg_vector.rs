@@ -1,3 +1,6 @@
+use pgrx::pgrx_sql_entity_graph::metadata::{
+    ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable,
+};
 use pgrx::*;
 
 use super::{distance::preprocess_cosine, meta_page};
@@ -20,11 +23,72 @@ impl PgVectorInternal {
     }
 }
 
+/// What to do with input vectors that have NaN or infinite components.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OnInvalid {
+    /// Fail the insert or the build
+    Error = 0,
+    /// Leave the row out of the index, like a NULL vector
+    Skip = 1,
+}
+
+pub const DEFAULT_ON_INVALID_STR: &str = "error";
+
+impl OnInvalid {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => OnInvalid::Error,
+            1 => OnInvalid::Skip,
+            _ => panic!("Invalid on_invalid value"),
+        }
+    }
+
+    pub fn from_str(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "error" => OnInvalid::Error,
+            "skip" => OnInvalid::Skip,
+            _ => panic!("Invalid on_invalid value. Must be one of 'error', 'skip'"),
+        }
+    }
+
+    /// The on_invalid value of this behavior.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OnInvalid::Error => "error",
+            OnInvalid::Skip => "skip",
+        }
+    }
+}
+
+/// What to do with a query vector whose number of dimensions differs from the index's,
+/// see the diskann.dimension_mode GUC. The variants are named as they are set.
+#[allow(non_camel_case_types)]
+#[derive(PostgresGucEnum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DimensionMode {
+    /// Fail the query
+    strict,
+    /// Drop the extra dimensions of longer vectors
+    truncate,
+    /// Append zeros to shorter vectors
+    pad_zero,
+}
+
+/// Returns the position and value of the first NaN or infinite component of the vector, if any.
+fn find_non_finite(vector: &[f32]) -> Option<(usize, f32)> {
+    vector
+        .iter()
+        .position(|v| !v.is_finite())
+        .map(|position| (position, vector[position]))
+}
+
 pub struct PgVector {
     index_distance: Option<*mut PgVectorInternal>,
     index_distance_needs_pfree: bool,
     full_distance: Option<*mut PgVectorInternal>,
     full_distance_needs_pfree: bool,
+    /// The distance function of the ORDER BY operator of the index and a copy of the vector
+    /// as given, see use_operator_distance().
+    operator_distance: Option<(pg_sys::Oid, *mut PgVectorInternal)>,
 }
 
 impl Drop for PgVector {
@@ -43,6 +107,9 @@ impl Drop for PgVector {
                 }
             }
         }
+        if let Some((_, original)) = self.operator_distance {
+            unsafe { pg_sys::pfree(original.cast()) };
+        }
     }
 }
 
@@ -60,12 +127,47 @@ impl PgVector {
             return None;
         }
         let datums = std::slice::from_raw_parts(datum_parts, index + 1);
-        Some(Self::from_datum(
-            datums[index],
-            meta_page,
-            index_distance,
-            full_distance,
-        ))
+        let datum = decrypt_datum(datums[index], meta_page);
+
+        /* NaN and infinite components would poison the distances, and through them the graph.
+        Checked before the cosine normalization, which would spread them to the whole vector. */
+        let raw = datum.cast_mut_ptr::<pg_sys::varlena>();
+        let detoasted = pg_sys::pg_detoast_datum(raw);
+        let non_finite = find_non_finite((*detoasted.cast::<PgVectorInternal>()).to_slice());
+        if !std::ptr::eq(detoasted, raw) {
+            pg_sys::pfree(detoasted.cast());
+        }
+        if let Some((position, value)) = non_finite {
+            match meta_page.get_on_invalid() {
+                OnInvalid::Error => pgrx::error!(
+                    "vector has a non-finite value ({}) at position {}, it can't be indexed",
+                    value,
+                    position + 1
+                ),
+                OnInvalid::Skip => {
+                    free_decrypted_datum(datum, datums[index]);
+                    return None;
+                }
+            }
+        }
+
+        let vector = Self::from_datum(datum, meta_page, index_distance, full_distance);
+        free_decrypted_datum(datum, datums[index]);
+        Some(vector)
+    }
+
+    /// A vector read back from the heap, as opposed to a query vector.
+    /// Goes through the decrypt_function of the index, if any.
+    pub unsafe fn from_heap_datum(
+        datum: pg_sys::Datum,
+        meta_page: &meta_page::MetaPage,
+        index_distance: bool,
+        full_distance: bool,
+    ) -> PgVector {
+        let decrypted = decrypt_datum(datum, meta_page);
+        let vector = Self::from_datum(decrypted, meta_page, index_distance, full_distance);
+        free_decrypted_datum(decrypted, datum);
+        vector
     }
 
     unsafe fn create_inner(
@@ -114,6 +216,7 @@ impl PgVector {
                 index_distance_needs_pfree: true,
                 full_distance: Some(inner),
                 full_distance_needs_pfree: false,
+                operator_distance: None,
             };
         }
 
@@ -134,6 +237,81 @@ impl PgVector {
             index_distance_needs_pfree: true,
             full_distance: full,
             full_distance_needs_pfree: true,
+            operator_distance: None,
+        }
+    }
+
+    /// A zero vector with the dimensions of the index. Used in place of a NULL query vector
+    /// so that a scan still returns all the rows, in an arbitrary order.
+    pub unsafe fn zeros(meta_page: &meta_page::MetaPage) -> PgVector {
+        let dim = meta_page.get_num_dimensions() as usize;
+        let size = std::mem::size_of::<PgVectorInternal>() + dim * std::mem::size_of::<f32>();
+        let zeros = pg_sys::palloc0(size).cast::<PgVectorInternal>();
+        set_varsize(zeros.cast(), size as i32);
+        (*zeros).dim = dim as _;
+
+        /* from_datum makes its own copies */
+        let vector = Self::from_datum(pg_sys::Datum::from(zeros), meta_page, true, true);
+        pg_sys::pfree(zeros.cast());
+        vector
+    }
+
+    /// A vector for index distances only, from the components returned by to_index_slice()
+    /// of another PgVector. Used to hold on to vectors beyond the life of their datum.
+    pub unsafe fn from_index_slice(values: &[f32]) -> PgVector {
+        let size =
+            std::mem::size_of::<PgVectorInternal>() + values.len() * std::mem::size_of::<f32>();
+        let inner = pg_sys::palloc0(size).cast::<PgVectorInternal>();
+        set_varsize(inner.cast(), size as i32);
+        (*inner).dim = values.len() as _;
+        (*inner)
+            .x
+            .as_mut_slice(values.len())
+            .copy_from_slice(values);
+
+        PgVector {
+            index_distance: Some(inner),
+            index_distance_needs_pfree: true,
+            full_distance: None,
+            full_distance_needs_pfree: false,
+            operator_distance: None,
+        }
+    }
+
+    /// Makes full_distance_to_heap_datum() call the function of the ORDER BY operator of the
+    /// index, pgvector's own, with `datum`, the vector this PgVector was made from. The exact
+    /// distances then match what the operator returns, bit for bit, at the cost of a function
+    /// call per distance.
+    pub unsafe fn use_operator_distance(&mut self, index: &PgRelation, datum: pg_sys::Datum) {
+        let function = operator_distance_function(index);
+        let original = pg_sys::pg_detoast_datum_copy(datum.cast_mut_ptr());
+        self.operator_distance = Some((function, original.cast()));
+    }
+
+    /// The exact distance between this vector and a vector read from the heap, after the
+    /// decrypt_function of the index if any.
+    pub unsafe fn full_distance_to_heap_datum(
+        &self,
+        datum: pg_sys::Datum,
+        meta_page: &meta_page::MetaPage,
+        distance_fn: fn(&[f32], &[f32]) -> f32,
+    ) -> f32 {
+        match self.operator_distance {
+            Some((function, original)) => {
+                let decrypted = decrypt_datum(datum, meta_page);
+                let distance = pg_sys::OidFunctionCall2Coll(
+                    function,
+                    pg_sys::InvalidOid,
+                    decrypted,
+                    pg_sys::Datum::from(original),
+                );
+                free_decrypted_datum(decrypted, datum);
+                f64::from_datum(distance, false).unwrap() as f32
+            }
+            None => {
+                let vec = Self::from_heap_datum(datum, meta_page, false, true);
+                distance_fn(vec.to_full_slice(), self.to_full_slice())
+            }
         }
     }
 
@@ -145,3 +323,160 @@ impl PgVector {
         unsafe { (*self.full_distance.unwrap()).to_slice() }
     }
 }
+
+/// The function behind the ORDER BY operator of the opclass of the index.
+unsafe fn operator_distance_function(index: &PgRelation) -> pg_sys::Oid {
+    let opfamily = *index.rd_opfamily;
+    let input_type = *index.rd_opcintype;
+    let operator = pg_sys::get_opfamily_member(opfamily, input_type, input_type, 1);
+    if operator == pg_sys::InvalidOid {
+        pgrx::error!("no ORDER BY operator in the operator family of the index");
+    }
+    pg_sys::get_opcode(operator)
+}
+
+/// Applies the decrypt_function of the index to a vector read from the heap.
+/// Returns the datum itself if the index doesn't have one.
+pub unsafe fn decrypt_datum(
+    datum: pg_sys::Datum,
+    meta_page: &meta_page::MetaPage,
+) -> pg_sys::Datum {
+    match meta_page.get_decrypt_function() {
+        Some(function) => pg_sys::OidFunctionCall1Coll(function, pg_sys::InvalidOid, datum),
+        None => datum,
+    }
+}
+
+/// Frees the result of decrypt_datum(), unless it is the original datum.
+pub unsafe fn free_decrypted_datum(decrypted: pg_sys::Datum, original: pg_sys::Datum) {
+    if decrypted != original {
+        pg_sys::pfree(decrypted.cast_mut_ptr());
+    }
+}
+
+/// Adapts a query vector to the number of dimensions of the index, as allowed by
+/// diskann.dimension_mode. Returns the datum itself if the dimensions already match, and a
+/// new vector, to free with free_adapted_datum(), otherwise.
+pub unsafe fn adapt_query_datum(
+    datum: pg_sys::Datum,
+    meta_page: &meta_page::MetaPage,
+) -> pg_sys::Datum {
+    let raw = datum.cast_mut_ptr::<pg_sys::varlena>();
+    let detoasted = pg_sys::pg_detoast_datum(raw);
+    let values = (*detoasted.cast::<PgVectorInternal>()).to_slice();
+    let dimensions = meta_page.get_num_dimensions() as usize;
+
+    let adapted = if values.len() == dimensions {
+        datum
+    } else {
+        let allowed = match super::guc::TSV_DIMENSION_MODE.get() {
+            DimensionMode::strict => false,
+            DimensionMode::truncate => values.len() > dimensions,
+            DimensionMode::pad_zero => values.len() < dimensions,
+        };
+        if !allowed {
+            meta_page.check_query_dimensions(values.len());
+        }
+
+        let size =
+            std::mem::size_of::<PgVectorInternal>() + dimensions * std::mem::size_of::<f32>();
+        let vector = pg_sys::palloc0(size).cast::<PgVectorInternal>();
+        set_varsize(vector.cast(), size as i32);
+        (*vector).dim = dimensions as _;
+        let kept = std::cmp::min(values.len(), dimensions);
+        (*vector).x.as_mut_slice(dimensions)[..kept].copy_from_slice(&values[..kept]);
+        pg_sys::Datum::from(vector)
+    };
+
+    if !std::ptr::eq(detoasted, raw) {
+        pg_sys::pfree(detoasted.cast());
+    }
+    adapted
+}
+
+/// Frees the result of adapt_query_datum(), unless it is the original datum.
+pub unsafe fn free_adapted_datum(adapted: pg_sys::Datum, original: pg_sys::Datum) {
+    if adapted != original {
+        pg_sys::pfree(adapted.cast_mut_ptr());
+    }
+}
+
+/// Copies the components of a vector datum as they are stored, without the
+/// preprocessing done for the distance function of the index.
+pub unsafe fn datum_to_vec(datum: pg_sys::Datum) -> Vec<f32> {
+    let raw = datum.cast_mut_ptr::<pg_sys::varlena>();
+    let detoasted = pg_sys::pg_detoast_datum(raw);
+    let vec = (*detoasted.cast::<PgVectorInternal>()).to_slice().to_vec();
+    if !std::ptr::eq(detoasted, raw) {
+        pg_sys::pfree(detoasted.cast());
+    }
+    vec
+}
+
+/// Copies the components of a vector read from the heap, after the decrypt_function of the
+/// index if any.
+pub unsafe fn heap_datum_to_vec(datum: pg_sys::Datum, meta_page: &meta_page::MetaPage) -> Vec<f32> {
+    let decrypted = decrypt_datum(datum, meta_page);
+    let vec = datum_to_vec(decrypted);
+    free_decrypted_datum(decrypted, datum);
+    vec
+}
+
+/// A pgvector `vector` passed as an argument to one of our SQL functions.
+/// It is kept as a Datum and converted with the meta page of the index it is used with.
+pub struct VectorArg(pg_sys::Datum);
+
+impl VectorArg {
+    pub unsafe fn to_pg_vector(&self, meta_page: &meta_page::MetaPage) -> PgVector {
+        let datum = adapt_query_datum(self.0, meta_page);
+        let vector = PgVector::from_datum(datum, meta_page, true, true);
+        free_adapted_datum(datum, self.0);
+        vector
+    }
+
+    /// Like to_pg_vector(), for the query the exact distances are computed to.
+    pub unsafe fn to_full_distance_query(
+        &self,
+        index: &PgRelation,
+        meta_page: &meta_page::MetaPage,
+    ) -> PgVector {
+        let mut vector = self.to_pg_vector(meta_page);
+        if super::guc::TSV_RESCORE_WITH_PGVECTOR.get() {
+            let datum = adapt_query_datum(self.0, meta_page);
+            vector.use_operator_distance(index, datum);
+            free_adapted_datum(datum, self.0);
+        }
+        vector
+    }
+
+    pub unsafe fn to_vec(&self, meta_page: &meta_page::MetaPage) -> Vec<f32> {
+        let datum = adapt_query_datum(self.0, meta_page);
+        let vec = datum_to_vec(datum);
+        free_adapted_datum(datum, self.0);
+        vec
+    }
+}
+
+impl FromDatum for VectorArg {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        _typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        if is_null {
+            None
+        } else {
+            Some(VectorArg(datum))
+        }
+    }
+}
+
+unsafe impl SqlTranslatable for VectorArg {
+    fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+        Ok(SqlMapping::literal("vector"))
+    }
+
+    fn return_sql() -> Result<Returns, ReturnsError> {
+        Ok(Returns::One(SqlMapping::literal("vector")))
+    }
+}
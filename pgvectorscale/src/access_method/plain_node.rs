@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::pin::Pin;
 
 use pgrx::pg_sys::{InvalidBlockNumber, InvalidOffsetNumber};
@@ -12,11 +13,66 @@ use crate::util::{ArchivedItemPointer, HeapPointer, ItemPointer, ReadableBuffer,
 
 use super::meta_page::MetaPage;
 
+/// The element type of the full vectors stored in plain nodes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StoredVectorType {
+    F32 = 0,
+    /// bfloat16: half the space, with about 3 significant decimal digits per element
+    Bf16 = 1,
+}
+
+pub const DEFAULT_STORED_VECTOR_TYPE_STR: &str = "f32";
+
+impl StoredVectorType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => StoredVectorType::F32,
+            1 => StoredVectorType::Bf16,
+            _ => panic!("Invalid stored vector type"),
+        }
+    }
+
+    pub fn from_str(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "f32" => StoredVectorType::F32,
+            "bf16" => StoredVectorType::Bf16,
+            _ => panic!("Invalid stored vector type. Must be one of 'f32', 'bf16'"),
+        }
+    }
+
+    /// The stored_vector_type value of this type.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StoredVectorType::F32 => "f32",
+            StoredVectorType::Bf16 => "bf16",
+        }
+    }
+}
+
+/// Converts to bfloat16, rounding to nearest even
+fn f32_to_bf16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    if value.is_nan() {
+        //keep it a NaN after truncation
+        return ((bits >> 16) | 0x0040) as u16;
+    }
+    let rounding = 0x7fff + ((bits >> 16) & 1);
+    (bits.wrapping_add(rounding) >> 16) as u16
+}
+
+fn bf16_to_f32(value: u16) -> f32 {
+    f32::from_bits((value as u32) << 16)
+}
+
 #[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
 #[archive(check_bytes)]
+#[page_type(Node)]
 pub struct Node {
+    /// The full vector, when stored as f32. Empty otherwise.
     pub vector: Vec<f32>,
-    pub pq_vector: Vec<u8>,
+    /// The full vector packed as little-endian bf16, when stored as bf16. Empty otherwise.
+    /// (This used to hold PQ codes, which were never written for plain nodes.)
+    pub packed_vector: Vec<u8>,
     neighbor_index_pointers: Vec<ItemPointer>,
     pub heap_item_pointer: HeapPointer,
 }
@@ -24,15 +80,14 @@ pub struct Node {
 impl Node {
     fn new_internal(
         vector: Vec<f32>,
-        pq_vector: Vec<u8>,
+        packed_vector: Vec<u8>,
         heap_item_pointer: ItemPointer,
         meta_page: &MetaPage,
     ) -> Self {
         let num_neighbors = meta_page.get_num_neighbors();
         Self {
             vector,
-            // always use vectors of num_clusters on length because we never want the serialized size of a Node to change
-            pq_vector,
+            packed_vector,
             // always use vectors of num_neighbors on length because we never want the serialized size of a Node to change
             neighbor_index_pointers: (0..num_neighbors)
                 .map(|_| ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber))
@@ -41,18 +96,60 @@ impl Node {
         }
     }
 
+    /// The serialized size of a node with an f32 vector of `num_dimensions` and room for
+    /// `num_neighbors` neighbors.
+    pub fn test_size(num_neighbors: usize, num_dimensions: usize) -> usize {
+        let n = Self {
+            vector: vec![0.0; num_dimensions],
+            packed_vector: vec![],
+            neighbor_index_pointers: (0..num_neighbors)
+                .map(|_| ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber))
+                .collect(),
+            heap_item_pointer: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+        };
+        n.serialize_to_vec().len()
+    }
+
     pub fn new_for_full_vector(
         vector: Vec<f32>,
         heap_item_pointer: ItemPointer,
         meta_page: &MetaPage,
     ) -> Self {
-        let pq_vector = Vec::with_capacity(0);
-        Self::new_internal(vector, pq_vector, heap_item_pointer, meta_page)
+        match meta_page.get_stored_vector_type() {
+            StoredVectorType::F32 => {
+                Self::new_internal(vector, Vec::with_capacity(0), heap_item_pointer, meta_page)
+            }
+            StoredVectorType::Bf16 => {
+                let packed_vector = vector
+                    .iter()
+                    .flat_map(|&v| f32_to_bf16(v).to_le_bytes())
+                    .collect();
+                Self::new_internal(
+                    Vec::with_capacity(0),
+                    packed_vector,
+                    heap_item_pointer,
+                    meta_page,
+                )
+            }
+        }
     }
 }
 
 /// contains helpers for mutate-in-place. See struct_mutable_refs in test_alloc.rs in rkyv
 impl ArchivedNode {
+    /// The full vector of the node, decoded to f32 if it is stored as bf16.
+    pub fn vector(&self) -> Cow<'_, [f32]> {
+        if !self.vector.is_empty() || self.packed_vector.is_empty() {
+            return Cow::Borrowed(self.vector.as_slice());
+        }
+        Cow::Owned(
+            self.packed_vector
+                .chunks_exact(2)
+                .map(|b| bf16_to_f32(u16::from_le_bytes([b[0], b[1]])))
+                .collect(),
+        )
+    }
+
     pub fn is_deleted(&self) -> bool {
         self.heap_item_pointer.offset == InvalidOffsetNumber
     }
@@ -84,6 +181,37 @@ impl ArchivedNode {
             .map(|ip| ip.deserialize_item_pointer())
     }
 
+    /// Adds `neighbor` after the last neighbor if the list has room for it, and returns
+    /// whether it did.
+    pub fn append_neighbor(
+        mut self: Pin<&mut Self>,
+        neighbor: ItemPointer,
+        meta_page: &MetaPage,
+    ) -> bool {
+        let num_neighbors = self.num_neighbors();
+        let capacity = std::cmp::min(
+            meta_page.get_num_neighbors() as usize,
+            self.neighbor_index_pointers.len(),
+        );
+        if num_neighbors >= capacity {
+            return false;
+        }
+
+        let mut a_index_pointer = self
+            .as_mut()
+            .neighbor_index_pointer()
+            .index_pin(num_neighbors);
+        a_index_pointer.block_number = neighbor.block_number;
+        a_index_pointer.offset = neighbor.offset;
+        if num_neighbors + 1 < capacity {
+            let mut past_last_index_pointers =
+                self.neighbor_index_pointer().index_pin(num_neighbors + 1);
+            past_last_index_pointers.block_number = InvalidBlockNumber;
+            past_last_index_pointers.offset = InvalidOffsetNumber;
+        }
+        true
+    }
+
     pub fn set_neighbors(
         mut self: Pin<&mut Self>,
         neighbors: &[NeighborWithDistance],
@@ -130,3 +258,20 @@ impl ArchivedData for ArchivedNode {
         self.heap_item_pointer.deserialize_item_pointer()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{bf16_to_f32, f32_to_bf16};
+
+    #[test]
+    fn test_bf16_round_trip() {
+        for &v in [0.0f32, 1.0, -2.5, 0.15625, 1e-20, 3.0e38].iter() {
+            let back = bf16_to_f32(f32_to_bf16(v));
+            assert!((back - v).abs() <= v.abs() / 128.0, "{} became {}", v, back);
+        }
+        /* 1 + 2^-8 is halfway between two bf16 values and rounds to the even one */
+        assert_eq!(bf16_to_f32(f32_to_bf16(1.0 + 1.0 / 256.0)), 1.0);
+        assert!(bf16_to_f32(f32_to_bf16(f32::NAN)).is_nan());
+        assert_eq!(bf16_to_f32(f32_to_bf16(f32::INFINITY)), f32::INFINITY);
+    }
+}
@@ -2,7 +2,7 @@ use super::{
     graph::{ListSearchNeighbor, ListSearchResult},
     graph_neighbor_store::GraphNeighborStore,
     pg_vector::PgVector,
-    plain_node::{ArchivedNode, Node, ReadableNode},
+    plain_node::{ArchivedNode, Node, ReadableNode, StoredVectorType},
     stats::{
         GreedySearchStats, StatsDistanceComparison, StatsHeapNodeRead, StatsNodeModify,
         StatsNodeRead, StatsNodeWrite, WriteStats,
@@ -11,7 +11,7 @@ use super::{
     storage_common::get_attribute_number_from_index,
 };
 
-use pgrx::PgRelation;
+use pgrx::{pg_sys::InvalidOffsetNumber, PgRelation};
 
 use crate::util::{
     page::PageType, table_slot::TableSlot, tape::Tape, HeapPointer, IndexPointer, ItemPointer,
@@ -125,11 +125,11 @@ impl<'a> NodeDistanceMeasure for IndexFullDistanceMeasure<'a> {
         let rn2 = &self.readable_node;
         let node1 = rn1.get_archived_node();
         let node2 = rn2.get_archived_node();
-        assert!(node1.vector.len() > 0);
-        assert!(node1.vector.len() == node2.vector.len());
-        let vec1 = node1.vector.as_slice();
-        let vec2 = node2.vector.as_slice();
-        (self.storage.get_distance_function())(vec1, vec2)
+        let vec1 = node1.vector();
+        let vec2 = node2.vector();
+        assert!(vec1.len() > 0);
+        assert!(vec1.len() == vec2.len());
+        (self.storage.get_distance_function())(&vec1, &vec2)
     }
 }
 
@@ -159,7 +159,10 @@ impl PlainStorageLsnPrivateData {
 
 impl<'a> Storage for PlainStorage<'a> {
     type QueryDistanceMeasure = PlainDistanceMeasure;
-    type NodeDistanceMeasure<'b> = IndexFullDistanceMeasure<'b> where Self: 'b;
+    type NodeDistanceMeasure<'b>
+        = IndexFullDistanceMeasure<'b>
+    where
+        Self: 'b;
     type ArchivedType = ArchivedNode;
     type LSNPrivateData = PlainStorageLsnPrivateData;
 
@@ -219,15 +222,24 @@ impl<'a> Storage for PlainStorage<'a> {
         meta_page: &MetaPage,
         stats: &mut S,
     ) -> f32 {
-        /* Plain storage only needs to resort when the index is using less dimensions than the underlying data. */
-        assert!(meta_page.get_num_dimensions() > meta_page.get_num_dimensions_to_index());
+        /* Plain storage only needs to resort when the index is using less dimensions than the underlying data,
+        or stores them with less precision. */
+        assert!(
+            meta_page.get_num_dimensions() > meta_page.get_num_dimensions_to_index()
+                || meta_page.get_stored_vector_type() != StoredVectorType::F32
+        );
 
         let slot = unsafe { TableSlot::new(self.heap_rel, heap_pointer, stats) };
         match qdm {
             PlainDistanceMeasure::Full(query) => {
                 let datum = unsafe { slot.get_attribute(self.heap_attr).unwrap() };
-                let vec = unsafe { PgVector::from_datum(datum, meta_page, false, true) };
-                self.get_distance_function()(vec.to_full_slice(), query.to_full_slice())
+                unsafe {
+                    query.full_distance_to_heap_datum(
+                        datum,
+                        meta_page,
+                        self.get_distance_function(),
+                    )
+                }
             }
         }
     }
@@ -266,7 +278,7 @@ impl<'a> Storage for PlainStorage<'a> {
             PlainDistanceMeasure::Full(query) => PlainDistanceMeasure::calculate_distance(
                 self.distance_fn,
                 query.to_index_slice(),
-                node.vector.as_slice(),
+                &node.vector(),
                 &mut lsr.stats,
             ),
         };
@@ -287,6 +299,10 @@ impl<'a> Storage for PlainStorage<'a> {
         let lsn = lsr.get_lsn_by_idx(lsn_idx);
         //clone needed so we don't continue to borrow lsr
         let neighbors = lsn.get_private_data().neighbors.clone();
+        let is_deleted = lsn.get_private_data().heap_pointer.offset == InvalidOffsetNumber;
+        if !lsr.may_visit_neighbors(is_deleted) {
+            return;
+        }
 
         for &neighbor_index_pointer in neighbors.iter() {
             if !lsr.prepare_insert(neighbor_index_pointer) {
@@ -301,7 +317,7 @@ impl<'a> Storage for PlainStorage<'a> {
                 PlainDistanceMeasure::Full(query) => PlainDistanceMeasure::calculate_distance(
                     self.distance_fn,
                     query.to_index_slice(),
-                    node_neighbor.vector.as_slice(),
+                    &node_neighbor.vector(),
                     &mut lsr.stats,
                 ),
             };
@@ -336,6 +352,25 @@ impl<'a> Storage for PlainStorage<'a> {
         node.commit();
     }
 
+    fn append_neighbor_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        neighbor: IndexPointer,
+        stats: &mut S,
+    ) -> bool {
+        let node = unsafe { Node::modify(self.index, index_pointer, stats) };
+        let archived = node.get_archived_node();
+        if archived.iter_neighbors().any(|n| n == neighbor) {
+            return true;
+        }
+        if !archived.append_neighbor(neighbor, meta) {
+            return false;
+        }
+        node.commit();
+        true
+    }
+
     fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
         self.distance_fn
     }
@@ -400,6 +435,70 @@ mod tests {
         Ok(())
     }
 
+    #[pg_test]
+    unsafe fn test_plain_storage_flat_index_creation() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = plain, index_type = flat",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_bf16_index_creation() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = plain, stored_vector_type = bf16",
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_plain_storage_flat_delete_vacuum_full() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_full_scaffold(
+            "storage_layout = plain, index_type = flat",
+        );
+    }
+
+    #[pg_test(
+        error = "diskann index \"idxtest\" is corrupted: item (0,1) is not on a Node page, the index must be rebuilt"
+    )]
+    unsafe fn test_plain_storage_neighbor_on_wrong_page_type() -> spi::Result<()> {
+        use crate::access_method::{
+            meta_page::MetaPage, neighbor_with_distance::NeighborWithDistance, plain_node::Node,
+            stats::PruneNeighborStats,
+        };
+        use crate::util::ItemPointer;
+
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH (storage_layout = plain);",
+        )?;
+
+        /* point the entry point at the meta page instead of a neighbor */
+        let index = PgRelation::open_with_name_and_share_lock("idxtest").unwrap();
+        let meta_page = MetaPage::fetch(&index);
+        let init_id = meta_page.get_init_ids().unwrap()[0];
+        let mut stats = PruneNeighborStats::new();
+        let node = Node::modify(&index, init_id, &mut stats);
+        node.get_archived_node().set_neighbors(
+            &[NeighborWithDistance::new(ItemPointer::new(0, 1), 0.0)],
+            &meta_page,
+        );
+        node.commit();
+        drop(index);
+
+        Spi::run(
+            "SET enable_seqscan = 0;
+            SELECT * FROM test ORDER BY embedding <=> '[1,1,1]' LIMIT 3;",
+        )?;
+        Ok(())
+    }
+
     #[pg_test]
     unsafe fn test_plain_storage_index_updates() -> spi::Result<()> {
         crate::access_method::build::tests::test_index_updates(
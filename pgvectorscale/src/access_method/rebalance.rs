@@ -0,0 +1,407 @@
+//! Graph degree rebalancing.
+//!
+//! Over time some nodes accumulate far more in-edges than others. These hubs
+//! are visited by most searches and slow traversal down. Rebalancing moves the
+//! longest in-edges of a hub to the hub's own neighbors, which keeps the rest of
+//! the graph connected to the hub's neighborhood at the cost of one extra hop.
+//!
+//! The degree of the whole graph can also be lowered after the build, by pruning every
+//! neighbor list down to a smaller num_neighbors. Raising it takes a REINDEX: every node is
+//! written with room for exactly num_neighbors neighbors.
+
+use std::collections::{HashMap, HashSet};
+
+use pgrx::pg_sys::AsPgCStr;
+use pgrx::*;
+
+use crate::util::IndexPointer;
+
+use super::graph::Graph;
+use super::graph_neighbor_store::GraphNeighborStore;
+use super::meta_page::MetaPage;
+use super::neighbor_with_distance::NeighborWithDistance;
+use super::plain_storage::PlainStorage;
+use super::sbq::SbqSpeedupStorage;
+use super::stats::PruneNeighborStats;
+use super::storage::{ArchivedData, NodeDistanceMeasure, Storage, StorageType};
+use super::storage_common::{check_index_owner, check_is_diskann_index, for_each_node};
+
+/// By default a node is considered a hub if it has more than this many times
+/// `num_neighbors` in-edges.
+const DEFAULT_HUB_FACTOR: u32 = 2;
+
+/// Summary of the in-degree distribution of the graph.
+struct DegreeSummary {
+    num_nodes: i64,
+    min_in_degree: i32,
+    max_in_degree: i32,
+    avg_in_degree: f64,
+    num_hubs: i64,
+}
+
+impl DegreeSummary {
+    fn new(in_degrees: &HashMap<IndexPointer, u32>, max_in_degree: u32) -> Self {
+        let num_nodes = in_degrees.len();
+        let total: u64 = in_degrees.values().map(|&d| d as u64).sum();
+        Self {
+            num_nodes: num_nodes as i64,
+            min_in_degree: in_degrees.values().copied().min().unwrap_or(0) as i32,
+            max_in_degree: in_degrees.values().copied().max().unwrap_or(0) as i32,
+            avg_in_degree: if num_nodes == 0 {
+                0.0
+            } else {
+                total as f64 / num_nodes as f64
+            },
+            num_hubs: in_degrees.values().filter(|&&d| d > max_in_degree).count() as i64,
+        }
+    }
+}
+
+fn in_degrees(neighbors: &HashMap<IndexPointer, Vec<IndexPointer>>) -> HashMap<IndexPointer, u32> {
+    let mut in_degrees: HashMap<IndexPointer, u32> =
+        neighbors.keys().map(|&node| (node, 0)).collect();
+    for list in neighbors.values() {
+        for n in list {
+            *in_degrees.entry(*n).or_insert(0) += 1;
+        }
+    }
+    in_degrees
+}
+
+/// Rebalances the graph so that no node has more than `max_in_degree` in-edges.
+/// Returns the in-degree summary before and after, and the number of edges moved.
+fn rebalance_storage<S: Storage>(
+    storage: &S,
+    index: &PgRelation,
+    meta_page: &MetaPage,
+    max_in_degree: u32,
+) -> (DegreeSummary, DegreeSummary, i64) {
+    let mut stats = PruneNeighborStats::new();
+
+    let mut neighbors: HashMap<IndexPointer, Vec<IndexPointer>> = HashMap::new();
+    for_each_node::<S, _>(index, |index_pointer, node| {
+        neighbors.insert(index_pointer, node.get_index_pointer_to_neighbors());
+    });
+
+    let mut in_degrees = in_degrees(&neighbors);
+    let before = DegreeSummary::new(&in_degrees, max_in_degree);
+
+    let mut in_edges: HashMap<IndexPointer, Vec<IndexPointer>> = HashMap::new();
+    for (&from, list) in neighbors.iter() {
+        for &to in list {
+            in_edges.entry(to).or_default().push(from);
+        }
+    }
+
+    let mut hubs: Vec<IndexPointer> = in_degrees
+        .iter()
+        .filter(|(_, &d)| d > max_in_degree)
+        .map(|(&node, _)| node)
+        .collect();
+    hubs.sort();
+
+    let mut modified: HashSet<IndexPointer> = HashSet::new();
+    let mut edges_moved = 0;
+    for hub in hubs {
+        let hub_dm = unsafe { storage.get_node_distance_measure(hub, &mut stats) };
+        let mut sources: Vec<NeighborWithDistance> = in_edges[&hub]
+            .iter()
+            .map(|&from| unsafe {
+                NeighborWithDistance::new(from, hub_dm.get_distance(from, &mut stats))
+            })
+            .collect();
+        drop(hub_dm);
+
+        //keep the closest in-edges, move the farthest ones
+        sources.sort();
+        let hub_neighbors = neighbors.get(&hub).cloned().unwrap_or_default();
+        for source in sources.iter().skip(max_in_degree as usize) {
+            let from = source.get_index_pointer_to_neighbor();
+            let from_dm = unsafe { storage.get_node_distance_measure(from, &mut stats) };
+            let from_list = neighbors.get(&from).unwrap();
+
+            //the replacement is the closest neighbor of the hub that isn't a hub itself
+            let replacement = hub_neighbors
+                .iter()
+                .filter(|&&n| n != from && !from_list.contains(&n))
+                .filter(|&n| in_degrees[n] < max_in_degree)
+                .map(|&n| unsafe {
+                    NeighborWithDistance::new(n, from_dm.get_distance(n, &mut stats))
+                })
+                .min();
+
+            let from_list = neighbors.get_mut(&from).unwrap();
+            let pos = from_list.iter().position(|&n| n == hub).unwrap();
+            match replacement {
+                Some(r) => {
+                    from_list[pos] = r.get_index_pointer_to_neighbor();
+                    *in_degrees
+                        .get_mut(&r.get_index_pointer_to_neighbor())
+                        .unwrap() += 1;
+                }
+                None => {
+                    //dropping the edge is fine, the hub stays reachable through its other in-edges
+                    from_list.remove(pos);
+                }
+            }
+            *in_degrees.get_mut(&hub).unwrap() -= 1;
+            modified.insert(from);
+            edges_moved += 1;
+        }
+    }
+
+    let mut modified: Vec<IndexPointer> = modified.into_iter().collect();
+    modified.sort();
+    for node in modified {
+        let dm = unsafe { storage.get_node_distance_measure(node, &mut stats) };
+        let new_neighbors: Vec<NeighborWithDistance> = neighbors[&node]
+            .iter()
+            .map(|&n| unsafe { NeighborWithDistance::new(n, dm.get_distance(n, &mut stats)) })
+            .collect();
+        drop(dm);
+        storage.set_neighbors_on_disk(meta_page, node, &new_neighbors, &mut stats);
+    }
+
+    let after = DegreeSummary::new(&in_degrees, max_in_degree);
+    (before, after, edges_moved)
+}
+
+/// Prunes the in-edges of over-connected hub nodes in a diskann index. A node is a
+/// hub if it has more than `max_in_degree` in-edges (by default twice `num_neighbors`).
+/// Returns the in-degree distribution before and after rebalancing.
+#[pg_extern]
+pub fn tsv_rebalance(
+    index: PgRelation,
+    max_in_degree: default!(Option<i32>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(stage, String),
+        name!(num_nodes, i64),
+        name!(min_in_degree, i32),
+        name!(max_in_degree, i32),
+        name!(avg_in_degree, f64),
+        name!(num_hubs, i64),
+        name!(edges_moved, i64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_index_owner(&index);
+    unsafe { pg_sys::PreventCommandDuringRecovery("tsv_rebalance()".as_pg_cstr()) };
+
+    /* block concurrent inserts, which update neighbor lists too, and self-conflicting so that two
+     * calls don't move the same edges */
+    unsafe { pg_sys::LockRelationOid(index.oid(), pg_sys::ShareRowExclusiveLock as _) };
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let meta_page = MetaPage::fetch(&index);
+    let max_in_degree = match max_in_degree {
+        Some(d) if d < 1 => error!("max_in_degree must be at least 1"),
+        Some(d) => d as u32,
+        None => meta_page.get_num_neighbors() * DEFAULT_HUB_FACTOR,
+    };
+
+    let (before, after, edges_moved) = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::load_for_insert(&index, &heap, meta_page.get_distance_function());
+            rebalance_storage(&storage, &index, &meta_page, max_in_degree)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let mut stats = PruneNeighborStats::new();
+            let storage = SbqSpeedupStorage::load_for_insert(&heap, &index, &meta_page, &mut stats);
+            rebalance_storage(&storage, &index, &meta_page, max_in_degree)
+        }
+    };
+
+    let rows = [("before", before, 0), ("after", after, edges_moved)]
+        .into_iter()
+        .map(|(stage, s, moved)| {
+            (
+                stage.to_string(),
+                s.num_nodes,
+                s.min_in_degree,
+                s.max_in_degree,
+                s.avg_in_degree,
+                s.num_hubs,
+                moved,
+            )
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// Prunes every neighbor list longer than the num_neighbors of `meta_page` with the neighbor
+/// selection of the index, and returns the number of lists pruned.
+fn prune_storage<S: Storage>(storage: &S, index: &PgRelation, meta_page: &mut MetaPage) -> i64 {
+    let num_neighbors = meta_page.get_num_neighbors() as usize;
+    let mut nodes: Vec<IndexPointer> = vec![];
+    for_each_node::<S, _>(index, |index_pointer, node| {
+        if !node.is_deleted() && node.get_index_pointer_to_neighbors().len() > num_neighbors {
+            nodes.push(index_pointer);
+        }
+    });
+
+    let graph = Graph::new(GraphNeighborStore::Disk, meta_page);
+    let mut stats = PruneNeighborStats::new();
+    for &index_pointer in nodes.iter() {
+        check_for_interrupts!();
+        let mut neighbors = vec![];
+        storage.get_neighbors_with_distances_from_disk(index_pointer, &mut neighbors, &mut stats);
+        let pruned = graph.prune_neighbors(neighbors, storage, &mut stats);
+        storage.set_neighbors_on_disk(graph.get_meta_page(), index_pointer, &pruned, &mut stats);
+    }
+    nodes.len() as i64
+}
+
+/// Lowers the num_neighbors of a diskann index without rebuilding it: every neighbor list
+/// with more than `num_neighbors` neighbors is pruned down to them, keeping the ones the
+/// neighbor selection of the index prefers, and later inserts keep within the new degree.
+/// Returns the number of neighbor lists pruned.
+///
+/// Raising num_neighbors needs a REINDEX. Nodes are written in tuples sized for the
+/// num_neighbors they were built with, so growing the lists means writing every node again
+/// in a larger tuple. That moves every node, and with it the index pointers that all the
+/// neighbor lists hold, which amounts to a rebuild.
+#[pg_extern]
+pub fn tsv_reduce_num_neighbors(index: PgRelation, num_neighbors: i32) -> i64 {
+    check_is_diskann_index(&index);
+    check_index_owner(&index);
+    unsafe { pg_sys::PreventCommandDuringRecovery("tsv_reduce_num_neighbors()".as_pg_cstr()) };
+
+    /* block concurrent inserts, which update neighbor lists too, and self-conflicting so that two
+     * calls don't prune the same lists */
+    unsafe { pg_sys::LockRelationOid(index.oid(), pg_sys::ShareRowExclusiveLock as _) };
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let mut meta_page = MetaPage::fetch(&index);
+    if num_neighbors as i64 > meta_page.get_num_neighbors() as i64 {
+        error!(
+            "num_neighbors can't be raised above {} without a rebuild, use REINDEX with the new num_neighbors instead",
+            meta_page.get_num_neighbors()
+        );
+    }
+    let build_neighbor_slack =
+        meta_page.get_num_neighbors() - meta_page.get_num_neighbors_for_build();
+    if num_neighbors as i64 <= build_neighbor_slack as i64 {
+        error!(
+            "num_neighbors must be greater than the build_neighbor_slack of the index ({})",
+            build_neighbor_slack
+        );
+    }
+
+    let mut stats = PruneNeighborStats::new();
+    MetaPage::update_num_neighbors(&index, num_neighbors as u32, &mut stats);
+    meta_page = MetaPage::fetch(&index);
+    if !meta_page.get_index_type().has_graph() {
+        return 0;
+    }
+
+    match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::load_for_insert(&index, &heap, meta_page.get_distance_function());
+            prune_storage(&storage, &index, &mut meta_page)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let storage = SbqSpeedupStorage::load_for_insert(&heap, &index, &meta_page, &mut stats);
+            prune_storage(&storage, &index, &mut meta_page)
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_tsv_rebalance() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(64, 500)?;
+        Spi::run(
+            "CREATE INDEX idx_diskann ON test_data USING diskann (embedding) WITH (num_neighbors = 20);",
+        )?;
+
+        let before_max: Option<i32> = Spi::get_one(
+            "SELECT max_in_degree FROM tsv_rebalance('idx_diskann'::regclass, 1000) WHERE stage = 'before'",
+        )?;
+        let before_max = before_max.unwrap();
+        assert!(before_max > 10, "unexpected max in-degree {}", before_max);
+
+        /* rebalance to a cap below the current maximum */
+        let cap = before_max - 5;
+        let after: Option<i32> = Spi::get_one(&format!(
+            "SELECT max_in_degree FROM tsv_rebalance('idx_diskann'::regclass, {cap}) WHERE stage = 'after'"
+        ))?;
+        assert!(after.unwrap() <= cap);
+
+        /* the graph is still searchable end to end */
+        let cnt: Option<i64> = Spi::get_one(
+            "SET enable_seqscan = 0;
+            SET diskann.query_search_list_size = 2;
+            WITH cte as (select * from test_data order by embedding <=> (SELECT embedding FROM test_data LIMIT 1)) SELECT count(*) from cte;",
+        )?;
+        assert_eq!(cnt.unwrap(), 500);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_reduce_num_neighbors() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized", "io_optimized"] {
+            crate::access_method::build::tests::create_test_data(32, 300)?;
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout}, num_neighbors = 20);"
+            ))?;
+
+            let max_degree =
+                "SELECT max(degree) FROM tsv_degree_histogram('idx_diskann'::regclass)";
+            assert!(Spi::get_one::<i32>(max_degree)?.unwrap() > 10);
+
+            let pruned = Spi::get_one::<i64>(
+                "SELECT tsv_reduce_num_neighbors('idx_diskann'::regclass, 10)",
+            )?;
+            assert!(pruned.unwrap() > 0);
+            assert!(Spi::get_one::<i32>(max_degree)?.unwrap() <= 10);
+
+            /* inserts keep within the new degree, and searches still work */
+            Spi::run(
+                "INSERT INTO test_data (embedding)
+                SELECT ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector
+                FROM generate_series(1, 32 * 50) i
+                GROUP BY i % 50;",
+            )?;
+            assert!(Spi::get_one::<i32>(max_degree)?.unwrap() <= 10);
+            Spi::run("SET enable_seqscan = 0;")?;
+            let cnt: Option<i64> = Spi::get_one(
+                "SELECT count(*) FROM (SELECT * FROM test_data ORDER BY embedding <=> (SELECT embedding FROM test_data LIMIT 1) LIMIT 20) t",
+            )?;
+            assert_eq!(cnt.unwrap(), 20);
+            Spi::run("RESET enable_seqscan;")?;
+            assert!(
+                Spi::get_one::<String>("SELECT tsv_index_ddl('idx_diskann'::regclass)")?
+                    .unwrap()
+                    .contains("num_neighbors = 10")
+            );
+        }
+
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "num_neighbors can't be raised above 20 without a rebuild, use REINDEX with the new num_neighbors instead"
+    )]
+    unsafe fn test_tsv_reduce_num_neighbors_raise() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding) WITH (num_neighbors = 20);
+            SELECT tsv_reduce_num_neighbors('idx_diskann'::regclass, 64);",
+        )?;
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
 use super::{
-    distance::distance_xor_optimized,
+    distance::{distance_xor_optimized, distances_xor_optimized},
     graph::{ListSearchNeighbor, ListSearchResult},
     graph_neighbor_store::GraphNeighborStore,
+    node_cache,
     pg_vector::PgVector,
     stats::{
         GreedySearchStats, StatsDistanceComparison, StatsHeapNodeRead, StatsNodeModify,
@@ -10,10 +11,13 @@ use super::{
     storage::{ArchivedData, NodeDistanceMeasure, Storage},
     storage_common::get_attribute_number_from_index,
 };
-use std::{cell::RefCell, collections::HashMap, iter::once, marker::PhantomData, pin::Pin};
+use std::{
+    cell::RefCell, collections::HashMap, iter::once, marker::PhantomData, pin::Pin,
+    ptr::addr_of_mut,
+};
 
 use pgrx::{
-    pg_sys::{InvalidBlockNumber, InvalidOffsetNumber, BLCKSZ},
+    pg_sys::{self, InvalidBlockNumber, InvalidOffsetNumber, BLCKSZ},
     PgRelation,
 };
 use rkyv::{vec::ArchivedVec, Archive, Deserialize, Serialize};
@@ -52,6 +56,13 @@ impl SbqMeans {
             let quantizer_item_pointer = meta_page.get_quantizer_metadata_pointer().unwrap();
             let bq = SbqMeans::read(index, quantizer_item_pointer, stats);
             let archived = bq.get_archived_node();
+            if archived.means.len() != meta_page.get_num_dimensions_to_index() as usize {
+                pgrx::error!(
+                    "SBQ means have {} dimensions but the meta page has {} dimensions to index, the index is corrupted and must be rebuilt",
+                    archived.means.len(),
+                    meta_page.get_num_dimensions_to_index()
+                );
+            }
 
             quantizer.load(
                 archived.count,
@@ -101,6 +112,29 @@ impl SbqQuantizer {
         }
     }
 
+    /// A quantizer of `num_bits_per_dimension` trained on `samples` like a build trains it on
+    /// the table, for estimating the precision of SBQ without an index.
+    pub fn train(samples: &[Vec<f32>], num_bits_per_dimension: u8) -> SbqQuantizer {
+        let num_dimensions = samples.first().map_or(0, |sample| sample.len());
+        let mut quantizer = Self {
+            use_mean: true,
+            training: true,
+            count: 0,
+            mean: vec![0.0; num_dimensions],
+            m2: if num_bits_per_dimension > 1 {
+                vec![0.0; num_dimensions]
+            } else {
+                vec![]
+            },
+            num_bits_per_dimension,
+        };
+        for sample in samples {
+            quantizer.add_sample(sample);
+        }
+        quantizer.finish_training();
+        quantizer
+    }
+
     fn load(&mut self, count: u64, mean: Vec<f32>, m2: Vec<f32>) {
         self.count = count;
         self.mean = mean;
@@ -126,7 +160,7 @@ impl SbqQuantizer {
             * std::mem::size_of::<SbqVectorElement>()
     }
 
-    fn quantize(&self, full_vector: &[f32]) -> Vec<SbqVectorElement> {
+    pub fn quantize(&self, full_vector: &[f32]) -> Vec<SbqVectorElement> {
         assert!(!self.training);
         if self.use_mean {
             let mut res_vector = vec![0; self.quantized_size(full_vector.len())];
@@ -237,6 +271,34 @@ impl SbqQuantizer {
     }
 }
 
+/// What searches do when the SBQ code of a node doesn't have the length of the codes of the
+/// quantizer, see the diskann.on_corrupt_code GUC. The variants are named as they are set.
+#[allow(non_camel_case_types)]
+#[derive(pgrx::PostgresGucEnum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OnCorruptCode {
+    /// Fail the search
+    error,
+    /// Treat the node as infinitely far from the query
+    max_distance,
+}
+
+/// Whether a code of `found` words read from a node is usable where `expected` words are
+/// (`at_least` for the codes that are cut to the dimensions of the neighbors). Fails unless
+/// diskann.on_corrupt_code is max_distance.
+fn check_code_length(found: usize, expected: usize, at_least: bool) -> bool {
+    if found == expected || (at_least && found > expected) {
+        return true;
+    }
+    if super::guc::TSV_ON_CORRUPT_CODE.get() == OnCorruptCode::error {
+        pgrx::error!(
+            "the SBQ code of a node has {} words but the quantizer expects {}, the index is corrupted and must be rebuilt",
+            found,
+            expected
+        );
+    }
+    false
+}
+
 pub struct SbqSearchDistanceMeasure {
     quantized_vector: Vec<SbqVectorElement>,
     query: PgVector,
@@ -258,6 +320,45 @@ impl SbqSearchDistanceMeasure {
         }
     }
 
+    /// Like calculate_bq_distance, for the vectors of the neighbors stored in a node read from
+    /// disk. The distances to all of them are computed in one batch.
+    pub fn calculate_neighbor_bq_distances<S: StatsDistanceComparison>(
+        &self,
+        bq_vectors: &[&[SbqVectorElement]],
+        stats: &mut S,
+    ) -> Vec<f32> {
+        debug_assert!(self.num_dimensions_for_neighbors > 0);
+        debug_assert!(self.quantized_vector.len() >= self.quantized_dimensions);
+        let query = &self.quantized_vector.as_slice()[..self.quantized_dimensions];
+        let valid: Vec<bool> = bq_vectors
+            .iter()
+            .map(|bq_vector| check_code_length(bq_vector.len(), self.quantized_dimensions, true))
+            .collect();
+        let neighbors: Vec<&[SbqVectorElement]> = bq_vectors
+            .iter()
+            .zip(valid.iter())
+            .filter(|(_, &valid)| valid)
+            .map(|(bq_vector, _)| {
+                stats.record_quantized_distance_comparison();
+                &bq_vector[..self.quantized_dimensions]
+            })
+            .collect();
+
+        let mut count_ones = Vec::with_capacity(neighbors.len());
+        distances_xor_optimized(query, &neighbors, &mut count_ones);
+        let mut count_ones = count_ones.into_iter();
+        valid
+            .into_iter()
+            .map(|valid| {
+                if valid {
+                    count_ones.next().unwrap() as f32
+                } else {
+                    f32::MAX
+                }
+            })
+            .collect()
+    }
+
     pub fn calculate_bq_distance<S: StatsDistanceComparison>(
         &self,
         bq_vector: &[SbqVectorElement],
@@ -270,13 +371,17 @@ impl SbqSearchDistanceMeasure {
             GraphNeighborStore::Disk => {
                 if self.num_dimensions_for_neighbors > 0 {
                     debug_assert!(self.quantized_vector.len() >= self.quantized_dimensions);
-                    debug_assert!(bq_vector.len() >= self.quantized_dimensions);
+                    if !check_code_length(bq_vector.len(), self.quantized_dimensions, true) {
+                        return f32::MAX;
+                    }
                     (
                         &self.quantized_vector.as_slice()[..self.quantized_dimensions],
                         &bq_vector[..self.quantized_dimensions],
                     )
                 } else {
-                    debug_assert!(self.quantized_vector.len() == bq_vector.len());
+                    if !check_code_length(bq_vector.len(), self.quantized_vector.len(), false) {
+                        return f32::MAX;
+                    }
                     (self.quantized_vector.as_slice(), bq_vector)
                 }
             }
@@ -379,6 +484,12 @@ pub struct SbqSpeedupStorage<'a> {
     heap_attr: pgrx::pg_sys::AttrNumber,
     qv_cache: RefCell<QuantizedVectorCache>,
     num_dimensions_for_neighbors: usize,
+    /// The meta page of indexes built with exact_traversal, whose searches compute the
+    /// distances to the nodes they visit from the heap. None for builds and inserts.
+    exact_traversal: Option<MetaPage>,
+    /// The LSN of the meta page to key the node cache with, None when not caching, see
+    /// node_cache::meta_lsn().
+    node_cache_lsn: Option<pgrx::pg_sys::XLogRecPtr>,
 }
 
 impl<'a> SbqSpeedupStorage<'a> {
@@ -395,9 +506,15 @@ impl<'a> SbqSpeedupStorage<'a> {
             heap_attr: get_attribute_number_from_index(index),
             qv_cache: RefCell::new(QuantizedVectorCache::new(1000)),
             num_dimensions_for_neighbors: meta_page.get_num_dimensions_for_neighbors() as usize,
+            exact_traversal: None,
+            node_cache_lsn: None,
         }
     }
 
+    pub fn get_quantizer(&self) -> &SbqQuantizer {
+        &self.quantizer
+    }
+
     fn load_quantizer<S: StatsNodeRead>(
         index_relation: &PgRelation,
         meta_page: &super::meta_page::MetaPage,
@@ -420,6 +537,8 @@ impl<'a> SbqSpeedupStorage<'a> {
             heap_attr: get_attribute_number_from_index(index_relation),
             qv_cache: RefCell::new(QuantizedVectorCache::new(1000)),
             num_dimensions_for_neighbors: meta_page.get_num_dimensions_for_neighbors() as usize,
+            exact_traversal: None,
+            node_cache_lsn: node_cache::meta_lsn(index_relation),
         }
     }
 
@@ -438,6 +557,10 @@ impl<'a> SbqSpeedupStorage<'a> {
             heap_attr: get_attribute_number_from_index(index_relation),
             qv_cache: RefCell::new(QuantizedVectorCache::new(1000)),
             num_dimensions_for_neighbors: meta_page.get_num_dimensions_for_neighbors() as usize,
+            exact_traversal: (meta_page.get_exact_traversal()
+                || super::guc::TSV_EXACT_TRAVERSAL.get())
+            .then(|| meta_page.clone()),
+            node_cache_lsn: node_cache::meta_lsn(index_relation),
         }
     }
 
@@ -451,6 +574,47 @@ impl<'a> SbqSpeedupStorage<'a> {
         node.bq_vector.as_slice().to_vec()
     }
 
+    /// The exact distance from the query to the row of the node at `index_pointer`, computed
+    /// from the vector in the heap. Nodes whose row is gone, deleted ones included, get the SBQ
+    /// distance of their quantized vector, as in searches without exact_traversal, so that the
+    /// search still goes through them to their neighbors.
+    fn get_exact_distance<S: StatsNodeRead + StatsHeapNodeRead + StatsDistanceComparison>(
+        &self,
+        meta_page: &MetaPage,
+        sdm: &SbqSearchDistanceMeasure,
+        index_pointer: IndexPointer,
+        gns: &GraphNeighborStore,
+        stats: &mut S,
+    ) -> f32 {
+        let heap_pointer = {
+            let rn = unsafe { SbqNode::read(self.index, index_pointer, stats) };
+            rn.get_archived_node()
+                .heap_item_pointer
+                .deserialize_item_pointer()
+        };
+        if heap_pointer.offset != InvalidOffsetNumber {
+            let slot = unsafe {
+                TableSlot::new_visible(
+                    self.heap_rel,
+                    heap_pointer,
+                    addr_of_mut!(pg_sys::SnapshotAnyData),
+                    stats,
+                )
+            };
+            if let Some(datum) = slot.and_then(|slot| unsafe { slot.get_attribute(self.heap_attr) })
+            {
+                return unsafe {
+                    sdm.query
+                        .full_distance_to_heap_datum(datum, meta_page, self.distance_fn)
+                };
+            }
+        }
+
+        /* the node is read again rather than kept locked while the heap is read */
+        let rn = unsafe { SbqNode::read(self.index, index_pointer, stats) };
+        sdm.calculate_bq_distance(rn.get_archived_node().bq_vector.as_slice(), gns, stats)
+    }
+
     fn write_quantizer_metadata<S: StatsNodeWrite + StatsNodeModify>(&self, stats: &mut S) {
         if self.quantizer.use_mean {
             let index_pointer = unsafe { SbqMeans::store(&self.index, &self.quantizer, stats) };
@@ -476,18 +640,83 @@ impl<'a> SbqSpeedupStorage<'a> {
                 let rn_visiting =
                     unsafe { SbqNode::read(self.index, lsn_index_pointer, &mut lsr.stats) };
                 let node_visiting = rn_visiting.get_archived_node();
+                if !lsr.may_visit_neighbors(node_visiting.is_deleted()) {
+                    return;
+                }
                 //OPT: get neighbors from private data just like plain storage in the self.num_dimensions_for_neighbors == 0 case
                 let neighbors = node_visiting.get_index_pointer_to_neighbors();
 
-                for (i, &neighbor_index_pointer) in neighbors.iter().enumerate() {
+                if let Some(meta_page) = &self.exact_traversal {
+                    for &neighbor_index_pointer in neighbors.iter() {
+                        if !lsr.prepare_insert(neighbor_index_pointer) {
+                            continue;
+                        }
+                        let distance = self.get_exact_distance(
+                            meta_page,
+                            lsr.sdm.as_ref().unwrap(),
+                            neighbor_index_pointer,
+                            gns,
+                            &mut lsr.stats,
+                        );
+                        let lsn = ListSearchNeighbor::new(
+                            neighbor_index_pointer,
+                            distance,
+                            PhantomData::<bool>,
+                        );
+                        lsr.insert_neighbor(lsn);
+                    }
+                    return;
+                }
+
+                if self.num_dimensions_for_neighbors > 0 {
+                    /* the vectors of the neighbors are stored in the node itself,
+                    compare the query to all the unseen ones in one go */
+                    let mut index_pointers = Vec::with_capacity(neighbors.len());
+                    let mut bq_vectors = Vec::with_capacity(neighbors.len());
+                    for (i, &neighbor_index_pointer) in neighbors.iter().enumerate() {
+                        if lsr.prepare_insert(neighbor_index_pointer) {
+                            index_pointers.push(neighbor_index_pointer);
+                            bq_vectors.push(node_visiting.neighbor_vectors[i].as_slice());
+                        }
+                    }
+                    let distances = lsr
+                        .sdm
+                        .as_ref()
+                        .unwrap()
+                        .calculate_neighbor_bq_distances(&bq_vectors, &mut lsr.stats);
+
+                    for (neighbor_index_pointer, distance) in
+                        index_pointers.into_iter().zip(distances)
+                    {
+                        let lsn = ListSearchNeighbor::new(
+                            neighbor_index_pointer,
+                            distance,
+                            PhantomData::<bool>,
+                        );
+                        lsr.insert_neighbor(lsn);
+                    }
+                    return;
+                }
+
+                for &neighbor_index_pointer in neighbors.iter() {
                     if !lsr.prepare_insert(neighbor_index_pointer) {
                         continue;
                     }
 
-                    let distance = if self.num_dimensions_for_neighbors > 0 {
-                        let bq_vector = node_visiting.neighbor_vectors[i].as_slice();
+                    let distance = if let Some(meta_lsn) = self.node_cache_lsn {
+                        let bq_vector = node_cache::get_or_read(
+                            self.index,
+                            meta_lsn,
+                            neighbor_index_pointer,
+                            || {
+                                self.get_quantized_vector_from_index_pointer(
+                                    neighbor_index_pointer,
+                                    &mut lsr.stats,
+                                )
+                            },
+                        );
                         lsr.sdm.as_ref().unwrap().calculate_bq_distance(
-                            bq_vector,
+                            &bq_vector,
                             gns,
                             &mut lsr.stats,
                         )
@@ -552,7 +781,10 @@ pub type SbqSpeedupStorageLsnPrivateData = PhantomData<bool>; //no data stored
 
 impl<'a> Storage for SbqSpeedupStorage<'a> {
     type QueryDistanceMeasure = SbqSearchDistanceMeasure;
-    type NodeDistanceMeasure<'b> = SbqNodeDistanceMeasure<'b> where Self: 'b;
+    type NodeDistanceMeasure<'b>
+        = SbqNodeDistanceMeasure<'b>
+    where
+        Self: 'b;
     type ArchivedType = ArchivedSbqNode;
     type LSNPrivateData = SbqSpeedupStorageLsnPrivateData; //no data stored
 
@@ -644,8 +876,10 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
         let slot = unsafe { self.get_heap_table_slot_from_heap_pointer(heap_pointer, stats) };
 
         let datum = unsafe { slot.get_attribute(self.heap_attr).unwrap() };
-        let vec = unsafe { PgVector::from_datum(datum, meta_page, false, true) };
-        self.get_distance_function()(vec.to_full_slice(), qdm.query.to_full_slice())
+        unsafe {
+            qdm.query
+                .full_distance_to_heap_datum(datum, meta_page, self.get_distance_function())
+        }
     }
 
     fn get_neighbors_with_distances_from_disk<S: StatsNodeRead + StatsDistanceComparison>(
@@ -679,6 +913,17 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
             panic!("should not have had an init id already inserted");
         }
 
+        if let Some(meta_page) = &self.exact_traversal {
+            let distance = self.get_exact_distance(
+                meta_page,
+                lsr.sdm.as_ref().unwrap(),
+                index_pointer,
+                gns,
+                &mut lsr.stats,
+            );
+            return ListSearchNeighbor::new(index_pointer, distance, PhantomData::<bool>);
+        }
+
         let rn = unsafe { SbqNode::read(self.index, index_pointer, &mut lsr.stats) };
         let node = rn.get_archived_node();
 
@@ -736,6 +981,31 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
         node.commit();
     }
 
+    fn append_neighbor_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        neighbor: IndexPointer,
+        stats: &mut S,
+    ) -> bool {
+        /* read the vector of the neighbor before taking the lock, as in set_neighbors_on_disk */
+        let mut cache = QuantizedVectorCache::new(1);
+        if meta.get_num_dimensions_for_neighbors() > 0 {
+            cache.preload(once(neighbor), self, stats);
+        }
+
+        let node = unsafe { SbqNode::modify(self.index, index_pointer, stats) };
+        let archived = node.get_archived_node();
+        if archived.iter_neighbors().any(|n| n == neighbor) {
+            return true;
+        }
+        if !archived.append_neighbor(neighbor, meta, &cache) {
+            return false;
+        }
+        node.commit();
+        true
+    }
+
     fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
         self.distance_fn
     }
@@ -745,6 +1015,7 @@ use pgvectorscale_derive::{Readable, Writeable};
 
 #[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
 #[archive(check_bytes)]
+#[page_type(SbqNode)]
 pub struct SbqNode {
     pub heap_item_pointer: HeapPointer,
     pub bq_vector: Vec<u64>, //don't use SbqVectorElement because we don't want to change the size in on-disk format by accident
@@ -806,7 +1077,7 @@ impl SbqNode {
         }
     }
 
-    fn test_size(
+    pub fn test_size(
         num_neighbors: usize,
         num_dimensions: usize,
         num_dimensions_for_neighbors: usize,
@@ -907,6 +1178,52 @@ impl ArchivedSbqNode {
         }
     }
 
+    /// Adds `neighbor` after the last neighbor if the list has room for it, and returns
+    /// whether it did. The quantized vector of `neighbor` must be in `cache` if the node stores
+    /// the vectors of its neighbors.
+    fn append_neighbor(
+        mut self: Pin<&mut Self>,
+        neighbor: ItemPointer,
+        meta_page: &MetaPage,
+        cache: &QuantizedVectorCache,
+    ) -> bool {
+        let num_neighbors = self.num_neighbors();
+        let capacity = std::cmp::min(
+            meta_page.get_num_neighbors() as usize,
+            self.neighbor_index_pointers.len(),
+        );
+        if num_neighbors >= capacity {
+            return false;
+        }
+
+        let mut a_index_pointer = self
+            .as_mut()
+            .neighbor_index_pointer()
+            .index_pin(num_neighbors);
+        a_index_pointer.block_number = neighbor.block_number;
+        a_index_pointer.offset = neighbor.offset;
+
+        if meta_page.get_num_dimensions_for_neighbors() > 0 {
+            let quantized = &cache.must_get(neighbor)[..SbqQuantizer::quantized_size_internal(
+                meta_page.get_num_dimensions_for_neighbors() as _,
+                meta_page.get_bq_num_bits_per_dimension(),
+            )];
+            let mut neighbor_vector = self.as_mut().neighbor_vector().index_pin(num_neighbors);
+            for (index_in_q_vec, val) in quantized.iter().enumerate() {
+                let mut x = neighbor_vector.as_mut().index_pin(index_in_q_vec);
+                *x = *val;
+            }
+        }
+
+        if num_neighbors + 1 < capacity {
+            let mut past_last_index_pointers =
+                self.neighbor_index_pointer().index_pin(num_neighbors + 1);
+            past_last_index_pointers.block_number = InvalidBlockNumber;
+            past_last_index_pointers.offset = InvalidOffsetNumber;
+        }
+        true
+    }
+
     pub fn num_neighbors(&self) -> usize {
         self.neighbor_index_pointers
             .iter()
@@ -1075,4 +1392,82 @@ mod tests {
         )?;
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_flat_index_creation() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = memory_optimized, index_type = flat",
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bq_compressed_storage_flat_delete_vacuum_plain() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_plain_scaffold(
+            "storage_layout = memory_optimized, index_type = flat",
+        );
+    }
+
+    #[pg_test]
+    unsafe fn test_exact_traversal_guc() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 300)?;
+        Spi::run(
+            "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = memory_optimized);
+
+            SET enable_seqscan = 0;
+            SET diskann.trace_candidates = on;",
+        )?;
+
+        let query = "SELECT count(*) FROM (SELECT * FROM test_data ORDER BY embedding <=> (SELECT embedding FROM test_data WHERE ctid = '(0,1)') LIMIT 10) t";
+        let max_error = "SELECT max(abs(index_distance - full_distance)) FROM tsv_traced_candidates('idx_diskann'::regclass)";
+
+        /* the SBQ distances are approximate */
+        Spi::get_one::<i64>(query)?;
+        assert!(Spi::get_one::<f64>(max_error)?.unwrap() > 1e-3);
+
+        /* with the GUC on, the searches of the same index use the exact ones */
+        Spi::run("SELECT tsv_trace_reset(); SET diskann.exact_traversal = on;")?;
+        assert_eq!(Spi::get_one::<i64>(query)?.unwrap(), 10);
+        assert!(Spi::get_one::<f64>(max_error)?.unwrap() < 1e-5);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_heap_reads_only_for_returned_rows() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 1000)?;
+        Spi::run(
+            "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = memory_optimized);
+
+            CREATE TEMP TABLE target AS SELECT embedding FROM test_data LIMIT 1;",
+        )?;
+
+        let stats = "SELECT heap_reads, visited_nodes FROM tsv_search_stats('idx_diskann'::regclass, (SELECT embedding FROM target), 10)";
+
+        /* the traversal uses the SBQ distances, the table is only read for the returned rows */
+        let (lazy_heap_reads, visited) = Spi::get_two::<i64, i64>(stats)?;
+        assert_eq!(lazy_heap_reads, Some(10));
+        assert!(visited.unwrap() > 10);
+
+        /* computing the exact distances throughout reads the table for every visited node */
+        Spi::run("SET diskann.exact_traversal = on")?;
+        let (exact_heap_reads, _) = Spi::get_two::<i64, i64>(stats)?;
+        info!(
+            "heap reads for 10 rows: {} with SBQ traversal, {} with exact traversal",
+            lazy_heap_reads.unwrap(),
+            exact_heap_reads.unwrap()
+        );
+        assert!(exact_heap_reads.unwrap() > visited.unwrap());
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_flat_empty_table_insert() -> spi::Result<()> {
+        crate::access_method::build::tests::test_empty_table_insert_scaffold(
+            "storage_layout = memory_optimized, index_type = flat",
+        )
+    }
 }
@@ -1,10 +1,12 @@
-use std::collections::BinaryHeap;
+use std::{collections::BinaryHeap, time::Instant};
 
 use pgrx::{pg_sys::InvalidOffsetNumber, *};
 
 use crate::{
     access_method::{
-        graph_neighbor_store::GraphNeighborStore, meta_page::MetaPage, pg_vector::PgVector,
+        graph_neighbor_store::GraphNeighborStore,
+        meta_page::MetaPage,
+        pg_vector::{adapt_query_datum, free_adapted_datum, PgVector},
         sbq::SbqSpeedupStorage,
     },
     util::{buffer::PinnedBufferShare, HeapPointer, IndexPointer},
@@ -12,14 +14,20 @@ use crate::{
 
 use super::{
     graph::{Graph, ListSearchResult},
+    plain_node::StoredVectorType,
     plain_storage::{PlainDistanceMeasure, PlainStorage, PlainStorageLsnPrivateData},
     sbq::{SbqMeans, SbqQuantizer, SbqSearchDistanceMeasure, SbqSpeedupStorageLsnPrivateData},
     stats::QuantizerStats,
     storage::{Storage, StorageType},
+    storage_common::live_nodes,
 };
 
 /* Be very careful not to transfer PgRelations in the state, as they can change between calls. That means we shouldn't be
-using lifetimes here. Everything should be owned */
+using lifetimes here. Everything should be owned.
+
+The quantizer is loaded from the pages of the index when the scan starts. The scan holds its AccessShareLock on the index
+until it ends, which keeps REINDEX out (it needs an AccessExclusiveLock, and refuses to run under a scan of the same
+session) and makes REINDEX CONCURRENTLY wait before it swaps the indexes, so a scan never sees parts of two builds. */
 enum StorageState {
     SbqSpeedup(
         SbqQuantizer,
@@ -34,6 +42,8 @@ struct TSVScanState {
     distance_fn: Option<fn(&[f32], &[f32]) -> f32>,
     meta_page: MetaPage,
     last_buffer: Option<PinnedBufferShare>,
+    /// Whether the scan returned a row since it was last (re)started.
+    returned_any: bool,
 }
 
 impl TSVScanState {
@@ -43,6 +53,7 @@ impl TSVScanState {
             distance_fn: None,
             meta_page: meta_page,
             last_buffer: None,
+            returned_any: false,
         }
     }
 
@@ -78,9 +89,21 @@ impl TSVScanState {
 
         self.storage = PgMemoryContexts::CurrentMemoryContext.leak_and_drop_on_delete(store_type);
         self.distance_fn = Some(distance);
+        self.returned_any = false;
     }
 }
 
+/// What an index scan does when it finds no rows at all, see the diskann.on_empty_result GUC.
+/// The variants are named as they are set.
+#[allow(non_camel_case_types)]
+#[derive(PostgresGucEnum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OnEmptyResult {
+    /// Return no rows, like any other scan
+    empty,
+    /// Fail the query
+    error,
+}
+
 struct ResortData {
     heap_pointer: HeapPointer,
     index_pointer: IndexPointer,
@@ -151,6 +174,15 @@ impl StreamingStats {
     }
 }
 
+/// The search list size up to which diskann.target_recall grows the search list,
+/// the maximum of diskann.query_search_list_size
+const TARGET_RECALL_MAX_SEARCH_LIST_SIZE: usize = 10000;
+
+/// Only check the ratio of deleted nodes once a scan has consumed at least this many nodes
+const DELETED_RATIO_MIN_NODES: usize = 100;
+/// Warn when more than this fraction of the nodes consumed by a scan are deleted
+const DELETED_RATIO_WARNING_THRESHOLD: f64 = 0.5;
+
 struct TSVResponseIterator<QDM, PD> {
     lsr: ListSearchResult<QDM, PD>,
     search_list_size: usize,
@@ -162,6 +194,10 @@ struct TSVResponseIterator<QDM, PD> {
     next_calls: i32,
     next_calls_with_resort: i32,
     full_distance_comparisons: i32,
+    started: Instant,
+    deleted_nodes_consumed: usize,
+    live_nodes_consumed: usize,
+    warned_deleted_ratio: bool,
 }
 
 impl<QDM, PD> TSVResponseIterator<QDM, PD> {
@@ -177,7 +213,20 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
         let mut meta_page = MetaPage::fetch(&index);
         let graph = Graph::new(GraphNeighborStore::Disk, &mut meta_page);
 
-        let lsr = graph.greedy_search_streaming_init(query, search_list_size, storage);
+        let traced_query = super::trace::is_enabled().then(|| query.to_full_slice().to_vec());
+        let mut lsr = if graph.get_meta_page().get_index_type().has_graph() {
+            graph.greedy_search_streaming_init(query, search_list_size, storage)
+        } else {
+            graph.greedy_search_streaming_init_from(
+                live_nodes::<S>(index),
+                query,
+                search_list_size,
+                storage,
+            )
+        };
+        if let Some(traced_query) = traced_query {
+            lsr.start_trace(index, &traced_query);
+        }
         let resort_size = super::guc::TSV_RESORT_SIZE.get() as usize;
 
         Self {
@@ -191,6 +240,10 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
             next_calls: 0,
             next_calls_with_resort: 0,
             full_distance_comparisons: 0,
+            started: Instant::now(),
+            deleted_nodes_consumed: 0,
+            live_nodes_consumed: 0,
+            warned_deleted_ratio: false,
         }
     }
 }
@@ -203,6 +256,28 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
         self.next_calls += 1;
         let graph = Graph::new(GraphNeighborStore::Disk, &mut self.meta_page);
 
+        if self.next_calls == 1 {
+            /* grow the search list until the estimated recall reaches diskann.target_recall */
+            let target_recall = super::guc::TSV_TARGET_RECALL.get();
+            if target_recall > 0.0 {
+                graph.greedy_search_iterate(&mut self.lsr, self.search_list_size, None, storage);
+                while self.search_list_size < TARGET_RECALL_MAX_SEARCH_LIST_SIZE
+                    && self.lsr.estimated_recall(self.search_list_size) < target_recall
+                {
+                    self.search_list_size = std::cmp::min(
+                        self.search_list_size * 2,
+                        TARGET_RECALL_MAX_SEARCH_LIST_SIZE,
+                    );
+                    graph.greedy_search_iterate(
+                        &mut self.lsr,
+                        self.search_list_size,
+                        None,
+                        storage,
+                    );
+                }
+            }
+        }
+
         /* Iterate until we find a non-deleted tuple */
         loop {
             graph.greedy_search_iterate(&mut self.lsr, self.search_list_size, None, storage);
@@ -213,8 +288,11 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
                 Some((heap_pointer, index_pointer)) => {
                     if heap_pointer.offset == InvalidOffsetNumber {
                         /* deleted tuple */
+                        self.deleted_nodes_consumed += 1;
+                        self.check_deleted_ratio();
                         continue;
                     }
+                    self.live_nodes_consumed += 1;
                     return Some((heap_pointer, index_pointer));
                 }
                 None => {
@@ -224,6 +302,24 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
         }
     }
 
+    /// Warns (once per scan) if most of the nodes the scan walks through are deleted.
+    /// This happens on churny tables between vacuums and slows searches down.
+    fn check_deleted_ratio(&mut self) {
+        let consumed = self.deleted_nodes_consumed + self.live_nodes_consumed;
+        if self.warned_deleted_ratio || consumed < DELETED_RATIO_MIN_NODES {
+            return;
+        }
+
+        let ratio = self.deleted_nodes_consumed as f64 / consumed as f64;
+        if ratio > DELETED_RATIO_WARNING_THRESHOLD {
+            self.warned_deleted_ratio = true;
+            warning!(
+                "{:.0}% of the nodes traversed by a diskann index scan were deleted, consider running VACUUM on the table or REINDEX on the index",
+                ratio * 100.0
+            );
+        }
+    }
+
     fn next_with_resort<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
         &mut self,
         _index: &PgRelation,
@@ -303,6 +399,7 @@ pub extern "C" fn ambeginscan(
     };
     let indexrel = unsafe { PgRelation::from_pg(index_relation) };
     let meta_page = MetaPage::fetch(&indexrel);
+    meta_page.check_column_dimensions(&indexrel);
 
     let state: TSVScanState = TSVScanState::new(meta_page);
     scandesc.opaque =
@@ -341,17 +438,31 @@ pub extern "C" fn amrescan(
 
     let state = unsafe { (scan.opaque as *mut TSVScanState).as_mut() }.expect("no scandesc state");
 
+    /* the query vector is already evaluated here, whether it came from a literal, a cast or a parameter */
     let query = unsafe {
-        PgVector::from_datum(
-            orderby_keys[0].sk_argument,
-            &state.meta_page,
-            true, /* needed for search */
-            true, /* needed for resort */
-        )
+        if orderby_keys[0].sk_flags & pg_sys::SK_ISNULL as i32 != 0 {
+            PgVector::zeros(&state.meta_page)
+        } else {
+            let datum = adapt_query_datum(orderby_keys[0].sk_argument, &state.meta_page);
+            let mut query = PgVector::from_datum(
+                datum,
+                &state.meta_page,
+                true, /* needed for search */
+                true, /* needed for resort */
+            );
+            if super::guc::TSV_RESCORE_WITH_PGVECTOR.get() {
+                query.use_operator_distance(&indexrel, datum);
+            }
+            free_adapted_datum(datum, orderby_keys[0].sk_argument);
+            query
+        }
     };
     state.initialize(&indexrel, &heaprel, query, search_list_size);
 }
 
+/// Returns the next row of the scan. Every call advances the search just far enough to find
+/// one more row (plus the rows rescoring looks ahead at), so a cursor only pays for the rows
+/// it fetches, and closing it ends the search.
 #[pg_guard]
 pub extern "C" fn amgettuple(
     scan: pg_sys::IndexScanDesc,
@@ -381,6 +492,7 @@ pub extern "C" fn amgettuple(
                 PlainStorage::load_for_search(&indexrel, &heaprel, state.distance_fn.unwrap());
             let next = if state.meta_page.get_num_dimensions()
                 == state.meta_page.get_num_dimensions_to_index()
+                && state.meta_page.get_stored_vector_type() == StoredVectorType::F32
             {
                 /* no need to resort */
                 iter.next(&storage)
@@ -414,10 +526,16 @@ fn get_tuple(
                 &indexrel,
                 index_pointer.block_number,
             ));
+            state.returned_any = true;
             true
         }
         None => {
             state.last_buffer = None;
+            if !state.returned_any && super::guc::TSV_ON_EMPTY_RESULT.get() == OnEmptyResult::error
+            {
+                let indexrel = unsafe { PgRelation::from_pg(scan.indexRelation) };
+                error!("diskann index \"{}\" found no rows", indexrel.name());
+            }
             false
         }
     }
@@ -430,35 +548,503 @@ pub extern "C" fn amendscan(scan: pg_sys::IndexScanDesc) {
         let c = pg_sys::client_min_messages;
         std::cmp::min(l, c)
     };
-    if min_level <= pg_sys::DEBUG1 as _ {
+    let log_min_duration = super::guc::TSV_LOG_MIN_DURATION_SEARCH.get();
+    if min_level <= pg_sys::DEBUG1 as _ || log_min_duration >= 0 {
         let scan: PgBox<pg_sys::IndexScanDescData> = unsafe { PgBox::from_pg(scan) };
         let state =
             unsafe { (scan.opaque as *mut TSVScanState).as_mut() }.expect("no scandesc state");
 
-        let mut storage = unsafe { state.storage.as_mut() }.expect("no storage in state");
+        /* the scan may end without ever being started (e.g. the executor never fetched a tuple) */
+        let mut storage = match unsafe { state.storage.as_mut() } {
+            Some(storage) => storage,
+            None => return,
+        };
         match &mut storage {
-            StorageState::SbqSpeedup(_bq, iter) => end_scan::<SbqSpeedupStorage>(iter),
-            StorageState::Plain(iter) => end_scan::<PlainStorage>(iter),
+            StorageState::SbqSpeedup(_bq, iter) => {
+                end_scan::<SbqSpeedupStorage>(iter, min_level, log_min_duration)
+            }
+            StorageState::Plain(iter) => {
+                end_scan::<PlainStorage>(iter, min_level, log_min_duration)
+            }
         }
     }
 }
 
 fn end_scan<S: Storage>(
     iter: &mut TSVResponseIterator<S::QueryDistanceMeasure, S::LSNPrivateData>,
+    min_level: i32,
+    log_min_duration: i32,
 ) {
     debug_assert!(iter.quantizer_stats.node_reads == 1);
     debug_assert!(iter.quantizer_stats.node_writes == 0);
 
-    debug1!(
-        "Query stats - reads_index={} reads_heap={} d_total={} d_quantized={} d_full={} next={} resort={} visits={} candidate={}",
-        iter.lsr.stats.get_node_reads(),
-        iter.lsr.stats.get_node_heap_reads(),
-        iter.lsr.stats.get_total_distance_comparisons(),
-        iter.lsr.stats.get_quantized_distance_comparisons(),
-        iter.full_distance_comparisons,
-        iter.next_calls,
-        iter.next_calls_with_resort,
-        iter.lsr.stats.get_visited_nodes(),
-        iter.lsr.stats.get_candidate_nodes(),
-    );
+    if min_level <= pg_sys::DEBUG1 as _ {
+        debug1!(
+            "Query stats - reads_index={} reads_heap={} d_total={} d_quantized={} d_full={} next={} resort={} visits={} candidate={} deleted={}",
+            iter.lsr.stats.get_node_reads(),
+            iter.lsr.stats.get_node_heap_reads(),
+            iter.lsr.stats.get_total_distance_comparisons(),
+            iter.lsr.stats.get_quantized_distance_comparisons(),
+            iter.full_distance_comparisons,
+            iter.next_calls,
+            iter.next_calls_with_resort,
+            iter.lsr.stats.get_visited_nodes(),
+            iter.lsr.stats.get_candidate_nodes(),
+            iter.deleted_nodes_consumed,
+        );
+    }
+
+    let duration = iter.started.elapsed();
+    if log_min_duration >= 0 && duration.as_millis() >= log_min_duration as u128 {
+        log!(
+            "diskann search duration: {:.3} ms reads_index={} reads_heap={} d_total={} d_quantized={} d_full={} next={} resort={} visits={} candidate={} deleted={}",
+            duration.as_secs_f64() * 1000.0,
+            iter.lsr.stats.get_node_reads(),
+            iter.lsr.stats.get_node_heap_reads(),
+            iter.lsr.stats.get_total_distance_comparisons(),
+            iter.lsr.stats.get_quantized_distance_comparisons(),
+            iter.full_distance_comparisons,
+            iter.next_calls,
+            iter.next_calls_with_resort,
+            iter.lsr.stats.get_visited_nodes(),
+            iter.lsr.stats.get_candidate_nodes(),
+            iter.deleted_nodes_consumed,
+        );
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_log_min_duration_search() -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET diskann.log_min_duration_search = 0;
+            ",
+        ))?;
+
+        let res: Option<i64> = Spi::get_one(&format!(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by embedding <=> '[0,0,0]') SELECT count(*) from cte;",
+        ))?;
+        assert_eq!(3, res.unwrap());
+
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "diskann index search exceeded diskann.search_work_mem (64kB), increase it or lower the number of rows requested"
+    )]
+    unsafe fn test_search_work_mem() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) SELECT ARRAY[i, i + 1, i * 2]::vector FROM generate_series(1, 1000) i;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;",
+        )?;
+
+        /* no limit by default */
+        let res: Option<i64> = Spi::get_one(
+            "WITH cte as (select * from test order by embedding <=> '[1,2,3]' LIMIT 1000000) SELECT count(*) from cte;",
+        )?;
+        assert_eq!(1000, res.unwrap());
+
+        Spi::run("SET diskann.search_work_mem = 64;")?;
+        Spi::get_one::<i64>(
+            "WITH cte as (select * from test order by embedding <=> '[1,2,3]' LIMIT 1000000) SELECT count(*) from cte;",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_max_search_hops() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) SELECT ARRAY[i, i + 1, i * 2]::vector FROM generate_series(1, 1000) i;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH (num_neighbors = 10);
+
+            SET enable_seqscan = 0;",
+        )?;
+
+        let count_all = "WITH cte as (select * from test order by embedding <=> '[1,2,3]' LIMIT 1000000) SELECT count(*) from cte;";
+        let res: Option<i64> = Spi::get_one(count_all)?;
+        assert_eq!(1000, res.unwrap());
+
+        /* the search stops after expanding 3 nodes, and only returns the nodes it has seen by then */
+        Spi::run("SET diskann.max_search_hops = 3;")?;
+        let res: Option<i64> = Spi::get_one(count_all)?;
+        let seen = res.unwrap();
+        assert!(seen > 0, "no rows returned");
+        assert!(seen <= 1 + 3 * 10, "{seen} rows returned");
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_target_recall() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) SELECT ARRAY[i, i + 1, i * 2]::vector FROM generate_series(1, 1000) i;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;
+            SET diskann.query_search_list_size = 10;
+            SET diskann.target_recall = 0.99;",
+        )?;
+
+        let res: Option<i64> = Spi::get_one(
+            "WITH cte as (select * from test order by embedding <=> '[1,2,3]' LIMIT 1000000) SELECT count(*) from cte;",
+        )?;
+        assert_eq!(1000, res.unwrap());
+
+        let nearest: Option<String> = Spi::get_one(
+            "SELECT embedding::text FROM test ORDER BY embedding <=> '[500,501,1000]' LIMIT 1;",
+        )?;
+        assert_eq!(nearest.unwrap(), "[500,501,1000]");
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "diskann index \"idxtest\" was built for vectors of 3 dimensions but the column now has 4 dimensions, REINDEX the index"
+    )]
+    unsafe fn test_column_dimensions_changed() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            UPDATE pg_attribute SET atttypmod = 4
+             WHERE attrelid = 'test'::regclass AND attname = 'embedding';
+
+            SET enable_seqscan = 0;",
+        )?;
+
+        Spi::run("SELECT * FROM test ORDER BY embedding <=> '[1,2,3,4]' LIMIT 1;")?;
+        Ok(())
+    }
+
+    #[pg_test(error = "different vector dimensions 3 and 4")]
+    unsafe fn test_query_dimensions_mismatch() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;",
+        )?;
+
+        Spi::run("SELECT * FROM test ORDER BY embedding <=> '[1,2,3,4]'::vector LIMIT 1;")?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_dimension_mode() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]'), ('[1,0,0]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;",
+        )?;
+
+        Spi::run("SET diskann.dimension_mode = truncate;")?;
+        let nearest: Option<String> = Spi::get_one(
+            "SELECT embedding::text FROM test ORDER BY embedding <=> '[7,8,10,100]'::vector LIMIT 1;",
+        )?;
+        assert_eq!(nearest.unwrap(), "[7,8,10]");
+
+        Spi::run("SET diskann.dimension_mode = pad_zero;")?;
+        let nearest: Option<String> = Spi::get_one(
+            "SELECT embedding::text FROM test ORDER BY embedding <=> '[1,0.01]'::vector LIMIT 1;",
+        )?;
+        assert_eq!(nearest.unwrap(), "[1,0,0]");
+
+        /* the SQL functions adapt their query the same way */
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM tsv_search_multimetric('idxtest'::regclass, '[1,0.01]', 4)",
+        )?;
+        assert_eq!(cnt.unwrap(), 4);
+        Ok(())
+    }
+
+    #[pg_test(error = "different vector dimensions 3 and 4")]
+    unsafe fn test_dimension_mode_pad_zero_longer_query() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;
+            SET diskann.dimension_mode = pad_zero;",
+        )?;
+
+        Spi::run("SELECT * FROM test ORDER BY embedding <=> '[1,2,3,4]'::vector LIMIT 1;")?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_on_empty_result() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;",
+        )?;
+
+        /* an empty result by default */
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM (SELECT * FROM test ORDER BY embedding <=> '[1,2,3]' LIMIT 1) t",
+        )?;
+        assert_eq!(cnt.unwrap(), 0);
+
+        /* scans that find rows are unaffected, whether they stop early or run out of rows */
+        Spi::run(
+            "INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+            SET diskann.on_empty_result = error;",
+        )?;
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM (SELECT * FROM test ORDER BY embedding <=> '[1,2,3]' LIMIT 1) t",
+        )?;
+        assert_eq!(cnt.unwrap(), 1);
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM (SELECT * FROM test ORDER BY embedding <=> '[1,2,3]' LIMIT 10) t",
+        )?;
+        assert_eq!(cnt.unwrap(), 3);
+        Ok(())
+    }
+
+    #[pg_test(error = "diskann index \"idxtest\" found no rows")]
+    unsafe fn test_on_empty_result_error() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;
+            SET diskann.on_empty_result = error;",
+        )?;
+
+        Spi::run("SELECT * FROM test ORDER BY embedding <=> '[1,2,3]' LIMIT 1;")?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_text_literal_query_uses_index() -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;
+            ",
+        ))?;
+
+        for query_vector in ["'[1,2,3]'", "'[1,2,3]'::vector", "'[1,2,3]'::text::vector"] {
+            let explain: Option<pgrx::datum::Json> = Spi::get_one(&format!(
+                "EXPLAIN (format json) SELECT * FROM test ORDER BY embedding <=> {query_vector} LIMIT 2;"
+            ))?;
+            let plan = explain.unwrap().0.to_string();
+            assert!(
+                plan.contains("idxtest"),
+                "query vector {} does not use the index: {}",
+                query_vector,
+                plan
+            );
+
+            let res: Option<i64> = Spi::get_one(&format!(
+                "WITH cte as (select * from test order by embedding <=> {query_vector}) SELECT count(*) from cte;"
+            ))?;
+            assert_eq!(3, res.unwrap());
+        }
+
+        /* a NULL query vector returns all the rows in an arbitrary order */
+        let res: Option<i64> = Spi::get_one(
+            "WITH cte as (select * from test order by embedding <=> NULL::vector) SELECT count(*) from cte;",
+        )?;
+        assert_eq!(3, res.unwrap());
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_prepared_statement_query_vector() -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;
+            SET plan_cache_mode = force_generic_plan;
+
+            PREPARE nearest(vector) AS
+                SELECT embedding::text FROM test ORDER BY embedding <=> $1 LIMIT 1;
+            ",
+        ))?;
+
+        /* the generic plan keeps the index path with the parameter as the query vector */
+        let explain: Option<pgrx::datum::Json> =
+            Spi::get_one("EXPLAIN (format json) EXECUTE nearest('[1,2,3]');")?;
+        let plan = explain.unwrap().0.to_string();
+        assert!(
+            plan.contains("idxtest"),
+            "generic plan does not use the index: {}",
+            plan
+        );
+
+        /* each execution uses its own parameter value */
+        let res: Option<String> = Spi::get_one("EXECUTE nearest('[1,2,3]');")?;
+        assert_eq!(res.unwrap(), "[1,2,3]");
+        let res: Option<String> = Spi::get_one("EXECUTE nearest('[7,8,10]');")?;
+        assert_eq!(res.unwrap(), "[7,8,10]");
+
+        Spi::run("DEALLOCATE nearest;")?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "cannot REINDEX INDEX \"idxtest\" because it is being used by active queries in this session"
+    )]
+    unsafe fn test_reindex_under_open_scan() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) SELECT ARRAY[i, i + 1, i * 2]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding);
+
+            SET enable_seqscan = 0;
+
+            DECLARE c CURSOR FOR SELECT * FROM test ORDER BY embedding <=> '[1,2,3]';
+            FETCH 10 FROM c;
+
+            REINDEX INDEX idxtest;",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_reindex_after_scan() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) SELECT ARRAY[i, i + 1, i * 2]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding) WITH (storage_layout = memory_optimized);
+
+            SET enable_seqscan = 0;
+
+            DECLARE c CURSOR FOR SELECT * FROM test ORDER BY embedding <=> '[1,2,3]';
+            FETCH 10 FROM c;",
+        )?;
+
+        /* once the scan is closed, REINDEX rebuilds the index and its quantizer */
+        Spi::run(
+            "CLOSE c;
+            INSERT INTO test(embedding) SELECT ARRAY[i, -i, 0]::vector FROM generate_series(1, 300) i;
+            REINDEX INDEX idxtest;",
+        )?;
+        let nearest = Spi::get_one::<f64>(
+            "SELECT embedding <=> '[1,-1,0]' FROM test ORDER BY embedding <=> '[1,-1,0]' LIMIT 1",
+        )?;
+        assert!(nearest.unwrap() < 0.0001, "{:?}", nearest);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_cursor_fetches_incrementally() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 3000)?;
+        Spi::run(
+            "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (storage_layout = plain, num_neighbors = 10);
+
+            SET enable_seqscan = 0;
+            SET diskann.query_search_list_size = 10;
+            SET diskann.trace_candidates = on;
+
+            DECLARE c CURSOR FOR SELECT * FROM test_data ORDER BY embedding <=> (SELECT embedding FROM test_data LIMIT 1);
+            FETCH 10 FROM c;",
+        )?;
+        let candidates = || {
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM tsv_traced_candidates('idx_diskann'::regclass)",
+            )
+            .map(|count| count.unwrap())
+        };
+
+        /* the first rows only cost a small part of the index */
+        let first = candidates()?;
+        assert!(first > 0 && first < 1000, "{}", first);
+
+        /* fetching more rows continues the same search */
+        Spi::run("FETCH 10 FROM c;")?;
+        let second = candidates()?;
+        assert!(second >= first, "{} {}", first, second);
+        Spi::run("FETCH 1000 FROM c;")?;
+        let third = candidates()?;
+        assert!(third > second, "{} {}", second, third);
+
+        /* closing the cursor ends it */
+        Spi::run("CLOSE c;")?;
+        assert_eq!(candidates()?, third);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,1680 @@
+//! Index searches used by the SQL-callable functions (as opposed to index scans, see scan.rs).
+
+use std::collections::{HashMap, HashSet};
+
+use pgrx::{
+    pg_sys::{AsPgCStr, InvalidOffsetNumber},
+    *,
+};
+use rand::seq::SliceRandom;
+
+use crate::util::{table_slot::TableSlot, HeapPointer, IndexPointer};
+
+use super::graph::Graph;
+use super::graph_neighbor_store::GraphNeighborStore;
+use super::meta_page::MetaPage;
+use super::pg_vector::{
+    decrypt_datum, free_decrypted_datum, heap_datum_to_vec, PgVector, VectorArg,
+};
+use super::plain_storage::PlainStorage;
+use super::sbq::{SbqMeans, SbqSpeedupStorage};
+use super::stats::{GreedySearchStats, QuantizerStats};
+use super::storage::{ArchivedData, Storage, StorageType};
+use super::storage_common::{
+    check_heap_select_privilege, check_is_diskann_index, for_each_node,
+    get_attribute_number_from_index, live_heap_pointers, live_nodes, with_node,
+};
+
+/// A row found by a search, with its exact distance to the query.
+pub struct SearchResult {
+    pub heap_pointer: HeapPointer,
+    pub index_pointer: IndexPointer,
+    pub distance: f32,
+    /// The number of hops from an entry point after which the search found the node.
+    pub discovery_hops: u32,
+    /// The visible version of the row, to read other columns from.
+    pub slot: TableSlot,
+}
+
+/// Searches the index for `query` and calls `f` with the rows visible to the active snapshot,
+/// in approximate order of distance, until `f` returns false or the graph is exhausted.
+/// The distance passed to `f` is always the exact one, computed from the heap row.
+pub fn search_index<F: FnMut(&SearchResult) -> bool>(
+    index: &PgRelation,
+    query: &VectorArg,
+    search_list_size: usize,
+    f: F,
+) -> GreedySearchStats {
+    search_index_from(index, None, query, search_list_size, f)
+}
+
+/// Like search_index, but starts the greedy search from the `start` node instead of the
+/// init ids of the index if given.
+pub fn search_index_from<F: FnMut(&SearchResult) -> bool>(
+    index: &PgRelation,
+    start: Option<IndexPointer>,
+    query: &VectorArg,
+    search_list_size: usize,
+    f: F,
+) -> GreedySearchStats {
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let meta_page = MetaPage::fetch(index);
+
+    match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::load_for_search(index, &heap, meta_page.get_distance_function());
+            search_storage(
+                &storage,
+                index,
+                &heap,
+                meta_page,
+                start,
+                query,
+                search_list_size,
+                f,
+            )
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let mut stats = QuantizerStats::new();
+            let quantizer = unsafe { SbqMeans::load(index, &meta_page, &mut stats) };
+            let storage = SbqSpeedupStorage::load_for_search(index, &heap, &quantizer, &meta_page);
+            search_storage(
+                &storage,
+                index,
+                &heap,
+                meta_page,
+                start,
+                query,
+                search_list_size,
+                f,
+            )
+        }
+    }
+}
+
+fn search_storage<S: Storage, F: FnMut(&SearchResult) -> bool>(
+    storage: &S,
+    index: &PgRelation,
+    heap: &PgRelation,
+    mut meta_page: MetaPage,
+    start: Option<IndexPointer>,
+    query: &VectorArg,
+    search_list_size: usize,
+    mut f: F,
+) -> GreedySearchStats {
+    let full_query = unsafe { query.to_full_distance_query(index, &meta_page) };
+    let heap_attr = get_attribute_number_from_index(index);
+    let distance_fn = meta_page.get_distance_function();
+    let snapshot = unsafe { pg_sys::GetActiveSnapshot() };
+
+    let search_meta_page = meta_page.clone();
+    let graph = Graph::new(GraphNeighborStore::Disk, &mut meta_page);
+    let search_query = unsafe { query.to_pg_vector(&search_meta_page) };
+    let mut lsr = match start {
+        Some(start) => graph.greedy_search_streaming_init_from(
+            vec![start],
+            search_query,
+            search_list_size,
+            storage,
+        ),
+        None if !search_meta_page.get_index_type().has_graph() => graph
+            .greedy_search_streaming_init_from(
+                live_nodes::<S>(index),
+                search_query,
+                search_list_size,
+                storage,
+            ),
+        None => graph.greedy_search_streaming_init(search_query, search_list_size, storage),
+    };
+    lsr.start_trace(index, full_query.to_full_slice());
+
+    loop {
+        graph.greedy_search_iterate(&mut lsr, search_list_size, None, storage);
+        let (heap_pointer, index_pointer, discovery_hops) =
+            match lsr.consume_with_discovery_hops(storage) {
+                Some(item) => item,
+                None => break,
+            };
+        if heap_pointer.offset == InvalidOffsetNumber {
+            /* deleted tuple */
+            continue;
+        }
+
+        let slot = unsafe { TableSlot::new_visible(heap, heap_pointer, snapshot, &mut lsr.stats) };
+        let slot = match slot {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let datum = match unsafe { slot.get_attribute(heap_attr) } {
+            Some(datum) => datum,
+            None => continue,
+        };
+        let distance = unsafe {
+            full_query.full_distance_to_heap_datum(datum, &search_meta_page, distance_fn)
+        };
+
+        let result = SearchResult {
+            heap_pointer,
+            index_pointer,
+            distance,
+            discovery_hops,
+            slot,
+        };
+        if !f(&result) {
+            break;
+        }
+    }
+    lsr.stats
+}
+
+/// Finds the node pointing to the given heap row by scanning the whole index.
+pub fn find_node_by_heap_pointer(
+    index: &PgRelation,
+    heap_pointer: HeapPointer,
+) -> Option<IndexPointer> {
+    fn find<S: Storage>(index: &PgRelation, heap_pointer: HeapPointer) -> Option<IndexPointer> {
+        let mut found = None;
+        for_each_node::<S, _>(index, |index_pointer, node| {
+            if found.is_none() && node.get_heap_item_pointer() == heap_pointer {
+                found = Some(index_pointer);
+            }
+        });
+        found
+    }
+
+    match MetaPage::fetch(index).get_storage_type() {
+        StorageType::Plain => find::<PlainStorage>(index, heap_pointer),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            find::<SbqSpeedupStorage>(index, heap_pointer)
+        }
+    }
+}
+
+/// Counts the rows within `max_distance` of `query` without returning them. The count is
+/// exact: a greedy search gives no bound on the distance of the nodes it hasn't reached, so
+/// every row of the index is compared to the query with its exact distance, at the cost of a
+/// pass over the index and a fetch of every row.
+#[pg_extern]
+pub fn tsv_count_within(index: PgRelation, query: VectorArg, max_distance: f64) -> i64 {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let meta_page = MetaPage::fetch(&index);
+    let heap_pointers = match meta_page.get_storage_type() {
+        StorageType::Plain => live_heap_pointers::<PlainStorage>(&index),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            live_heap_pointers::<SbqSpeedupStorage>(&index)
+        }
+    };
+
+    let full_query = unsafe { query.to_full_distance_query(&index, &meta_page) };
+    let heap_attr = get_attribute_number_from_index(&index);
+    let distance_fn = meta_page.get_distance_function();
+    let snapshot = unsafe { pg_sys::GetActiveSnapshot() };
+    let mut stats = GreedySearchStats::new();
+
+    let mut count = 0;
+    for heap_pointer in heap_pointers {
+        check_for_interrupts!();
+        let slot =
+            match unsafe { TableSlot::new_visible(&heap, heap_pointer, snapshot, &mut stats) } {
+                Some(slot) => slot,
+                None => continue,
+            };
+        let datum = match unsafe { slot.get_attribute(heap_attr) } {
+            Some(datum) => datum,
+            None => continue,
+        };
+        let distance =
+            unsafe { full_query.full_distance_to_heap_datum(datum, &meta_page, distance_fn) };
+        if distance as f64 <= max_distance {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Runs a greedy search for `query` starting from the node of the heap row `start_ctid`,
+/// with the given search list size, and returns the `k` closest rows found along with
+/// the stats of the search. Meant for studying how the entry point affects recall.
+#[pg_extern]
+pub fn tsv_search_from(
+    index: PgRelation,
+    start_ctid: pg_sys::ItemPointerData,
+    query: VectorArg,
+    search_list_size: i32,
+    k: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(distance, f64),
+        name!(visited_nodes, i64),
+        name!(candidate_nodes, i64),
+        name!(node_reads, i64),
+        name!(distance_comparisons, i64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if search_list_size < 1 {
+        error!("search_list_size must be at least 1");
+    }
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+
+    let start_heap_pointer = unsafe { HeapPointer::with_item_pointer_data(start_ctid) };
+    let start = match find_node_by_heap_pointer(&index, start_heap_pointer) {
+        Some(start) => start,
+        None => error!("no index node found for ctid {:?}", start_heap_pointer),
+    };
+
+    let mut results = Vec::with_capacity(k as usize);
+    let stats = search_index_from(
+        &index,
+        Some(start),
+        &query,
+        search_list_size as usize,
+        |result| {
+            results.push((result.heap_pointer, result.distance));
+            results.len() < k as usize
+        },
+    );
+
+    let rows = results
+        .into_iter()
+        .map(|(heap_pointer, distance)| {
+            let mut ctid = pg_sys::ItemPointerData::default();
+            heap_pointer.to_item_pointer_data(&mut ctid);
+            (
+                ctid,
+                distance as f64,
+                stats.get_visited_nodes() as i64,
+                stats.get_candidate_nodes() as i64,
+                stats.get_node_reads() as i64,
+                stats.get_total_distance_comparisons() as i64,
+            )
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// Returns the distance the index computes from `query` to the node of each row in
+/// `heap_pointers`, closest first. Rows without a live node are left out.
+fn rank_storage<S: Storage>(
+    storage: &S,
+    index: &PgRelation,
+    mut meta_page: MetaPage,
+    query: &VectorArg,
+    heap_pointers: &HashSet<HeapPointer>,
+) -> Vec<(HeapPointer, f32)> {
+    let mut nodes: HashMap<IndexPointer, HeapPointer> = HashMap::new();
+    for_each_node::<S, _>(index, |index_pointer, node| {
+        let heap_pointer = node.get_heap_item_pointer();
+        if !node.is_deleted() && heap_pointers.contains(&heap_pointer) {
+            nodes.insert(index_pointer, heap_pointer);
+        }
+    });
+
+    let search_query = unsafe { query.to_pg_vector(&meta_page) };
+    let graph = Graph::new(GraphNeighborStore::Disk, &mut meta_page);
+    let mut lsr = graph.greedy_search_streaming_init_from(
+        nodes.keys().copied().collect(),
+        search_query,
+        nodes.len(),
+        storage,
+    );
+    lsr.drain_candidates()
+        .into_iter()
+        .map(|(index_pointer, distance)| (nodes[&index_pointer], distance))
+        .collect()
+}
+
+/// Ranks the rows `ctids` by their distance to `query` as the index computes it, from the
+/// nodes alone: the distance to the stored vector for the plain layout, the SBQ distance for
+/// the memory_optimized and io_optimized layouts, unless the index was built with
+/// exact_traversal. Meant for re-ranking candidates that another filter already narrowed
+/// down without fetching their vectors from the table, so the rows aren't checked for
+/// visibility. Rows the index doesn't have a node for are left out.
+#[pg_extern]
+pub fn tsv_rank_candidates(
+    index: PgRelation,
+    query: VectorArg,
+    ctids: Vec<pg_sys::ItemPointerData>,
+) -> TableIterator<'static, (name!(ctid, pg_sys::ItemPointerData), name!(distance, f64))> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    let heap_pointers: HashSet<HeapPointer> = ctids
+        .into_iter()
+        .map(|ctid| unsafe { HeapPointer::with_item_pointer_data(ctid) })
+        .collect();
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let meta_page = MetaPage::fetch(&index);
+    let ranked = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::load_for_search(&index, &heap, meta_page.get_distance_function());
+            rank_storage(&storage, &index, meta_page, &query, &heap_pointers)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let mut stats = QuantizerStats::new();
+            let quantizer = unsafe { SbqMeans::load(&index, &meta_page, &mut stats) };
+            let storage = SbqSpeedupStorage::load_for_search(&index, &heap, &quantizer, &meta_page);
+            rank_storage(&storage, &index, meta_page.clone(), &query, &heap_pointers)
+        }
+    };
+
+    let rows = ranked
+        .into_iter()
+        .map(|(heap_pointer, distance)| {
+            let mut ctid = pg_sys::ItemPointerData::default();
+            heap_pointer.to_item_pointer_data(&mut ctid);
+            (ctid, distance as f64)
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// Returns the distance the index computes from `query` to each entry point of its graph, as
+/// a search starting there would, with the row of the entry point, None if it is deleted.
+fn entry_point_distances<S: Storage>(
+    storage: &S,
+    index: &PgRelation,
+    mut meta_page: MetaPage,
+    query: &VectorArg,
+) -> Vec<(Option<HeapPointer>, f32)> {
+    let search_query = unsafe { query.to_pg_vector(&meta_page) };
+    let graph = Graph::new(GraphNeighborStore::Disk, &mut meta_page);
+    let mut lsr = graph.greedy_search_streaming_init(search_query, 1, storage);
+    lsr.drain_candidates()
+        .into_iter()
+        .map(|(index_pointer, distance)| {
+            let heap_pointer = with_node::<S, _, _>(index, index_pointer, |node| {
+                (!node.is_deleted()).then(|| node.get_heap_item_pointer())
+            });
+            (heap_pointer, distance)
+        })
+        .collect()
+}
+
+/// Returns the distance from `query` to each entry point of the graph of the index, where
+/// every search starts, with the distance the search would use: the distance to the stored
+/// vector for the plain layout, the SBQ distance otherwise. A large distance compared to the
+/// distances of the results means a long walk to the region of the query. The ctid is NULL
+/// for entry points whose row was deleted. Returns no rows for an empty index or one without
+/// a graph.
+#[pg_extern]
+pub fn tsv_entry_point_distance(
+    index: PgRelation,
+    query: VectorArg,
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, Option<pg_sys::ItemPointerData>),
+        name!(distance, f64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let meta_page = MetaPage::fetch(&index);
+    if !meta_page.get_index_type().has_graph() {
+        return TableIterator::new(vec![]);
+    }
+    let distances = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::load_for_search(&index, &heap, meta_page.get_distance_function());
+            entry_point_distances(&storage, &index, meta_page, &query)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let mut stats = QuantizerStats::new();
+            let quantizer = unsafe { SbqMeans::load(&index, &meta_page, &mut stats) };
+            let storage = SbqSpeedupStorage::load_for_search(&index, &heap, &quantizer, &meta_page);
+            entry_point_distances(&storage, &index, meta_page.clone(), &query)
+        }
+    };
+
+    let rows = distances
+        .into_iter()
+        .map(|(heap_pointer, distance)| {
+            let ctid = heap_pointer.map(|heap_pointer| {
+                let mut ctid = pg_sys::ItemPointerData::default();
+                heap_pointer.to_item_pointer_data(&mut ctid);
+                ctid
+            });
+            (ctid, distance as f64)
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// Searches the index for the `k` nearest rows to `query` and returns, for each of them, the
+/// number of hops from an entry point of the graph after which the greedy search first found
+/// its node. Meant for studying the graph: in a graph with the small-world property, the
+/// results of most queries are found within a few hops.
+#[pg_extern]
+pub fn tsv_search_hops(
+    index: PgRelation,
+    query: VectorArg,
+    k: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(distance, f64),
+        name!(discovery_hops, i32),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+
+    let search_list_size = std::cmp::max(
+        k as usize,
+        super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize,
+    );
+    let mut rows = Vec::with_capacity(k as usize);
+    search_index(&index, &query, search_list_size, |result| {
+        let mut ctid = pg_sys::ItemPointerData::default();
+        result.heap_pointer.to_item_pointer_data(&mut ctid);
+        rows.push((ctid, result.distance as f64, result.discovery_hops as i32));
+        rows.len() < k as usize
+    });
+    TableIterator::new(rows)
+}
+
+/// Searches the index for the `k` nearest rows to `query` and returns the work the search
+/// did: the index nodes it read, the table rows it read, the distances it computed and the
+/// nodes whose neighbors it looked at. For the memory_optimized and io_optimized layouts the
+/// traversal only uses the SBQ distances, and the table is only read for the `k` rows
+/// returned, unless exact_traversal is on, in which case every visited node costs a table
+/// read.
+#[pg_extern]
+pub fn tsv_search_stats(
+    index: PgRelation,
+    query: VectorArg,
+    k: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(node_reads, i64),
+        name!(heap_reads, i64),
+        name!(distance_comparisons, i64),
+        name!(visited_nodes, i64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+
+    let search_list_size = std::cmp::max(
+        k as usize,
+        super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize,
+    );
+    let mut found = 0;
+    let stats = search_index(&index, &query, search_list_size, |_| {
+        found += 1;
+        found < k
+    });
+    TableIterator::once((
+        stats.get_node_reads() as i64,
+        stats.get_node_heap_reads() as i64,
+        stats.get_total_distance_comparisons() as i64,
+        stats.get_visited_nodes() as i64,
+    ))
+}
+
+/// Searches the index for `query` once and returns the first `pool_size` candidates it
+/// finds, each with its rank in the order the index returns them, by the distance the index
+/// computes, and its rank by the exact distance from the table row. For the
+/// memory_optimized and io_optimized layouts the first is the SBQ order, so the difference
+/// between the two ranks shows how much reranking by exact distance reorders the results;
+/// the last approximate rank among the rows with exact rank up to k is the depth a search
+/// needs to reach to return the exact top k of the pool. Ranks start at 1.
+#[pg_extern]
+pub fn tsv_rerank_ranks(
+    index: PgRelation,
+    query: VectorArg,
+    pool_size: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(approximate_rank, i32),
+        name!(exact_rank, i32),
+        name!(distance, f64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if pool_size < 1 {
+        error!("pool_size must be at least 1");
+    }
+
+    let search_list_size = std::cmp::max(
+        pool_size as usize,
+        super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize,
+    );
+    let mut pool = Vec::with_capacity(pool_size as usize);
+    search_index(&index, &query, search_list_size, |result| {
+        pool.push((result.heap_pointer, result.distance));
+        pool.len() < pool_size as usize
+    });
+
+    let mut by_distance: Vec<usize> = (0..pool.len()).collect();
+    /* stable, so that ties keep their approximate order */
+    by_distance.sort_by(|&a, &b| pool[a].1.total_cmp(&pool[b].1));
+    let mut exact_ranks = vec![0; pool.len()];
+    for (rank, i) in by_distance.into_iter().enumerate() {
+        exact_ranks[i] = rank as i32 + 1;
+    }
+
+    let rows = pool
+        .into_iter()
+        .zip(exact_ranks)
+        .enumerate()
+        .map(|(i, ((heap_pointer, distance), exact_rank))| {
+            let mut ctid = pg_sys::ItemPointerData::default();
+            heap_pointer.to_item_pointer_data(&mut ctid);
+            (ctid, i as i32 + 1, exact_rank, distance as f64)
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// The distances between two vectors under the metrics of pgvector's operators:
+/// cosine distance (`<=>`), Euclidean distance (`<->`) and negative inner product (`<#>`).
+fn all_metrics(a: &[f32], b: &[f32]) -> (f64, f64, f64) {
+    assert_eq!(a.len(), b.len());
+    let mut dot = 0.0_f64;
+    let mut norm_a = 0.0_f64;
+    let mut norm_b = 0.0_f64;
+    let mut l2 = 0.0_f64;
+    for (x, y) in a.iter().zip(b.iter()) {
+        let (x, y) = (*x as f64, *y as f64);
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+        l2 += (x - y) * (x - y);
+    }
+
+    let norms = (norm_a * norm_b).sqrt();
+    let cosine = if norms == 0.0 {
+        f64::NAN
+    } else {
+        (1.0 - dot / norms).clamp(0.0, 2.0)
+    };
+    (cosine, l2.sqrt(), -dot)
+}
+
+/// Searches the index for the `k` nearest rows to `query` and returns, for each of them,
+/// its distance to the query under several metrics, computed on the vectors stored in the
+/// table. `distance` is the one the index orders by. Meant for evaluating how the metrics
+/// compare, not for serving queries.
+#[pg_extern]
+pub fn tsv_search_multimetric(
+    index: PgRelation,
+    query: VectorArg,
+    k: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(distance, f64),
+        name!(cosine_distance, f64),
+        name!(l2_distance, f64),
+        name!(negative_inner_product, f64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+
+    let search_list_size = std::cmp::max(
+        k as usize,
+        super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize,
+    );
+    let heap_attr = get_attribute_number_from_index(&index);
+    let meta_page = MetaPage::fetch(&index);
+    let query_vec = unsafe { query.to_vec(&meta_page) };
+
+    let mut rows = Vec::with_capacity(k as usize);
+    search_index(&index, &query, search_list_size, |result| {
+        /* search_index only returns rows with a vector */
+        let datum = unsafe { result.slot.get_attribute(heap_attr) }.unwrap();
+        let vec = unsafe { heap_datum_to_vec(datum, &meta_page) };
+        let (cosine, l2, negative_inner_product) = all_metrics(&vec, &query_vec);
+
+        let mut ctid = pg_sys::ItemPointerData::default();
+        result.heap_pointer.to_item_pointer_data(&mut ctid);
+        rows.push((
+            ctid,
+            result.distance as f64,
+            cosine,
+            l2,
+            negative_inner_product,
+        ));
+        rows.len() < k as usize
+    });
+    TableIterator::new(rows)
+}
+
+/// The exact distance from `query` to the vector of the row at `heap_pointer` under `index`,
+/// or None if the row isn't visible or has no vector.
+unsafe fn distance_to_row(
+    index: &PgRelation,
+    heap: &PgRelation,
+    meta_page: &MetaPage,
+    query: &PgVector,
+    heap_pointer: HeapPointer,
+    stats: &mut GreedySearchStats,
+) -> Option<f32> {
+    let snapshot = pg_sys::GetActiveSnapshot();
+    let slot = TableSlot::new_visible(heap, heap_pointer, snapshot, stats)?;
+    let datum = slot.get_attribute(get_attribute_number_from_index(index))?;
+    Some(query.full_distance_to_heap_datum(datum, meta_page, meta_page.get_distance_function()))
+}
+
+/// Searches two indexes of the same table, e.g. on a text and an image embedding column, for
+/// `query1` and `query2` and returns the `k` rows with the smallest weighted sum of distances
+/// `weight1 * distance1 + weight2 * distance2`. The candidates are the nearest rows found by
+/// either search; the distance of a candidate found by one search only is computed from its
+/// vector in the table for the other. Rows with no vector in either column are left out.
+#[pg_extern]
+pub fn tsv_fused_search(
+    index1: PgRelation,
+    query1: VectorArg,
+    weight1: f64,
+    index2: PgRelation,
+    query2: VectorArg,
+    weight2: f64,
+    k: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(distance, f64),
+        name!(distance1, f64),
+        name!(distance2, f64),
+    ),
+> {
+    check_is_diskann_index(&index1);
+    check_heap_select_privilege(&index1);
+    check_is_diskann_index(&index2);
+    check_heap_select_privilege(&index2);
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+    if !(weight1 >= 0.0 && weight2 >= 0.0) {
+        error!("weights must not be negative");
+    }
+    if unsafe { (*index1.rd_index).indrelid != (*index2.rd_index).indrelid } {
+        error!(
+            "diskann indexes \"{}\" and \"{}\" must be on the same table",
+            index1.name(),
+            index2.name()
+        );
+    }
+
+    let heap = index1
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let search_list_size = std::cmp::max(
+        k as usize,
+        super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize,
+    );
+
+    /* the distances to the candidates found by each search */
+    let mut candidates: HashMap<HeapPointer, (Option<f32>, Option<f32>)> = HashMap::new();
+    let mut found = 0;
+    search_index(&index1, &query1, search_list_size, |result| {
+        candidates.entry(result.heap_pointer).or_default().0 = Some(result.distance);
+        found += 1;
+        found < search_list_size
+    });
+    let mut found = 0;
+    search_index(&index2, &query2, search_list_size, |result| {
+        candidates.entry(result.heap_pointer).or_default().1 = Some(result.distance);
+        found += 1;
+        found < search_list_size
+    });
+
+    let meta_page1 = MetaPage::fetch(&index1);
+    let meta_page2 = MetaPage::fetch(&index2);
+    let full_query1 = unsafe { query1.to_full_distance_query(&index1, &meta_page1) };
+    let full_query2 = unsafe { query2.to_full_distance_query(&index2, &meta_page2) };
+    let mut stats = GreedySearchStats::new();
+
+    let mut rows = vec![];
+    for (heap_pointer, (distance1, distance2)) in candidates {
+        check_for_interrupts!();
+        let distance1 = distance1.or_else(|| unsafe {
+            distance_to_row(
+                &index1,
+                &heap,
+                &meta_page1,
+                &full_query1,
+                heap_pointer,
+                &mut stats,
+            )
+        });
+        let distance2 = distance2.or_else(|| unsafe {
+            distance_to_row(
+                &index2,
+                &heap,
+                &meta_page2,
+                &full_query2,
+                heap_pointer,
+                &mut stats,
+            )
+        });
+        if let (Some(distance1), Some(distance2)) = (distance1, distance2) {
+            let (distance1, distance2) = (distance1 as f64, distance2 as f64);
+            rows.push((
+                heap_pointer,
+                weight1 * distance1 + weight2 * distance2,
+                distance1,
+                distance2,
+            ));
+        }
+    }
+    rows.sort_by(|a, b| a.1.total_cmp(&b.1));
+    rows.truncate(k as usize);
+
+    let rows = rows
+        .into_iter()
+        .map(|(heap_pointer, distance, distance1, distance2)| {
+            let mut ctid = pg_sys::ItemPointerData::default();
+            heap_pointer.to_item_pointer_data(&mut ctid);
+            (ctid, distance, distance1, distance2)
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// Searches each of `indexes`, e.g. the indexes of the tables a data set is sharded across,
+/// for the `k` rows nearest to `query` and merges the results into the `k` nearest overall,
+/// nearest first, with the oid of the index each row was found by: `source::regclass` names
+/// it. Saves sorting the UNION ALL of the rows of every table.
+#[pg_extern]
+pub fn tsv_search_union(
+    indexes: Vec<pg_sys::Oid>,
+    query: VectorArg,
+    k: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(source, pg_sys::Oid),
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(distance, f64),
+    ),
+> {
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+
+    let search_list_size = std::cmp::max(
+        k as usize,
+        super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize,
+    );
+    /* the k nearest rows of each index, nearest first */
+    let mut results: Vec<(pg_sys::Oid, Vec<(HeapPointer, f32)>)> = vec![];
+    for oid in indexes {
+        let index =
+            unsafe { PgRelation::with_lock(oid, pg_sys::AccessShareLock as pg_sys::LOCKMODE) };
+        check_is_diskann_index(&index);
+        check_heap_select_privilege(&index);
+        let mut rows = Vec::with_capacity(k as usize);
+        search_index(&index, &query, search_list_size, |result| {
+            rows.push((result.heap_pointer, result.distance));
+            rows.len() < k as usize
+        });
+        rows.sort_by(|a, b| a.1.total_cmp(&b.1));
+        results.push((oid, rows));
+    }
+
+    /* k-way merge */
+    let mut positions = vec![0; results.len()];
+    let mut rows = Vec::with_capacity(k as usize);
+    while rows.len() < k as usize {
+        let nearest = (0..results.len())
+            .filter(|&i| positions[i] < results[i].1.len())
+            .min_by(|&a, &b| {
+                results[a].1[positions[a]]
+                    .1
+                    .total_cmp(&results[b].1[positions[b]].1)
+            });
+        let i = match nearest {
+            Some(i) => i,
+            None => break,
+        };
+        let (heap_pointer, distance) = results[i].1[positions[i]];
+        positions[i] += 1;
+
+        let mut ctid = pg_sys::ItemPointerData::default();
+        heap_pointer.to_item_pointer_data(&mut ctid);
+        rows.push((results[i].0, ctid, distance as f64));
+    }
+    TableIterator::new(rows)
+}
+
+/// Searches the index for the `k` rows nearest to `query`, with older rows down-weighted:
+/// the nearest candidates are reranked by `distance + recency_weight * (1 - 0.5 ^ (age /
+/// half_life))`, where `age` is the time since the `time_column` of the row, a timestamptz
+/// column such as an insertion time. A row as old as `half_life` is pushed back by half of
+/// `recency_weight`; a row with a NULL time, by all of it. Only the candidates the search
+/// finds are reranked, `diskann.query_search_list_size` of them or `k` if more.
+#[pg_extern]
+pub fn tsv_search_recent(
+    index: PgRelation,
+    query: VectorArg,
+    k: i32,
+    time_column: &str,
+    half_life: Interval,
+    recency_weight: default!(f64, 1.0),
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(distance, f64),
+        name!(score, f64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+    let half_life_micros = half_life.as_micros() as f64;
+    if half_life_micros <= 0.0 {
+        error!("half_life must be positive");
+    }
+    if recency_weight < 0.0 {
+        error!("recency_weight must not be negative");
+    }
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let time_attr = unsafe { pg_sys::get_attnum(heap.oid(), time_column.as_pg_cstr()) };
+    if time_attr == pg_sys::InvalidAttrNumber as pg_sys::AttrNumber {
+        error!(
+            "column \"{}\" of relation \"{}\" does not exist",
+            time_column,
+            heap.name()
+        );
+    }
+    if unsafe { pg_sys::get_atttype(heap.oid(), time_attr) } != pg_sys::TIMESTAMPTZOID {
+        error!("column \"{}\" must be of type timestamptz", time_column);
+    }
+
+    let now = unsafe { pg_sys::GetCurrentTransactionStartTimestamp() };
+    let search_list_size = std::cmp::max(
+        k as usize,
+        super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize,
+    );
+    let mut rows = Vec::with_capacity(search_list_size);
+    search_index(&index, &query, search_list_size, |result| {
+        let decay = match unsafe { result.slot.get_attribute(time_attr) } {
+            Some(time) => {
+                let age = (now - time.value() as pg_sys::TimestampTz).max(0) as f64;
+                1.0 - 0.5_f64.powf(age / half_life_micros)
+            }
+            None => 1.0,
+        };
+        let distance = result.distance as f64;
+        rows.push((
+            result.heap_pointer,
+            distance,
+            distance + recency_weight * decay,
+        ));
+        rows.len() < search_list_size
+    });
+    rows.sort_by(|a, b| a.2.total_cmp(&b.2));
+    rows.truncate(k as usize);
+
+    let rows = rows
+        .into_iter()
+        .map(|(heap_pointer, distance, score)| {
+            let mut ctid = pg_sys::ItemPointerData::default();
+            heap_pointer.to_item_pointer_data(&mut ctid);
+            (ctid, distance, score)
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
+
+/// Searches the index for `query` and returns `k` of the nearest candidates by maximal
+/// marginal relevance: each next row is the one with the highest `lambda * -distance + (1 -
+/// lambda) * distance to the nearest row returned before it`, so that the rows are close to the
+/// query but unlike each other. A lambda of 1 keeps the order of the distances, lower ones
+/// trade distance for diversity. The candidates are the rows the search finds,
+/// `diskann.query_search_list_size` of them or `k` if more, and the distances between them are
+/// computed from their vectors in the table. Index scans are not affected: they always return
+/// rows by distance.
+#[pg_extern]
+pub fn tsv_search_mmr(
+    index: PgRelation,
+    query: VectorArg,
+    k: i32,
+    lambda: default!(f64, 0.5),
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(distance, f64),
+        name!(score, f64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+    if !(0.0..=1.0).contains(&lambda) {
+        error!("lambda must be between 0 and 1");
+    }
+
+    let search_list_size = std::cmp::max(
+        k as usize,
+        super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize,
+    );
+    let heap_attr = get_attribute_number_from_index(&index);
+    let meta_page = MetaPage::fetch(&index);
+    let distance_fn = meta_page.get_distance_function();
+
+    /* the candidates, with the distance to the nearest row selected so far */
+    let mut pool = Vec::with_capacity(search_list_size);
+    search_index(&index, &query, search_list_size, |result| {
+        /* search_index only returns rows with a vector */
+        let datum = unsafe { result.slot.get_attribute(heap_attr) }.unwrap();
+        let vec = unsafe { heap_datum_to_vec(datum, &meta_page) };
+        pool.push((result.heap_pointer, result.distance, vec, None::<f32>));
+        pool.len() < search_list_size
+    });
+
+    let mut rows = Vec::with_capacity(k as usize);
+    while rows.len() < k as usize && !pool.is_empty() {
+        check_for_interrupts!();
+        let score = |candidate: &(HeapPointer, f32, Vec<f32>, Option<f32>)| {
+            let (_, distance, _, selected_distance) = candidate;
+            -lambda * *distance as f64 + (1.0 - lambda) * selected_distance.unwrap_or(0.0) as f64
+        };
+        let mut best = 0;
+        for (i, candidate) in pool.iter().enumerate().skip(1) {
+            if score(candidate) > score(&pool[best]) {
+                best = i;
+            }
+        }
+        let selected_score = score(&pool[best]);
+        let (heap_pointer, distance, selected, _) = pool.swap_remove(best);
+        for (_, _, vec, selected_distance) in pool.iter_mut() {
+            let distance = distance_fn(vec, &selected);
+            if selected_distance.map_or(true, |d| distance < d) {
+                *selected_distance = Some(distance);
+            }
+        }
+
+        let mut ctid = pg_sys::ItemPointerData::default();
+        heap_pointer.to_item_pointer_data(&mut ctid);
+        rows.push((ctid, distance as f64, selected_score));
+    }
+    TableIterator::new(rows)
+}
+
+/// Searches the index for the vectors of `sample_size` random rows and compares the `k`
+/// nearest rows each search returns with the exact ones, found by computing the distance to
+/// every row of the index. Returns the number of queries run, the average recall@k (rows tied
+/// with the k-th nearest count as hits), the number of searches that returned a row twice and
+/// the number that returned fewer than `k` rows while the table has more. Meant as a health
+/// check after upgrades or configuration changes: it holds the vectors of all the rows in
+/// memory and computes the distance from every query to every row.
+#[pg_extern]
+pub fn tsv_selftest(
+    index: PgRelation,
+    sample_size: i32,
+    k: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(queries, i64),
+        name!(recall, Option<f64>),
+        name!(duplicate_results, i64),
+        name!(short_results, i64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    if sample_size < 1 {
+        error!("sample_size must be at least 1");
+    }
+    if k < 1 {
+        error!("k must be at least 1");
+    }
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let meta_page = MetaPage::fetch(&index);
+    let heap_pointers = match meta_page.get_storage_type() {
+        StorageType::Plain => live_heap_pointers::<PlainStorage>(&index),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            live_heap_pointers::<SbqSpeedupStorage>(&index)
+        }
+    };
+    let heap_attr = get_attribute_number_from_index(&index);
+    let distance_fn = meta_page.get_distance_function();
+    let snapshot = unsafe { pg_sys::GetActiveSnapshot() };
+    let mut stats = GreedySearchStats::new();
+
+    /* the vectors of the visible rows, for the brute-force searches */
+    let rows: Vec<(HeapPointer, PgVector)> = heap_pointers
+        .into_iter()
+        .filter_map(|heap_pointer| {
+            let slot =
+                unsafe { TableSlot::new_visible(&heap, heap_pointer, snapshot, &mut stats) }?;
+            let datum = unsafe { slot.get_attribute(heap_attr) }?;
+            let vector = unsafe { PgVector::from_heap_datum(datum, &meta_page, false, true) };
+            Some((heap_pointer, vector))
+        })
+        .collect();
+    let positions: HashMap<HeapPointer, usize> = rows
+        .iter()
+        .enumerate()
+        .map(|(position, (heap_pointer, _))| (*heap_pointer, position))
+        .collect();
+
+    let k = k as usize;
+    let expected = std::cmp::min(k, rows.len());
+    let search_list_size = std::cmp::max(k, super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize);
+    let mut queries = 0;
+    let mut recall_sum = 0.0;
+    let mut duplicate_results = 0;
+    let mut short_results = 0;
+    for (query_heap_pointer, query) in
+        rows.choose_multiple(&mut rand::thread_rng(), sample_size as usize)
+    {
+        check_for_interrupts!();
+        let distances: Vec<f32> = rows
+            .iter()
+            .map(|(_, vector)| distance_fn(vector.to_full_slice(), query.to_full_slice()))
+            .collect();
+        let mut sorted = distances.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let kth_distance = sorted[expected - 1];
+
+        /* search with the vector as stored in the table, like a query would */
+        let slot =
+            unsafe { TableSlot::new_visible(&heap, *query_heap_pointer, snapshot, &mut stats) };
+        let datum = match slot
+            .as_ref()
+            .and_then(|slot| unsafe { slot.get_attribute(heap_attr) })
+        {
+            Some(datum) => datum,
+            None => continue,
+        };
+        let decrypted = unsafe { decrypt_datum(datum, &meta_page) };
+        let query_arg = unsafe { VectorArg::from_datum(decrypted, false) }.unwrap();
+        let mut found = Vec::with_capacity(k);
+        search_index(&index, &query_arg, search_list_size, |result| {
+            found.push(result.heap_pointer);
+            found.len() < k
+        });
+        unsafe { free_decrypted_datum(decrypted, datum) };
+
+        let unique: HashSet<HeapPointer> = found.iter().copied().collect();
+        if unique.len() < found.len() {
+            duplicate_results += 1;
+        }
+        if found.len() < expected {
+            short_results += 1;
+        }
+        let hits = unique
+            .iter()
+            .filter(|heap_pointer| {
+                positions
+                    .get(heap_pointer)
+                    .map_or(false, |position| distances[*position] <= kth_distance)
+            })
+            .count();
+        recall_sum += hits as f64 / expected as f64;
+        queries += 1;
+    }
+
+    let recall = if queries == 0 {
+        None
+    } else {
+        Some(recall_sum / queries as f64)
+    };
+    TableIterator::once((queries, recall, duplicate_results, short_results))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_tsv_count_within() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 300)?;
+        Spi::run("CREATE INDEX idx_diskann ON test_data USING diskann (embedding);")?;
+
+        let query = "(SELECT embedding FROM test_data LIMIT 1)";
+
+        /* only the row itself is at distance 0 */
+        let cnt: Option<i64> = Spi::get_one(&format!(
+            "SELECT tsv_count_within('idx_diskann'::regclass, {query}, 0.00001)"
+        ))?;
+        assert_eq!(cnt.unwrap(), 1);
+
+        /* cosine distances are at most 2 */
+        let cnt: Option<i64> = Spi::get_one(&format!(
+            "SELECT tsv_count_within('idx_diskann'::regclass, {query}, 2.0)"
+        ))?;
+        assert_eq!(cnt.unwrap(), 300);
+
+        /* radiuses in between count exactly the rows within them */
+        for radius in [0.1, 0.15, 0.2, 0.25] {
+            let exact: Option<i64> = Spi::get_one(&format!(
+                "SELECT count(*) FROM test_data WHERE (embedding <=> {query}) <= {radius}"
+            ))?;
+            let cnt: Option<i64> = Spi::get_one(&format!(
+                "SELECT tsv_count_within('idx_diskann'::regclass, {query}, {radius})"
+            ))?;
+            assert_eq!(cnt.unwrap(), exact.unwrap(), "radius {}", radius);
+        }
+        let exact: Option<i64> = Spi::get_one(&format!(
+            "SELECT count(*) FROM test_data WHERE (embedding <=> {query}) <= 0.15"
+        ))?;
+        assert!(exact.unwrap() > 1, "radius too small for the test data");
+
+        /* rows deleted but not yet vacuumed are not counted */
+        Spi::run(&format!("DELETE FROM test_data WHERE embedding = {query}"))?;
+        let cnt: Option<i64> = Spi::get_one(&format!(
+            "SELECT tsv_count_within('idx_diskann'::regclass, (SELECT embedding FROM test_data LIMIT 1), 2.0)"
+        ))?;
+        assert_eq!(cnt.unwrap(), 299);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_count_within_hot_update() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (title text, embedding vector (3)) WITH (fillfactor = 50);
+            INSERT INTO test_data (title, embedding)
+            SELECT 'row ' || i, ('[' || random() || ',' || random() || ',' || random() || ']')::vector
+            FROM generate_series(1, 100) i;
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);
+            UPDATE test_data SET title = title || ' updated';",
+        )?;
+        let hot_updated: Option<i64> =
+            Spi::get_one("SELECT pg_stat_get_xact_tuples_hot_updated('test_data'::regclass)")?;
+        assert!(hot_updated.unwrap() > 0, "the update wasn't HOT");
+
+        /* the index points to the root of each HOT chain, whose visible member is the update */
+        let cnt: Option<i64> =
+            Spi::get_one("SELECT tsv_count_within('idx_diskann'::regclass, '[1,1,1]', 2.0)")?;
+        assert_eq!(cnt.unwrap(), 100);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_search_from() -> spi::Result<()> {
+        crate::access_method::build::tests::create_test_data(32, 300)?;
+        Spi::run("CREATE INDEX idx_diskann ON test_data USING diskann (embedding);")?;
+
+        /* start from a row on the second page, search for the first one */
+        let nearest: Option<pg_sys::ItemPointerData> = Spi::get_one(
+            "SELECT ctid FROM tsv_search_from(
+                'idx_diskann'::regclass,
+                '(1,1)'::tid,
+                (SELECT embedding FROM test_data WHERE ctid = '(0,1)'),
+                50,
+                10)
+            LIMIT 1",
+        )?;
+        let nearest = crate::util::ItemPointer::with_item_pointer_data(nearest.unwrap());
+        assert_eq!(nearest, crate::util::ItemPointer::new(0, 1));
+
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM tsv_search_from(
+                'idx_diskann'::regclass,
+                '(1,1)'::tid,
+                (SELECT embedding FROM test_data WHERE ctid = '(0,1)'),
+                50,
+                10)
+            WHERE visited_nodes > 0 AND distance >= 0",
+        )?;
+        assert_eq!(cnt.unwrap(), 10);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_search_hops() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            INSERT INTO test_data (embedding)
+                SELECT ARRAY[i, i % 10 + 1, i * 2]::vector FROM generate_series(1, 300) i;
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                WITH (num_neighbors = 10);",
+        )?;
+
+        let (cnt, min_hops) = Spi::get_two::<i64, i32>(
+            "SELECT count(*), min(discovery_hops) FROM tsv_search_hops('idx_diskann'::regclass, '[150,1,300]', 10)",
+        )?;
+        assert_eq!(cnt.unwrap(), 10);
+        assert!(min_hops.unwrap() >= 0);
+
+        /* the entry point is found at 0 hops, the rest further away */
+        let (init_hops, init_rows) = Spi::get_two::<i32, i64>(
+            "SELECT min(discovery_hops), count(*) FILTER (WHERE discovery_hops = 0)
+            FROM tsv_search_hops('idx_diskann'::regclass, '[150,1,300]', 300)",
+        )?;
+        assert_eq!(init_hops.unwrap(), 0);
+        assert!(init_rows.unwrap() >= 1);
+
+        let max_hops = Spi::get_one::<i32>(
+            "SELECT max(discovery_hops) FROM tsv_search_hops('idx_diskann'::regclass, '[150,1,300]', 300)",
+        )?;
+        assert!(max_hops.unwrap() >= 1);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_search_multimetric() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            INSERT INTO test_data (embedding) VALUES ('[1,0,0]'), ('[0,2,0]'), ('[3,4,0]'), ('[-1,-1,-1]');
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);",
+        )?;
+
+        /* the metrics match the ones of pgvector's operators */
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*)
+            FROM tsv_search_multimetric('idx_diskann'::regclass, '[1,1,0]', 4) m
+            JOIN test_data t ON t.ctid = m.ctid
+            WHERE abs(m.cosine_distance - (t.embedding <=> '[1,1,0]')) < 1e-6
+            AND abs(m.l2_distance - (t.embedding <-> '[1,1,0]')) < 1e-6
+            AND abs(m.negative_inner_product - (t.embedding <#> '[1,1,0]')) < 1e-6
+            AND abs(m.distance - m.cosine_distance) < 1e-6",
+        )?;
+        assert_eq!(cnt.unwrap(), 4);
+
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*) FROM tsv_search_multimetric('idx_diskann'::regclass, '[1,1,0]', 2)",
+        )?;
+        assert_eq!(cnt.unwrap(), 2);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_fused_search() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (id int, text_embedding vector (3), image_embedding vector (2));
+            INSERT INTO test_data (id, text_embedding, image_embedding) VALUES
+                (1, '[1,0,0]', '[0,1]'),
+                (2, '[1,0.1,0]', '[1,0]'),
+                (3, '[0,1,0]', '[1,0.1]'),
+                (4, '[0,0,1]', '[-1,-1]');
+            CREATE INDEX idx_text ON test_data USING diskann (text_embedding);
+            CREATE INDEX idx_image ON test_data USING diskann (image_embedding);",
+        )?;
+
+        let fused = "SELECT array_agg(t.id ORDER BY f.distance)
+            FROM tsv_fused_search('idx_text'::regclass, '[1,0,0]', {w1}, 'idx_image'::regclass, '[1,0]', {w2}, 3) f
+            JOIN test_data t ON t.ctid = f.ctid";
+
+        /* each weight alone ranks by its own column */
+        let ids = Spi::get_one::<Vec<i32>>(&fused.replace("{w1}", "1").replace("{w2}", "0"))?;
+        assert_eq!(ids.unwrap()[..2], [1, 2]);
+        let ids = Spi::get_one::<Vec<i32>>(&fused.replace("{w1}", "0").replace("{w2}", "1"))?;
+        assert_eq!(ids.unwrap()[..2], [2, 3]);
+
+        /* together, the row close to both queries comes first */
+        let ids = Spi::get_one::<Vec<i32>>(&fused.replace("{w1}", "1").replace("{w2}", "1"))?;
+        assert_eq!(ids.unwrap()[0], 2);
+
+        /* the distances are the ones of the operators */
+        let cnt = Spi::get_one::<i64>(
+            "SELECT count(*)
+            FROM tsv_fused_search('idx_text'::regclass, '[1,0,0]', 0.3, 'idx_image'::regclass, '[1,0]', 0.7, 4) f
+            JOIN test_data t ON t.ctid = f.ctid
+            WHERE abs(f.distance1 - (t.text_embedding <=> '[1,0,0]')) < 1e-6
+            AND abs(f.distance2 - (t.image_embedding <=> '[1,0]')) < 1e-6
+            AND abs(f.distance - (0.3 * f.distance1 + 0.7 * f.distance2)) < 1e-6",
+        )?;
+        assert_eq!(cnt.unwrap(), 4);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_search_union() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE shard_a (id int, embedding vector (3));
+            CREATE TABLE shard_b (id int, embedding vector (3));
+            INSERT INTO shard_a (id, embedding)
+                SELECT i, ARRAY[i, i % 7, 1]::vector FROM generate_series(1, 100) i WHERE i % 2 = 0;
+            INSERT INTO shard_b (id, embedding)
+                SELECT i, ARRAY[i, i % 7, 1]::vector FROM generate_series(1, 100) i WHERE i % 2 = 1;
+            CREATE INDEX idx_a ON shard_a USING diskann (embedding) WITH (storage_layout = plain);
+            CREATE INDEX idx_b ON shard_b USING diskann (embedding);",
+        )?;
+
+        /* the same rows, in the same order, as sorting the union of the shards */
+        let union = Spi::get_one::<Vec<i32>>(
+            "SELECT array_agg(coalesce(a.id, b.id) ORDER BY u.distance)
+            FROM tsv_search_union(ARRAY['idx_a'::regclass, 'idx_b'::regclass], '[40,5,1]', 10) u
+            LEFT JOIN shard_a a ON u.source = 'idx_a'::regclass AND a.ctid = u.ctid
+            LEFT JOIN shard_b b ON u.source = 'idx_b'::regclass AND b.ctid = u.ctid",
+        )?;
+        let expected = Spi::get_one::<Vec<i32>>(
+            "SELECT array_agg(id ORDER BY distance) FROM (
+                SELECT id, embedding <=> '[40,5,1]' AS distance FROM shard_a
+                UNION ALL
+                SELECT id, embedding <=> '[40,5,1]' AS distance FROM shard_b
+                ORDER BY distance LIMIT 10) t",
+        )?;
+        assert_eq!(union, expected);
+
+        /* the distances come out nearest first */
+        let out_of_order = Spi::get_one::<i64>(
+            "SELECT count(*) FROM (
+                SELECT distance < lag(distance) OVER (ORDER BY ord) AS out_of_order
+                FROM tsv_search_union(ARRAY['idx_a'::regclass, 'idx_b'::regclass], '[40,5,1]', 20)
+                    WITH ORDINALITY u(source, ctid, distance, ord)) u
+            WHERE out_of_order",
+        )?;
+        assert_eq!(out_of_order, Some(0));
+
+        Ok(())
+    }
+
+    #[pg_test(error = "diskann indexes \"idx_a\" and \"idx_b\" must be on the same table")]
+    unsafe fn test_tsv_fused_search_different_tables() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_a (embedding vector (3));
+            CREATE TABLE test_b (embedding vector (3));
+            CREATE INDEX idx_a ON test_a USING diskann (embedding);
+            CREATE INDEX idx_b ON test_b USING diskann (embedding);
+            SELECT * FROM tsv_fused_search('idx_a'::regclass, '[1,0,0]', 1, 'idx_b'::regclass, '[1,0,0]', 1, 3);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_search_recent() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (id int, embedding vector (3), inserted_at timestamptz);
+            INSERT INTO test_data (id, embedding, inserted_at) VALUES
+                (1, '[1,0,0]', now() - interval '30 days'),
+                (2, '[1,0.2,0]', now()),
+                (3, '[0,1,0]', now()),
+                (4, '[1,0.1,0]', NULL);
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);",
+        )?;
+
+        let ranked = "SELECT array_agg(t.id ORDER BY r.score)
+            FROM tsv_search_recent('idx_diskann'::regclass, '[1,0,0]', 4, 'inserted_at', '1 day', {weight}) r
+            JOIN test_data t ON t.ctid = r.ctid";
+
+        /* without weight, the order of the distances */
+        let ids = Spi::get_one::<Vec<i32>>(&ranked.replace("{weight}", "0"))?;
+        assert_eq!(ids.unwrap(), vec![1, 4, 2, 3]);
+
+        /* with it, the fresh rows come first, then the old one, then the one with no time */
+        let ids = Spi::get_one::<Vec<i32>>(&ranked.replace("{weight}", "10"))?;
+        assert_eq!(ids.unwrap(), vec![2, 3, 1, 4]);
+
+        /* a fresh row keeps its distance as score */
+        let (distance, score) = Spi::get_two::<f64, f64>(
+            "SELECT r.distance, r.score
+            FROM tsv_search_recent('idx_diskann'::regclass, '[1,0,0]', 4, 'inserted_at', '1 day') r
+            JOIN test_data t ON t.ctid = r.ctid WHERE t.id = 2",
+        )?;
+        assert!((distance.unwrap() - score.unwrap()).abs() < 1e-9);
+
+        Ok(())
+    }
+
+    #[pg_test(error = "column \"embedding\" must be of type timestamptz")]
+    unsafe fn test_tsv_search_recent_wrong_column_type() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);
+            SELECT * FROM tsv_search_recent('idx_diskann'::regclass, '[1,0,0]', 4, 'embedding', '1 day');",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_search_mmr() -> spi::Result<()> {
+        /* 9 directions 10 degrees apart, 10 rows each */
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[cos(radians(k * 10)), sin(radians(k * 10)), 0]::vector
+            FROM generate_series(0, 8) k, generate_series(1, 10) copy;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding) WITH (storage_layout = plain);",
+        )?;
+
+        let distinct_in_top_5 = "SELECT count(DISTINCT t.embedding::text)
+            FROM tsv_search_mmr('idxtest'::regclass, '[1,0,0]', 5, {lambda}) r
+            JOIN test t ON t.ctid = r.ctid";
+
+        /* a lambda of 1 is the order of the distances */
+        let distinct = Spi::get_one::<i64>(&distinct_in_top_5.replace("{lambda}", "1"))?;
+        assert_eq!(distinct.unwrap(), 1);
+
+        let distinct = Spi::get_one::<i64>(&distinct_in_top_5.replace("{lambda}", "0.3"))?;
+        assert_eq!(distinct.unwrap(), 5);
+
+        /* the nearest row still comes first */
+        let first = Spi::get_one::<String>(
+            "SELECT t.embedding::text FROM tsv_search_mmr('idxtest'::regclass, '[1,0,0]', 5, 0.3) r
+            JOIN test t ON t.ctid = r.ctid LIMIT 1",
+        )?;
+        assert_eq!(first.unwrap(), "[1,0,0]");
+
+        /* index scans keep the order of the distances */
+        Spi::run("SET enable_seqscan = 0;")?;
+        let distinct = Spi::get_one::<i64>(
+            "SELECT count(DISTINCT embedding::text) FROM (SELECT embedding FROM test ORDER BY embedding <=> '[1,0,0]' LIMIT 5) t",
+        )?;
+        assert_eq!(distinct.unwrap(), 1);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_selftest() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized"] {
+            crate::access_method::build::tests::create_test_data(32, 300)?;
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout});"
+            ))?;
+
+            let (queries, recall) = Spi::get_two::<i64, f64>(
+                "SELECT queries, recall FROM tsv_selftest('idx_diskann'::regclass, 20, 10)",
+            )?;
+            assert_eq!(queries.unwrap(), 20);
+            let recall = recall.unwrap();
+            assert!(recall > 0.9 && recall <= 1.0, "recall {}", recall);
+
+            let anomalies = Spi::get_one::<i64>(
+                "SELECT duplicate_results + short_results FROM tsv_selftest('idx_diskann'::regclass, 20, 10)",
+            )?;
+            assert_eq!(anomalies.unwrap(), 0);
+        }
+
+        /* fewer rows than asked for: every row is a query, and k is capped */
+        Spi::run("DELETE FROM test_data WHERE ctid NOT IN (SELECT ctid FROM test_data LIMIT 5)")?;
+        let (queries, recall) = Spi::get_two::<i64, f64>(
+            "SELECT queries, recall FROM tsv_selftest('idx_diskann'::regclass, 20, 10)",
+        )?;
+        assert_eq!(queries.unwrap(), 5);
+        assert_eq!(recall.unwrap(), 1.0);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_rescore_with_pgvector() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (embedding vector (3));
+            INSERT INTO test_data (embedding)
+                SELECT ARRAY[i * 0.37, i % 7 + 0.1, -i * 1.3]::vector FROM generate_series(1, 100) i;
+            CREATE INDEX idx_diskann ON test_data USING diskann (embedding);
+            SET diskann.rescore_with_pgvector = on;",
+        )?;
+
+        /* the distances are the ones of the <=> operator, to the last bit of a real */
+        let cnt: Option<i64> = Spi::get_one(
+            "SELECT count(*)
+            FROM tsv_search_multimetric('idx_diskann'::regclass, '[0.5,1.5,-2]', 10) m
+            JOIN test_data t ON t.ctid = m.ctid
+            WHERE m.distance::real = (t.embedding <=> '[0.5,1.5,-2]')::real",
+        )?;
+        assert_eq!(cnt.unwrap(), 10);
+
+        /* and index scans rescore with them */
+        Spi::run("SET enable_seqscan = 0;")?;
+        let nearest: Option<String> = Spi::get_one(
+            "SELECT embedding::text FROM test_data ORDER BY embedding <=> '[3.7,3.1,-13]' LIMIT 1",
+        )?;
+        assert_eq!(nearest.unwrap(), "[3.7,3.1,-13]");
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_rank_candidates() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized"] {
+            Spi::run(&format!(
+                "DROP TABLE IF EXISTS test_data;
+                CREATE TABLE test_data (id int, embedding vector (3));
+                INSERT INTO test_data (id, embedding)
+                    SELECT i, ARRAY[i, i % 7, 1]::vector FROM generate_series(1, 100) i;
+                CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout});
+                DELETE FROM test_data WHERE id = 50;"
+            ))?;
+
+            let ranked = Spi::get_one::<Vec<i32>>(
+                "SELECT array_agg(t.id ORDER BY r.distance, t.id)
+                   FROM tsv_rank_candidates('idx_diskann'::regclass, '[100,0,1]',
+                        ARRAY(SELECT ctid FROM test_data WHERE id IN (3, 40, 90, 97)) || '(4000,1)'::tid) r
+                   JOIN test_data t ON t.ctid = r.ctid",
+            )?
+            .unwrap();
+            assert_eq!(ranked.len(), 4, "{:?}", ranked);
+            if storage_layout == "plain" {
+                assert_eq!(ranked, vec![97, 90, 40, 3]);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_entry_point_distance() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized"] {
+            Spi::run(&format!(
+                "DROP TABLE IF EXISTS test_data;
+                CREATE TABLE test_data (id int, embedding vector (3));
+                INSERT INTO test_data (id, embedding)
+                    SELECT i, ARRAY[i, i % 7, 1]::vector FROM generate_series(1, 100) i;
+                CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout});"
+            ))?;
+
+            /* the build starts the graph at the first row */
+            let (id, distance) = Spi::get_two::<i32, f64>(
+                "SELECT t.id, e.distance
+                   FROM tsv_entry_point_distance('idx_diskann'::regclass, '[1,1,1]') e
+                   JOIN test_data t ON t.ctid = e.ctid",
+            )?;
+            assert_eq!(id, Some(1), "{}", storage_layout);
+            assert!(distance.unwrap().abs() < 0.0001, "{:?}", distance);
+
+            let far = Spi::get_one::<f64>(
+                "SELECT distance FROM tsv_entry_point_distance('idx_diskann'::regclass, '[100,2,1]')",
+            )?;
+            assert!(far.unwrap() > 0.0, "{:?}", far);
+        }
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tsv_rerank_ranks() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized"] {
+            crate::access_method::build::tests::create_test_data(32, 300)?;
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout});
+
+                DROP TABLE IF EXISTS target;
+                CREATE TEMP TABLE target AS SELECT embedding FROM test_data LIMIT 1;"
+            ))?;
+
+            /* both ranks are permutations of 1..pool_size */
+            let (cnt, approximate, exact) = Spi::get_three::<i64, i64, i64>(
+                "SELECT count(*), count(DISTINCT approximate_rank) FILTER (WHERE approximate_rank BETWEEN 1 AND 50),
+                        count(DISTINCT exact_rank) FILTER (WHERE exact_rank BETWEEN 1 AND 50)
+                FROM tsv_rerank_ranks('idx_diskann'::regclass, (SELECT embedding FROM target), 50)",
+            )?;
+            assert_eq!(cnt, Some(50), "{}", storage_layout);
+            assert_eq!(approximate, Some(50), "{}", storage_layout);
+            assert_eq!(exact, Some(50), "{}", storage_layout);
+
+            /* the exact ranks follow the distances */
+            let out_of_order = Spi::get_one::<i64>(
+                "SELECT count(*) FROM (
+                    SELECT distance < lag(distance) OVER (ORDER BY exact_rank) AS out_of_order
+                    FROM tsv_rerank_ranks('idx_diskann'::regclass, (SELECT embedding FROM target), 50)) r
+                WHERE out_of_order",
+            )?;
+            assert_eq!(out_of_order, Some(0), "{}", storage_layout);
+
+            /* the row itself is the nearest */
+            let rank = Spi::get_one::<i32>(
+                "SELECT r.exact_rank
+                FROM tsv_rerank_ranks('idx_diskann'::regclass, (SELECT embedding FROM target), 50) r
+                JOIN test_data t ON t.ctid = r.ctid
+                WHERE t.embedding = (SELECT embedding FROM target)",
+            )?;
+            assert_eq!(rank, Some(1), "{}", storage_layout);
+        }
+
+        Ok(())
+    }
+}
@@ -121,17 +121,54 @@ pub trait Storage {
         stats: &mut S,
     );
 
+    /// Adds `neighbor` to the neighbor list of the node at `index_pointer` if the list has room
+    /// for it. Unlike a get_neighbors_with_distances_from_disk()/set_neighbors_on_disk() round
+    /// trip, the list is read under the same lock it is written with, so concurrent inserts
+    /// adding themselves to the same node don't overwrite each other's edges. Only that one
+    /// buffer is locked. Returns false, without changing anything, if the list is full; the
+    /// caller then prunes it. A neighbor that is already in the list counts as added.
+    fn append_neighbor_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        neighbor: IndexPointer,
+        stats: &mut S,
+    ) -> bool;
+
     fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32;
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum StorageType {
     Plain = 0,
     SbqSpeedup = 1,
     SbqCompression = 2,
 }
 
-pub const DEFAULT_STORAGE_TYPE_STR: &str = "memory_optimized";
+pub const AUTO_STORAGE_TYPE_STR: &str = "auto";
+
+/// The storage_layout of indexes created without the option, see the
+/// diskann.default_storage_layout GUC. The variants are named as they are set.
+#[allow(non_camel_case_types)]
+#[derive(pgrx::PostgresGucEnum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DefaultStorageLayout {
+    memory_optimized,
+    io_optimized,
+    plain,
+    auto,
+}
+
+impl DefaultStorageLayout {
+    /// The storage_layout value of this layout.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DefaultStorageLayout::memory_optimized => "memory_optimized",
+            DefaultStorageLayout::io_optimized => "io_optimized",
+            DefaultStorageLayout::plain => "plain",
+            DefaultStorageLayout::auto => AUTO_STORAGE_TYPE_STR,
+        }
+    }
+}
 
 impl StorageType {
     pub fn from_u8(value: u8) -> Self {
@@ -143,6 +180,15 @@ impl StorageType {
         }
     }
 
+    /// The storage_layout value of this storage type.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageType::Plain => "plain",
+            StorageType::SbqSpeedup => "io_optimized",
+            StorageType::SbqCompression => "memory_optimized",
+        }
+    }
+
     pub fn from_str(value: &str) -> Self {
         match value.to_lowercase().as_str() {
             "plain" => StorageType::Plain,
@@ -1,5 +1,14 @@
+use std::ops::Range;
+
+use pgrx::pg_sys::{AsPgCStr, FirstOffsetNumber};
 use pgrx::{pg_sys, PgRelation};
 
+use crate::util::page::ReadablePage;
+use crate::util::ports::{pg_class_ownercheck, PageGetItem, PageGetItemId, PageGetMaxOffsetNumber};
+use crate::util::{HeapPointer, IndexPointer, ItemPointer};
+
+use super::storage::{ArchivedData, Storage};
+
 pub fn get_attribute_number_from_index(index: &PgRelation) -> pg_sys::AttrNumber {
     unsafe {
         let a = index.rd_index;
@@ -8,3 +17,133 @@ pub fn get_attribute_number_from_index(index: &PgRelation) -> pg_sys::AttrNumber
         (*a).indkey.values.as_slice(natts as _)[0]
     }
 }
+
+/// Errors out unless the relation is a diskann index.
+/// Used by SQL functions taking an index as a regclass argument.
+pub fn check_is_diskann_index(index: &PgRelation) {
+    let is_diskann = index.is_index()
+        && unsafe {
+            let am_oid = pg_sys::get_am_oid("diskann".as_pg_cstr(), false);
+            (*index.rd_rel).relam == am_oid
+        };
+    if !is_diskann {
+        pgrx::error!("\"{}\" is not a diskann index", index.name());
+    }
+}
+
+/// Errors out unless the current user owns the index, as REINDEX does.
+/// Used by SQL functions that rewrite the index.
+pub fn check_index_owner(index: &PgRelation) {
+    unsafe {
+        if !pg_class_ownercheck(index.oid(), pg_sys::GetUserId()) {
+            pg_sys::aclcheck_error(
+                pg_sys::AclResult_ACLCHECK_NOT_OWNER,
+                pg_sys::ObjectType_OBJECT_INDEX,
+                index.name().as_pg_cstr(),
+            );
+        }
+    }
+}
+
+/// Errors out unless the current user has SELECT on the table of the index. Used by SQL
+/// functions that return or compare the rows of the table, which they read through the
+/// decrypt_function of the index, if any.
+pub fn check_heap_select_privilege(index: &PgRelation) {
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    unsafe {
+        let result = pg_sys::pg_class_aclcheck(
+            heap.oid(),
+            pg_sys::GetUserId(),
+            pg_sys::ACL_SELECT as pg_sys::AclMode,
+        );
+        if result != pg_sys::AclResult_ACLCHECK_OK {
+            pg_sys::aclcheck_error(
+                result,
+                pg_sys::ObjectType_OBJECT_TABLE,
+                heap.name().as_pg_cstr(),
+            );
+        }
+    }
+}
+
+/// Calls `f` for every node stored in the index, in physical order.
+/// The page of the node is share-locked while `f` runs, so `f` must not modify the index.
+pub fn for_each_node<S: Storage, F: FnMut(IndexPointer, &S::ArchivedType)>(
+    index: &PgRelation,
+    f: F,
+) {
+    let nblocks = unsafe {
+        pg_sys::RelationGetNumberOfBlocksInFork(index.as_ptr(), pg_sys::ForkNumber_MAIN_FORKNUM)
+    };
+    for_each_node_in_blocks::<S, _>(index, 0..nblocks, f);
+}
+
+/// Calls `f` for every node stored on the blocks `blocks` of the index, in physical order.
+/// The page of the node is share-locked while `f` runs, so `f` must not modify the index.
+pub fn for_each_node_in_blocks<S: Storage, F: FnMut(IndexPointer, &S::ArchivedType)>(
+    index: &PgRelation,
+    blocks: Range<pg_sys::BlockNumber>,
+    mut f: F,
+) {
+    for block_number in blocks {
+        let page = unsafe { ReadablePage::read(index, block_number) };
+        if page.get_type() != S::page_type() {
+            continue;
+        }
+
+        let max_offset = unsafe { PageGetMaxOffsetNumber(*page) };
+        for offset_number in FirstOffsetNumber..(max_offset + 1) as _ {
+            unsafe {
+                let item_id = PageGetItemId(*page, offset_number);
+                let item = PageGetItem(*page, item_id) as *mut u8;
+                let len = (*item_id).lp_len();
+                let data = std::slice::from_raw_parts_mut(item, len as _);
+                let node = S::ArchivedType::with_data(data);
+                f(ItemPointer::new(block_number, offset_number), &node);
+            }
+        }
+    }
+}
+
+/// Calls `f` with the node at `index_pointer`.
+/// The page of the node is share-locked while `f` runs, so `f` must not modify the index.
+pub fn with_node<S: Storage, R, F: FnOnce(&S::ArchivedType) -> R>(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+    f: F,
+) -> R {
+    unsafe {
+        let page = ReadablePage::read(index, index_pointer.block_number);
+        let item_id = PageGetItemId(*page, index_pointer.offset);
+        let item = PageGetItem(*page, item_id) as *mut u8;
+        let len = (*item_id).lp_len();
+        let data = std::slice::from_raw_parts_mut(item, len as _);
+        let node = S::ArchivedType::with_data(data);
+        f(&node)
+    }
+}
+
+/// Returns the pointers to all the nodes of the index that aren't deleted.
+/// Used as the starting points of searches on flat indexes.
+pub fn live_nodes<S: Storage>(index: &PgRelation) -> Vec<IndexPointer> {
+    let mut nodes = vec![];
+    for_each_node::<S, _>(index, |index_pointer, node| {
+        if !node.is_deleted() {
+            nodes.push(index_pointer);
+        }
+    });
+    nodes
+}
+
+/// Returns the heap pointers of the non-deleted nodes of the index, in index order.
+pub fn live_heap_pointers<S: Storage>(index: &PgRelation) -> Vec<HeapPointer> {
+    let mut heap_pointers = vec![];
+    for_each_node::<S, _>(index, |_, node| {
+        if !node.is_deleted() {
+            heap_pointers.push(node.get_heap_item_pointer());
+        }
+    });
+    heap_pointers
+}
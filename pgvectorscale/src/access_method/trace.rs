@@ -0,0 +1,232 @@
+//! A per-backend trace of the candidates evaluated by index searches, for offline analysis.
+//!
+//! With `diskann.trace_candidates` on, every search records its query and each node it
+//! computes a distance to, with the distance the search used: the SBQ distance for the
+//! memory_optimized and io_optimized layouts, the distance to the stored vector for plain.
+//! tsv_traced_candidates() returns the trace along with the exact distance of each candidate,
+//! computed from the table when it is called, so that the trace can be copied into a table
+//! with `INSERT ... SELECT` and compared offline.
+//!
+//! Searches only append to the trace, which grows until tsv_trace_reset(): each search takes
+//! 4 bytes per dimension for its query and 12 bytes per candidate. Entries are keyed by the
+//! relfilenode of the index, which a REINDEX changes, so a rebuilt index doesn't resolve the
+//! node pointers of the old one.
+
+use std::cell::RefCell;
+
+use pgrx::*;
+
+use crate::util::{table_slot::TableSlot, IndexPointer};
+
+use super::meta_page::MetaPage;
+use super::pg_vector::PgVector;
+use super::plain_storage::PlainStorage;
+use super::sbq::SbqSpeedupStorage;
+use super::stats::GreedySearchStats;
+use super::storage::{ArchivedData, Storage, StorageType};
+use super::storage_common::{
+    check_heap_select_privilege, check_is_diskann_index, get_attribute_number_from_index, with_node,
+};
+
+struct TracedSearch {
+    id: u64,
+    relfilenode: pg_sys::Oid,
+    query: Vec<f32>,
+    /// The node of each candidate and the distance the search computed to it.
+    candidates: Vec<(IndexPointer, f32)>,
+}
+
+struct Trace {
+    searches: Vec<TracedSearch>,
+    next_id: u64,
+}
+
+thread_local! {
+    static TRACE: RefCell<Trace> = RefCell::new(Trace { searches: vec![], next_id: 1 });
+}
+
+/// Whether diskann.trace_candidates is on.
+pub fn is_enabled() -> bool {
+    super::guc::TSV_TRACE_CANDIDATES.get()
+}
+
+/// Adds a search of `index` for `query` to the trace and returns its id.
+pub fn start_search(index: &PgRelation, query: &[f32]) -> u64 {
+    let relfilenode = unsafe { (*index.rd_rel).relfilenode };
+    TRACE.with(|trace| {
+        let mut trace = trace.borrow_mut();
+        let id = trace.next_id;
+        trace.next_id += 1;
+        trace.searches.push(TracedSearch {
+            id,
+            relfilenode,
+            query: query.to_vec(),
+            candidates: vec![],
+        });
+        id
+    })
+}
+
+/// Records a candidate of the search `search_id`. Does nothing if the trace was reset since
+/// the search started.
+pub fn record(search_id: u64, index_pointer: IndexPointer, distance: f32) {
+    TRACE.with(|trace| {
+        let mut trace = trace.borrow_mut();
+        if let Some(search) = trace.searches.iter_mut().rev().find(|s| s.id == search_id) {
+            search.candidates.push((index_pointer, distance));
+        }
+    })
+}
+
+type TracedCandidate = (
+    i64,
+    Vec<f32>,
+    Option<pg_sys::ItemPointerData>,
+    f64,
+    Option<f64>,
+);
+
+/// Resolves the candidates traced for `index` to their heap rows and exact distances.
+fn resolve<S: Storage>(index: &PgRelation, meta_page: &MetaPage) -> Vec<TracedCandidate> {
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let heap_attr = get_attribute_number_from_index(index);
+    let distance_fn = meta_page.get_distance_function();
+    let relfilenode = unsafe { (*index.rd_rel).relfilenode };
+    let snapshot = unsafe { pg_sys::GetActiveSnapshot() };
+    let mut stats = GreedySearchStats::new();
+
+    let searches: Vec<(u64, Vec<f32>, Vec<(IndexPointer, f32)>)> = TRACE.with(|trace| {
+        trace
+            .borrow()
+            .searches
+            .iter()
+            .filter(|s| s.relfilenode == relfilenode)
+            .map(|s| (s.id, s.query.clone(), s.candidates.clone()))
+            .collect()
+    });
+
+    let mut rows = vec![];
+    for (id, query, candidates) in searches {
+        for (index_pointer, distance) in candidates {
+            check_for_interrupts!();
+            let heap_pointer = with_node::<S, _, _>(index, index_pointer, |node| {
+                (!node.is_deleted()).then(|| node.get_heap_item_pointer())
+            });
+            let full_distance = heap_pointer.and_then(|heap_pointer| unsafe {
+                let slot = TableSlot::new_visible(&heap, heap_pointer, snapshot, &mut stats)?;
+                let datum = slot.get_attribute(heap_attr)?;
+                let vector = PgVector::from_heap_datum(datum, meta_page, false, true);
+                Some(distance_fn(&query, vector.to_full_slice()) as f64)
+            });
+            let ctid = heap_pointer.map(|heap_pointer| {
+                let mut ctid = pg_sys::ItemPointerData::default();
+                heap_pointer.to_item_pointer_data(&mut ctid);
+                ctid
+            });
+            rows.push((
+                id as i64,
+                query.clone(),
+                ctid,
+                distance as f64,
+                full_distance,
+            ));
+        }
+    }
+    rows
+}
+
+/// Returns the candidates that the searches of `index` in this session evaluated while
+/// diskann.trace_candidates was on: the id and query of the search, the row of the candidate
+/// (NULL if its node has been deleted since), the distance the search computed to it and the
+/// exact distance computed from the row visible now (NULL if there is none).
+#[pg_extern]
+pub fn tsv_traced_candidates(
+    index: PgRelation,
+) -> TableIterator<
+    'static,
+    (
+        name!(search_id, i64),
+        name!(query, Vec<f32>),
+        name!(ctid, Option<pg_sys::ItemPointerData>),
+        name!(index_distance, f64),
+        name!(full_distance, Option<f64>),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_heap_select_privilege(&index);
+    let meta_page = MetaPage::fetch(&index);
+    let rows = match meta_page.get_storage_type() {
+        StorageType::Plain => resolve::<PlainStorage>(&index, &meta_page),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            resolve::<SbqSpeedupStorage>(&index, &meta_page)
+        }
+    };
+    TableIterator::new(rows)
+}
+
+/// Empties the candidate trace of this session.
+#[pg_extern]
+pub fn tsv_trace_reset() {
+    TRACE.with(|trace| trace.borrow_mut().searches.clear());
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_tsv_traced_candidates() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized"] {
+            crate::access_method::build::tests::create_test_data(32, 300)?;
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann ON test_data USING diskann (embedding)
+                    WITH (storage_layout = {storage_layout});
+
+                SET enable_seqscan = 0;
+                SELECT tsv_trace_reset();"
+            ))?;
+
+            let query = "SELECT count(*) FROM (SELECT * FROM test_data ORDER BY embedding <=> (SELECT embedding FROM test_data WHERE ctid = '(0,1)') LIMIT 10) t";
+            let traced = "SELECT count(*) FROM tsv_traced_candidates('idx_diskann'::regclass)";
+
+            /* nothing is traced by default */
+            Spi::get_one::<i64>(query)?;
+            assert_eq!(Spi::get_one::<i64>(traced)?.unwrap(), 0);
+
+            Spi::run("SET diskann.trace_candidates = on;")?;
+            Spi::get_one::<i64>(query)?;
+            Spi::get_one::<i64>(query)?;
+            let (searches, candidates, closest) = Spi::get_three::<i64, i64, f64>(
+                "SELECT count(DISTINCT search_id), count(*), min(full_distance) FROM tsv_traced_candidates('idx_diskann'::regclass)",
+            )?;
+            assert_eq!(searches.unwrap(), 2);
+            assert!(candidates.unwrap() >= 20, "{:?}", candidates);
+            /* the query row itself is among the candidates */
+            assert!(closest.unwrap() < 1e-6, "{:?}", closest);
+
+            if storage_layout == "plain" {
+                /* plain searches already use the exact distances */
+                let max_error = Spi::get_one::<f64>(
+                    "SELECT max(abs(index_distance - full_distance)) FROM tsv_traced_candidates('idx_diskann'::regclass)",
+                )?;
+                assert!(max_error.unwrap() < 1e-5, "{:?}", max_error);
+            }
+
+            /* rows deleted since the search aren't visible anymore, they have no exact distance */
+            Spi::run("DELETE FROM test_data WHERE ctid = '(0,1)';")?;
+            let deleted = Spi::get_two::<i64, i64>(
+                "SELECT count(*), count(full_distance) FROM tsv_traced_candidates('idx_diskann'::regclass) WHERE ctid = '(0,1)'",
+            )?;
+            assert!(deleted.0.unwrap() > 0, "{:?}", deleted);
+            assert_eq!(deleted.1, Some(0));
+
+            Spi::run("SELECT tsv_trace_reset(); RESET diskann.trace_candidates;")?;
+            assert_eq!(Spi::get_one::<i64>(traced)?.unwrap(), 0);
+        }
+
+        Ok(())
+    }
+}
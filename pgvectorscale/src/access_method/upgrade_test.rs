@@ -21,26 +21,15 @@ pub mod tests {
         Ok(())
     }
 
-    #[test]
-    #[ignore]
-    ///This function is only a mock to bring up the test framewokr in test_delete_vacuum
-    fn test_upgrade() {
-        pgrx_tests::run_test(
-            "test_delete_mock_fn",
-            None,
-            crate::pg_test::postgresql_conf_options(),
-        )
-        .unwrap();
-
-        let (mut client, _) = pgrx_tests::client().unwrap();
-
-        client
-            .execute(
-                &format!("DROP EXTENSION IF EXISTS vectorscale CASCADE;"),
-                &[],
-            )
-            .unwrap();
+    fn pg_config() -> pgrx_pg_config::PgConfig {
+        let pgrx = pgrx_pg_config::Pgrx::from_config().unwrap();
+        let pg_version = pg_sys::get_pg_major_version_num();
+        pgrx.get(&format!("pg{}", pg_version)).unwrap()
+    }
 
+    /// Installs the extension as of the git ref `version`, built from the crate in `crate_dir`.
+    /// Releases older than the pgrx we use have their pgrx dependency replaced.
+    fn install_old_version(version: &str, crate_dir: &str, replace_pgrx: bool) {
         let current_file = file!();
 
         // Convert the file path to an absolute path
@@ -53,11 +42,9 @@ pub mod tests {
 
         copy_dir_all(absolute_path.clone(), temp_dir.path()).unwrap();
 
-        let pgrx = pgrx_pg_config::Pgrx::from_config().unwrap();
         let pg_version = pg_sys::get_pg_major_version_num();
-        let pg_config = pgrx.get(&format!("pg{}", pg_version)).unwrap();
+        let pg_config = pg_config();
 
-        let version = "0.0.2";
         let res = std::process::Command::new("git")
             .current_dir(temp_path)
             .arg("checkout")
@@ -73,49 +60,87 @@ pub mod tests {
             temp_dir.path()
         );
 
-        // use latest pgrx
-        let res = std::process::Command::new("cargo")
-            .current_dir(temp_path.join("timescale_vector"))
-            .args(["rm", "pgrx"])
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::piped())
-            .output()
-            .unwrap();
-        assert!(res.status.success(), "failed: {:?}", res);
+        if replace_pgrx {
+            // use latest pgrx
+            let res = std::process::Command::new("cargo")
+                .current_dir(temp_path.join(crate_dir))
+                .args(["rm", "pgrx"])
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::piped())
+                .output()
+                .unwrap();
+            assert!(res.status.success(), "failed: {:?}", res);
 
-        let res = std::process::Command::new("cargo")
-            .current_dir(temp_path.join("timescale_vector"))
-            .args(["rm", "--dev", "pgrx-tests"])
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::piped())
-            .output()
-            .unwrap();
-        assert!(res.status.success(), "failed: {:?}", res);
+            let res = std::process::Command::new("cargo")
+                .current_dir(temp_path.join(crate_dir))
+                .args(["rm", "--dev", "pgrx-tests"])
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::piped())
+                .output()
+                .unwrap();
+            assert!(res.status.success(), "failed: {:?}", res);
+
+            let res = std::process::Command::new("cargo")
+                .current_dir(temp_path.join(crate_dir))
+                .args(["add", "-F", &format!("pg{}", pg_version), "pgrx"])
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::piped())
+                .output()
+                .unwrap();
+            assert!(res.status.success(), "failed: {:?}", res);
+        }
 
         let res = std::process::Command::new("cargo")
-            .current_dir(temp_path.join("timescale_vector"))
-            .args(["add", "-F", &format!("pg{}", pg_version), "pgrx"])
+            .current_dir(temp_path.join(crate_dir))
+            .arg("pgrx")
+            .arg("install")
+            .arg("--test")
+            .arg("--pg-config")
+            .arg(pg_config.path().unwrap())
             .stdout(Stdio::inherit())
             .stderr(Stdio::piped())
             .output()
             .unwrap();
         assert!(res.status.success(), "failed: {:?}", res);
+    }
 
-        //let contents = fs::read_to_string(temp_path.join("pgvectorscale/Cargo.toml")).unwrap();
-        //print!("cargo {}", contents);
-
+    /// Reinstalls the extension from this tree over an old version.
+    fn reinstall_current_version() {
         let res = std::process::Command::new("cargo")
-            .current_dir(temp_path.join("timescale_vector"))
             .arg("pgrx")
             .arg("install")
             .arg("--test")
             .arg("--pg-config")
-            .arg(pg_config.path().unwrap())
+            .arg(pg_config().path().unwrap())
             .stdout(Stdio::inherit())
             .stderr(Stdio::piped())
             .output()
             .unwrap();
         assert!(res.status.success(), "failed: {:?}", res);
+    }
+
+    #[test]
+    #[ignore]
+    ///This function is only a mock to bring up the test framewokr in test_delete_vacuum
+    fn test_upgrade() {
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        let (mut client, _) = pgrx_tests::client().unwrap();
+
+        client
+            .execute(
+                &format!("DROP EXTENSION IF EXISTS vectorscale CASCADE;"),
+                &[],
+            )
+            .unwrap();
+
+        let version = "0.0.2";
+        install_old_version(version, "timescale_vector", true);
 
         client
             .execute(
@@ -163,17 +188,7 @@ pub mod tests {
         assert_eq!(cnt, 303, "count before upgrade");
 
         //reinstall myself
-        let res = std::process::Command::new("cargo")
-            .arg("pgrx")
-            .arg("install")
-            .arg("--test")
-            .arg("--pg-config")
-            .arg(pg_config.path().unwrap())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::piped())
-            .output()
-            .unwrap();
-        assert!(res.status.success(), "failed: {:?}", res);
+        reinstall_current_version();
 
         client
             .execute(
@@ -200,4 +215,104 @@ pub mod tests {
         let cnt: i64 = client.query_one(&format!("WITH cte as (select * from test order by embedding <=> '[1,1,1,{suffix}]') SELECT count(*) from cte;"), &[]).unwrap().get(0);
         assert_eq!(cnt, 303, "count after upgrade");
     }
+
+    #[test]
+    #[ignore]
+    /// Indexes built by 0.2.0 have a version 2 meta page, which is read as a version 3 one
+    /// with the defaults of the options added since.
+    ///
+    /// This only checks that the library of this tree reads version 2 meta pages. The crate
+    /// version is still 0.2.0, so the ALTER EXTENSION below is a no-op and the SQL objects
+    /// stay those of 0.2.0. Known gap: the functions added since 0.2.0 (the tsv_* ones)
+    /// don't reach existing installations until the version is bumped and a
+    /// vectorscale--0.2.0--<next>.sql upgrade script is added for them.
+    fn test_upgrade_meta_page_v2() {
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        let (mut client, _) = pgrx_tests::client().unwrap();
+
+        client
+            .execute("DROP EXTENSION IF EXISTS vectorscale CASCADE;", &[])
+            .unwrap();
+
+        let version = "0.2.0";
+        install_old_version(version, "pgvectorscale", false);
+
+        client
+            .execute(
+                &format!(
+                    "CREATE EXTENSION vectorscale VERSION '{}' CASCADE;",
+                    version
+                ),
+                &[],
+            )
+            .unwrap();
+
+        for layout in ["memory_optimized", "plain"] {
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE test_{layout}(embedding vector(256));
+
+        select setseed(0.5);
+        -- generate 300 vectors
+        INSERT INTO test_{layout}(embedding)
+        SELECT
+         *
+        FROM (
+            SELECT
+        ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+        FROM
+         generate_series(1, 256 * 300) i
+        GROUP BY
+        i % 300) g;
+
+        CREATE INDEX idx_{layout}
+              ON test_{layout}
+           USING diskann(embedding) WITH (storage_layout = {layout});
+            "
+                ))
+                .unwrap();
+
+            client.execute("set enable_seqscan = 0;", &[]).unwrap();
+            let cnt: i64 = client.query_one(&format!("WITH cte as (select * from test_{layout} order by embedding <=> (SELECT embedding FROM test_{layout} LIMIT 1)) SELECT count(*) from cte;"), &[]).unwrap().get(0);
+            assert_eq!(cnt, 300, "count before upgrade with {}", layout);
+        }
+
+        //reinstall myself
+        reinstall_current_version();
+
+        //need to recreate the client to load the new library
+        let (mut client, _) = pgrx_tests::client().unwrap();
+        // a no-op until the version moves past 0.2.0, see above
+        client
+            .execute(
+                &format!(
+                    "ALTER EXTENSION vectorscale UPDATE TO '{}'",
+                    env!("CARGO_PKG_VERSION")
+                ),
+                &[],
+            )
+            .unwrap();
+
+        client.execute("set enable_seqscan = 0;", &[]).unwrap();
+        for layout in ["memory_optimized", "plain"] {
+            let cnt: i64 = client.query_one(&format!("WITH cte as (select * from test_{layout} order by embedding <=> (SELECT embedding FROM test_{layout} LIMIT 1)) SELECT count(*) from cte;"), &[]).unwrap().get(0);
+            assert_eq!(cnt, 300, "count after upgrade with {}", layout);
+
+            // inserts read the upgraded meta page too
+            client
+                .execute(
+                    &format!("INSERT INTO test_{layout}(embedding) SELECT embedding FROM test_{layout} LIMIT 1;"),
+                    &[],
+                )
+                .unwrap();
+            let cnt: i64 = client.query_one(&format!("WITH cte as (select * from test_{layout} order by embedding <=> (SELECT embedding FROM test_{layout} LIMIT 1)) SELECT count(*) from cte;"), &[]).unwrap().get(0);
+            assert_eq!(cnt, 301, "count after insert with {}", layout);
+        }
+    }
 }
@@ -1,5 +1,8 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
 use pgrx::{
-    pg_sys::{FirstOffsetNumber, IndexBulkDeleteResult},
+    pg_sys::{AsPgCStr, FirstOffsetNumber, IndexBulkDeleteResult},
     *,
 };
 
@@ -8,13 +11,18 @@ use crate::{
     util::{
         page::WritablePage,
         ports::{PageGetItem, PageGetItemId, PageGetMaxOffsetNumber},
-        ItemPointer,
+        IndexPointer, ItemPointer,
     },
 };
 
 use crate::access_method::storage::ArchivedData;
 
-use super::storage::{Storage, StorageType};
+use super::neighbor_with_distance::NeighborWithDistance;
+use super::stats::PruneNeighborStats;
+use super::storage::{NodeDistanceMeasure, Storage, StorageType};
+use super::storage_common::{
+    check_index_owner, check_is_diskann_index, for_each_node_in_blocks, with_node,
+};
 
 #[pg_guard]
 pub extern "C" fn ambulkdelete(
@@ -41,35 +49,34 @@ pub extern "C" fn ambulkdelete(
     let storage = meta_page.get_storage_type();
     match storage {
         StorageType::SbqSpeedup | StorageType::SbqCompression => {
-            bulk_delete_for_storage::<SbqSpeedupStorage>(
+            bulk_delete_for_storage::<SbqSpeedupStorage, _>(
                 &index_relation,
-                nblocks,
+                0..nblocks,
                 results,
-                callback,
-                callback_state,
+                |ctid| unsafe { callback.unwrap()(ctid, callback_state) },
             );
         }
         StorageType::Plain => {
-            bulk_delete_for_storage::<PlainStorage>(
+            bulk_delete_for_storage::<PlainStorage, _>(
                 &index_relation,
-                nblocks,
+                0..nblocks,
                 results,
-                callback,
-                callback_state,
+                |ctid| unsafe { callback.unwrap()(ctid, callback_state) },
             );
         }
     }
     results
 }
 
-fn bulk_delete_for_storage<S: Storage>(
+/// Marks deleted the nodes on the blocks `blocks` whose heap pointer `is_dead` returns true
+/// for, and counts the removed and remaining nodes in `results`.
+fn bulk_delete_for_storage<S: Storage, F: FnMut(&mut pg_sys::ItemPointerData) -> bool>(
     index: &PgRelation,
-    nblocks: u32,
+    blocks: Range<pg_sys::BlockNumber>,
     results: *mut IndexBulkDeleteResult,
-    callback: pg_sys::IndexBulkDeleteCallback,
-    callback_state: *mut ::std::os::raw::c_void,
+    mut is_dead: F,
 ) {
-    for block_number in 0..nblocks {
+    for block_number in blocks {
         let page = unsafe { WritablePage::cleanup(&index, block_number) };
         if page.get_type() != S::page_type() {
             continue;
@@ -97,7 +104,7 @@ fn bulk_delete_for_storage<S: Storage>(
                 };
                 heap_pointer.to_item_pointer_data(&mut ctid);
 
-                let deleted = callback.unwrap()(&mut ctid, callback_state);
+                let deleted = is_dead(&mut ctid);
                 if deleted {
                     node.delete();
                     modified = true;
@@ -134,6 +141,205 @@ pub extern "C" fn amvacuumcleanup(
     }
 }
 
+/// Returns the neighbors of the node at `index_pointer` if it is deleted, None if it is live.
+/// Remembers the answer in `deleted`.
+fn deleted_node_neighbors<S: Storage>(
+    index: &PgRelation,
+    deleted: &mut HashMap<IndexPointer, Option<Vec<IndexPointer>>>,
+    index_pointer: IndexPointer,
+) -> Option<Vec<IndexPointer>> {
+    deleted
+        .entry(index_pointer)
+        .or_insert_with(|| {
+            with_node::<S, _, _>(index, index_pointer, |node| {
+                node.is_deleted()
+                    .then(|| node.get_index_pointer_to_neighbors())
+            })
+        })
+        .clone()
+}
+
+/// Replaces the edges to deleted nodes in the neighbor lists of the live nodes on the blocks
+/// `blocks` with the closest live neighbors of the deleted nodes, so that the graph stays
+/// connected through them. Returns the number of neighbor lists rewritten.
+fn repair_neighbor_lists<S: Storage>(
+    storage: &S,
+    index: &PgRelation,
+    meta_page: &MetaPage,
+    blocks: Range<pg_sys::BlockNumber>,
+) -> i64 {
+    let mut lists: Vec<(IndexPointer, Vec<IndexPointer>)> = vec![];
+    for_each_node_in_blocks::<S, _>(index, blocks, |index_pointer, node| {
+        if !node.is_deleted() {
+            lists.push((index_pointer, node.get_index_pointer_to_neighbors()));
+        }
+    });
+
+    let mut stats = PruneNeighborStats::new();
+    let mut deleted: HashMap<IndexPointer, Option<Vec<IndexPointer>>> = HashMap::new();
+    let mut repaired = 0;
+    for (index_pointer, neighbors) in lists {
+        check_for_interrupts!();
+        let mut live = vec![];
+        let mut bridges = vec![];
+        for &neighbor in neighbors.iter() {
+            match deleted_node_neighbors::<S>(index, &mut deleted, neighbor) {
+                None => live.push(neighbor),
+                Some(neighbor_neighbors) => bridges.extend(neighbor_neighbors),
+            }
+        }
+        let num_removed = neighbors.len() - live.len();
+        if num_removed == 0 {
+            continue;
+        }
+
+        bridges.sort();
+        bridges.dedup();
+        bridges.retain(|&bridge| {
+            bridge != index_pointer
+                && !live.contains(&bridge)
+                && deleted_node_neighbors::<S>(index, &mut deleted, bridge).is_none()
+        });
+
+        let dm = unsafe { storage.get_node_distance_measure(index_pointer, &mut stats) };
+        let mut bridges: Vec<NeighborWithDistance> = bridges
+            .into_iter()
+            .map(|n| unsafe { NeighborWithDistance::new(n, dm.get_distance(n, &mut stats)) })
+            .collect();
+        bridges.sort();
+        bridges.truncate(num_removed);
+
+        let mut new_neighbors: Vec<NeighborWithDistance> = live
+            .into_iter()
+            .map(|n| unsafe { NeighborWithDistance::new(n, dm.get_distance(n, &mut stats)) })
+            .chain(bridges)
+            .collect();
+        drop(dm);
+        new_neighbors.sort();
+
+        storage.set_neighbors_on_disk(meta_page, index_pointer, &new_neighbors, &mut stats);
+        repaired += 1;
+    }
+    repaired
+}
+
+/// Vacuums the blocks `start_block` (inclusive) to `end_block` (exclusive) of a diskann index
+/// without a VACUUM of the table: marks deleted the nodes whose rows are dead to every
+/// transaction and, with `repair_neighbors`, replaces the edges to deleted nodes in the
+/// neighbor lists of the nodes on these blocks with edges to the neighbors of the deleted
+/// nodes. Calling it over consecutive ranges spreads the work of vacuuming a large index.
+/// Returns the number of nodes marked deleted and of neighbor lists rewritten.
+///
+/// A node is only marked deleted once no transaction can see its row, following HOT chains,
+/// so concurrent searches never lose a visible row. Inserts can run concurrently unless
+/// `repair_neighbors` is set, which blocks them until the end of the transaction because they
+/// update neighbor lists too. Edges from nodes outside the range keep pointing to the deleted
+/// nodes, which searches walk through as usual, until their own range is repaired. A VACUUM
+/// of the table still has to run to reclaim the rows themselves.
+#[pg_extern]
+pub fn tsv_vacuum_range(
+    index: PgRelation,
+    start_block: i64,
+    end_block: i64,
+    repair_neighbors: default!(bool, false),
+) -> TableIterator<
+    'static,
+    (
+        name!(nodes_deleted, i64),
+        name!(neighbor_lists_repaired, i64),
+    ),
+> {
+    check_is_diskann_index(&index);
+    check_index_owner(&index);
+    unsafe { pg_sys::PreventCommandDuringRecovery("tsv_vacuum_range()".as_pg_cstr()) };
+    if start_block < 0 || end_block < start_block {
+        error!(
+            "invalid block range {}..{}, start_block must be at least 0 and at most end_block",
+            start_block, end_block
+        );
+    }
+
+    /* self-conflicting, so that two calls don't rewrite the same lists; repairs block inserts */
+    let lockmode = if repair_neighbors {
+        pg_sys::ShareRowExclusiveLock
+    } else {
+        pg_sys::ShareUpdateExclusiveLock
+    };
+    unsafe { pg_sys::LockRelationOid(index.oid(), lockmode as _) };
+
+    let heap = index
+        .heap_relation()
+        .expect("diskann index has no heap relation");
+    let nblocks = unsafe {
+        pg_sys::RelationGetNumberOfBlocksInFork(index.as_ptr(), pg_sys::ForkNumber_MAIN_FORKNUM)
+    };
+    let blocks = (start_block.min(nblocks as i64) as pg_sys::BlockNumber)
+        ..(end_block.min(nblocks as i64) as pg_sys::BlockNumber);
+
+    let results = unsafe { PgBox::<pg_sys::IndexBulkDeleteResult>::alloc0() };
+    unsafe {
+        /* fetch the rows like an index scan, which tells if the whole HOT chain is dead */
+        let table_am = heap.rd_tableam;
+        let fetch = (*table_am).index_fetch_begin.unwrap()(heap.as_ptr());
+        let slot = pg_sys::table_slot_create(heap.as_ptr(), std::ptr::null_mut());
+        let snapshot = pg_sys::GetActiveSnapshot();
+        let is_dead = |ctid: &mut pg_sys::ItemPointerData| {
+            let mut call_again = false;
+            let mut all_dead = false;
+            let found = (*table_am).index_fetch_tuple.unwrap()(
+                fetch,
+                ctid,
+                snapshot,
+                slot,
+                &mut call_again,
+                &mut all_dead,
+            );
+            !found && all_dead
+        };
+
+        match MetaPage::fetch(&index).get_storage_type() {
+            StorageType::SbqSpeedup | StorageType::SbqCompression => {
+                bulk_delete_for_storage::<SbqSpeedupStorage, _>(
+                    &index,
+                    blocks.clone(),
+                    results.as_ptr(),
+                    is_dead,
+                )
+            }
+            StorageType::Plain => bulk_delete_for_storage::<PlainStorage, _>(
+                &index,
+                blocks.clone(),
+                results.as_ptr(),
+                is_dead,
+            ),
+        }
+
+        (*table_am).index_fetch_end.unwrap()(fetch);
+        pg_sys::ExecDropSingleTupleTableSlot(slot);
+    }
+
+    let meta_page = MetaPage::fetch(&index);
+    let repaired = if !repair_neighbors || !meta_page.get_index_type().has_graph() {
+        0
+    } else {
+        match meta_page.get_storage_type() {
+            StorageType::Plain => {
+                let storage =
+                    PlainStorage::load_for_insert(&index, &heap, meta_page.get_distance_function());
+                repair_neighbor_lists(&storage, &index, &meta_page, blocks)
+            }
+            StorageType::SbqSpeedup | StorageType::SbqCompression => {
+                let mut stats = PruneNeighborStats::new();
+                let storage =
+                    SbqSpeedupStorage::load_for_insert(&heap, &index, &meta_page, &mut stats);
+                repair_neighbor_lists(&storage, &index, &meta_page, blocks)
+            }
+        }
+    };
+
+    TableIterator::once((results.tuples_removed as i64, repaired))
+}
+
 #[cfg(any(test, feature = "pg_test"))]
 #[pgrx::pg_schema]
 pub mod tests {
@@ -355,6 +561,261 @@ pub mod tests {
         client.execute("DROP TABLE test_vac_full", &[]).unwrap();
     }
 
+    #[cfg(test)]
+    static VAC_DELETED_RATIO_MUTEX: once_cell::sync::Lazy<std::sync::Mutex<()>> =
+        once_cell::sync::Lazy::new(std::sync::Mutex::default);
+
+    #[test]
+    fn test_high_delete_ratio() {
+        //do not run this test in parallel
+        let _lock = VAC_DELETED_RATIO_MUTEX.lock().unwrap();
+
+        //see test_delete_vacuum_plain_scaffold for why we bring up the db this way
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        let (mut client, _) = pgrx_tests::client().unwrap();
+
+        client
+            .batch_execute(
+                "CREATE TABLE test_vac_ratio(id INT GENERATED ALWAYS AS IDENTITY, embedding vector(64));
+
+        select setseed(0.5);
+        INSERT INTO test_vac_ratio (embedding)
+        SELECT
+         *
+        FROM (
+            SELECT
+        ('[ ' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+        FROM
+         generate_series(1, 64 * 400) i
+        GROUP BY
+        i % 400) g;
+
+        CREATE INDEX idxtest_vac_ratio
+              ON test_vac_ratio
+           USING diskann(embedding);
+
+        DELETE FROM test_vac_ratio WHERE id % 10 != 0;
+            ",
+            )
+            .unwrap();
+
+        client.close().unwrap();
+
+        //vacuum marks the deleted nodes in the graph
+        let (mut client, _) = pgrx_tests::client().unwrap();
+        client.execute("VACUUM test_vac_ratio", &[]).unwrap();
+
+        client.execute("set enable_seqscan = 0;", &[]).unwrap();
+        let query = "WITH cte as (select * from test_vac_ratio order by embedding <=> (SELECT embedding FROM test_vac_ratio WHERE id = 10)) SELECT count(*) from cte;";
+
+        //90% of the nodes are deleted: the scan warns, and still walks through them all
+        let cnt: i64 = client.query_one(query, &[]).unwrap().get(0);
+        assert_eq!(cnt, 40, "count with a high deleted ratio");
+
+        client.execute("DROP INDEX idxtest_vac_ratio", &[]).unwrap();
+        client.execute("DROP TABLE test_vac_ratio", &[]).unwrap();
+    }
+
+    #[cfg(test)]
+    static VAC_BRIDGE_DEPTH_MUTEX: once_cell::sync::Lazy<std::sync::Mutex<()>> =
+        once_cell::sync::Lazy::new(std::sync::Mutex::default);
+
+    #[test]
+    fn test_deleted_bridge_depth() {
+        //do not run this test in parallel
+        let _lock = VAC_BRIDGE_DEPTH_MUTEX.lock().unwrap();
+
+        //see test_delete_vacuum_plain_scaffold for why we bring up the db this way
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        for index_options in [
+            "storage_layout = plain",
+            "storage_layout = memory_optimized",
+            "storage_layout = memory_optimized, exact_traversal = true",
+        ] {
+            let (mut client, _) = pgrx_tests::client().unwrap();
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE test_vac_bridge(id INT GENERATED ALWAYS AS IDENTITY, embedding vector(64));
+
+            select setseed(0.5);
+            INSERT INTO test_vac_bridge (embedding)
+            SELECT
+             *
+            FROM (
+                SELECT
+            ('[ ' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+            FROM
+             generate_series(1, 64 * 400) i
+            GROUP BY
+            i % 400) g;
+
+            CREATE INDEX idxtest_vac_bridge
+                  ON test_vac_bridge
+               USING diskann(embedding)
+                WITH ({index_options}, num_neighbors = 10);
+
+            DELETE FROM test_vac_bridge WHERE id % 10 != 0;"
+                ))
+                .unwrap();
+            client.close().unwrap();
+
+            //vacuum marks the deleted nodes, which leaves long chains of them in the graph
+            let (mut client, _) = pgrx_tests::client().unwrap();
+            client.execute("VACUUM test_vac_bridge", &[]).unwrap();
+
+            client.execute("set enable_seqscan = 0;", &[]).unwrap();
+            let query = "WITH cte as (select * from test_vac_bridge order by embedding <=> (SELECT embedding FROM test_vac_bridge WHERE id = 10)) SELECT count(*) from cte;";
+            let mut count = |depth: i32| -> i64 {
+                client
+                    .execute(&format!("set diskann.deleted_bridge_depth = {depth};"), &[])
+                    .unwrap();
+                client.query_one(query, &[]).unwrap().get(0)
+            };
+
+            //no limit: every live row is reachable through the deleted nodes
+            assert_eq!(count(-1), 40, "{}", index_options);
+
+            //never going through deleted nodes cuts off most of the live rows
+            let cnt_0 = count(0);
+            let cnt_1 = count(1);
+            assert!(cnt_0 < 40, "{}: {}", index_options, cnt_0);
+            assert!(
+                cnt_0 <= cnt_1 && cnt_1 <= 40,
+                "{}: {} {}",
+                index_options,
+                cnt_0,
+                cnt_1
+            );
+
+            client.execute("DROP TABLE test_vac_bridge", &[]).unwrap();
+        }
+    }
+
+    #[cfg(test)]
+    static VAC_RANGE_MUTEX: once_cell::sync::Lazy<std::sync::Mutex<()>> =
+        once_cell::sync::Lazy::new(std::sync::Mutex::default);
+
+    #[test]
+    fn test_tsv_vacuum_range() {
+        //do not run this test in parallel
+        let _lock = VAC_RANGE_MUTEX.lock().unwrap();
+
+        //see test_delete_vacuum_plain_scaffold for why we bring up the db this way
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        for storage_layout in ["plain", "memory_optimized"] {
+            let (mut client, _) = pgrx_tests::client().unwrap();
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE test_vac_range(id INT GENERATED ALWAYS AS IDENTITY, embedding vector(64));
+
+            select setseed(0.5);
+            INSERT INTO test_vac_range (embedding)
+            SELECT
+             *
+            FROM (
+                SELECT
+            ('[ ' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+            FROM
+             generate_series(1, 64 * 400) i
+            GROUP BY
+            i % 400) g;
+
+            CREATE INDEX idxtest_vac_range
+                  ON test_vac_range
+               USING diskann(embedding)
+                WITH (storage_layout = {storage_layout});
+
+            DELETE FROM test_vac_range WHERE id % 10 != 0;"
+                ))
+                .unwrap();
+            client.close().unwrap();
+
+            //once the deletes are committed, the ranges together mark every deleted row
+            let (mut client, _) = pgrx_tests::client().unwrap();
+            let nblocks: i64 = client
+                .query_one(
+                    "SELECT pg_relation_size('idxtest_vac_range') / current_setting('block_size')::bigint",
+                    &[],
+                )
+                .unwrap()
+                .get(0);
+            let mid = nblocks / 2;
+            let (first_deleted, first_repaired): (i64, i64) = {
+                let row = client
+                    .query_one(
+                        "SELECT nodes_deleted, neighbor_lists_repaired FROM tsv_vacuum_range('idxtest_vac_range'::regclass, 0, $1, true)",
+                        &[&mid],
+                    )
+                    .unwrap();
+                (row.get(0), row.get(1))
+            };
+            assert!(
+                first_deleted > 0 && first_deleted < 360,
+                "{}",
+                first_deleted
+            );
+            assert!(first_repaired > 0);
+
+            let rest_deleted: i64 = client
+                .query_one(
+                    "SELECT nodes_deleted FROM tsv_vacuum_range('idxtest_vac_range'::regclass, $1, $2, true)",
+                    &[&mid, &(nblocks + 10)],
+                )
+                .unwrap()
+                .get(0);
+            assert_eq!(first_deleted + rest_deleted, 360, "{}", storage_layout);
+
+            //a second pass only repairs the lists that pointed to nodes of the other range
+            let second_deleted: i64 = client
+                .query_one(
+                    "SELECT nodes_deleted FROM tsv_vacuum_range('idxtest_vac_range'::regclass, 0, $1, true)",
+                    &[&nblocks],
+                )
+                .unwrap()
+                .get(0);
+            assert_eq!(second_deleted, 0);
+
+            //after which there is nothing left to do
+            let row = client
+                .query_one(
+                    "SELECT nodes_deleted, neighbor_lists_repaired FROM tsv_vacuum_range('idxtest_vac_range'::regclass, 0, $1, true)",
+                    &[&nblocks],
+                )
+                .unwrap();
+            assert_eq!((row.get::<_, i64>(0), row.get::<_, i64>(1)), (0, 0));
+
+            client.execute("set enable_seqscan = 0;", &[]).unwrap();
+            let cnt: i64 = client
+                .query_one(
+                    "WITH cte as (select * from test_vac_range order by embedding <=> (SELECT embedding FROM test_vac_range WHERE id = 10)) SELECT count(*) from cte;",
+                    &[],
+                )
+                .unwrap()
+                .get(0);
+            assert_eq!(cnt, 40, "{}", storage_layout);
+
+            client.execute("DROP TABLE test_vac_range", &[]).unwrap();
+        }
+    }
+
     #[pg_test]
     ///This function is only a mock to bring up the test framewokr in test_delete_vacuum
     fn test_delete_mock_fn() -> spi::Result<()> {
@@ -110,6 +110,16 @@ impl ItemPointer {
         page.get_item_unchecked(self.offset)
     }
 
+    /// Like read_bytes, but errors out unless the item is on a page of type `page_type`.
+    pub unsafe fn read_bytes_of_type(
+        self,
+        index: &PgRelation,
+        page_type: page::PageType,
+    ) -> ReadableBuffer {
+        let page = ReadablePage::read(index, self.block_number);
+        page.get_item_of_type(index, self, page_type)
+    }
+
     pub unsafe fn modify_bytes(self, index: &PgRelation) -> WritableBuffer {
         let page = WritablePage::modify(index, self.block_number);
         let item_id = PageGetItemId(*page, self.offset);
@@ -10,8 +10,8 @@ use std::ops::Deref;
 
 use super::{
     buffer::{LockedBufferExclusive, LockedBufferShare},
-    ports::{PageGetItem, PageGetItemId},
-    ReadableBuffer,
+    ports::{PageGetItem, PageGetItemId, PageGetMaxOffsetNumber},
+    ItemPointer, ReadableBuffer,
 };
 pub struct WritablePage<'a> {
     buffer: LockedBufferExclusive<'a>,
@@ -272,6 +272,34 @@ impl<'a> ReadablePage<'a> {
             len: len as _,
         }
     }
+
+    /// Like get_item_unchecked, but errors out unless this is a page of type `page_type`
+    /// with an item at the offset of `item`, which a pointer from a corrupted index may not
+    /// point to.
+    pub unsafe fn get_item_of_type(
+        self,
+        index: &PgRelation,
+        item: ItemPointer,
+        page_type: PageType,
+    ) -> ReadableBuffer<'a> {
+        let opaque_data = &*TsvPageOpaqueData::with_page(self.page);
+        let has_item = item.offset >= 1
+            && item.offset as usize <= PageGetMaxOffsetNumber(self.page)
+            && (*PageGetItemId(self.page, item.offset)).lp_flags() == pg_sys::LP_NORMAL;
+        if opaque_data.page_id != TSV_PAGE_ID
+            || opaque_data.page_type != page_type as u8
+            || !has_item
+        {
+            error!(
+                "diskann index \"{}\" is corrupted: item ({},{}) is not on a {:?} page, the index must be rebuilt",
+                index.name(),
+                item.block_number,
+                item.offset,
+                page_type
+            );
+        }
+        self.get_item_unchecked(item.offset)
+    }
 }
 
 impl<'a> Deref for ReadablePage<'a> {
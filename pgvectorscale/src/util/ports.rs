@@ -119,3 +119,58 @@ pub unsafe fn slot_getattr(
     }
     return Some(*slot.tts_values.add(index));
 }
+
+/// Drops all the buffers of the main fork of `rel` from shared buffers, without writing them.
+/// Wraps the buffer manager function, whose name and signature change across versions.
+///
+/// # Safety
+///
+/// Dirty buffers are discarded, so the caller must flush them first and hold a lock that keeps
+/// other backends from reading the relation in the meantime.
+#[cfg(any(feature = "pg12"))]
+pub unsafe fn DropRelationMainForkBuffers(rel: pg_sys::Relation) {
+    let rnode = pg_sys::RelFileNodeBackend {
+        node: (*rel).rd_node,
+        backend: (*rel).rd_backend,
+    };
+    pg_sys::DropRelFileNodeBuffers(rnode, pg_sys::ForkNumber_MAIN_FORKNUM, 0);
+}
+
+#[cfg(any(feature = "pg13"))]
+pub unsafe fn DropRelationMainForkBuffers(rel: pg_sys::Relation) {
+    let rnode = pg_sys::RelFileNodeBackend {
+        node: (*rel).rd_node,
+        backend: (*rel).rd_backend,
+    };
+    let mut fork = pg_sys::ForkNumber_MAIN_FORKNUM;
+    let mut first_block: pg_sys::BlockNumber = 0;
+    pg_sys::DropRelFileNodeBuffers(rnode, &mut fork, 1, &mut first_block);
+}
+
+#[cfg(any(feature = "pg14", feature = "pg15"))]
+pub unsafe fn DropRelationMainForkBuffers(rel: pg_sys::Relation) {
+    let smgr = pg_sys::smgropen((*rel).rd_node, (*rel).rd_backend);
+    let mut fork = pg_sys::ForkNumber_MAIN_FORKNUM;
+    let mut first_block: pg_sys::BlockNumber = 0;
+    pg_sys::DropRelFileNodeBuffers(smgr, &mut fork, 1, &mut first_block);
+}
+
+#[cfg(any(feature = "pg16"))]
+pub unsafe fn DropRelationMainForkBuffers(rel: pg_sys::Relation) {
+    let smgr = pg_sys::smgropen((*rel).rd_locator, (*rel).rd_backend);
+    let mut fork = pg_sys::ForkNumber_MAIN_FORKNUM;
+    let mut first_block: pg_sys::BlockNumber = 0;
+    pg_sys::DropRelationBuffers(smgr, &mut fork, 1, &mut first_block);
+}
+
+/// Whether `roleid` owns the relation `class_oid`. Wraps the ownership check, which is generic
+/// over the catalog from PG 16 on.
+#[cfg(any(feature = "pg12", feature = "pg13", feature = "pg14", feature = "pg15"))]
+pub unsafe fn pg_class_ownercheck(class_oid: pg_sys::Oid, roleid: pg_sys::Oid) -> bool {
+    pg_sys::pg_class_ownercheck(class_oid, roleid)
+}
+
+#[cfg(any(feature = "pg16"))]
+pub unsafe fn pg_class_ownercheck(class_oid: pg_sys::Oid, roleid: pg_sys::Oid) -> bool {
+    pg_sys::object_ownercheck(pg_sys::RelationRelationId, class_oid, roleid)
+}
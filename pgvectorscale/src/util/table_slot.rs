@@ -39,6 +39,47 @@ impl TableSlot {
         Self { slot }
     }
 
+    /// Returns the version of the row visible to `snapshot`, or None if there is none. Follows
+    /// the HOT chain from `heap_pointer` like an index scan does, so it finds the row after
+    /// updates that didn't touch the indexed column.
+    pub unsafe fn new_visible<S: StatsHeapNodeRead>(
+        heap_rel: &PgRelation,
+        heap_pointer: HeapPointer,
+        snapshot: pg_sys::Snapshot,
+        stats: &mut S,
+    ) -> Option<Self> {
+        let slot = PgBox::from_pg(pg_sys::table_slot_create(
+            heap_rel.as_ptr(),
+            std::ptr::null_mut(),
+        ));
+
+        let table_am = heap_rel.rd_tableam;
+        let fetch = (*table_am).index_fetch_begin.unwrap()(heap_rel.as_ptr());
+        let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
+            ..Default::default()
+        };
+        heap_pointer.to_item_pointer_data(&mut ctid);
+        let mut call_again = false;
+        let mut all_dead = false;
+        let found = (*table_am).index_fetch_tuple.unwrap()(
+            fetch,
+            &mut ctid,
+            snapshot,
+            slot.as_ptr(),
+            &mut call_again,
+            &mut all_dead,
+        );
+        (*table_am).index_fetch_end.unwrap()(fetch);
+        stats.record_heap_read();
+
+        let slot = Self { slot };
+        if found {
+            Some(slot)
+        } else {
+            None
+        }
+    }
+
     pub unsafe fn get_attribute(&self, attribute_number: pg_sys::AttrNumber) -> Option<Datum> {
         slot_getattr(&self.slot, attribute_number)
     }